@@ -0,0 +1,429 @@
+use super::{recovery::DirtyTable, TxnEntry};
+use crate::sql::{tx::TxnID, SqlResult};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryInto,
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+/// Log sequence number, the position of a record in the log. Records are laid out back to
+/// back across segments, so a record is found at its offset in the segment starting at the
+/// greatest LSN not after it
+pub type LSN = u64;
+
+/// Length prefix of each record in the log file
+const LEN_SIZE: u64 = 4;
+
+/// Segments are started once the current one would grow past this size
+pub const DEFAULT_SEGMENT_SIZE: u64 = 1 << 20;
+
+const MASTER: &str = "master";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LogRecord {
+    /// Previous record of the same txn, None for its Begin record and checkpoint records
+    pub prev_lsn: Option<LSN>,
+    /// 0 for checkpoint records
+    pub txn: TxnID,
+    pub body: LogBody,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum LogBody {
+    Begin,
+    /// A write of key, images are None when the key does not exist
+    Update {
+        key: Vec<u8>,
+        before: Option<Vec<u8>>,
+        after: Option<Vec<u8>>,
+    },
+    /// Compensation log record, written when an update is undone. CLRs are only redone,
+    /// undo_next is the next record of the txn to undo
+    Clr {
+        key: Vec<u8>,
+        after: Option<Vec<u8>>,
+        undo_next: Option<LSN>,
+    },
+    Commit,
+    Abort,
+    /// The txn is finished and will not appear in the log anymore
+    End,
+    BeginCheckpoint,
+    /// Transaction and dirty tables as of the matching BeginCheckpoint record
+    EndCheckpoint {
+        txns: HashMap<TxnID, TxnEntry>,
+        dirty: DirtyTable,
+        next_txn: TxnID,
+    },
+}
+
+/// Append only log kept in a directory of segment files named after their first LSN,
+/// along with the master record. Appended records are buffered in memory until flushed,
+/// a record is durable once [Wal::flushed_lsn] is past it
+pub struct Wal {
+    dir: PathBuf,
+    segments: BTreeMap<LSN, File>,
+    segment_size: u64,
+    /// First LSN of the segment appended records go to
+    segment_start: LSN,
+    /// Segments started since the last flush, their files are created by the flush
+    pending: Vec<LSN>,
+    /// Records appended since the last flush
+    tail: Vec<u8>,
+    /// LSN of the next appended record
+    next_lsn: LSN,
+    /// Records before this LSN are in the segment files
+    flushed_lsn: LSN,
+    /// BeginCheckpoint record of the last complete checkpoint
+    master: Option<LSN>,
+}
+
+impl Wal {
+    /// Opens the log in dir. A record torn by a crash while it was written is cut off
+    pub fn open<P: AsRef<Path>>(dir: P) -> SqlResult<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).map_err(|e| format!("failed creating log dir: {}", e))?;
+        let mut segments = BTreeMap::new();
+        for entry in fs::read_dir(&dir).map_err(|e| format!("failed listing log dir: {}", e))? {
+            let path = entry
+                .map_err(|e| format!("failed listing log dir: {}", e))?
+                .path();
+            if path.extension().map_or(true, |ext| ext != "log") {
+                continue;
+            }
+            let start = path
+                .file_stem()
+                .and_then(|stem| stem.to_str()?.parse::<LSN>().ok())
+                .ok_or_else(|| format!("invalid log segment {:?}", path))?;
+            segments.insert(start, open_segment(&path)?);
+        }
+        if segments.is_empty() {
+            segments.insert(0, open_segment(&segment_path(&dir, 0))?);
+        }
+
+        // only the last segment can be torn, a segment is started after the previous one
+        // is synced
+        let (start, file) = segments.iter_mut().next_back().unwrap();
+        let start = *start;
+        let mut buf = vec![];
+        file.read_to_end(&mut buf)
+            .map_err(|e| format!("failed reading log file: {}", e))?;
+        let mut end = 0;
+        while let Some((_, size)) = decode(&buf[end..]) {
+            end += size;
+        }
+        if end < buf.len() {
+            file.set_len(end as u64)
+                .map_err(|e| format!("failed truncating log file: {}", e))?;
+        }
+
+        let master = match fs::read(dir.join(MASTER)) {
+            Ok(buf) => Some(u64::from_be_bytes(buf.as_slice().try_into()?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(format!("failed reading master record: {}", e).into()),
+        };
+        let next_lsn = start + end as LSN;
+        Ok(Wal {
+            dir,
+            segments,
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            segment_start: start,
+            pending: vec![],
+            tail: vec![],
+            next_lsn,
+            flushed_lsn: next_lsn,
+            master,
+        })
+    }
+
+    pub fn with_segment_size(mut self, size: u64) -> Self {
+        self.segment_size = size;
+        self
+    }
+
+    /// Buffers the record and returns its LSN
+    pub fn append(&mut self, record: &LogRecord) -> SqlResult<LSN> {
+        let payload = bincode::serialize(record)
+            .map_err(|e| format!("failed serializing log record: {}", e))?;
+        let lsn = self.next_lsn;
+        let size = LEN_SIZE + payload.len() as u64;
+        if lsn > self.segment_start && lsn - self.segment_start + size > self.segment_size {
+            self.segment_start = lsn;
+            self.pending.push(lsn);
+        }
+        self.tail
+            .extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        self.tail.extend_from_slice(&payload);
+        self.next_lsn += size;
+        Ok(lsn)
+    }
+
+    /// Makes the records up to and including the record at lsn durable
+    pub fn flush(&mut self, lsn: LSN) -> SqlResult<()> {
+        if lsn < self.flushed_lsn || self.tail.is_empty() {
+            return Ok(());
+        }
+        let mut pos = self.flushed_lsn;
+        for start in std::mem::take(&mut self.pending) {
+            self.write_segment(pos, start)?;
+            let file = open_segment(&segment_path(&self.dir, start))?;
+            self.segments.insert(start, file);
+            pos = start;
+        }
+        self.write_segment(pos, self.next_lsn)?;
+        self.tail.clear();
+        self.flushed_lsn = self.next_lsn;
+        Ok(())
+    }
+
+    /// Writes the buffered records from start to end to the last segment
+    fn write_segment(&mut self, start: LSN, end: LSN) -> SqlResult<()> {
+        let tail =
+            &self.tail[(start - self.flushed_lsn) as usize..(end - self.flushed_lsn) as usize];
+        let (segment, file) = self.segments.iter_mut().next_back().unwrap();
+        file.seek(SeekFrom::Start(start - segment))
+            .map_err(|e| format!("failed seeking log file: {}", e))?;
+        file.write_all(tail)
+            .map_err(|e| format!("failed writing log file: {}", e))?;
+        file.sync_data()
+            .map_err(|e| format!("failed syncing log file: {}", e))?;
+        Ok(())
+    }
+
+    pub fn flushed_lsn(&self) -> LSN {
+        self.flushed_lsn
+    }
+
+    pub fn next_lsn(&self) -> LSN {
+        self.next_lsn
+    }
+
+    /// LSN of the oldest record kept by the log
+    pub fn first_lsn(&self) -> LSN {
+        *self.segments.keys().next().unwrap()
+    }
+
+    /// BeginCheckpoint record of the last complete checkpoint, recovery starts from it
+    pub fn master(&self) -> Option<LSN> {
+        self.master
+    }
+
+    /// Points the master record to the checkpoint at lsn, whose records must be durable
+    pub fn set_master(&mut self, lsn: LSN) -> SqlResult<()> {
+        // the record is replaced at once by renaming
+        let tmp = self.dir.join(format!("{}.tmp", MASTER));
+        let mut file =
+            File::create(&tmp).map_err(|e| format!("failed creating master record: {}", e))?;
+        file.write_all(&lsn.to_be_bytes())
+            .and_then(|_| file.sync_all())
+            .map_err(|e| format!("failed writing master record: {}", e))?;
+        fs::rename(&tmp, self.dir.join(MASTER))
+            .map_err(|e| format!("failed writing master record: {}", e))?;
+        self.master = Some(lsn);
+        Ok(())
+    }
+
+    /// Removes the segments whose records are all before lsn, the last segment is kept
+    pub fn truncate(&mut self, lsn: LSN) -> SqlResult<()> {
+        let starts = self.segments.keys().copied().collect::<Vec<_>>();
+        for pair in starts.windows(2) {
+            if pair[1] > lsn {
+                break;
+            }
+            self.segments.remove(&pair[0]);
+            fs::remove_file(segment_path(&self.dir, pair[0]))
+                .map_err(|e| format!("failed removing log segment: {}", e))?;
+        }
+        Ok(())
+    }
+
+    /// Reads the record at lsn and the LSN of the record following it
+    pub fn read(&mut self, lsn: LSN) -> SqlResult<(LogRecord, LSN)> {
+        if lsn < self.first_lsn() {
+            return Err(format!("log record {} is truncated", lsn).into());
+        }
+        let decoded = if lsn >= self.flushed_lsn {
+            let offset = (lsn - self.flushed_lsn) as usize;
+            decode(self.tail.get(offset..).unwrap_or_default())
+        } else {
+            let (segment, file) = self.segments.range_mut(..=lsn).next_back().unwrap();
+            let mut len = [0; LEN_SIZE as usize];
+            file.seek(SeekFrom::Start(lsn - segment))
+                .and_then(|_| file.read_exact(&mut len))
+                .map_err(|e| format!("failed reading log record {}: {}", lsn, e))?;
+            let mut buf = len.to_vec();
+            buf.resize(LEN_SIZE as usize + u32::from_be_bytes(len) as usize, 0);
+            file.read_exact(&mut buf[LEN_SIZE as usize..])
+                .map_err(|e| format!("failed reading log record {}: {}", lsn, e))?;
+            decode(&buf)
+        };
+        match decoded {
+            Some((record, size)) => Ok((record, lsn + size as u64)),
+            None => Err(format!("no log record at {}", lsn).into()),
+        }
+    }
+
+    /// Iterates the records from lsn to the end of the log, including the ones not flushed
+    pub fn iter_from(&mut self, lsn: LSN) -> WalIter {
+        WalIter { wal: self, lsn }
+    }
+}
+
+fn segment_path(dir: &Path, start: LSN) -> PathBuf {
+    dir.join(format!("{:020}.log", start))
+}
+
+fn open_segment(path: &Path) -> SqlResult<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(path)
+        .map_err(|e| format!("failed opening log file: {}", e).into())
+}
+
+/// Decodes the record at the start of buf and its size, None if buf does not start with
+/// a whole record
+fn decode(buf: &[u8]) -> Option<(LogRecord, usize)> {
+    let len = u32::from_be_bytes(buf.get(..LEN_SIZE as usize)?.try_into().ok()?) as usize;
+    let size = LEN_SIZE as usize + len;
+    let record = bincode::deserialize(buf.get(LEN_SIZE as usize..size)?).ok()?;
+    Some((record, size))
+}
+
+pub struct WalIter<'a> {
+    wal: &'a mut Wal,
+    lsn: LSN,
+}
+
+impl<'a> Iterator for WalIter<'a> {
+    type Item = SqlResult<(LSN, LogRecord)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.lsn >= self.wal.next_lsn {
+            return None;
+        }
+        let lsn = self.lsn;
+        match self.wal.read(lsn) {
+            Ok((record, next)) => {
+                self.lsn = next;
+                Some(Ok((lsn, record)))
+            }
+            Err(e) => {
+                self.lsn = self.wal.next_lsn;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{segment_path, LogBody, LogRecord, Wal};
+    use std::{fs::OpenOptions, io::Write};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_wal() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path();
+        let mut wal = Wal::open(path).unwrap();
+        let begin = LogRecord {
+            prev_lsn: None,
+            txn: 1,
+            body: LogBody::Begin,
+        };
+        let lsn1 = wal.append(&begin).unwrap();
+        let update = LogRecord {
+            prev_lsn: Some(lsn1),
+            txn: 1,
+            body: LogBody::Update {
+                key: b"a".to_vec(),
+                before: None,
+                after: Some(b"1".to_vec()),
+            },
+        };
+        let lsn2 = wal.append(&update).unwrap();
+        assert!(lsn1 < lsn2);
+        assert_eq!(0, wal.flushed_lsn());
+        // records not flushed are read from the tail
+        assert_eq!(update, wal.read(lsn2).unwrap().0);
+
+        wal.flush(lsn1).unwrap();
+        assert_eq!(wal.next_lsn(), wal.flushed_lsn());
+        let commit = LogRecord {
+            prev_lsn: Some(lsn2),
+            txn: 1,
+            body: LogBody::Commit,
+        };
+        let lsn3 = wal.append(&commit).unwrap();
+        let records = wal.iter_from(lsn2).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(vec![(lsn2, update.clone()), (lsn3, commit)], records);
+
+        // the commit record was not flushed and is lost
+        drop(wal);
+        let mut wal = Wal::open(path).unwrap();
+        assert_eq!(lsn3, wal.next_lsn());
+        assert_eq!(begin, wal.read(lsn1).unwrap().0);
+        assert_eq!(update, wal.read(lsn2).unwrap().0);
+        assert!(wal.read(lsn3).is_err());
+
+        // a torn record is cut off on open
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(segment_path(path, 0))
+            .unwrap();
+        file.write_all(&[0, 0, 0, 10, 1, 2]).unwrap();
+        drop(file);
+        let mut wal = Wal::open(path).unwrap();
+        assert_eq!(lsn3, wal.next_lsn());
+        assert_eq!(2, wal.iter_from(0).count());
+    }
+
+    #[test]
+    fn test_wal_segments() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path();
+        let mut wal = Wal::open(path).unwrap().with_segment_size(64);
+        let lsns = (0..20)
+            .map(|i| {
+                wal.append(&LogRecord {
+                    prev_lsn: None,
+                    txn: i,
+                    body: LogBody::Begin,
+                })
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+        wal.flush(lsns[19]).unwrap();
+        let segments = || std::fs::read_dir(path).unwrap().count();
+        assert!(segments() > 2);
+        assert_eq!(None, wal.master());
+        wal.set_master(lsns[10]).unwrap();
+
+        // records are found across segments after a restart
+        drop(wal);
+        let mut wal = Wal::open(path).unwrap().with_segment_size(64);
+        assert_eq!(Some(lsns[10]), wal.master());
+        let txns = wal
+            .iter_from(0)
+            .map(|item| item.unwrap().1.txn)
+            .collect::<Vec<_>>();
+        assert_eq!((0..20).collect::<Vec<_>>(), txns);
+
+        let before = segments();
+        wal.truncate(lsns[10]).unwrap();
+        assert!(segments() < before);
+        assert!(wal.first_lsn() > lsns[0] && wal.first_lsn() <= lsns[10]);
+        assert!(wal.read(lsns[0]).is_err());
+        assert_eq!(10, wal.read(lsns[10]).unwrap().0.txn);
+        let first = wal.first_lsn();
+        drop(wal);
+        let mut wal = Wal::open(path).unwrap();
+        assert_eq!(first, wal.first_lsn());
+        assert_eq!(19, wal.iter_from(lsns[10]).last().unwrap().unwrap().1.txn);
+    }
+}
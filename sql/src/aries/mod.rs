@@ -0,0 +1,485 @@
+//! Write-ahead logging and crash recovery following ARIES. Writes go to an in-memory cache
+//! of the store that may be flushed at any time, also with the writes of txns that have
+//! not committed (steal), and commits only force the log (no force). After a crash
+//! [Aries::open] repeats history from the log and rolls back the txns that did not
+//! commit. Isolation is left to the lock manager, reads see the latest write.
+//!
+//! Checkpoints are fuzzy: they log the transaction and dirty tables without flushing
+//! anything, recovery starts from the last one and the log before the oldest record
+//! recovery may need can be truncated
+use self::log::{LogBody, LogRecord, Wal, LSN};
+use crate::{
+    sql::{tx::TxnID, Error, SqlResult},
+    storage::mvcc::Store,
+};
+use parking_lot::Mutex;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryInto,
+};
+
+pub mod log;
+pub mod recovery;
+
+/// Transactions and the state of the store, guarded by one lock as every operation appends
+/// to the log
+pub struct Aries {
+    inner: Mutex<Inner>,
+}
+
+pub(crate) struct Inner {
+    wal: Wal,
+    store: Box<dyn Store + Send>,
+    /// Writes not flushed to the store yet, keyed by the written key
+    dirty: BTreeMap<Vec<u8>, Page>,
+    txns: HashMap<TxnID, TxnEntry>,
+    next_txn: TxnID,
+}
+
+/// A key written since the store was last flushed
+pub(crate) struct Page {
+    value: Option<Vec<u8>>,
+    /// LSN of the latest record applied to the key
+    page_lsn: LSN,
+    /// LSN of the first record that made the key dirty, redo starts from the smallest one
+    rec_lsn: LSN,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum TxnStatus {
+    Running,
+    Committed,
+    Aborting,
+}
+
+/// An entry of the transaction table
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TxnEntry {
+    pub status: TxnStatus,
+    /// Begin record of the txn, the log is kept from it until the txn ends
+    pub first_lsn: LSN,
+    /// Latest record of the txn, the prevLSN of its next record
+    pub last_lsn: LSN,
+    /// Next record to undo if the txn rolls back
+    pub undo_next: Option<LSN>,
+}
+
+impl Aries {
+    /// Recovers the store from the log
+    pub fn open(wal: Wal, store: Box<dyn Store + Send>) -> SqlResult<Self> {
+        let mut inner = Inner {
+            wal,
+            store,
+            dirty: BTreeMap::new(),
+            txns: HashMap::new(),
+            next_txn: 1,
+        };
+        inner.recover()?;
+        Ok(Aries {
+            inner: Mutex::new(inner),
+        })
+    }
+
+    pub fn begin(&self) -> SqlResult<TxnID> {
+        let mut inner = self.inner.lock();
+        let txn = inner.next_txn;
+        inner.next_txn += 1;
+        let lsn = inner.wal.append(&LogRecord {
+            prev_lsn: None,
+            txn,
+            body: LogBody::Begin,
+        })?;
+        inner.txns.insert(
+            txn,
+            TxnEntry {
+                status: TxnStatus::Running,
+                first_lsn: lsn,
+                last_lsn: lsn,
+                undo_next: None,
+            },
+        );
+        Ok(txn)
+    }
+
+    pub fn get(&self, key: &[u8]) -> SqlResult<Option<Vec<u8>>> {
+        self.inner.lock().get(key)
+    }
+
+    pub fn set(&self, txn: TxnID, key: &[u8], value: Vec<u8>) -> SqlResult<()> {
+        self.inner.lock().write(txn, key, Some(value))
+    }
+
+    pub fn delete(&self, txn: TxnID, key: &[u8]) -> SqlResult<()> {
+        self.inner.lock().write(txn, key, None)
+    }
+
+    /// Commits the txn once its commit record is durable, its writes may still be only in
+    /// memory
+    pub fn commit(&self, txn: TxnID) -> SqlResult<()> {
+        let mut inner = self.inner.lock();
+        let lsn = inner.append(txn, LogBody::Commit)?;
+        inner.wal.flush(lsn)?;
+        inner.txn_mut(txn)?.status = TxnStatus::Committed;
+        inner.end(txn)
+    }
+
+    /// Rolls back the writes of the txn
+    pub fn abort(&self, txn: TxnID) -> SqlResult<()> {
+        let mut inner = self.inner.lock();
+        inner.append(txn, LogBody::Abort)?;
+        inner.txn_mut(txn)?.status = TxnStatus::Aborting;
+        inner.undo(vec![txn])
+    }
+
+    /// Writes the dirty keys to the store, the log is flushed first up to the latest
+    /// record applied to them
+    pub fn flush(&self) -> SqlResult<()> {
+        self.inner.lock().flush_pages()
+    }
+
+    /// Takes a fuzzy checkpoint and points the master record to it, returns the LSN of its
+    /// BeginCheckpoint record
+    pub fn checkpoint(&self) -> SqlResult<LSN> {
+        self.inner.lock().checkpoint()
+    }
+
+    /// Removes the log segments recovery no longer needs: the ones before the last
+    /// checkpoint, the oldest recLSN of the dirty keys and the first record of the active
+    /// txns. Nothing is removed before the first checkpoint
+    pub fn truncate_log(&self) -> SqlResult<()> {
+        let mut inner = self.inner.lock();
+        let mut lsn = match inner.wal.master() {
+            Some(lsn) => lsn,
+            None => return Ok(()),
+        };
+        let rec_lsns = inner.dirty.values().map(|page| page.rec_lsn);
+        let first_lsns = inner.txns.values().map(|entry| entry.first_lsn);
+        if let Some(min) = rec_lsns.chain(first_lsns).min() {
+            lsn = lsn.min(min);
+        }
+        inner.wal.truncate(lsn)
+    }
+
+    /// Txns that have begun and not ended
+    pub fn active(&self) -> Vec<TxnID> {
+        let mut ids = self.inner.lock().txns.keys().copied().collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids
+    }
+}
+
+impl Inner {
+    fn txn_mut(&mut self, txn: TxnID) -> SqlResult<&mut TxnEntry> {
+        self.txns
+            .get_mut(&txn)
+            .ok_or_else(|| Error::NotFound(format!("transaction {} is not active", txn)))
+    }
+
+    /// Appends a record to the prevLSN chain of txn
+    fn append(&mut self, txn: TxnID, body: LogBody) -> SqlResult<LSN> {
+        let prev_lsn = self.txn_mut(txn)?.last_lsn;
+        let lsn = self.wal.append(&LogRecord {
+            prev_lsn: Some(prev_lsn),
+            txn,
+            body,
+        })?;
+        self.txn_mut(txn)?.last_lsn = lsn;
+        Ok(lsn)
+    }
+
+    fn end(&mut self, txn: TxnID) -> SqlResult<()> {
+        self.append(txn, LogBody::End)?;
+        self.txns.remove(&txn);
+        Ok(())
+    }
+
+    fn get(&self, key: &[u8]) -> SqlResult<Option<Vec<u8>>> {
+        match self.dirty.get(key) {
+            Some(page) => Ok(page.value.clone()),
+            None => Ok(self.read_page(key)?.map(|(_, value)| value)),
+        }
+    }
+
+    fn write(&mut self, txn: TxnID, key: &[u8], after: Option<Vec<u8>>) -> SqlResult<()> {
+        if self.txn_mut(txn)?.status != TxnStatus::Running {
+            return Err(Error::Abort);
+        }
+        let before = self.get(key)?;
+        let lsn = self.append(
+            txn,
+            LogBody::Update {
+                key: key.to_vec(),
+                before,
+                after: after.clone(),
+            },
+        )?;
+        self.txn_mut(txn)?.undo_next = Some(lsn);
+        self.apply(key, after, lsn);
+        Ok(())
+    }
+
+    /// Applies the record at lsn to the cached key
+    fn apply(&mut self, key: &[u8], value: Option<Vec<u8>>, lsn: LSN) {
+        let page = self.dirty.entry(key.to_vec()).or_insert(Page {
+            value: None,
+            page_lsn: lsn,
+            rec_lsn: lsn,
+        });
+        page.value = value;
+        page.page_lsn = lsn;
+    }
+
+    /// Reads the pageLSN and value of a key from the store. Stored values are prefixed by
+    /// the pageLSN, deleted keys are removed from the store
+    fn read_page(&self, key: &[u8]) -> SqlResult<Option<(LSN, Vec<u8>)>> {
+        match self.store.get(key)? {
+            Some(mut value) => {
+                if value.len() < 8 {
+                    return Err(Error::Internal(format!("invalid page of key {:?}", key)));
+                }
+                let lsn = u64::from_be_bytes(value[..8].try_into()?);
+                Ok(Some((lsn, value.split_off(8))))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn checkpoint(&mut self) -> SqlResult<LSN> {
+        let begin = self.wal.append(&LogRecord {
+            prev_lsn: None,
+            txn: 0,
+            body: LogBody::BeginCheckpoint,
+        })?;
+        let dirty = self
+            .dirty
+            .iter()
+            .map(|(key, page)| (key.clone(), page.rec_lsn))
+            .collect();
+        let end = self.wal.append(&LogRecord {
+            prev_lsn: None,
+            txn: 0,
+            body: LogBody::EndCheckpoint {
+                txns: self.txns.clone(),
+                dirty,
+                next_txn: self.next_txn,
+            },
+        })?;
+        // the checkpoint is complete once both records are durable
+        self.wal.flush(end)?;
+        self.wal.set_master(begin)?;
+        Ok(begin)
+    }
+
+    fn flush_pages(&mut self) -> SqlResult<()> {
+        let max_lsn = match self.dirty.values().map(|p| p.page_lsn).max() {
+            Some(lsn) => lsn,
+            None => return Ok(()),
+        };
+        self.wal.flush(max_lsn)?;
+        for (key, page) in std::mem::take(&mut self.dirty) {
+            match page.value {
+                Some(value) => {
+                    let mut stored = page.page_lsn.to_be_bytes().to_vec();
+                    stored.extend(value);
+                    self.store.set(&key, stored)?;
+                }
+                None => self.store.delete(&key)?,
+            }
+        }
+        self.store.flush()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{log::Wal, Aries};
+    use crate::{
+        sql::SqlResult,
+        storage::{
+            memory::Memory,
+            mvcc::{Range, Scan, Store},
+        },
+    };
+    use parking_lot::Mutex;
+    use std::{path::Path, sync::Arc};
+    use tempfile::TempDir;
+
+    /// A store outliving the engines opened on it, standing for the disk across crashes
+    #[derive(Clone, Default)]
+    pub struct Disk(Arc<Mutex<Memory>>);
+
+    impl Store for Disk {
+        fn delete(&mut self, key: &[u8]) -> SqlResult<()> {
+            self.0.lock().delete(key)
+        }
+        fn flush(&mut self) -> SqlResult<()> {
+            self.0.lock().flush()
+        }
+        fn get(&self, key: &[u8]) -> SqlResult<Option<Vec<u8>>> {
+            self.0.lock().get(key)
+        }
+        fn scan(&self, range: Range) -> Scan {
+            self.0.lock().scan(range)
+        }
+        fn set(&mut self, key: &[u8], value: Vec<u8>) -> SqlResult<()> {
+            self.0.lock().set(key, value)
+        }
+    }
+
+    impl Disk {
+        pub fn keys(&self) -> Vec<Vec<u8>> {
+            self.scan(Range::from(..))
+                .map(|item| item.unwrap().0)
+                .collect()
+        }
+    }
+
+    fn open(dir: &Path, disk: &Disk) -> Aries {
+        Aries::open(Wal::open(dir).unwrap(), Box::new(disk.clone())).unwrap()
+    }
+
+    #[test]
+    fn test_commit_abort() {
+        let dir = TempDir::new().unwrap();
+        let db = open(dir.path(), &Disk::default());
+        let t1 = db.begin().unwrap();
+        db.set(t1, b"a", b"1".to_vec()).unwrap();
+        db.set(t1, b"b", b"1".to_vec()).unwrap();
+        db.commit(t1).unwrap();
+
+        let t2 = db.begin().unwrap();
+        db.set(t2, b"a", b"2".to_vec()).unwrap();
+        db.delete(t2, b"b").unwrap();
+        db.set(t2, b"c", b"2".to_vec()).unwrap();
+        assert_eq!(Some(b"2".to_vec()), db.get(b"a").unwrap());
+        assert_eq!(None, db.get(b"b").unwrap());
+        // steal the writes of t2 before it rolls back
+        db.flush().unwrap();
+        db.abort(t2).unwrap();
+
+        assert_eq!(Some(b"1".to_vec()), db.get(b"a").unwrap());
+        assert_eq!(Some(b"1".to_vec()), db.get(b"b").unwrap());
+        assert_eq!(None, db.get(b"c").unwrap());
+        assert!(db.active().is_empty());
+        assert!(db.commit(t2).is_err());
+    }
+
+    /// Crashes while t2 and t4 are running: t1 and t3 committed, t2 wrote to disk before
+    /// the crash and t4's records never reached the log
+    #[test]
+    fn test_recovery() {
+        let dir = TempDir::new().unwrap();
+        let wal = dir.path();
+        let disk = Disk::default();
+
+        let db = open(wal, &disk);
+        let t1 = db.begin().unwrap();
+        db.set(t1, b"a", b"t1".to_vec()).unwrap();
+        db.set(t1, b"b", b"t1".to_vec()).unwrap();
+        db.commit(t1).unwrap();
+
+        let t2 = db.begin().unwrap();
+        db.set(t2, b"a", b"t2".to_vec()).unwrap();
+        db.delete(t2, b"b").unwrap();
+        db.set(t2, b"c", b"t2".to_vec()).unwrap();
+        db.flush().unwrap();
+        // t2 keeps writing after its writes were stolen
+        db.set(t2, b"a", b"t2 again".to_vec()).unwrap();
+
+        let t3 = db.begin().unwrap();
+        db.set(t3, b"d", b"t3".to_vec()).unwrap();
+        db.commit(t3).unwrap();
+
+        let t4 = db.begin().unwrap();
+        db.set(t4, b"e", b"t4".to_vec()).unwrap();
+        assert_eq!(vec![t2, t4], db.active());
+        drop(db);
+
+        // the disk has the writes of t2 but not the ones of t1 and t3 made after the flush
+        assert_eq!(vec![b"a".to_vec(), b"c".to_vec()], disk.keys(),);
+
+        let check = |db: &Aries| {
+            assert_eq!(Some(b"t1".to_vec()), db.get(b"a").unwrap());
+            assert_eq!(Some(b"t1".to_vec()), db.get(b"b").unwrap());
+            assert_eq!(None, db.get(b"c").unwrap());
+            assert_eq!(Some(b"t3".to_vec()), db.get(b"d").unwrap());
+            assert_eq!(None, db.get(b"e").unwrap());
+            assert!(db.active().is_empty());
+        };
+        let db = open(wal, &disk);
+        check(&db);
+        // ids of the txns in the log are not reused
+        let t5 = db.begin().unwrap();
+        assert!(t5 > t3);
+        db.set(t5, b"e", b"t5".to_vec()).unwrap();
+        drop(db);
+
+        // crashing again before anything is flushed recovers the same state
+        let db = open(wal, &disk);
+        check(&db);
+        db.flush().unwrap();
+        drop(db);
+        let db = open(wal, &disk);
+        check(&db);
+    }
+
+    /// Recovers from the last checkpoint after the log before it was truncated: t1 was
+    /// flushed before the checkpoint, t2 was running and t3 had dirty keys during it
+    #[test]
+    fn test_checkpoint() {
+        let dir = TempDir::new().unwrap();
+        let disk = Disk::default();
+        let open = |disk: &Disk| {
+            let wal = Wal::open(dir.path()).unwrap().with_segment_size(128);
+            Aries::open(wal, Box::new(disk.clone())).unwrap()
+        };
+        let segments = || std::fs::read_dir(dir.path()).unwrap().count();
+
+        let db = open(&disk);
+        let t1 = db.begin().unwrap();
+        for i in 0..10u8 {
+            db.set(t1, &[i], b"t1".to_vec()).unwrap();
+        }
+        db.commit(t1).unwrap();
+        db.flush().unwrap();
+
+        let t2 = db.begin().unwrap();
+        db.set(t2, b"a", b"t2".to_vec()).unwrap();
+        let t3 = db.begin().unwrap();
+        db.set(t3, b"b", b"t3".to_vec()).unwrap();
+        db.checkpoint().unwrap();
+        // the first record of t2 is kept
+        let before = segments();
+        db.truncate_log().unwrap();
+        assert!(segments() < before);
+
+        db.set(t3, b"c", b"t3".to_vec()).unwrap();
+        db.commit(t3).unwrap();
+        db.set(t2, &[0], b"t2".to_vec()).unwrap();
+        drop(db);
+
+        let check = |db: &Aries| {
+            for i in 0..10u8 {
+                assert_eq!(Some(b"t1".to_vec()), db.get(&[i]).unwrap());
+            }
+            assert_eq!(None, db.get(b"a").unwrap());
+            assert_eq!(Some(b"t3".to_vec()), db.get(b"b").unwrap());
+            assert_eq!(Some(b"t3".to_vec()), db.get(b"c").unwrap());
+            assert!(db.active().is_empty());
+        };
+        let db = open(&disk);
+        check(&db);
+        let t4 = db.begin().unwrap();
+        assert!(t4 > t3);
+
+        // once everything is flushed and checkpointed the log shrinks to the last segments
+        db.abort(t4).unwrap();
+        db.flush().unwrap();
+        db.checkpoint().unwrap();
+        db.truncate_log().unwrap();
+        assert!(segments() <= 3);
+        drop(db);
+        check(&open(&disk));
+    }
+}
@@ -0,0 +1,162 @@
+//! The three passes of recovery. Analysis rebuilds the transaction table and the dirty
+//! keys from the last checkpoint, redo repeats history from the oldest record a dirty key
+//! may miss, undo rolls back the txns that did not commit as [super::Aries::abort] does
+use super::{
+    log::{LogBody, LSN},
+    Inner, TxnEntry, TxnStatus,
+};
+use crate::sql::{tx::TxnID, SqlResult};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+/// Dirty keys found by analysis with their recLSN
+pub type DirtyTable = HashMap<Vec<u8>, LSN>;
+
+impl Inner {
+    pub(crate) fn recover(&mut self) -> SqlResult<()> {
+        let dirty = self.analysis()?;
+        self.redo(&dirty)?;
+
+        let mut losers = vec![];
+        for (txn, entry) in self.txns.clone() {
+            match entry.status {
+                // the commit record is durable, only the end record is missing
+                TxnStatus::Committed => self.end(txn)?,
+                TxnStatus::Running | TxnStatus::Aborting => losers.push(txn),
+            }
+        }
+        self.undo(losers)?;
+        let lsn = self.wal.next_lsn();
+        self.wal.flush(lsn)
+    }
+
+    /// Scans the log from the last checkpoint to find the txns that had not ended at the
+    /// crash and the keys that may have been dirty
+    fn analysis(&mut self) -> SqlResult<DirtyTable> {
+        let mut dirty = DirtyTable::new();
+        let mut ended = HashSet::new();
+        let start = self.wal.master().unwrap_or_else(|| self.wal.first_lsn());
+        for item in self.wal.iter_from(start) {
+            let (lsn, record) = item?;
+            match record.body {
+                LogBody::BeginCheckpoint => continue,
+                // the tables are older than the records scanned since the BeginCheckpoint
+                LogBody::EndCheckpoint {
+                    txns,
+                    dirty: checkpoint_dirty,
+                    next_txn,
+                } => {
+                    for (txn, entry) in txns {
+                        if !ended.contains(&txn) {
+                            self.txns.entry(txn).or_insert(entry);
+                        }
+                    }
+                    for (key, rec_lsn) in checkpoint_dirty {
+                        let entry = dirty.entry(key).or_insert(rec_lsn);
+                        *entry = (*entry).min(rec_lsn);
+                    }
+                    self.next_txn = self.next_txn.max(next_txn);
+                    continue;
+                }
+                _ => {}
+            }
+            self.next_txn = self.next_txn.max(record.txn + 1);
+            let entry = self.txns.entry(record.txn).or_insert(TxnEntry {
+                status: TxnStatus::Running,
+                first_lsn: lsn,
+                last_lsn: lsn,
+                undo_next: None,
+            });
+            entry.last_lsn = lsn;
+            match record.body {
+                LogBody::Begin => {}
+                LogBody::Update { key, .. } => {
+                    entry.undo_next = Some(lsn);
+                    dirty.entry(key).or_insert(lsn);
+                }
+                LogBody::Clr { key, undo_next, .. } => {
+                    entry.undo_next = undo_next;
+                    dirty.entry(key).or_insert(lsn);
+                }
+                LogBody::Commit => entry.status = TxnStatus::Committed,
+                LogBody::Abort => entry.status = TxnStatus::Aborting,
+                LogBody::End => {
+                    self.txns.remove(&record.txn);
+                    ended.insert(record.txn);
+                }
+                LogBody::BeginCheckpoint | LogBody::EndCheckpoint { .. } => unreachable!(),
+            }
+        }
+        Ok(dirty)
+    }
+
+    /// Reapplies updates and CLRs of every txn, a record is skipped when the stored key
+    /// already has it, i.e. its pageLSN is not older than the record
+    fn redo(&mut self, dirty: &DirtyTable) -> SqlResult<()> {
+        // the log is only truncated before records whose writes are flushed
+        let mut lsn = match dirty.values().min() {
+            Some(lsn) => (*lsn).max(self.wal.first_lsn()),
+            None => return Ok(()),
+        };
+        while lsn < self.wal.next_lsn() {
+            let (record, next) = self.wal.read(lsn)?;
+            let (key, after) = match record.body {
+                LogBody::Update { key, after, .. } | LogBody::Clr { key, after, .. } => {
+                    (key, after)
+                }
+                _ => {
+                    lsn = next;
+                    continue;
+                }
+            };
+            if dirty.get(&key).map_or(false, |rec_lsn| *rec_lsn <= lsn) {
+                let page_lsn = match self.dirty.get(&key) {
+                    Some(page) => Some(page.page_lsn),
+                    None => self.read_page(&key)?.map(|(page_lsn, _)| page_lsn),
+                };
+                if page_lsn.map_or(true, |page_lsn| page_lsn < lsn) {
+                    self.apply(&key, after, lsn);
+                }
+            }
+            lsn = next;
+        }
+        Ok(())
+    }
+
+    /// Rolls back txns together, always undoing the latest record among them. Each undone
+    /// update is compensated by a CLR pointing to the next record to undo, so a rollback
+    /// interrupted by a crash resumes where it stopped
+    pub(crate) fn undo(&mut self, txns: Vec<TxnID>) -> SqlResult<()> {
+        let mut todo = BinaryHeap::new();
+        for txn in txns {
+            match self.txn_mut(txn)?.undo_next {
+                Some(lsn) => todo.push((lsn, txn)),
+                None => self.end(txn)?,
+            }
+        }
+        while let Some((lsn, txn)) = todo.pop() {
+            let (record, _) = self.wal.read(lsn)?;
+            let next = match record.body {
+                LogBody::Update { key, before, .. } => {
+                    let clr = self.append(
+                        txn,
+                        LogBody::Clr {
+                            key: key.clone(),
+                            after: before.clone(),
+                            undo_next: record.prev_lsn,
+                        },
+                    )?;
+                    self.apply(&key, before, clr);
+                    record.prev_lsn
+                }
+                LogBody::Clr { undo_next, .. } => undo_next,
+                _ => record.prev_lsn,
+            };
+            self.txn_mut(txn)?.undo_next = next;
+            match next {
+                Some(lsn) => todo.push((lsn, txn)),
+                None => self.end(txn)?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,726 @@
+use crate::sql::{
+    exe::{BoxedDataIter, Executor, Operator, PlanType, SchemaDataIter},
+    join::{grace::PartitionedQueue, hash_util::hash_to_buckets},
+    memory::MemoryReservation,
+    util::GeneratorIteratorAdapter,
+    DataBlock, Error, ExecutionContext, SqlResult,
+};
+use ahash::RandomState;
+use datafusion::{
+    arrow::{
+        array::{ArrayRef, UInt32Array, UInt64Array},
+        compute::kernels::take::take,
+        datatypes::{Schema, SchemaRef},
+    },
+    physical_plan::{
+        aggregates::{create_aggregate_expr, AggregateFunction},
+        expressions::{lit, Column, DistinctCount},
+        Accumulator, AggregateExpr, PhysicalExpr,
+    },
+    scalar::ScalarValue,
+};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    mem::size_of,
+    sync::Arc,
+};
+
+pub struct AggregationPlan {
+    pub group_by: Vec<Column>,
+    pub aggregates: Vec<AggregateCall>,
+    pub source_plan: Box<PlanType>,
+}
+
+/// A single aggregate function in the select list, e.g COUNT(DISTINCT col_a) AS name
+pub struct AggregateCall {
+    pub func: AggregateFunction,
+    pub distinct: bool,
+    /// None means `*`, only valid for COUNT
+    pub arg: Option<Column>,
+    pub name: String,
+}
+
+#[derive(Clone, Debug)]
+struct Config {
+    bucket_size: usize,
+    batch_size: usize,
+}
+
+/// Hash aggregation, output schema is the group by columns followed by the aggregates.
+///
+/// Rows are aggregated into an in-memory group table until a new group cannot be
+/// reserved from the memory pool of the [ExecutionContext]. After that, rows
+/// of groups that already exist are still aggregated in place, while rows of new groups
+/// are partitioned with [hash_to_buckets] and spilled into a [PartitionedQueue]. In-memory
+/// groups and spilled groups are thus disjoint, so each spilled partition is later
+/// aggregated independently the same way, using a different hash seed per level.
+///
+/// The state of COUNT(DISTINCT) grows with the values of its group, this growth is
+/// reserved as well but cannot be spilled, the query fails once it does not fit
+#[derive(Debug)]
+pub struct HashAggregateOp {
+    config: Config,
+    group_by: Vec<Column>,
+    aggregates: Vec<Arc<dyn AggregateExpr>>,
+    input: Box<dyn Operator>,
+    schema: SchemaRef,
+    /// Estimated bytes of the accumulators of one group when it is created
+    group_state_size: usize,
+}
+unsafe impl Send for HashAggregateOp {}
+unsafe impl Sync for HashAggregateOp {}
+
+struct Group {
+    key: Vec<ScalarValue>,
+    accumulators: Vec<GroupAccumulator>,
+}
+
+enum GroupAccumulator {
+    /// State of a fixed size, accounted for by the group state size
+    Fixed(Box<dyn Accumulator>),
+    /// Distinct non null values of COUNT(DISTINCT), kept here instead of in the
+    /// accumulator of DataFusion so that the growth of the state is known
+    DistinctCount(HashSet<ScalarValue>),
+}
+
+impl GroupAccumulator {
+    /// Returns the bytes by which the state grew
+    fn update_batch(&mut self, values: &[ArrayRef]) -> SqlResult<usize> {
+        match self {
+            GroupAccumulator::Fixed(acc) => {
+                acc.update_batch(values)?;
+                Ok(0)
+            }
+            GroupAccumulator::DistinctCount(distinct) => {
+                let mut grown = 0;
+                for row in 0..values[0].len() {
+                    let value = ScalarValue::try_from_array(&values[0], row)?;
+                    if value.is_null() {
+                        continue;
+                    }
+                    let size = HashAggregateOp::value_memory_size(&value);
+                    if distinct.insert(value) {
+                        grown += size;
+                    }
+                }
+                Ok(grown)
+            }
+        }
+    }
+
+    fn evaluate(&self) -> SqlResult<ScalarValue> {
+        match self {
+            GroupAccumulator::Fixed(acc) => Ok(acc.evaluate()?),
+            GroupAccumulator::DistinctCount(distinct) => {
+                Ok(ScalarValue::UInt64(Some(distinct.len() as u64)))
+            }
+        }
+    }
+}
+
+/// Result of aggregating one input stream
+struct AggregatedLevel {
+    groups: Vec<Group>,
+    spilled: Option<(Arc<dyn PartitionedQueue>, BTreeSet<usize>)>,
+    /// Memory of groups, released once they are returned
+    reservation: MemoryReservation,
+}
+
+impl HashAggregateOp {
+    pub fn from_plan(plan: AggregationPlan, ctx: ExecutionContext) -> SqlResult<Self> {
+        let input = Executor::create_from_subplan_operator(*plan.source_plan, ctx)?;
+        let config = Config {
+            bucket_size: 16,
+            batch_size: 1024,
+        };
+        Self::new(config, input, plan.group_by, plan.aggregates)
+    }
+
+    fn new(
+        config: Config,
+        input: Box<dyn Operator>,
+        group_by: Vec<Column>,
+        calls: Vec<AggregateCall>,
+    ) -> SqlResult<Self> {
+        let input_schema = input.schema();
+        let mut aggregates = Vec::with_capacity(calls.len());
+        for call in calls {
+            match call.func {
+                AggregateFunction::Count
+                | AggregateFunction::Sum
+                | AggregateFunction::Min
+                | AggregateFunction::Max
+                | AggregateFunction::Avg => {}
+                _ => {
+                    return Err(Error::Value(format!(
+                        "unsupported aggregate function {}",
+                        call.func
+                    )))
+                }
+            }
+            if call.distinct && call.func != AggregateFunction::Count {
+                return Err(Error::Value(format!(
+                    "DISTINCT is only supported for COUNT, got {}",
+                    call.func
+                )));
+            }
+            let arg: Arc<dyn PhysicalExpr> = match (call.arg, &call.func) {
+                (Some(col), _) => Arc::new(col),
+                (None, AggregateFunction::Count) => lit(ScalarValue::UInt8(Some(1))),
+                (None, _) => {
+                    return Err(Error::Value(format!("{} requires an argument", call.func)))
+                }
+            };
+            aggregates.push(create_aggregate_expr(
+                &call.func,
+                call.distinct,
+                &[arg],
+                &input_schema,
+                call.name,
+            )?);
+        }
+
+        let mut fields = group_by
+            .iter()
+            .map(|col| input_schema.field(col.index()).clone())
+            .collect::<Vec<_>>();
+        let mut state_fields = 0;
+        for aggr in &aggregates {
+            fields.push(aggr.field()?);
+            state_fields += aggr.state_fields()?.len();
+        }
+        Ok(HashAggregateOp {
+            config,
+            group_by,
+            aggregates,
+            input,
+            schema: Arc::new(Schema::new(fields)),
+            group_state_size: state_fields * size_of::<ScalarValue>(),
+        })
+    }
+
+    /// Groups are not stored in Arrow buffers, their size is estimated from the key,
+    /// which is stored both in the group and in the group table
+    fn group_memory_size(key: &[ScalarValue], group_state_size: usize) -> usize {
+        let key_size = key.iter().map(Self::value_memory_size).sum::<usize>();
+        2 * key_size + group_state_size
+    }
+
+    fn value_memory_size(value: &ScalarValue) -> usize {
+        let heap = match value {
+            ScalarValue::Utf8(Some(v)) | ScalarValue::LargeUtf8(Some(v)) => v.len(),
+            ScalarValue::Binary(Some(v)) | ScalarValue::LargeBinary(Some(v)) => v.len(),
+            _ => 0,
+        };
+        size_of::<ScalarValue>() + heap
+    }
+
+    fn new_group(aggregates: &[Arc<dyn AggregateExpr>], key: Vec<ScalarValue>) -> SqlResult<Group> {
+        let accumulators = aggregates
+            .iter()
+            .map(|aggr| {
+                if aggr.as_any().is::<DistinctCount>() {
+                    return Ok(GroupAccumulator::DistinctCount(HashSet::new()));
+                }
+                Ok(GroupAccumulator::Fixed(aggr.create_accumulator()?))
+            })
+            .collect::<SqlResult<Vec<_>>>()?;
+        Ok(Group { key, accumulators })
+    }
+
+    fn aggregate_stream(
+        ctx: &ExecutionContext,
+        config: &Config,
+        group_by: &[Column],
+        (aggregates, group_state_size): (&[Arc<dyn AggregateExpr>], usize),
+        input_schema: &SchemaRef,
+        stream: BoxedDataIter,
+        level: usize,
+    ) -> SqlResult<AggregatedLevel> {
+        let mut reservation = ctx.new_reservation("aggregation");
+        let mut groups: Vec<Group> = vec![];
+        let mut group_indices: HashMap<Vec<ScalarValue>, usize> = HashMap::new();
+        let mut spilled: Option<(Arc<dyn PartitionedQueue>, BTreeSet<usize>)> = None;
+        let random_state = RandomState::with_seed(level);
+        let mut reused_buffer = Vec::new();
+        // memory freed by other operators of the query must not bring a spilled key back
+        // into memory, so every new group is spilled once the first one is
+        let mut spilling = false;
+
+        for batch in stream {
+            let batch = batch?;
+            let group_values = group_by
+                .iter()
+                .map(|col| batch.column(col.index()).clone())
+                .collect::<Vec<_>>();
+            let mut rows_per_group: HashMap<usize, Vec<u32>> = HashMap::new();
+            let mut spilled_rows = vec![];
+            for row in 0..batch.num_rows() {
+                let key = group_values
+                    .iter()
+                    .map(|array| ScalarValue::try_from_array(array, row))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let group_idx = match group_indices.get(&key) {
+                    Some(idx) => *idx,
+                    None => {
+                        let size = Self::group_memory_size(&key, group_state_size);
+                        if spilling || !reservation.try_grow(size) {
+                            if !groups.is_empty() {
+                                spilling = true;
+                                spilled_rows.push(row as u64);
+                                continue;
+                            }
+                            // spilling does not make room for the first group
+                            reservation.grow(size)?;
+                        }
+                        let idx = groups.len();
+                        group_indices.insert(key.clone(), idx);
+                        groups.push(Self::new_group(aggregates, key)?);
+                        idx
+                    }
+                };
+                rows_per_group
+                    .entry(group_idx)
+                    .or_default()
+                    .push(row as u32);
+            }
+
+            let aggr_inputs = aggregates
+                .iter()
+                .map(|aggr| {
+                    aggr.expressions()
+                        .iter()
+                        .map(|expr| Ok(expr.evaluate(&batch)?.into_array(batch.num_rows())))
+                        .collect::<SqlResult<Vec<ArrayRef>>>()
+                })
+                .collect::<SqlResult<Vec<_>>>()?;
+            let mut grown = 0;
+            for (group_idx, rows) in rows_per_group {
+                let indices = UInt32Array::from(rows);
+                let group = &mut groups[group_idx];
+                for (acc, inputs) in group.accumulators.iter_mut().zip(aggr_inputs.iter()) {
+                    let values = inputs
+                        .iter()
+                        .map(|array| take(array.as_ref(), &indices, None))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    grown += acc.update_batch(&values)?;
+                }
+            }
+            // rows of groups in memory cannot be spilled, neither can their state
+            reservation.grow(grown)?;
+
+            if !spilled_rows.is_empty() {
+                let (queue, partitions) = spilled
+                    .get_or_insert_with(|| (ctx.new_queue(input_schema.clone()), BTreeSet::new()));
+                let indices = UInt64Array::from(spilled_rows);
+                let columns = batch
+                    .columns()
+                    .iter()
+                    .map(|c| take(c.as_ref(), &indices, None))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let spilled_batch = DataBlock::try_new(batch.schema(), columns)?;
+                Self::spill_batch(
+                    &spilled_batch,
+                    group_by,
+                    config,
+                    &random_state,
+                    &mut reused_buffer,
+                    queue,
+                    partitions,
+                )?;
+            }
+        }
+
+        // an aggregate without group by always returns one row
+        if group_by.is_empty() && groups.is_empty() {
+            reservation.grow(group_state_size)?;
+            groups.push(Self::new_group(aggregates, vec![])?);
+        }
+        Ok(AggregatedLevel {
+            groups,
+            spilled,
+            reservation,
+        })
+    }
+
+    fn spill_batch(
+        batch: &DataBlock,
+        group_by: &[Column],
+        config: &Config,
+        random_state: &RandomState,
+        reused_buffer: &mut Vec<u64>,
+        queue: &Arc<dyn PartitionedQueue>,
+        partitions: &mut BTreeSet<usize>,
+    ) -> SqlResult<()> {
+        reused_buffer.clear();
+        reused_buffer.resize(batch.num_rows(), 0);
+        let group_values = group_by
+            .iter()
+            .map(|col| batch.column(col.index()).clone())
+            .collect::<Vec<_>>();
+        hash_to_buckets(
+            &group_values,
+            random_state,
+            reused_buffer,
+            config.bucket_size,
+        )?;
+        let mut buckets = vec![vec![]; config.bucket_size];
+        for (row, bucket_idx) in reused_buffer.iter().enumerate() {
+            buckets[*bucket_idx as usize].push(row as u64);
+        }
+        for (bucket_idx, row_indices) in buckets.into_iter().enumerate() {
+            if row_indices.is_empty() {
+                continue;
+            }
+            let indices = UInt64Array::from(row_indices);
+            let columns = batch
+                .columns()
+                .iter()
+                .map(|c| take(c.as_ref(), &indices, None))
+                .collect::<Result<Vec<_>, _>>()?;
+            queue.enqueue(bucket_idx, DataBlock::try_new(batch.schema(), columns)?)?;
+            partitions.insert(bucket_idx);
+        }
+        Ok(())
+    }
+
+    fn build_output(
+        schema: &SchemaRef,
+        key_len: usize,
+        groups: Vec<Group>,
+    ) -> SqlResult<DataBlock> {
+        let mut columns = Vec::with_capacity(schema.fields().len());
+        for key_idx in 0..key_len {
+            let values = groups.iter().map(|g| g.key[key_idx].clone());
+            columns.push(ScalarValue::iter_to_array(values)?);
+        }
+        for aggr_idx in 0..schema.fields().len() - key_len {
+            let values = groups
+                .iter()
+                .map(|g| g.accumulators[aggr_idx].evaluate())
+                .collect::<SqlResult<Vec<_>>>()?;
+            columns.push(ScalarValue::iter_to_array(values)?);
+        }
+        Ok(DataBlock::try_new(schema.clone(), columns)?)
+    }
+}
+
+impl Operator for HashAggregateOp {
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn execute_sync(&mut self, ctx: ExecutionContext) -> SqlResult<BoxedDataIter> {
+        let input_schema = self.input.schema();
+        let first_stream = self.input.execute_sync(ctx.clone())?;
+        let config = self.config.clone();
+        let group_by = self.group_by.clone();
+        let aggregates = self.aggregates.clone();
+        let group_state_size = self.group_state_size;
+        let schema = self.schema.clone();
+
+        let gen = move || {
+            // each item is a stream of rows whose groups are all absent from other items
+            let mut pending: Vec<(BoxedDataIter, usize)> = vec![(first_stream, 0)];
+            while let Some((stream, level)) = pending.pop() {
+                let aggregated = match Self::aggregate_stream(
+                    &ctx,
+                    &config,
+                    &group_by,
+                    (&aggregates, group_state_size),
+                    &input_schema,
+                    stream,
+                    level,
+                ) {
+                    Ok(aggregated) => aggregated,
+                    Err(e) => {
+                        yield Err(e);
+                        return;
+                    }
+                };
+                if let Some((queue, partitions)) = aggregated.spilled {
+                    for p_index in partitions {
+                        match queue.dequeue(p_index, config.batch_size) {
+                            Ok(stream) => pending.push((stream, level + 1)),
+                            Err(e) => {
+                                yield Err(e);
+                                return;
+                            }
+                        }
+                    }
+                }
+                if !aggregated.groups.is_empty() {
+                    yield Self::build_output(&schema, group_by.len(), aggregated.groups);
+                }
+                drop(aggregated.reservation);
+            }
+        };
+        let iter = GeneratorIteratorAdapter::new(gen);
+        Ok(SchemaDataIter::new(self.schema.clone(), Box::new(iter)))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{AggregateCall, Config, HashAggregateOp};
+    use crate::sql::{
+        exe::{BoxedDataIter, ExecutionContext, Operator, SchemaDataIter},
+        inmem_op::InMemOp,
+        memory::MemoryPool,
+        util::collect,
+        DataBlock, Error, SqlResult,
+    };
+    use datafusion::{
+        arrow::{
+            array::{Array, Int32Array, StringArray},
+            datatypes::{DataType, Field, Schema, SchemaRef},
+        },
+        physical_plan::{aggregates::AggregateFunction, expressions::Column},
+        scalar::ScalarValue,
+    };
+    use std::sync::Arc;
+
+    /// Columns: grp_a int, grp_b utf8, val int, split into batches of 3 rows
+    fn build_input(grp_a: Vec<i32>, grp_b: Vec<&str>, val: Vec<Option<i32>>) -> Box<dyn Operator> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("grp_a", DataType::Int32, false),
+            Field::new("grp_b", DataType::Utf8, false),
+            Field::new("val", DataType::Int32, true),
+        ]));
+        let batch = DataBlock::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(Int32Array::from(grp_a)) as Arc<dyn Array>,
+                Arc::new(StringArray::from(grp_b)) as Arc<dyn Array>,
+                Arc::new(Int32Array::from(val)) as Arc<dyn Array>,
+            ],
+        )
+        .unwrap();
+        let batches = (0..batch.num_rows())
+            .step_by(3)
+            .map(|offset| batch.slice(offset, 3.min(batch.num_rows() - offset)))
+            .collect();
+        Box::new(InMemOp::new(schema, batches))
+    }
+
+    fn call(
+        func: AggregateFunction,
+        distinct: bool,
+        arg: Option<Column>,
+        name: &str,
+    ) -> AggregateCall {
+        AggregateCall {
+            func,
+            distinct,
+            arg,
+            name: name.to_string(),
+        }
+    }
+
+    fn all_calls() -> Vec<AggregateCall> {
+        let val = || Some(Column::new("val", 2));
+        vec![
+            call(AggregateFunction::Count, false, None, "cnt"),
+            call(AggregateFunction::Count, true, val(), "cnt_distinct"),
+            call(AggregateFunction::Sum, false, val(), "sum"),
+            call(AggregateFunction::Min, false, val(), "min"),
+            call(AggregateFunction::Max, false, val(), "max"),
+            call(AggregateFunction::Avg, false, val(), "avg"),
+        ]
+    }
+
+    fn run(config: Config, input: Box<dyn Operator>, group_by: Vec<Column>) -> Vec<DataBlock> {
+        let op =
+            HashAggregateOp::new(config, input, group_by, all_calls()).expect("creating aggregate");
+        execute(op, ExecutionContext::new_for_test()).expect("executing aggregate")
+    }
+
+    fn execute(mut op: HashAggregateOp, ctx: ExecutionContext) -> SqlResult<Vec<DataBlock>> {
+        let stream = op.execute_sync(ctx.clone())?;
+        let batches = collect(stream)?;
+        assert_eq!(0, ctx.memory_pool().used());
+        Ok(batches)
+    }
+
+    /// Context whose memory pool fits 2 groups keyed by one int, each with up to
+    /// `distinct` distinct int values. A third group is only created before the
+    /// distinct values are reserved, so the values of 2 groups must take less than a group
+    fn two_groups_ctx(op: &HashAggregateOp, distinct: usize) -> ExecutionContext {
+        let key = [ScalarValue::Int32(Some(0))];
+        let group_size = HashAggregateOp::group_memory_size(&key, op.group_state_size);
+        let values_size = distinct * HashAggregateOp::value_memory_size(&key[0]);
+        assert!(2 * values_size < group_size);
+        let pool = MemoryPool::new(2 * (group_size + values_size));
+        ExecutionContext::new_for_test().with_memory_pool(Arc::new(pool))
+    }
+
+    #[test]
+    fn test_multi_column_group_by() {
+        let input = build_input(
+            vec![1, 1, 1, 2, 2, 1, 1],
+            vec!["x", "x", "y", "x", "x", "x", "y"],
+            vec![Some(1), Some(1), Some(5), None, Some(4), Some(3), Some(7)],
+        );
+        let config = Config {
+            bucket_size: 10,
+            batch_size: 10,
+        };
+        let batches = run(
+            config,
+            input,
+            vec![Column::new("grp_a", 0), Column::new("grp_b", 1)],
+        );
+        let expected = [
+            "+-------+-------+-----+--------------+-----+-----+-----+--------------------+",
+            "| grp_a | grp_b | cnt | cnt_distinct | sum | min | max | avg                |",
+            "+-------+-------+-----+--------------+-----+-----+-----+--------------------+",
+            "| 1     | x     | 3   | 2            | 5   | 1   | 3   | 1.6666666666666667 |",
+            "| 1     | y     | 2   | 2            | 12  | 5   | 7   | 6                  |",
+            "| 2     | x     | 2   | 1            | 4   | 4   | 4   | 4                  |",
+            "+-------+-------+-----+--------------+-----+-----+-----+--------------------+",
+        ];
+        crate::assert_batches_sorted_eq!(expected, &batches);
+    }
+
+    #[test]
+    fn test_aggregate_without_group_by() {
+        let input = build_input(vec![], vec![], vec![]);
+        let config = Config {
+            bucket_size: 10,
+            batch_size: 10,
+        };
+        let batches = run(config, input, vec![]);
+        let expected = [
+            "+-----+--------------+-----+-----+-----+-----+",
+            "| cnt | cnt_distinct | sum | min | max | avg |",
+            "+-----+--------------+-----+-----+-----+-----+",
+            "| 0   | 0            |     |     |     |     |",
+            "+-----+--------------+-----+-----+-----+-----+",
+        ];
+        crate::assert_batches_sorted_eq!(expected, &batches);
+    }
+
+    #[test]
+    fn test_spill_groups() {
+        let grp_a = (0..40).map(|i| i % 10).collect::<Vec<_>>();
+        let val = (0..40).map(Some).collect::<Vec<_>>();
+        let input = build_input(grp_a, vec!["x"; 40], val);
+        // only 2 groups fit in memory, the other 8 are spilled and re-aggregated
+        let config = Config {
+            bucket_size: 3,
+            batch_size: 4,
+        };
+        let op = HashAggregateOp::new(config, input, vec![Column::new("grp_a", 0)], all_calls())
+            .expect("creating aggregate");
+        let ctx = two_groups_ctx(&op, 4);
+        let batches = execute(op, ctx).expect("executing aggregate");
+        assert!(batches.len() > 1);
+        let expected = [
+            "+-------+-----+--------------+-----+-----+-----+-----+",
+            "| grp_a | cnt | cnt_distinct | sum | min | max | avg |",
+            "+-------+-----+--------------+-----+-----+-----+-----+",
+            "| 0     | 4   | 4            | 60  | 0   | 30  | 15  |",
+            "| 1     | 4   | 4            | 64  | 1   | 31  | 16  |",
+            "| 2     | 4   | 4            | 68  | 2   | 32  | 17  |",
+            "| 3     | 4   | 4            | 72  | 3   | 33  | 18  |",
+            "| 4     | 4   | 4            | 76  | 4   | 34  | 19  |",
+            "| 5     | 4   | 4            | 80  | 5   | 35  | 20  |",
+            "| 6     | 4   | 4            | 84  | 6   | 36  | 21  |",
+            "| 7     | 4   | 4            | 88  | 7   | 37  | 22  |",
+            "| 8     | 4   | 4            | 92  | 8   | 38  | 23  |",
+            "| 9     | 4   | 4            | 96  | 9   | 39  | 24  |",
+            "+-------+-----+--------------+-----+-----+-----+-----+",
+        ];
+        crate::assert_batches_sorted_eq!(expected, &batches);
+    }
+
+    /// Holds memory of the pool until its input is polled for the second batch
+    #[derive(Debug)]
+    struct ReleasingOp {
+        input: Box<dyn Operator>,
+        held: usize,
+    }
+
+    impl Operator for ReleasingOp {
+        fn execute_sync(&mut self, ctx: ExecutionContext) -> SqlResult<BoxedDataIter> {
+            let mut reservation = Some(ctx.new_reservation("releasing"));
+            reservation.as_mut().unwrap().grow(self.held)?;
+            let mut polled = 0;
+            let iter = self.input.execute_sync(ctx)?.map(move |batch| {
+                polled += 1;
+                if polled > 1 {
+                    reservation.take();
+                }
+                batch
+            });
+            Ok(SchemaDataIter::new(self.input.schema(), Box::new(iter)))
+        }
+
+        fn schema(&self) -> SchemaRef {
+            self.input.schema()
+        }
+    }
+
+    #[test]
+    fn test_spilled_group_stays_spilled() {
+        let val = (0..6).map(Some).collect::<Vec<_>>();
+        let input = build_input(vec![0, 1, 1, 1, 0, 0], vec!["x"; 6], val);
+        let config = Config {
+            bucket_size: 3,
+            batch_size: 4,
+        };
+        let op = HashAggregateOp::new(config, input, vec![Column::new("grp_a", 0)], all_calls())
+            .expect("creating aggregate");
+        let ctx = two_groups_ctx(&op, 4);
+        // group 1 is spilled in the first batch, and would fit in the second one
+        let key = [ScalarValue::Int32(Some(0))];
+        let held = HashAggregateOp::group_memory_size(&key, op.group_state_size)
+            + 4 * HashAggregateOp::value_memory_size(&key[0]);
+        let input = Box::new(ReleasingOp {
+            input: op.input,
+            held,
+        });
+        let op = HashAggregateOp::new(op.config, input, vec![Column::new("grp_a", 0)], all_calls())
+            .expect("creating aggregate");
+        let batches = execute(op, ctx).expect("executing aggregate");
+        let expected = [
+            "+-------+-----+--------------+-----+-----+-----+-----+",
+            "| grp_a | cnt | cnt_distinct | sum | min | max | avg |",
+            "+-------+-----+--------------+-----+-----+-----+-----+",
+            "| 0     | 3   | 3            | 9   | 0   | 5   | 3   |",
+            "| 1     | 3   | 3            | 6   | 1   | 3   | 2   |",
+            "+-------+-----+--------------+-----+-----+-----+-----+",
+        ];
+        crate::assert_batches_sorted_eq!(expected, &batches);
+    }
+
+    #[test]
+    fn test_aggregate_memory_limit_exceeded() {
+        let input = build_input(vec![1, 2], vec!["x", "y"], vec![Some(1), Some(2)]);
+        let config = Config {
+            bucket_size: 3,
+            batch_size: 4,
+        };
+        let op = HashAggregateOp::new(config, input, vec![Column::new("grp_a", 0)], all_calls())
+            .expect("creating aggregate");
+        let ctx = ExecutionContext::new_for_test().with_memory_pool(Arc::new(MemoryPool::new(1)));
+        let ret = execute(op, ctx);
+        assert!(matches!(ret, Err(Error::ResourceExhausted(_))));
+    }
+
+    #[test]
+    fn test_distinct_state_memory_limit_exceeded() {
+        let val = (0..40).map(Some).collect::<Vec<_>>();
+        let input = build_input(vec![1; 40], vec!["x"; 40], val);
+        let config = Config {
+            bucket_size: 3,
+            batch_size: 4,
+        };
+        let op = HashAggregateOp::new(config, input, vec![Column::new("grp_a", 0)], all_calls())
+            .expect("creating aggregate");
+        // the group fits, its 40 distinct values do not
+        let ctx = two_groups_ctx(&op, 4);
+        let ret = execute(op, ctx);
+        assert!(matches!(ret, Err(Error::ResourceExhausted(_))));
+    }
+}
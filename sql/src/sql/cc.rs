@@ -1,171 +1,916 @@
-use lock_api::RawMutex as ApiRawMutex;
-use parking_lot::RawMutex;
-use std::collections::{HashMap, VecDeque};
-
 use crate::sql::{
-    common::RID,
-    tx::{IsolationLevel, TwoPLState, Txn},
+    exe::RID,
+    tx::{IsolationLevel, TwoPLState, Txn, TxnID},
+    Error, SqlResult,
+};
+use parking_lot::{Condvar, Mutex, MutexGuard};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    fmt,
+    sync::{Arc, Weak},
+    thread,
+    time::Duration,
 };
+
+/// What a lock is taken on. Rows are locked by RID, tables hold intention locks for the
+/// row locks taken under them, or shared and exclusive locks covering all their rows
+/// including those inserted later
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum LockTarget {
+    Table(String),
+    Row(RID),
+}
+
+impl fmt::Display for LockTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LockTarget::Table(table) => write!(f, "table {}", table),
+            LockTarget::Row(rid) => write!(f, "row {}", rid),
+        }
+    }
+}
+
+/// How the lock manager keeps transactions from waiting on each other forever. Txn ids
+/// are increasing, so a smaller id means an older txn
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DeadlockPolicy {
+    /// A background thread looks for cycles in the waits-for graph at the given
+    /// interval, and aborts the youngest txn of each cycle
+    Detection(Duration),
+    /// An older txn aborts the younger txns it would wait for, a younger txn waits
+    WoundWait,
+    /// An older txn waits, a younger txn aborts itself instead of waiting for an older one
+    WaitDie,
+}
+
+impl Default for DeadlockPolicy {
+    fn default() -> Self {
+        DeadlockPolicy::Detection(Duration::from_millis(50))
+    }
+}
+
+/// Requests of one target in arrival order, granted requests are always at the front
 pub struct LockRequestQueue {
-    latch: RawMutex,
     queue: VecDeque<LockRq>,
+    /// Txn waiting to upgrade its lock, only one upgrade may wait at a time
+    upgrading: Option<TxnID>,
+    /// Notified whenever a request leaves the queue
+    cv: Arc<Condvar>,
 }
 pub struct LockRq {
-    txn_id: u32,
+    txn: Arc<Txn>,
     lock_mode: LockMode,
     granted: bool,
 }
-pub enum LockMode {}
-pub struct TxMgr {}
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LockMode {
+    Shared,
+    Exclusive,
+    IntentionShared,
+    IntentionExclusive,
+    SharedIntentionExclusive,
+}
 
+impl LockMode {
+    pub fn compatible(self, other: LockMode) -> bool {
+        use LockMode::*;
+        match (self, other) {
+            (Exclusive, _) | (_, Exclusive) => false,
+            (IntentionShared, _) | (_, IntentionShared) => true,
+            (SharedIntentionExclusive, _) | (_, SharedIntentionExclusive) => false,
+            (Shared, Shared) | (IntentionExclusive, IntentionExclusive) => true,
+            (Shared, IntentionExclusive) | (IntentionExclusive, Shared) => false,
+        }
+    }
+
+    /// Checks if holding this mode grants everything the other mode does
+    pub fn covers(self, other: LockMode) -> bool {
+        use LockMode::*;
+        match (self, other) {
+            (a, b) if a == b => true,
+            (Exclusive, _) | (_, IntentionShared) => true,
+            (SharedIntentionExclusive, Shared | IntentionExclusive) => true,
+            _ => false,
+        }
+    }
+
+    /// The weakest mode covering both
+    fn join(self, other: LockMode) -> LockMode {
+        if self.covers(other) {
+            self
+        } else if other.covers(self) {
+            other
+        } else {
+            // only shared and intention exclusive do not cover each other
+            LockMode::SharedIntentionExclusive
+        }
+    }
+
+    /// Modes that read without intending to write
+    fn is_shared(self) -> bool {
+        matches!(self, LockMode::Shared | LockMode::IntentionShared)
+    }
+}
+
+impl LockRequestQueue {
+    fn new() -> Self {
+        LockRequestQueue {
+            queue: VecDeque::new(),
+            upgrading: None,
+            cv: Arc::new(Condvar::new()),
+        }
+    }
+
+    fn position(&self, txn_id: TxnID) -> Option<usize> {
+        self.queue.iter().position(|rq| rq.txn.id() == txn_id)
+    }
+
+    /// Requests before the one at pos that keep it from being granted
+    fn blockers(&self, pos: usize) -> impl Iterator<Item = &LockRq> {
+        let mode = self.queue[pos].lock_mode;
+        self.queue
+            .iter()
+            .take(pos)
+            .filter(move |rq| !mode.compatible(rq.lock_mode))
+    }
+
+    /// A request is granted once every request before it is compatible with it, so
+    /// requests are granted in FIFO order
+    fn grantable(&self, pos: usize) -> bool {
+        self.blockers(pos).next().is_none()
+    }
+}
+
+/// Lock manager for strict two phase locking over rows and tables. Locking calls block
+/// until the lock is granted, and fail with [Error::Abort] after marking the txn as
+/// aborted when the request breaks the rules of its isolation level:
+/// - ReadUncommitted never takes shared locks
+/// - no lock is taken once the txn is shrinking
+/// - releasing a shared lock makes the txn shrinking, except under ReadComitted where
+/// shared locks are released right after reading
+/// - locks that allow writing are held until the txn commits or aborts
+///
+/// Row locks alone cannot stop a txn from inserting rows into a range another txn has
+/// scanned. Serializable txns avoid such phantoms by scanning under a shared table
+/// lock, which conflicts with the intention exclusive lock every writer takes first.
+///
+/// Deadlocks are handled according to the [DeadlockPolicy], the aborted txns get
+/// [Error::Abort] from their pending or next lock request and must be aborted through
+/// [crate::sql::tx::TxnManager] to release their locks.
 pub struct LockMgr {
-    latch: RawMutex,
-    lock_table: HashMap<RID, LockRequestQueue>,
+    lock_table: Mutex<HashMap<LockTarget, LockRequestQueue>>,
+    policy: DeadlockPolicy,
 }
 
 impl LockMgr {
-    fn lock_s(&mut self, txn: &mut Txn, rid: RID) -> bool {
-        match txn.isolation_level() {
-            IsolationLevel::ReadUncommitted => {
-                txn.abort();
-                return false;
-            }
-            IsolationLevel::RepeatableRead => {
-                if txn.state() == TwoPLState::Shrinking {
+    /// With [DeadlockPolicy::Detection], the detector runs until the lock manager is
+    /// dropped
+    pub fn new(policy: DeadlockPolicy) -> Arc<Self> {
+        let mgr = Arc::new(LockMgr {
+            lock_table: Mutex::new(HashMap::new()),
+            policy,
+        });
+        if let DeadlockPolicy::Detection(interval) = policy {
+            let weak = Arc::downgrade(&mgr);
+            thread::spawn(move || Self::run_detection(weak, interval));
+        }
+        mgr
+    }
+
+    pub fn policy(&self) -> DeadlockPolicy {
+        self.policy
+    }
+
+    fn run_detection(mgr: Weak<LockMgr>, interval: Duration) {
+        loop {
+            thread::sleep(interval);
+            match mgr.upgrade() {
+                Some(mgr) => {
+                    mgr.detect_deadlocks();
+                }
+                None => return,
+            }
+        }
+    }
+
+    /// Edges from waiting txns to the txns they wait for
+    fn waits_for(
+        table: &HashMap<LockTarget, LockRequestQueue>,
+    ) -> BTreeMap<TxnID, BTreeSet<TxnID>> {
+        let mut graph: BTreeMap<TxnID, BTreeSet<TxnID>> = BTreeMap::new();
+        for queue in table.values() {
+            for (pos, rq) in queue.queue.iter().enumerate() {
+                if rq.granted || rq.txn.state() == TwoPLState::Aborted {
+                    continue;
+                }
+                for blocker in queue.blockers(pos) {
+                    if blocker.txn.state() != TwoPLState::Aborted {
+                        graph
+                            .entry(rq.txn.id())
+                            .or_default()
+                            .insert(blocker.txn.id());
+                    }
+                }
+            }
+        }
+        graph
+    }
+
+    /// Finds a cycle reachable from node, nodes of the current path are on path
+    fn find_cycle(
+        graph: &BTreeMap<TxnID, BTreeSet<TxnID>>,
+        node: TxnID,
+        path: &mut Vec<TxnID>,
+        visited: &mut BTreeSet<TxnID>,
+    ) -> Option<Vec<TxnID>> {
+        if let Some(start) = path.iter().position(|id| *id == node) {
+            return Some(path[start..].to_vec());
+        }
+        if !visited.insert(node) {
+            return None;
+        }
+        path.push(node);
+        for next in graph.get(&node).into_iter().flatten() {
+            if let Some(cycle) = Self::find_cycle(graph, *next, path, visited) {
+                return Some(cycle);
+            }
+        }
+        path.pop();
+        None
+    }
+
+    /// Breaks every cycle of the waits-for graph by aborting the youngest txn of the
+    /// cycle, and returns the aborted txns
+    pub fn detect_deadlocks(&self) -> Vec<TxnID> {
+        let table = self.lock_table.lock();
+        let mut graph = Self::waits_for(&table);
+        let mut victims = vec![];
+        loop {
+            let mut visited = BTreeSet::new();
+            let cycle = graph
+                .keys()
+                .find_map(|node| Self::find_cycle(&graph, *node, &mut vec![], &mut visited));
+            let victim = match cycle {
+                Some(cycle) => *cycle.iter().max().unwrap(),
+                None => break,
+            };
+            graph.remove(&victim);
+            victims.push(victim);
+        }
+        for queue in table.values() {
+            for rq in &queue.queue {
+                if victims.contains(&rq.txn.id()) {
+                    rq.txn.abort();
+                }
+            }
+        }
+        Self::wake(&table, &victims);
+        victims
+    }
+
+    /// Wakes up the given aborted txns wherever they wait, so that they give up their
+    /// requests
+    fn wake(table: &HashMap<LockTarget, LockRequestQueue>, txns: &[TxnID]) {
+        for queue in table.values() {
+            if queue
+                .queue
+                .iter()
+                .any(|rq| !rq.granted && txns.contains(&rq.txn.id()))
+            {
+                queue.cv.notify_all();
+            }
+        }
+    }
+
+    /// Applies wound-wait or wait-die to the txn whose request at pos is not granted,
+    /// and returns the txns it wounded
+    fn prevent_deadlock(&self, queue: &LockRequestQueue, pos: usize) -> Vec<TxnID> {
+        let txn = &queue.queue[pos].txn;
+        let mut wounded = vec![];
+        match self.policy {
+            DeadlockPolicy::Detection(_) => {}
+            DeadlockPolicy::WoundWait => {
+                for blocker in queue.blockers(pos) {
+                    if blocker.txn.id() > txn.id() && blocker.txn.state() != TwoPLState::Aborted {
+                        blocker.txn.abort();
+                        wounded.push(blocker.txn.id());
+                    }
+                }
+            }
+            DeadlockPolicy::WaitDie => {
+                if queue
+                    .blockers(pos)
+                    .any(|blocker| blocker.txn.id() < txn.id())
+                {
                     txn.abort();
-                    return false;
                 }
             }
-            _ => {}
+        }
+        wounded
+    }
+
+    fn abort(txn: &Txn) -> SqlResult<()> {
+        txn.abort();
+        Err(Error::Abort)
+    }
+
+    /// Checks the state of a txn requesting a lock
+    fn check_growing(txn: &Txn) -> SqlResult<()> {
+        match txn.state() {
+            TwoPLState::Growing => Ok(()),
+            TwoPLState::Aborted => Err(Error::Abort),
+            TwoPLState::Shrinking => Self::abort(txn),
+            TwoPLState::Committed => Err(Error::Value(format!(
+                "transaction {} is already committed",
+                txn.id()
+            ))),
+        }
+    }
+
+    pub fn lock_s(&self, txn: &Arc<Txn>, rid: RID) -> SqlResult<()> {
+        self.lock(txn, LockTarget::Row(rid), LockMode::Shared)
+    }
+
+    pub fn lock_x(&self, txn: &Arc<Txn>, rid: RID) -> SqlResult<()> {
+        self.lock(txn, LockTarget::Row(rid), LockMode::Exclusive)
+    }
+
+    pub fn lock_table(&self, txn: &Arc<Txn>, table: &str, mode: LockMode) -> SqlResult<()> {
+        self.lock(txn, LockTarget::Table(table.to_string()), mode)
+    }
+
+    /// Takes a lock in mode on target, a txn already holding another mode on target has
+    /// it upgraded to a mode covering both
+    pub fn lock(&self, txn: &Arc<Txn>, target: LockTarget, mode: LockMode) -> SqlResult<()> {
+        let reads = matches!(
+            mode,
+            LockMode::Shared | LockMode::IntentionShared | LockMode::SharedIntentionExclusive
+        );
+        if txn.isolation_level() == IsolationLevel::ReadUncommitted && reads {
+            return Self::abort(txn);
+        }
+        Self::check_growing(txn)?;
+        let held = match txn.lock_mode(&target) {
+            Some(held) if held.covers(mode) => return Ok(()),
+            Some(held) => held,
+            None => {
+                let mut table = self.lock_table.lock();
+                let queue = table
+                    .entry(target.clone())
+                    .or_insert_with(LockRequestQueue::new);
+                queue.queue.push_back(LockRq {
+                    txn: txn.clone(),
+                    lock_mode: mode,
+                    granted: false,
+                });
+                self.wait(&mut table, txn, &target)?;
+                txn.add_lock(target, mode);
+                return Ok(());
+            }
         };
-        if txn.s_locked(rid) || txn.x_locked(rid) {
-            return true;
+        self.upgrade(txn, target, held.join(mode))
+    }
+
+    /// Turns the shared lock of txn on rid into an exclusive one
+    pub fn upgrade_lock(&self, txn: &Arc<Txn>, rid: RID) -> SqlResult<()> {
+        Self::check_growing(txn)?;
+        if !txn.s_locked(rid) {
+            return Err(Error::Value(format!(
+                "transaction {} does not hold a shared lock on {}",
+                txn.id(),
+                rid
+            )));
         }
-        // can this be empty
-        let queue = self.get_queue(rid).unwrap();
+        self.upgrade(txn, LockTarget::Row(rid), LockMode::Exclusive)
     }
-    fn lock_x(_: Txn, _: RID) {}
-    fn upgrade_lock(_: Txn, _: RID) {}
-    fn unlock(_: Txn, _: RID) {}
 
-    fn get_queue(&self, rid: RID) -> Option<&LockRequestQueue> {
-        // let latch = self.latch.lock();
-        self.latch.lock();
-        let queue = self.lock_table.get(&rid);
-        unsafe {
-            self.latch.unlock();
+    /// Replaces the lock of txn on target with one in mode. The upgrade goes before the
+    /// waiting requests, and waits for the other holders of incompatible locks to
+    /// release them
+    fn upgrade(&self, txn: &Arc<Txn>, target: LockTarget, mode: LockMode) -> SqlResult<()> {
+        let mut table = self.lock_table.lock();
+        let queue = table.get_mut(&target).unwrap();
+        // two upgrades would wait on each other forever
+        if queue.upgrading.is_some() {
+            drop(table);
+            return Self::abort(txn);
+        }
+        let pos = queue.position(txn.id()).unwrap();
+        queue.queue.remove(pos);
+        let granted = queue.queue.iter().take_while(|rq| rq.granted).count();
+        queue.queue.insert(
+            granted,
+            LockRq {
+                txn: txn.clone(),
+                lock_mode: mode,
+                granted: false,
+            },
+        );
+        queue.upgrading = Some(txn.id());
+        let ret = self.wait(&mut table, txn, &target);
+        if let Some(queue) = table.get_mut(&target) {
+            queue.upgrading = None;
+        }
+        match ret {
+            Ok(()) => {
+                txn.add_lock(target, mode);
+                Ok(())
+            }
+            Err(e) => {
+                // the original lock was given up with the request
+                txn.remove_lock(&target);
+                Err(e)
+            }
+        }
+    }
+
+    /// Blocks until the request of txn on target can be granted. An aborted txn gives up
+    /// its request
+    fn wait(
+        &self,
+        table: &mut MutexGuard<HashMap<LockTarget, LockRequestQueue>>,
+        txn: &Txn,
+        target: &LockTarget,
+    ) -> SqlResult<()> {
+        loop {
+            let queue = table.get_mut(target).unwrap();
+            let pos = queue.position(txn.id()).unwrap();
+            if txn.state() == TwoPLState::Aborted {
+                queue.queue.remove(pos);
+                queue.cv.notify_all();
+                if queue.queue.is_empty() {
+                    table.remove(target);
+                }
+                return Err(Error::Abort);
+            }
+            if queue.grantable(pos) {
+                queue.queue[pos].granted = true;
+                return Ok(());
+            }
+            let wounded = self.prevent_deadlock(queue, pos);
+            if txn.state() == TwoPLState::Aborted {
+                continue;
+            }
+            let cv = queue.cv.clone();
+            // a wounded txn may be waiting on the queue of another target
+            Self::wake(table, &wounded);
+            cv.wait(table);
         }
-        queue
+    }
+
+    pub fn unlock(&self, txn: &Txn, rid: RID) -> SqlResult<()> {
+        self.release(txn, &LockTarget::Row(rid))
+    }
+
+    /// Releases the lock of txn on target. Locks allowing writes can only be released
+    /// once the txn has committed or aborted
+    pub fn release(&self, txn: &Txn, target: &LockTarget) -> SqlResult<()> {
+        let finished = matches!(txn.state(), TwoPLState::Committed | TwoPLState::Aborted);
+        let mode = txn.lock_mode(target);
+        if !finished && mode.map_or(false, |mode| !mode.is_shared()) {
+            return Err(Error::Value(format!(
+                "transaction {} holds its {:?} lock on {} until it finishes",
+                txn.id(),
+                mode.unwrap(),
+                target
+            )));
+        }
+        let mut table = self.lock_table.lock();
+        if let Some(queue) = table.get_mut(target) {
+            if let Some(pos) = queue.position(txn.id()) {
+                queue.queue.remove(pos);
+                queue.cv.notify_all();
+            }
+            if queue.queue.is_empty() {
+                table.remove(target);
+            }
+        }
+        drop(table);
+        txn.remove_lock(target);
+        if txn.state() == TwoPLState::Growing
+            && txn.isolation_level() != IsolationLevel::ReadComitted
+        {
+            txn.set_state(TwoPLState::Shrinking);
+        }
+        Ok(())
+    }
+
+    /// Releases every lock of a finished txn
+    pub fn unlock_all(&self, txn: &Txn) -> SqlResult<()> {
+        for target in txn.locked() {
+            self.release(txn, &target)?;
+        }
+        Ok(())
     }
 }
 
-/* class LockManager {
-  enum class LockMode { SHARED, EXCLUSIVE };
-
-  class LockRequest {
-   public:
-    LockRequest(txn_id_t txn_id, LockMode lock_mode) : txn_id_(txn_id), lock_mode_(lock_mode), granted_(false) {}
-
-    txn_id_t txn_id_;
-    LockMode lock_mode_;
-    bool granted_;
-  };
-
-  class LockRequestQueue {
-   public:
-    std::list<LockRequest> request_queue_;
-    std::condition_variable cv_;  // for notifying blocked transactions on this rid
-    bool upgrading_ = false;
-  };
-
- public:
-  /**
-   * Creates a new lock manager configured for the deadlock detection policy.
-   */
-  LockManager() {
-    enable_cycle_detection_ = true;
-    cycle_detection_thread_ = new std::thread(&LockManager::RunCycleDetection, this);
-    LOG_INFO("Cycle detection thread launched");
-  }
-
-  ~LockManager() {
-    enable_cycle_detection_ = false;
-    cycle_detection_thread_->join();
-    delete cycle_detection_thread_;
-    LOG_INFO("Cycle detection thread stopped");
-  }
-
-  /*
-   * [LOCK_NOTE]: For all locking functions, we:
-   * 1. return false if the transaction is aborted; and
-   * 2. block on wait, return true when the lock request is granted; and
-   * 3. it is undefined behavior to try locking an already locked RID in the same transaction, i.e. the transaction
-   *    is responsible for keeping track of its current locks.
-   */
-
-  /**
-   * Acquire a lock on RID in shared mode. See [LOCK_NOTE] in header file.
-   * @param txn the transaction requesting the shared lock
-   * @param rid the RID to be locked in shared mode
-   * @return true if the lock is granted, false otherwise
-   */
-  bool LockShared(Transaction *txn, const RID &rid);
-
-  /**
-   * Acquire a lock on RID in exclusive mode. See [LOCK_NOTE] in header file.
-   * @param txn the transaction requesting the exclusive lock
-   * @param rid the RID to be locked in exclusive mode
-   * @return true if the lock is granted, false otherwise
-   */
-  bool LockExclusive(Transaction *txn, const RID &rid);
-
-  /**
-   * Upgrade a lock from a shared lock to an exclusive lock.
-   * @param txn the transaction requesting the lock upgrade
-   * @param rid the RID that should already be locked in shared mode by the requesting transaction
-   * @return true if the upgrade is successful, false otherwise
-   */
-  bool LockUpgrade(Transaction *txn, const RID &rid);
-
-  /**
-   * Release the lock held by the transaction.
-   * @param txn the transaction releasing the lock, it should actually hold the lock
-   * @param rid the RID that is locked by the transaction
-   * @return true if the unlock is successful, false otherwise
-   */
-  bool Unlock(Transaction *txn, const RID &rid);
-
-  /*** Graph API ***/
-  /**
-   * Adds edge t1->t2
-   */
-
-  /** Adds an edge from t1 -> t2. */
-  void AddEdge(txn_id_t t1, txn_id_t t2);
-
-  /** Removes an edge from t1 -> t2. */
-  void RemoveEdge(txn_id_t t1, txn_id_t t2);
-
-  /**
-   * Checks if the graph has a cycle, returning the newest transaction ID in the cycle if so.
-   * @param[out] txn_id if the graph has a cycle, will contain the newest transaction ID
-   * @return false if the graph has no cycle, otherwise stores the newest transaction ID in the cycle to txn_id
-   */
-  bool HasCycle(txn_id_t *txn_id);
-
-  /** @return the set of all edges in the graph, used for testing only! */
-  std::vector<std::pair<txn_id_t, txn_id_t>> GetEdgeList();
-
-  /** Runs cycle detection in the background. */
-  void RunCycleDetection();
-
- private:
-  std::mutex latch_;
-  std::atomic<bool> enable_cycle_detection_;
-  std::thread *cycle_detection_thread_;
-
-  /** Lock table for lock requests. */
-  std::unordered_map<RID, LockRequestQueue> lock_table_;
-  /** Waits-for graph representation. */
-  std::unordered_map<txn_id_t, std::vector<txn_id_t>> waits_for_;
-} */
+#[cfg(test)]
+pub mod tests {
+    use super::{DeadlockPolicy, LockMgr, LockMode};
+    use crate::{
+        sql::{
+            exe::{Catalog, ExecutionContext, Executor, RID},
+            tx::{IsolationLevel, TwoPLState, Txn, TxnManager},
+            util::collect,
+            DataBlock, Error, SqlResult,
+        },
+        storage::sled::Sled,
+    };
+    use datafusion::arrow::{
+        array::Int64Array,
+        compute::cast,
+        datatypes::{DataType, Field, Schema},
+    };
+    use std::{
+        sync::{mpsc, Arc},
+        thread,
+        time::Duration,
+    };
+    use tempfile::TempDir;
+
+    const WAIT: Duration = Duration::from_millis(50);
+
+    #[test]
+    fn test_shared_exclusive() {
+        let mgr = TxnManager::new();
+        let locks = mgr.lock_mgr();
+        let t1 = mgr.begin(IsolationLevel::RepeatableRead).unwrap();
+        let t2 = mgr.begin(IsolationLevel::RepeatableRead).unwrap();
+        locks.lock_s(&t1, 1).unwrap();
+        locks.lock_s(&t2, 1).unwrap();
+        assert!(t1.s_locked(1) && t2.s_locked(1));
+        locks.lock_x(&t1, 2).unwrap();
+
+        // t3 waits for t1 to release its exclusive lock
+        let (tx, rx) = mpsc::channel();
+        let t3 = mgr.begin(IsolationLevel::RepeatableRead).unwrap();
+        let handle = {
+            let (locks, t3) = (locks.clone(), t3.clone());
+            thread::spawn(move || {
+                locks.lock_s(&t3, 2).unwrap();
+                tx.send(()).unwrap();
+            })
+        };
+        assert!(rx.recv_timeout(WAIT).is_err());
+        assert!(locks.unlock(&t1, 2).is_err());
+        mgr.commit(&t1).unwrap();
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        handle.join().unwrap();
+        assert!(t3.s_locked(2));
+        assert!(t1.locked().is_empty());
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let mgr = TxnManager::new();
+        let locks = mgr.lock_mgr();
+        let t1 = mgr.begin(IsolationLevel::RepeatableRead).unwrap();
+        let t2 = mgr.begin(IsolationLevel::RepeatableRead).unwrap();
+        let t3 = mgr.begin(IsolationLevel::RepeatableRead).unwrap();
+        locks.lock_s(&t1, 1).unwrap();
+        locks.lock_s(&t2, 1).unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let handle = {
+            let (locks, t1) = (locks.clone(), t1.clone());
+            thread::spawn(move || {
+                locks.lock_x(&t1, 1).unwrap();
+                tx.send(()).unwrap();
+            })
+        };
+        assert!(rx.recv_timeout(WAIT).is_err());
+        // a second upgrade on the same rid would never be granted
+        assert_eq!(Err(Error::Abort), locks.upgrade_lock(&t2, 1));
+        assert_eq!(TwoPLState::Aborted, t2.state());
+
+        // waiting requests queue up behind the upgrade
+        let handle3 = {
+            let (locks, t3) = (locks.clone(), t3.clone());
+            thread::spawn(move || locks.lock_s(&t3, 1))
+        };
+        mgr.abort(&t2).unwrap();
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        handle.join().unwrap();
+        assert!(t1.x_locked(1) && !t1.s_locked(1));
+        thread::sleep(WAIT);
+        assert!(!t3.s_locked(1));
+        mgr.commit(&t1).unwrap();
+        handle3.join().unwrap().unwrap();
+        assert!(t3.s_locked(1));
+    }
+
+    #[test]
+    fn test_fifo() {
+        let mgr = Arc::new(TxnManager::new());
+        let locks = mgr.lock_mgr();
+        let t1 = mgr.begin(IsolationLevel::RepeatableRead).unwrap();
+        locks.lock_x(&t1, 1).unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let mut handles = vec![];
+        for lv in [IsolationLevel::RepeatableRead, IsolationLevel::ReadComitted] {
+            let txn = mgr.begin(lv).unwrap();
+            let (mgr, locks, tx) = (mgr.clone(), locks.clone(), tx.clone());
+            let exclusive = handles.is_empty();
+            handles.push(thread::spawn(move || {
+                if exclusive {
+                    locks.lock_x(&txn, 1).unwrap();
+                } else {
+                    locks.lock_s(&txn, 1).unwrap();
+                }
+                tx.send(txn.id()).unwrap();
+                thread::sleep(WAIT);
+                mgr.commit(&txn).unwrap();
+            }));
+            // make sure the requests arrive in order
+            thread::sleep(WAIT);
+        }
+        mgr.commit(&t1).unwrap();
+        let granted = vec![rx.recv().unwrap(), rx.recv().unwrap()];
+        assert_eq!(vec![t1.id() + 1, t1.id() + 2], granted);
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_two_phase_rules() {
+        let mgr = TxnManager::new();
+        let locks = mgr.lock_mgr();
+
+        let t1 = mgr.begin(IsolationLevel::ReadUncommitted).unwrap();
+        assert_eq!(Err(Error::Abort), locks.lock_s(&t1, 1));
+        assert_eq!(TwoPLState::Aborted, t1.state());
+        assert_eq!(Err(Error::Abort), locks.lock_x(&t1, 1));
+
+        // shared locks are released early under ReadComitted
+        let t2 = mgr.begin(IsolationLevel::ReadComitted).unwrap();
+        locks.lock_s(&t2, 1).unwrap();
+        locks.unlock(&t2, 1).unwrap();
+        assert_eq!(TwoPLState::Growing, t2.state());
+        locks.lock_x(&t2, 1).unwrap();
+        mgr.commit(&t2).unwrap();
+
+        let t3 = mgr.begin(IsolationLevel::RepeatableRead).unwrap();
+        locks.lock_s(&t3, 1).unwrap();
+        locks.lock_s(&t3, 2).unwrap();
+        locks.unlock(&t3, 1).unwrap();
+        assert_eq!(TwoPLState::Shrinking, t3.state());
+        assert_eq!(Err(Error::Abort), locks.lock_s(&t3, 3));
+        assert_eq!(TwoPLState::Aborted, t3.state());
+        assert_eq!(Err(Error::Abort), mgr.commit(&t3));
+        assert!(t3.locked().is_empty());
+    }
+
+    fn spawn_lock_x(
+        locks: &Arc<LockMgr>,
+        txn: &Arc<Txn>,
+        rid: RID,
+    ) -> thread::JoinHandle<SqlResult<()>> {
+        let (locks, txn) = (locks.clone(), txn.clone());
+        thread::spawn(move || locks.lock_x(&txn, rid))
+    }
+
+    /// t1 locks 1 then waits for 2, t2 locks 2 then waits for 1
+    fn classic_deadlock(policy: DeadlockPolicy) -> (Arc<TxnManager>, Arc<Txn>, Arc<Txn>) {
+        let mgr = Arc::new(TxnManager::new().with_deadlock_policy(policy));
+        let t1 = mgr.begin(IsolationLevel::RepeatableRead).unwrap();
+        let t2 = mgr.begin(IsolationLevel::RepeatableRead).unwrap();
+        let locks = mgr.lock_mgr();
+        locks.lock_x(&t1, 1).unwrap();
+        locks.lock_x(&t2, 2).unwrap();
+        (mgr, t1, t2)
+    }
+
+    #[test]
+    fn test_deadlock_detection() {
+        let (mgr, t1, t2) = classic_deadlock(DeadlockPolicy::Detection(Duration::from_secs(3600)));
+        let locks = mgr.lock_mgr();
+        let h1 = spawn_lock_x(&locks, &t1, 2);
+        let h2 = spawn_lock_x(&locks, &t2, 1);
+        thread::sleep(WAIT);
+        assert!(!h1.is_finished() && !h2.is_finished());
+
+        // the youngest txn of the cycle is aborted
+        assert_eq!(vec![t2.id()], locks.detect_deadlocks());
+        assert_eq!(Err(Error::Abort), h2.join().unwrap());
+        assert_eq!(TwoPLState::Aborted, t2.state());
+        assert!(locks.detect_deadlocks().is_empty());
+        thread::sleep(WAIT);
+        assert!(!h1.is_finished());
+        mgr.abort(&t2).unwrap();
+        h1.join().unwrap().unwrap();
+        assert!(t1.x_locked(2));
+        mgr.commit(&t1).unwrap();
+    }
+
+    #[test]
+    fn test_deadlock_detection_background() {
+        let (mgr, t1, t2) = classic_deadlock(DeadlockPolicy::Detection(WAIT));
+        let locks = mgr.lock_mgr();
+        // a cycle through three txns, t3 is the youngest
+        let t3 = mgr.begin(IsolationLevel::RepeatableRead).unwrap();
+        locks.lock_x(&t3, 3).unwrap();
+        let h1 = spawn_lock_x(&locks, &t1, 2);
+        let h2 = spawn_lock_x(&locks, &t2, 3);
+        let h3 = spawn_lock_x(&locks, &t3, 1);
+        assert_eq!(Err(Error::Abort), h3.join().unwrap());
+        mgr.abort(&t3).unwrap();
+        h2.join().unwrap().unwrap();
+        mgr.commit(&t2).unwrap();
+        h1.join().unwrap().unwrap();
+        mgr.commit(&t1).unwrap();
+    }
+
+    #[test]
+    fn test_wound_wait() {
+        let (mgr, t1, t2) = classic_deadlock(DeadlockPolicy::WoundWait);
+        let locks = mgr.lock_mgr();
+        // the older t1 wounds t2 and waits for it to release its lock
+        let h1 = spawn_lock_x(&locks, &t1, 2);
+        thread::sleep(WAIT);
+        assert_eq!(TwoPLState::Aborted, t2.state());
+        assert!(!h1.is_finished());
+        assert_eq!(Err(Error::Abort), locks.lock_x(&t2, 1));
+        mgr.abort(&t2).unwrap();
+        h1.join().unwrap().unwrap();
+
+        // a younger txn waits for an older one
+        let t3 = mgr.begin(IsolationLevel::RepeatableRead).unwrap();
+        let h3 = spawn_lock_x(&locks, &t3, 1);
+        thread::sleep(WAIT);
+        assert!(!h3.is_finished());
+        assert_eq!(TwoPLState::Growing, t1.state());
+        mgr.commit(&t1).unwrap();
+        h3.join().unwrap().unwrap();
+        mgr.commit(&t3).unwrap();
+    }
+
+    #[test]
+    fn test_wound_wait_elsewhere() {
+        let (mgr, t1, t2) = classic_deadlock(DeadlockPolicy::WoundWait);
+        let locks = mgr.lock_mgr();
+        // the wounded t2 waits on rid 1 when t1 requests rid 2
+        let h2 = spawn_lock_x(&locks, &t2, 1);
+        thread::sleep(WAIT);
+        assert!(!h2.is_finished());
+        let h1 = spawn_lock_x(&locks, &t1, 2);
+        assert_eq!(Err(Error::Abort), h2.join().unwrap());
+        assert_eq!(TwoPLState::Aborted, t2.state());
+        mgr.abort(&t2).unwrap();
+        h1.join().unwrap().unwrap();
+        assert!(t1.x_locked(1) && t1.x_locked(2));
+        mgr.commit(&t1).unwrap();
+    }
+
+    #[test]
+    fn test_wait_die() {
+        let (mgr, t1, t2) = classic_deadlock(DeadlockPolicy::WaitDie);
+        let locks = mgr.lock_mgr();
+        // the older t1 waits, the younger t2 dies instead of waiting
+        let h1 = spawn_lock_x(&locks, &t1, 2);
+        thread::sleep(WAIT);
+        assert!(!h1.is_finished());
+        assert_eq!(TwoPLState::Growing, t2.state());
+        assert_eq!(Err(Error::Abort), locks.lock_x(&t2, 1));
+        mgr.abort(&t2).unwrap();
+        h1.join().unwrap().unwrap();
+
+        // compatible requests never die
+        let t3 = mgr.begin(IsolationLevel::RepeatableRead).unwrap();
+        let t4 = mgr.begin(IsolationLevel::RepeatableRead).unwrap();
+        locks.lock_s(&t3, 3).unwrap();
+        locks.lock_s(&t4, 3).unwrap();
+        assert_eq!(Err(Error::Abort), locks.lock_x(&t4, 1));
+        mgr.abort(&t4).unwrap();
+        mgr.commit(&t1).unwrap();
+        mgr.commit(&t3).unwrap();
+    }
+
+    #[test]
+    fn test_lock_modes() {
+        use LockMode::*;
+        let modes = [
+            IntentionShared,
+            IntentionExclusive,
+            Shared,
+            SharedIntentionExclusive,
+            Exclusive,
+        ];
+        let compatible = [
+            [true, true, true, true, false],
+            [true, true, false, false, false],
+            [true, false, true, false, false],
+            [true, false, false, false, false],
+            [false, false, false, false, false],
+        ];
+        for (i, a) in modes.iter().enumerate() {
+            for (j, b) in modes.iter().enumerate() {
+                assert_eq!(compatible[i][j], a.compatible(*b), "{:?} {:?}", a, b);
+            }
+        }
+        assert_eq!(SharedIntentionExclusive, Shared.join(IntentionExclusive));
+        assert_eq!(Exclusive, SharedIntentionExclusive.join(Exclusive));
+        assert_eq!(Shared, IntentionShared.join(Shared));
+
+        // a shared table lock keeps writers out of the whole table
+        let mgr = TxnManager::new();
+        let locks = mgr.lock_mgr();
+        let t1 = mgr.begin(IsolationLevel::Serializable).unwrap();
+        let t2 = mgr.begin(IsolationLevel::Serializable).unwrap();
+        locks.lock_table(&t1, "t", Shared).unwrap();
+        locks.lock_table(&t2, "t", IntentionShared).unwrap();
+        let handle = {
+            let (locks, t2) = (locks.clone(), t2.clone());
+            thread::spawn(move || locks.lock_table(&t2, "t", IntentionExclusive))
+        };
+        thread::sleep(WAIT);
+        assert!(!handle.is_finished());
+        mgr.commit(&t1).unwrap();
+        handle.join().unwrap().unwrap();
+        assert_eq!(
+            Some(IntentionExclusive),
+            t2.lock_mode(&super::LockTarget::Table("t".to_string()))
+        );
+        mgr.commit(&t2).unwrap();
+    }
+
+    fn query(ctx: &ExecutionContext, sql: &str) -> SqlResult<Vec<DataBlock>> {
+        collect(Executor::execute_sql(sql, ctx.clone())?)
+    }
+
+    fn on_duty(ctx: &ExecutionContext) -> i64 {
+        let batches = query(ctx, "SELECT COUNT(*) FROM duties WHERE status = 'on'").unwrap();
+        let counts = cast(batches[0].column(0), &DataType::Int64).unwrap();
+        counts
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap()
+            .value(0)
+    }
+
+    /// Two doctors are on duty and each txn takes one of them off duty if the other one
+    /// is still on duty. Returns how many txns committed and how many doctors are left
+    fn doctors_on_duty(lv: IsolationLevel) -> (usize, i64) {
+        let dir = TempDir::new().expect("failed creating temp dir");
+        let path = dir.path().join("db").to_str().unwrap().to_string();
+        let db = Arc::new(Sled::new(path).expect("failed creating sled"));
+        let ctx = ExecutionContext::new(db.clone());
+        db.create_table(
+            "duties",
+            Schema::new(vec![
+                Field::new("doctor", DataType::Int32, false),
+                Field::new("status", DataType::Utf8, true),
+            ]),
+        )
+        .unwrap();
+        query(&ctx, "INSERT INTO duties VALUES (1, 'on'), (2, 'on')").unwrap();
+
+        let mgr = Arc::new(TxnManager::with_mvcc(db.mvcc()));
+        let txns = [mgr.begin(lv).unwrap(), mgr.begin(lv).unwrap()];
+        let ctxs = txns
+            .iter()
+            .map(|txn| {
+                ExecutionContext::new(db.clone())
+                    .with_txn_manager(mgr.clone())
+                    .with_txn(txn.clone())
+            })
+            .collect::<Vec<_>>();
+        // both read before either writes
+        for ctx in &ctxs {
+            assert_eq!(2, on_duty(ctx));
+        }
+        let (tx, rx) = mpsc::channel();
+        for (i, ctx) in ctxs.into_iter().enumerate() {
+            let tx = tx.clone();
+            let sql = format!(
+                "UPDATE duties SET status = 'reserve' WHERE doctor = {}",
+                i + 1
+            );
+            thread::spawn(move || tx.send((i, query(&ctx, &sql))).unwrap());
+        }
+        let mut committed = 0;
+        for _ in 0..txns.len() {
+            let (i, result) = rx.recv().unwrap();
+            match result {
+                Ok(_) if mgr.commit(&txns[i]).is_ok() => committed += 1,
+                Ok(_) => {}
+                Err(e) => {
+                    assert_eq!(Error::Abort, e);
+                    mgr.abort(&txns[i]).unwrap();
+                }
+            }
+        }
+        (committed, on_duty(&ExecutionContext::new(db)))
+    }
+
+    #[test]
+    fn test_doctors_on_duty() {
+        // write skew: the txns write different rows so snapshot isolation lets both
+        // commit, and nobody is left on duty
+        assert_eq!((2, 0), doctors_on_duty(IsolationLevel::RepeatableRead));
+        // the table locks taken by the serializable reads keep both writes from
+        // going through
+        assert_eq!((1, 1), doctors_on_duty(IsolationLevel::Serializable));
+    }
+}
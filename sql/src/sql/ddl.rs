@@ -0,0 +1,53 @@
+use super::exe::{BoxedDataIter, Operator, SchemaDataIter};
+use crate::sql::{Error, ExecutionContext, SqlResult};
+use datafusion::arrow::datatypes::{Schema, SchemaRef};
+use std::sync::Arc;
+
+pub struct CreateIndexPlan {
+    pub index: String,
+    pub table: String,
+    pub columns: Vec<String>,
+    /// An existing index with the same name is not an error
+    pub if_not_exists: bool,
+}
+
+/// Creates the index through the catalog of the context, returns no rows
+#[derive(Debug)]
+pub struct CreateIndex {
+    index: String,
+    table: String,
+    columns: Vec<String>,
+    if_not_exists: bool,
+}
+
+impl CreateIndex {
+    pub fn from_plan(plan: CreateIndexPlan, _: ExecutionContext) -> SqlResult<Self> {
+        Ok(CreateIndex {
+            index: plan.index,
+            table: plan.table,
+            columns: plan.columns,
+            if_not_exists: plan.if_not_exists,
+        })
+    }
+}
+
+impl Operator for CreateIndex {
+    fn execute_sync(&mut self, ctx: ExecutionContext) -> SqlResult<BoxedDataIter> {
+        let created = ctx
+            .get_storage()
+            .create_index(&self.index, &self.table, &self.columns);
+        match created {
+            Ok(_) => {}
+            Err(Error::AlreadyExists(_)) if self.if_not_exists => {}
+            Err(e) => return Err(e),
+        }
+        Ok(SchemaDataIter::new(
+            self.schema(),
+            Box::new(std::iter::empty()),
+        ))
+    }
+
+    fn schema(&self) -> SchemaRef {
+        Arc::new(Schema::empty())
+    }
+}
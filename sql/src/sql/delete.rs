@@ -0,0 +1,50 @@
+use super::exe::{
+    affected_rows_block, affected_rows_schema, BoxedDataIter, Executor, Operator, PlanType,
+    SchemaDataIter,
+};
+use crate::sql::{ExecutionContext, SqlResult};
+use datafusion::arrow::datatypes::SchemaRef;
+
+/// Child returns batches of RIDs, see [crate::sql::exe::rid_schema]
+pub struct DeletePlan {
+    pub table: String,
+    pub source_plan: Box<PlanType>,
+}
+
+/// Deletes every row whose RID is returned by its child, output is a one row batch
+/// containing the number of deleted rows
+#[derive(Debug)]
+pub struct Delete {
+    table: String,
+    source: Box<dyn Operator>,
+}
+
+impl Delete {
+    pub fn from_plan(plan: DeletePlan, ctx: ExecutionContext) -> SqlResult<Self> {
+        let source = Executor::create_from_subplan_operator(*plan.source_plan, ctx)?;
+        Ok(Delete {
+            table: plan.table,
+            source,
+        })
+    }
+}
+
+impl Operator for Delete {
+    fn execute_sync(&mut self, ctx: ExecutionContext) -> SqlResult<BoxedDataIter> {
+        let input = self.source.execute_sync(ctx.clone())?;
+        ctx.lock_for_write(&self.table)?;
+        let input = ctx.lock_rows(self.source.schema(), input);
+        let deleted = ctx
+            .get_storage()
+            .delete(&self.table, input, &ctx.get_txn())?;
+        let batch = affected_rows_block(deleted)?;
+        Ok(SchemaDataIter::new(
+            self.schema(),
+            Box::new(std::iter::once(Ok(batch))),
+        ))
+    }
+
+    fn schema(&self) -> SchemaRef {
+        affected_rows_schema()
+    }
+}
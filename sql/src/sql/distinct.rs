@@ -0,0 +1,217 @@
+use crate::sql::{
+    exe::{BoxedDataIter, Executor, Operator, PlanType, SchemaDataIter},
+    join::{
+        hash_util::create_hashes,
+        inmem::{equal_rows, JoinedTable},
+    },
+    memory::{batch_memory_size, MemoryReservation},
+    util::GeneratorIteratorAdapter,
+    DataBlock, ExecutionContext, SqlResult,
+};
+use ahash::RandomState;
+use datafusion::arrow::{
+    array::{ArrayRef, UInt32Array},
+    compute::take,
+    datatypes::SchemaRef,
+};
+use smallvec::{smallvec, SmallVec};
+use std::mem::{size_of, take as take_buffer};
+
+pub struct DistinctPlan {
+    pub source_plan: Box<PlanType>,
+}
+
+/// Returns the rows of the child without duplicates, nulls are equal to each other.
+/// Each batch is returned as soon as its new rows are known, the first occurrence
+/// of a row is kept.
+///
+/// Rows already returned are kept in memory reserved from the pool of the
+/// [ExecutionContext], the query fails once they do not fit anymore
+#[derive(Debug)]
+pub struct Distinct {
+    input: Box<dyn Operator>,
+}
+
+impl Distinct {
+    pub fn from_plan(plan: DistinctPlan, ctx: ExecutionContext) -> SqlResult<Self> {
+        let input = Executor::create_from_subplan_operator(*plan.source_plan, ctx)?;
+        Ok(Distinct { input })
+    }
+}
+
+/// Rows are identified by the index of the returned batch in the upper 32 bits
+/// and the row index in that batch in the lower 32 bits
+struct DistinctRows {
+    table: JoinedTable,
+    returned: Vec<Vec<ArrayRef>>,
+    hash_state: RandomState,
+    hash_buffer: Vec<u64>,
+    reservation: MemoryReservation,
+}
+
+impl DistinctRows {
+    fn new(reservation: MemoryReservation) -> Self {
+        DistinctRows {
+            table: JoinedTable::new(),
+            returned: vec![],
+            hash_state: RandomState::new(),
+            hash_buffer: vec![],
+            reservation,
+        }
+    }
+
+    /// Rows of the batch that were not returned before, None if there is none
+    fn push(&mut self, batch: DataBlock) -> SqlResult<Option<DataBlock>> {
+        let columns = batch.columns();
+        let mut hash_buffer = take_buffer(&mut self.hash_buffer);
+        hash_buffer.clear();
+        hash_buffer.resize(batch.num_rows(), 0);
+        create_hashes(columns, &self.hash_state, &mut hash_buffer)?;
+
+        let batch_index = self.returned.len() as u64;
+        let mut accepted: Vec<u32> = vec![];
+        for (row, hash) in hash_buffer.iter().enumerate() {
+            if let Some((_, ids)) = self.table.get(*hash, |(h, _)| h == hash) {
+                let mut duplicate = false;
+                for id in ids {
+                    let (index, other) = ((id >> 32) as usize, (id & u32::MAX as u64) as usize);
+                    // rows of this batch are compared before they are returned
+                    let (arrays, other) = if index as u64 == batch_index {
+                        (columns, accepted[other] as usize)
+                    } else {
+                        (self.returned[index].as_slice(), other)
+                    };
+                    if equal_rows(row, other, columns, arrays, true)? {
+                        duplicate = true;
+                        break;
+                    }
+                }
+                if duplicate {
+                    continue;
+                }
+            }
+            let id = batch_index << 32 | accepted.len() as u64;
+            match self.table.get_mut(*hash, |(h, _)| h == hash) {
+                Some((_, ids)) => ids.push(id),
+                None => {
+                    self.table
+                        .insert(*hash, (*hash, smallvec![id]), |(h, _)| *h);
+                }
+            }
+            accepted.push(row as u32);
+        }
+        self.hash_buffer = hash_buffer;
+        if accepted.is_empty() {
+            return Ok(None);
+        }
+
+        let rows = accepted.len();
+        let indices = UInt32Array::from(accepted);
+        let distinct = columns
+            .iter()
+            .map(|column| take(column.as_ref(), &indices, None))
+            .collect::<Result<Vec<_>, _>>()?;
+        let distinct = DataBlock::try_new(batch.schema(), distinct)?;
+        self.reservation
+            .grow(batch_memory_size(&distinct) + rows * size_of::<(u64, SmallVec<[u64; 1]>)>())?;
+        self.returned.push(distinct.columns().to_vec());
+        Ok(Some(distinct))
+    }
+}
+
+impl Operator for Distinct {
+    fn execute_sync(&mut self, ctx: ExecutionContext) -> SqlResult<BoxedDataIter> {
+        let input = self.input.execute_sync(ctx.clone())?;
+        let mut rows = DistinctRows::new(ctx.new_reservation("Distinct"));
+        let gen = move || {
+            for batch in input {
+                match batch.and_then(|batch| rows.push(batch)) {
+                    Ok(Some(batch)) => yield Ok(batch),
+                    Ok(None) => {}
+                    Err(e) => {
+                        yield Err(e);
+                        return;
+                    }
+                }
+            }
+        };
+        let iter = GeneratorIteratorAdapter::new(gen);
+        Ok(SchemaDataIter::new(self.schema(), Box::new(iter)))
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.input.schema()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::Distinct;
+    use crate::sql::{
+        exe::Operator, inmem_op::InMemOp, memory::MemoryPool, util::collect, DataBlock, Error,
+        ExecutionContext,
+    };
+    use datafusion::arrow::{
+        array::{Array, Int32Array, StringArray},
+        datatypes::{DataType, Field, Schema},
+    };
+    use std::sync::Arc;
+
+    fn input() -> InMemOp {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int32, true),
+            Field::new("b", DataType::Utf8, true),
+        ]));
+        let batch = |a: Vec<Option<i32>>, b: Vec<Option<&str>>| {
+            DataBlock::try_new(
+                schema.clone(),
+                vec![
+                    Arc::new(Int32Array::from(a)) as Arc<dyn Array>,
+                    Arc::new(StringArray::from(b)) as Arc<dyn Array>,
+                ],
+            )
+            .unwrap()
+        };
+        let batches = vec![
+            batch(
+                vec![Some(1), Some(1), None, None, Some(2)],
+                vec![Some("x"), Some("x"), None, None, Some("x")],
+            ),
+            batch(vec![Some(2), Some(1)], vec![Some("x"), Some("x")]),
+            batch(vec![None, Some(1)], vec![Some("y"), None]),
+        ];
+        InMemOp::new(schema, batches)
+    }
+
+    #[test]
+    fn test_distinct() {
+        let mut op = Distinct {
+            input: Box::new(input()),
+        };
+        let batches = collect(op.execute_sync(ExecutionContext::new_for_test()).unwrap()).unwrap();
+        // the second batch only has duplicates
+        assert_eq!(2, batches.len());
+        let expected = [
+            "+---+---+",
+            "| a | b |",
+            "+---+---+",
+            "|   |   |",
+            "|   | y |",
+            "| 1 |   |",
+            "| 1 | x |",
+            "| 2 | x |",
+            "+---+---+",
+        ];
+        crate::assert_batches_sorted_eq!(expected, &batches);
+    }
+
+    #[test]
+    fn test_distinct_memory_limit_exceeded() {
+        let ctx = ExecutionContext::new_for_test().with_memory_pool(Arc::new(MemoryPool::new(64)));
+        let mut op = Distinct {
+            input: Box::new(input()),
+        };
+        let ret = collect(op.execute_sync(ctx).unwrap());
+        assert!(matches!(ret, Err(Error::ResourceExhausted(_))));
+    }
+}
@@ -1,26 +1,48 @@
 use crate::{
     sql::{
-        insert::InsertPlan,
+        agg::{AggregationPlan, HashAggregateOp},
+        cc::LockMode,
+        ddl::{CreateIndex, CreateIndexPlan},
+        delete::{Delete, DeletePlan},
+        distinct::{Distinct, DistinctPlan},
+        filter::{Filter, FilterPlan},
+        insert::{Insert, InsertPlan},
         join::{
             grace::{GraceHashJoinOp, GraceHashJoinPlan, PartitionedQueue},
-            queue::MemoryAllocator,
+            nested_loop::{NestedLoopJoinOp, NestedLoopJoinPlan},
+            queue::{MemoryAllocator, QueueAllocator},
         },
-        scan::SeqScanPlan,
-        tx::Txn,
+        limit::{Limit, LimitPlan},
+        memory::{MemoryPool, MemoryReservation},
+        planner::Planner,
+        projection::{Projection, ProjectionPlan},
+        scan::{
+            index::{IndexRange, IndexScan, IndexScanPlan},
+            SeqScanPlan, SeqScanner,
+        },
+        sort::{SortOp, SortPlan},
+        tx::{IsolationLevel, Txn, TxnManager},
+        update::{Update, UpdatePlan},
         util::RawInput,
-        DataBlock, SqlResult,
+        DataBlock, Error, SqlResult,
     },
     storage::sled::Sled,
 };
-use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::{
+    array::{Array, UInt64Array},
+    datatypes::{DataType, Field, Schema, SchemaRef},
+};
+use serde_derive::{Deserialize, Serialize};
 use std::{fmt::Debug, sync::Arc};
 use tempfile::NamedTempFile;
 
 #[derive(Clone)]
 pub struct ExecutionContext {
     storage: Arc<dyn Storage>,
-    pub txn: Txn,
-    queue: Arc<MemoryAllocator>, // TODO: make this into a trait object
+    txn_mgr: Arc<TxnManager>,
+    txn: Option<Arc<Txn>>,
+    queue: Arc<dyn QueueAllocator>,
+    memory: Arc<MemoryPool>,
 }
 
 impl ExecutionContext {
@@ -32,21 +54,119 @@ impl ExecutionContext {
         let db = Sled::new(path).expect("failed creating sled");
 
         ExecutionContext {
-            txn: Txn::new(),
+            txn_mgr: Arc::new(TxnManager::with_mvcc(db.mvcc())),
+            txn: None,
             storage: Arc::new(db),
             queue: Arc::new(inmem),
+            memory: Arc::new(MemoryPool::unbounded()),
         }
     }
     pub fn new(store: Arc<dyn Storage>) -> Self {
         let inmem = MemoryAllocator::new();
         ExecutionContext {
-            txn: Txn::new(),
+            txn_mgr: Arc::new(TxnManager::new()),
+            txn: None,
             storage: store,
             queue: Arc::new(inmem),
+            memory: Arc::new(MemoryPool::unbounded()),
         }
     }
-    pub fn get_txn(&self) -> &Txn {
-        &self.txn
+
+    /// Manager the txns of the queries are begun from and the locks are taken from. By
+    /// default it has no MVCC, so storages commit each operation of a txn on its own
+    pub fn with_txn_manager(mut self, txn_mgr: Arc<TxnManager>) -> Self {
+        self.txn_mgr = txn_mgr;
+        self
+    }
+
+    pub fn txn_manager(&self) -> Arc<TxnManager> {
+        self.txn_mgr.clone()
+    }
+
+    /// Runs the queries in txn, begun from the [TxnManager] of the context, which the
+    /// caller commits or aborts. Without one, [Executor::execute] runs each query in a
+    /// txn of its own
+    pub fn with_txn(mut self, txn: Arc<Txn>) -> Self {
+        self.txn = Some(txn);
+        self
+    }
+
+    /// Locks the table before reading it. Serializable txns lock the whole table, so no
+    /// other txn can insert rows into the ranges they read until they finish
+    pub fn lock_for_read(&self, table: &str) -> SqlResult<()> {
+        let txn = match &self.txn {
+            Some(txn) => txn,
+            None => return Ok(()),
+        };
+        let mode = match txn.isolation_level() {
+            IsolationLevel::ReadUncommitted => return Ok(()),
+            IsolationLevel::Serializable => LockMode::Shared,
+            IsolationLevel::ReadComitted | IsolationLevel::RepeatableRead => {
+                LockMode::IntentionShared
+            }
+        };
+        self.txn_mgr.lock_mgr().lock_table(txn, table, mode)
+    }
+
+    /// Locks the table before writing rows into it
+    pub fn lock_for_write(&self, table: &str) -> SqlResult<()> {
+        match &self.txn {
+            Some(txn) => {
+                self.txn_mgr
+                    .lock_mgr()
+                    .lock_table(txn, table, LockMode::IntentionExclusive)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Passes input through after locking the rids of its last column exclusively
+    pub fn lock_rows(&self, schema: SchemaRef, input: BoxedDataIter) -> BoxedDataIter {
+        let txn = match &self.txn {
+            Some(txn) => txn.clone(),
+            None => return input,
+        };
+        let lock_mgr = self.txn_mgr.lock_mgr();
+        let iter = input.map(move |batch| {
+            let batch = batch?;
+            let rids = batch
+                .column(batch.num_columns() - 1)
+                .as_any()
+                .downcast_ref::<UInt64Array>()
+                .ok_or_else(|| Error::Internal("expect rid as the last column".to_string()))?;
+            for rid in rids.iter().flatten() {
+                lock_mgr.lock_x(&txn, rid)?;
+            }
+            Ok(batch)
+        });
+        SchemaDataIter::new(schema, Box::new(iter))
+    }
+
+    /// Queues used by operators to spill their partitions are allocated from queue,
+    /// in memory by default
+    pub fn with_queue_allocator(mut self, queue: Arc<dyn QueueAllocator>) -> Self {
+        self.queue = queue;
+        self
+    }
+
+    /// Memory pool shared by every operator of the query, unbounded by default
+    pub fn with_memory_pool(mut self, memory: Arc<MemoryPool>) -> Self {
+        self.memory = memory;
+        self
+    }
+
+    pub fn memory_pool(&self) -> Arc<MemoryPool> {
+        self.memory.clone()
+    }
+
+    pub fn new_reservation(&self, consumer: &str) -> MemoryReservation {
+        MemoryReservation::new(self.memory.clone(), consumer)
+    }
+
+    /// Txn the operators run in. Operators executed on their own outside of a txn run
+    /// each storage operation in a txn of its own, and take no locks
+    pub fn get_txn(&self) -> Arc<Txn> {
+        self.txn.clone().unwrap_or_else(|| Arc::new(Txn::new()))
     }
 
     pub fn get_storage(&self) -> Arc<dyn Storage> {
@@ -54,7 +174,7 @@ impl ExecutionContext {
     }
 
     pub fn new_queue(&self, schema: SchemaRef) -> Arc<dyn PartitionedQueue> {
-        (*self.queue).alloc(schema)
+        self.queue.alloc(schema)
     }
 }
 
@@ -105,97 +225,442 @@ pub enum PlanType {
     RawInput(RawInput),
     Insert(InsertPlan),
     GraceHashJoin(GraceHashJoinPlan),
-    IndexScan,
-    Update,
-    Delete,
-    Aggregation,
-    Limit,
+    NestedLoopJoin(NestedLoopJoinPlan),
+    IndexScan(IndexScanPlan),
+    Update(UpdatePlan),
+    Delete(DeletePlan),
+    Aggregation(AggregationPlan),
+    Sort(SortPlan),
+    Limit(LimitPlan),
+    Filter(FilterPlan),
+    Projection(ProjectionPlan),
+    Distinct(DistinctPlan),
+    CreateIndex(CreateIndexPlan),
     HashJoin,
 }
 
 pub struct Executor {}
 impl Executor {
     // TODO: maybe return some async iter like stream in the future
+    /// Runs the plan in the txn of ctx. Without one, the plan runs in a txn begun from
+    /// the [TxnManager] of ctx, which commits once the returned stream ends and aborts
+    /// when the query fails or the stream is dropped before its end
     pub fn execute(plan: PlanType, ctx: ExecutionContext) -> SqlResult<BoxedDataIter> {
-        let mut operator: Box<dyn Operator> = Self::create_operator(plan, ctx.clone());
-        operator.execute_sync(ctx)
-    }
-
-    pub fn create_operator(plan_type: PlanType, ctx: ExecutionContext) -> Box<dyn Operator> {
-        match plan_type {
-            /* PlanType::SeqScan(plan) => {
-                Box::new(SeqScanner::from_plan(plan, ctx.clone())) as Box<dyn Operator>
-            }
-            PlanType::Insert(plan) => Box::new(Insert::new(plan, ctx.clone())),
-            PlanType::RawInput(raw) => Box::new(raw), */
-            PlanType::GraceHashJoin(plan) => {
-                let cloned = ctx.clone();
-                Box::new(GraceHashJoinOp::from_plan(plan, ctx.clone()))
+        if ctx.txn.is_some() {
+            let mut operator: Box<dyn Operator> = Self::create_operator(plan, ctx.clone())?;
+            return operator.execute_sync(ctx);
+        }
+        let txn_mgr = ctx.txn_manager();
+        let txn = txn_mgr.begin(IsolationLevel::ReadComitted)?;
+        let ctx = ctx.with_txn(txn.clone());
+        let stream = Self::create_operator(plan, ctx.clone())
+            .and_then(|mut operator| Ok((operator.schema(), operator.execute_sync(ctx)?)));
+        match stream {
+            Ok((schema, stream)) => {
+                let iter = AutoCommit {
+                    stream,
+                    txn_mgr,
+                    txn: Some(txn),
+                };
+                Ok(SchemaDataIter::new(schema, Box::new(iter)))
             }
-            _ => {
-                todo!("todo")
+            Err(e) => {
+                txn_mgr.abort(&txn)?;
+                Err(e)
             }
         }
     }
-    pub fn create_from_subplan_operator(
+
+    /// Plans the statement against the catalog of ctx and executes it
+    pub fn execute_sql(sql: &str, ctx: ExecutionContext) -> SqlResult<BoxedDataIter> {
+        let plan = Planner::new(ctx.get_storage()).plan(sql)?;
+        Self::execute(plan, ctx)
+    }
+
+    pub fn create_operator(
         plan_type: PlanType,
         ctx: ExecutionContext,
-    ) -> Box<dyn Operator> {
-        match plan_type {
-            /* PlanType::SeqScan(plan) => {
-                Box::new(SeqScanner::from_plan(plan, ctx)) as Box<dyn Operator>
+    ) -> SqlResult<Box<dyn Operator>> {
+        let op: Box<dyn Operator> = match plan_type {
+            PlanType::SeqScan(plan) => Box::new(SeqScanner::from_plan(plan, ctx)?),
+            PlanType::Insert(plan) => Box::new(Insert::from_plan(plan, ctx)?),
+            PlanType::RawInput(raw) => Box::new(raw),
+            PlanType::GraceHashJoin(plan) => Box::new(GraceHashJoinOp::from_plan(plan, ctx)?),
+            PlanType::NestedLoopJoin(plan) => Box::new(NestedLoopJoinOp::from_plan(plan, ctx)?),
+            PlanType::Aggregation(plan) => Box::new(HashAggregateOp::from_plan(plan, ctx)?),
+            PlanType::Sort(plan) => Box::new(SortOp::from_plan(plan, ctx)?),
+            PlanType::Filter(plan) => Box::new(Filter::from_plan(plan, ctx)?),
+            PlanType::Projection(plan) => Box::new(Projection::from_plan(plan, ctx)?),
+            PlanType::Limit(plan) => Box::new(Limit::from_plan(plan, ctx)?),
+            PlanType::Distinct(plan) => Box::new(Distinct::from_plan(plan, ctx)?),
+            PlanType::CreateIndex(plan) => Box::new(CreateIndex::from_plan(plan, ctx)?),
+            PlanType::Update(plan) => Box::new(Update::from_plan(plan, ctx)?),
+            PlanType::Delete(plan) => Box::new(Delete::from_plan(plan, ctx)?),
+            PlanType::IndexScan(plan) => Box::new(IndexScan::from_plan(plan, ctx)?),
+            PlanType::HashJoin => {
+                return Err(Error::Internal(
+                    "plan type does not have an operator yet".to_string(),
+                ))
             }
-            PlanType::RawInput(raw) => Box::new(raw), */
-            _ => {
-                todo!("todo")
+        };
+        Ok(op)
+    }
+
+    /// Every operator can be the child of another one, the plan is built bottom up
+    pub fn create_from_subplan_operator(
+        plan_type: PlanType,
+        ctx: ExecutionContext,
+    ) -> SqlResult<Box<dyn Operator>> {
+        Self::create_operator(plan_type, ctx)
+    }
+}
+/// Stream of a query running in a txn of its own, finishes the txn with the query
+struct AutoCommit {
+    stream: BoxedDataIter,
+    txn_mgr: Arc<TxnManager>,
+    /// Taken once the txn is finished
+    txn: Option<Arc<Txn>>,
+}
+
+impl Iterator for AutoCommit {
+    type Item = SqlResult<DataBlock>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let txn = self.txn.take()?;
+        match self.stream.next() {
+            Some(Ok(batch)) => {
+                self.txn = Some(txn);
+                Some(Ok(batch))
             }
+            Some(Err(e)) => Some(self.txn_mgr.abort(&txn).and(Err(e))),
+            None => self.txn_mgr.commit(&txn).err().map(Err),
+        }
+    }
+}
+
+impl Drop for AutoCommit {
+    fn drop(&mut self) {
+        if let Some(txn) = self.txn.take() {
+            let _ = self.txn_mgr.abort(&txn);
         }
     }
 }
+
+pub type OID = u32;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TableMeta {
+    pub oid: OID,
+    pub name: String,
+    /// Column names, types, nullability and per column metadata
+    pub schema: Schema,
+}
+
+impl TableMeta {
+    pub fn schema_ref(&self) -> SchemaRef {
+        Arc::new(self.schema.clone())
+    }
+}
+
+/// Secondary index mapping the values of columns of a table to the RIDs of its rows
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct IndexMeta {
+    pub name: String,
+    pub table: String,
+    /// Names of the indexed columns, rows are ordered by the first one first
+    pub columns: Vec<String>,
+}
+
+/// Every implementation must persist the catalog in the same storage as the table data
 pub trait Catalog {
-    /* fn create_table(&self, tablename: String, schema: Schema) -> SqlResult<TableMeta>;
+    /// Returns [crate::sql::Error::AlreadyExists] if a table with the same name exists
+    fn create_table(&self, tablename: &str, schema: Schema) -> SqlResult<TableMeta>;
+
+    /// Returns [crate::sql::Error::NotFound] if the table does not exist
+    fn get_table(&self, tablename: &str) -> SqlResult<TableMeta>;
+
+    /// Removes the table and all of its rows
+    fn drop_table(&self, tablename: &str) -> SqlResult<()>;
 
-    fn get_table(&self, tablename: String) -> SqlResult<TableMeta>; */
+    /// Tables ordered by name
+    fn list_tables(&self) -> SqlResult<Vec<TableMeta>>;
+
+    /// Rows already in the table are indexed. Returns [crate::sql::Error::AlreadyExists]
+    /// if an index with the same name exists and [crate::sql::Error::NotFound] if the
+    /// table or one of the columns does not exist
+    fn create_index(&self, index: &str, table: &str, columns: &[String]) -> SqlResult<IndexMeta>;
+
+    /// Indexes of the table ordered by name
+    fn list_indexes(&self, table: &str) -> SqlResult<Vec<IndexMeta>>;
 }
 
 pub type RID = u64;
 
+/// Name of the only column of a batch of RIDs, as accepted by [Storage::get_tuples] and
+/// [Storage::delete] and returned by [Storage::insert_tuples]
+pub const RID_COLUMN: &str = "rid";
+
+pub fn rid_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![Field::new(
+        RID_COLUMN,
+        DataType::UInt64,
+        false,
+    )]))
+}
+
+/// Schema of rows followed by their RID, as returned by [Storage::scan_with_rids] and
+/// accepted by [Storage::update]
+pub fn with_rid_schema(schema: &Schema) -> SchemaRef {
+    let mut fields = schema.fields().clone();
+    fields.push(Field::new(RID_COLUMN, DataType::UInt64, false));
+    Arc::new(Schema::new(fields))
+}
+
+/// Name of the only column of the one row batch returned by operators that modify data
+pub const AFFECTED_ROWS_COLUMN: &str = "count";
+
+pub fn affected_rows_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![Field::new(
+        AFFECTED_ROWS_COLUMN,
+        DataType::UInt64,
+        false,
+    )]))
+}
+
+pub fn affected_rows_block(count: usize) -> SqlResult<DataBlock> {
+    let array = UInt64Array::from(vec![count as u64]);
+    Ok(DataBlock::try_new(
+        affected_rows_schema(),
+        vec![Arc::new(array)],
+    )?)
+}
+
+pub fn rids_to_block(rids: Vec<RID>) -> SqlResult<DataBlock> {
+    let array = UInt64Array::from(rids);
+    Ok(DataBlock::try_new(rid_schema(), vec![Arc::new(array)])?)
+}
+
+/// Drain a stream of RID batches into a flat vector
+pub fn collect_rids(rids: BoxedDataIter) -> SqlResult<Vec<RID>> {
+    let mut ret = vec![];
+    for batch in rids {
+        let batch = batch?;
+        let col = batch
+            .column(0)
+            .as_any()
+            .downcast_ref::<UInt64Array>()
+            .ok_or_else(|| {
+                format!(
+                    "expect rid column of type u64, got {}",
+                    batch.column(0).data_type()
+                )
+            })?;
+        ret.extend(col.iter().flatten());
+    }
+    Ok(ret)
+}
+
 pub trait Storage: Catalog + Sync + Send {
-    // TODO: batch insert
+    /// Returns the generated RIDs of the inserted rows, in insertion order
     fn insert_tuples(
         &self,
         table: &str,
         data: BoxedDataIter,
         txn: &Txn,
     ) -> SqlResult<BoxedDataIter>;
-    fn delete(&self, table: &str, data: BoxedDataIter, txn: &Txn) -> SqlResult<()>;
+    /// `data` is a stream of RID batches, RIDs that do not exist are ignored.
+    /// Returns the number of deleted rows
+    fn delete(&self, table: &str, data: BoxedDataIter, txn: &Txn) -> SqlResult<usize>;
+    /// `data` contains the new values of every column of the table followed by the RID
+    /// of the replaced row, see [with_rid_schema]. RIDs that do not exist are ignored.
+    /// Returns the number of updated rows
+    fn update(&self, table: &str, data: BoxedDataIter, txn: &Txn) -> SqlResult<usize>;
+    /// Rows are returned in the same order as the given RIDs
     fn get_tuples(&self, table: &str, rids: BoxedDataIter, txn: &Txn) -> SqlResult<BoxedDataIter>;
 
     fn scan(&self, table: &str, txn: &Txn) -> SqlResult<BoxedDataIter>;
+    /// Same rows as [Storage::scan], each followed by its RID
+    fn scan_with_rids(&self, table: &str, txn: &Txn) -> SqlResult<BoxedDataIter>;
+    /// RIDs of the rows whose indexed columns are in range, ordered by the indexed values.
+    /// Inserted, updated and deleted rows are reflected in every index of their table
+    fn index_scan(&self, index: &str, range: &IndexRange, txn: &Txn) -> SqlResult<BoxedDataIter>;
 }
 
 #[cfg(test)]
 pub mod tests {
-    use super::Storage;
+    use super::{Catalog, Storage};
     use crate::{
         sql::{
             exe::{Executor, PlanType},
+            insert::InsertPlan,
+            join::{grace::GraceHashJoinPlan, JoinType},
             scan::SeqScanPlan,
             table_gen::GenTableUtil,
-            ExecutionContext,
+            tx::{IsolationLevel, TwoPLState, TxnManager},
+            util::{collect, RawInput},
+            DataBlock, Error, ExecutionContext,
         },
         storage::sled::Sled,
     };
-    use std::{env::current_dir, rc::Rc, sync::Arc};
+    use datafusion::{
+        arrow::{
+            array::{Array, Int32Array},
+            datatypes::{DataType, Field, Schema},
+        },
+        physical_plan::expressions::Column,
+    };
+    use std::sync::Arc;
     use tempfile::NamedTempFile;
 
+    fn build_i32_block(cols_and_values: Vec<(&str, Vec<i32>)>) -> DataBlock {
+        let field_vec = cols_and_values
+            .iter()
+            .map(|(col_name, _)| Field::new(col_name, DataType::Int32, false))
+            .collect::<Vec<_>>();
+        let schema = Arc::new(Schema::new(field_vec));
+        let columns = cols_and_values
+            .into_iter()
+            .map(|(_, values)| Arc::new(Int32Array::from(values)) as Arc<dyn Array>)
+            .collect::<Vec<_>>();
+        DataBlock::try_new(schema, columns).unwrap()
+    }
+
+    /// Create the table and fill it through an Insert plan
+    pub fn insert_table(deps: &Dependencies, table: &str, block: DataBlock) {
+        deps.storage
+            .create_table(table, block.schema().as_ref().clone())
+            .expect("creating table");
+        let plan = PlanType::Insert(InsertPlan {
+            table: table.to_string(),
+            source_plan: Box::new(PlanType::RawInput(RawInput::new(block))),
+        });
+        let ret = Executor::execute(plan, deps.gen_table.ctx.clone()).expect("executing insert");
+        let batches = collect(ret).unwrap();
+        assert_eq!(1, batches.len());
+    }
+
+    #[test]
+    fn test_query_txn() {
+        let file = NamedTempFile::new().expect("failed creating temp file");
+        let path = file.into_temp_path().to_str().unwrap().to_string();
+        let db = Arc::new(Sled::new(path).expect("failed creating sled"));
+        let mgr = Arc::new(TxnManager::with_mvcc(db.mvcc()));
+        let ctx = ExecutionContext::new(db.clone()).with_txn_manager(mgr.clone());
+        db.create_table(
+            "t",
+            Schema::new(vec![Field::new("a", DataType::Int32, false)]),
+        )
+        .unwrap();
+
+        // each query runs in a txn of its own that commits at the end of its stream
+        let stream = Executor::execute_sql("INSERT INTO t VALUES (1), (2)", ctx.clone()).unwrap();
+        assert_eq!(1, mgr.active().len());
+        collect(stream).unwrap();
+        assert!(mgr.active().is_empty());
+
+        let txn = mgr.begin(IsolationLevel::RepeatableRead).unwrap();
+        let in_txn = ctx.clone().with_txn(txn.clone());
+        collect(Executor::execute_sql("INSERT INTO t VALUES (3)", in_txn.clone()).unwrap())
+            .unwrap();
+        assert_eq!(vec![txn.id()], mgr.active());
+        let count = |ctx: &ExecutionContext| {
+            let stream = Executor::execute_sql("SELECT a FROM t", ctx.clone()).unwrap();
+            collect(stream)
+                .unwrap()
+                .iter()
+                .map(|batch| batch.num_rows())
+                .sum::<usize>()
+        };
+        assert_eq!(3, count(&in_txn));
+        assert_eq!(2, count(&ctx));
+        mgr.abort(&txn).unwrap();
+
+        // failed and dropped queries abort their txn
+        let ret = collect(Executor::execute_sql("SELECT a / 0 FROM t", ctx.clone()).unwrap());
+        assert!(matches!(ret, Err(Error::Value(_))));
+        assert!(mgr.active().is_empty());
+        let stream = Executor::execute_sql("SELECT a FROM t", ctx.clone()).unwrap();
+        let txn = mgr.get(mgr.active()[0]).unwrap();
+        assert!(!txn.locked().is_empty());
+        drop(stream);
+        assert_eq!(TwoPLState::Aborted, txn.state());
+        assert!(txn.locked().is_empty());
+        assert_eq!(2, count(&ctx));
+    }
+
+    #[test]
+    fn test_seq_scan() {
+        let deps = setup();
+        insert_table(
+            &deps,
+            "t",
+            build_i32_block(vec![("col_a", vec![1, 2, 3]), ("col_b", vec![2, 3, 4])]),
+        );
+        let plan = PlanType::SeqScan(SeqScanPlan {
+            table: "t".to_string(),
+            predicate: None,
+            with_rids: false,
+        });
+        let stream = Executor::execute(plan, deps.gen_table.ctx.clone()).expect("executing scan");
+        let batches = collect(stream).unwrap();
+        let expected = [
+            "+-------+-------+",
+            "| col_a | col_b |",
+            "+-------+-------+",
+            "| 1     | 2     |",
+            "| 2     | 3     |",
+            "| 3     | 4     |",
+            "+-------+-------+",
+        ];
+        crate::assert_batches_sorted_eq!(expected, &batches);
+
+        let missing = PlanType::SeqScan(SeqScanPlan {
+            table: "missing".to_string(),
+            predicate: None,
+            with_rids: false,
+        });
+        assert!(Executor::execute(missing, deps.gen_table.ctx.clone()).is_err());
+    }
+
     #[test]
-    fn test_seq_scan() {}
+    fn test_grace_hash_join_from_plan() {
+        let deps = setup();
+        insert_table(
+            &deps,
+            "outer_t",
+            build_i32_block(vec![("col_a", vec![1, 2, 3]), ("col_b", vec![2, 3, 4])]),
+        );
+        let plan = PlanType::GraceHashJoin(GraceHashJoinPlan {
+            on_left: vec![Column::new("col_a", 0)],
+            on_right: vec![Column::new("col_a", 0)],
+            left_plan: Box::new(PlanType::SeqScan(SeqScanPlan {
+                table: "outer_t".to_string(),
+                predicate: None,
+                with_rids: false,
+            })),
+            right_plan: Box::new(PlanType::RawInput(RawInput::new(build_i32_block(vec![
+                ("col_a", vec![1, 4, 3, 5]),
+                ("col_c", vec![2, 3, 4, 1]),
+            ])))),
+            join_type: JoinType::Inner,
+        });
+        let stream = Executor::execute(plan, deps.gen_table.ctx.clone()).expect("executing join");
+        let batches = collect(stream).unwrap();
+        let expected = [
+            "+-------+-------+-------+-------+",
+            "| col_a | col_b | col_a | col_c |",
+            "+-------+-------+-------+-------+",
+            "| 1     | 2     | 1     | 2     |",
+            "| 3     | 4     | 3     | 4     |",
+            "+-------+-------+-------+-------+",
+        ];
+        crate::assert_batches_sorted_eq!(expected, &batches);
+    }
 
     pub struct Dependencies {
-        storage: Arc<dyn Storage>,
-        executor: Executor,
-        gen_table: GenTableUtil,
+        pub storage: Arc<dyn Storage>,
+        pub executor: Executor,
+        pub gen_table: GenTableUtil,
     }
 
     pub fn setup() -> Dependencies {
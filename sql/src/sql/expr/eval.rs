@@ -0,0 +1,430 @@
+use super::{BinaryOperator, Expr};
+use crate::sql::{DataBlock, Error, SqlResult};
+use datafusion::{
+    arrow::{
+        array::{
+            Array, ArrayRef, BooleanArray, Float32Array, Float64Array, Int16Array, Int32Array,
+            Int64Array, Int8Array, StringArray, UInt16Array, UInt32Array, UInt64Array, UInt8Array,
+        },
+        compute::{
+            cast,
+            kernels::{
+                arithmetic::{add, divide, modulus, multiply, negate, subtract},
+                boolean::{and_kleene, is_not_null, is_null, not, or_kleene},
+                comparison::{
+                    eq_dyn, gt_dyn, gt_eq_dyn, like_utf8, like_utf8_scalar, lt_dyn, lt_eq_dyn,
+                    neq_dyn, nlike_utf8, nlike_utf8_scalar,
+                },
+            },
+        },
+        datatypes::DataType,
+    },
+    scalar::ScalarValue,
+};
+use std::sync::Arc;
+
+macro_rules! primitive_op {
+    ($left:expr, $right:expr, $kernel:ident, $array:ty) => {{
+        let left = $left.as_any().downcast_ref::<$array>().unwrap();
+        let right = $right.as_any().downcast_ref::<$array>().unwrap();
+        Arc::new($kernel(left, right)?) as ArrayRef
+    }};
+}
+
+/// Integer arithmetic that fails on overflow and division by zero instead of panicking
+/// or wrapping around like the arrow kernels do
+macro_rules! checked_op {
+    ($left:expr, $op:expr, $right:expr, $method:ident, $array:ty) => {{
+        let left = $left.as_any().downcast_ref::<$array>().unwrap();
+        let right = $right.as_any().downcast_ref::<$array>().unwrap();
+        let array = left
+            .iter()
+            .zip(right.iter())
+            .map(|(l, r)| match (l, r) {
+                (Some(l), Some(r)) => l.$method(r).map(Some).ok_or_else(|| {
+                    if r == 0 && matches!($op, BinaryOperator::Divide | BinaryOperator::Modulo) {
+                        Error::Value("division by zero".to_string())
+                    } else {
+                        Error::Value(format!(
+                            "{} {} {} is out of range for type {}",
+                            l,
+                            $op,
+                            r,
+                            left.data_type()
+                        ))
+                    }
+                }),
+                _ => Ok(None),
+            })
+            .collect::<SqlResult<$array>>()?;
+        Arc::new(array) as ArrayRef
+    }};
+}
+
+macro_rules! numeric_op {
+    ($left:expr, $op:expr, $right:expr, $kernel:ident, $checked:ident) => {
+        match $left.data_type() {
+            DataType::Int8 => checked_op!($left, $op, $right, $checked, Int8Array),
+            DataType::Int16 => checked_op!($left, $op, $right, $checked, Int16Array),
+            DataType::Int32 => checked_op!($left, $op, $right, $checked, Int32Array),
+            DataType::Int64 => checked_op!($left, $op, $right, $checked, Int64Array),
+            DataType::UInt8 => checked_op!($left, $op, $right, $checked, UInt8Array),
+            DataType::UInt16 => checked_op!($left, $op, $right, $checked, UInt16Array),
+            DataType::UInt32 => checked_op!($left, $op, $right, $checked, UInt32Array),
+            DataType::UInt64 => checked_op!($left, $op, $right, $checked, UInt64Array),
+            DataType::Float32 => primitive_op!($left, $right, $kernel, Float32Array),
+            DataType::Float64 => primitive_op!($left, $right, $kernel, Float64Array),
+            other => {
+                return Err(Error::Value(format!(
+                    "arithmetic is not supported for type {}",
+                    other
+                )))
+            }
+        }
+    };
+}
+
+macro_rules! primitive_negate {
+    ($array:expr, $array_type:ty) => {
+        Arc::new(negate(
+            $array.as_any().downcast_ref::<$array_type>().unwrap(),
+        )?) as ArrayRef
+    };
+}
+
+macro_rules! checked_negate {
+    ($array:expr, $array_type:ty) => {{
+        let array = $array.as_any().downcast_ref::<$array_type>().unwrap();
+        let array = array
+            .iter()
+            .map(|v| match v {
+                Some(v) => v.checked_neg().map(Some).ok_or_else(|| {
+                    Error::Value(format!(
+                        "-{} is out of range for type {}",
+                        v,
+                        array.data_type()
+                    ))
+                }),
+                None => Ok(None),
+            })
+            .collect::<SqlResult<$array_type>>()?;
+        Arc::new(array) as ArrayRef
+    }};
+}
+
+impl Expr {
+    /// Evaluates the expression for every row of the batch, the returned array has
+    /// the length of the batch
+    pub fn evaluate(&self, batch: &DataBlock) -> SqlResult<ArrayRef> {
+        let rows = batch.num_rows();
+        let array = match self {
+            Expr::Column(col) => batch.column(col.index()).clone(),
+            Expr::Literal(value) => value.to_array_of_size(rows),
+            Expr::BinaryOp { left, op, right } => {
+                let left = left.evaluate(batch)?;
+                let right = right.evaluate(batch)?;
+                evaluate_binary(&left, *op, &right)?
+            }
+            Expr::Not(expr) => Arc::new(not(as_boolean(&expr.evaluate(batch)?)?)?),
+            Expr::Negative(expr) => {
+                let array = expr.evaluate(batch)?;
+                match array.data_type() {
+                    DataType::Int8 => checked_negate!(array, Int8Array),
+                    DataType::Int16 => checked_negate!(array, Int16Array),
+                    DataType::Int32 => checked_negate!(array, Int32Array),
+                    DataType::Int64 => checked_negate!(array, Int64Array),
+                    DataType::Float32 => primitive_negate!(array, Float32Array),
+                    DataType::Float64 => primitive_negate!(array, Float64Array),
+                    other => {
+                        return Err(Error::Value(format!(
+                            "cannot negate a value of type {}",
+                            other
+                        )))
+                    }
+                }
+            }
+            Expr::IsNull(expr) => Arc::new(is_null(expr.evaluate(batch)?.as_ref())?),
+            Expr::IsNotNull(expr) => Arc::new(is_not_null(expr.evaluate(batch)?.as_ref())?),
+            Expr::InList {
+                expr,
+                list,
+                negated,
+            } => {
+                // a row that matches no item is null if any compared item is null
+                let value = expr.evaluate(batch)?;
+                let mut found: Option<BooleanArray> = None;
+                for item in list {
+                    let item = item.evaluate(batch)?;
+                    let eq = compare(&value, BinaryOperator::Eq, &item)?;
+                    found = Some(match found {
+                        Some(found) => or_kleene(&found, &eq)?,
+                        None => eq,
+                    });
+                }
+                let found = found.unwrap_or_else(|| BooleanArray::from(vec![false; rows]));
+                if *negated {
+                    Arc::new(not(&found)?)
+                } else {
+                    Arc::new(found)
+                }
+            }
+            Expr::Like {
+                expr,
+                pattern,
+                negated,
+            } => evaluate_like(&expr.evaluate(batch)?, pattern, *negated, batch)?,
+            Expr::Cast { expr, data_type } => cast(&expr.evaluate(batch)?, data_type)?,
+        };
+        Ok(array)
+    }
+}
+
+fn as_boolean(array: &ArrayRef) -> SqlResult<&BooleanArray> {
+    array
+        .as_any()
+        .downcast_ref::<BooleanArray>()
+        .ok_or_else(|| {
+            Error::Value(format!(
+                "expected a boolean array, got type {}",
+                array.data_type()
+            ))
+        })
+}
+
+fn evaluate_binary(left: &ArrayRef, op: BinaryOperator, right: &ArrayRef) -> SqlResult<ArrayRef> {
+    if left.data_type() != right.data_type() {
+        return Err(Error::Internal(format!(
+            "operands of {} have different types {} and {}",
+            op,
+            left.data_type(),
+            right.data_type()
+        )));
+    }
+    let array = match op {
+        BinaryOperator::And => Arc::new(and_kleene(as_boolean(left)?, as_boolean(right)?)?),
+        BinaryOperator::Or => Arc::new(or_kleene(as_boolean(left)?, as_boolean(right)?)?),
+        BinaryOperator::Plus => numeric_op!(left, op, right, add, checked_add),
+        BinaryOperator::Minus => numeric_op!(left, op, right, subtract, checked_sub),
+        BinaryOperator::Multiply => numeric_op!(left, op, right, multiply, checked_mul),
+        BinaryOperator::Divide => numeric_op!(left, op, right, divide, checked_div),
+        BinaryOperator::Modulo => numeric_op!(left, op, right, modulus, checked_rem),
+        op => Arc::new(compare(left, op, right)?) as ArrayRef,
+    };
+    Ok(array)
+}
+
+/// Temporal values are compared through their integer representation, the kernels
+/// only support a few temporal types
+fn comparable(array: &ArrayRef) -> SqlResult<ArrayRef> {
+    let array = match array.data_type() {
+        DataType::Date32 => cast(array, &DataType::Int32)?,
+        DataType::Date64 | DataType::Timestamp(_, _) => cast(array, &DataType::Int64)?,
+        _ => array.clone(),
+    };
+    Ok(array)
+}
+
+fn compare(left: &ArrayRef, op: BinaryOperator, right: &ArrayRef) -> SqlResult<BooleanArray> {
+    let (left, right) = (comparable(left)?, comparable(right)?);
+    let (left, right) = (left.as_ref(), right.as_ref());
+    let array = match op {
+        BinaryOperator::Eq => eq_dyn(left, right)?,
+        BinaryOperator::NotEq => neq_dyn(left, right)?,
+        BinaryOperator::Lt => lt_dyn(left, right)?,
+        BinaryOperator::LtEq => lt_eq_dyn(left, right)?,
+        BinaryOperator::Gt => gt_dyn(left, right)?,
+        BinaryOperator::GtEq => gt_eq_dyn(left, right)?,
+        op => return Err(Error::Internal(format!("{} is not a comparison", op))),
+    };
+    Ok(array)
+}
+
+fn evaluate_like(
+    value: &ArrayRef,
+    pattern: &Expr,
+    negated: bool,
+    batch: &DataBlock,
+) -> SqlResult<ArrayRef> {
+    let value = match value.data_type() {
+        DataType::LargeUtf8 => cast(value, &DataType::Utf8)?,
+        _ => value.clone(),
+    };
+    let value = value
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .ok_or_else(|| {
+            Error::Value(format!(
+                "LIKE expects a string, got type {}",
+                value.data_type()
+            ))
+        })?;
+    let array = match pattern {
+        Expr::Literal(ScalarValue::Utf8(Some(pattern))) => {
+            if negated {
+                nlike_utf8_scalar(value, pattern)?
+            } else {
+                like_utf8_scalar(value, pattern)?
+            }
+        }
+        pattern => {
+            let pattern = pattern.evaluate(batch)?;
+            let pattern = match pattern.data_type() {
+                DataType::LargeUtf8 => cast(&pattern, &DataType::Utf8)?,
+                _ => pattern,
+            };
+            let pattern = match pattern.as_any().downcast_ref::<StringArray>() {
+                Some(pattern) => pattern,
+                // null pattern
+                None => return Ok(Arc::new(BooleanArray::from(vec![None; value.len()]))),
+            };
+            if negated {
+                nlike_utf8(value, pattern)?
+            } else {
+                like_utf8(value, pattern)?
+            }
+        }
+    };
+    Ok(Arc::new(array))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::sql::{
+        expr::{BinaryOperator, Expr},
+        DataBlock, Error,
+    };
+    use datafusion::{
+        arrow::{
+            array::{Array, ArrayRef, BooleanArray, Date32Array, Int32Array, StringArray},
+            datatypes::{DataType, Field, Schema},
+        },
+        scalar::ScalarValue,
+    };
+    use std::sync::Arc;
+
+    fn batch() -> DataBlock {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int32, true),
+            Field::new("b", DataType::Utf8, true),
+            Field::new("d", DataType::Date32, false),
+        ]));
+        DataBlock::try_new(
+            schema,
+            vec![
+                Arc::new(Int32Array::from(vec![Some(1), None, Some(3), Some(4)])),
+                Arc::new(StringArray::from(vec![
+                    Some("apple"),
+                    Some("banana"),
+                    None,
+                    Some("avocado"),
+                ])),
+                Arc::new(Date32Array::from(vec![10, 20, 30, 40])),
+            ],
+        )
+        .unwrap()
+    }
+
+    fn int(v: i32) -> Expr {
+        Expr::Literal(ScalarValue::Int32(Some(v)))
+    }
+
+    fn booleans(array: ArrayRef) -> Vec<Option<bool>> {
+        let array = array.as_any().downcast_ref::<BooleanArray>().unwrap();
+        array.iter().collect()
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let batch = batch();
+        let (a, b, d) = (
+            Expr::column("a", 0),
+            Expr::column("b", 1),
+            Expr::column("d", 2),
+        );
+
+        let sum = Expr::binary(
+            Expr::binary(a.clone(), BinaryOperator::Multiply, int(2)),
+            BinaryOperator::Plus,
+            Expr::Negative(Box::new(int(1))),
+        );
+        let sum = sum.evaluate(&batch).unwrap();
+        let sum = sum.as_any().downcast_ref::<Int32Array>().unwrap();
+        assert_eq!(
+            vec![Some(1), None, Some(5), Some(7)],
+            sum.iter().collect::<Vec<_>>()
+        );
+
+        // null AND false is false, null AND true is null
+        let gt = Expr::binary(a.clone(), BinaryOperator::Gt, int(2));
+        let starts_a = Expr::Like {
+            expr: Box::new(b.clone()),
+            pattern: Box::new(Expr::Literal(ScalarValue::Utf8(Some("a%".to_string())))),
+            negated: false,
+        };
+        let and = Expr::binary(gt, BinaryOperator::And, starts_a.clone());
+        assert_eq!(
+            vec![Some(false), Some(false), None, Some(true)],
+            booleans(and.evaluate(&batch).unwrap())
+        );
+        let not_like = Expr::Not(Box::new(starts_a));
+        assert_eq!(
+            vec![Some(false), Some(true), None, Some(false)],
+            booleans(not_like.evaluate(&batch).unwrap())
+        );
+
+        let in_list = Expr::InList {
+            expr: Box::new(a.clone()),
+            list: vec![int(3), Expr::Literal(ScalarValue::Int32(None))],
+            negated: true,
+        };
+        assert_eq!(
+            vec![None, None, Some(false), None],
+            booleans(in_list.evaluate(&batch).unwrap())
+        );
+
+        let is_null = Expr::IsNull(Box::new(b));
+        assert_eq!(
+            vec![Some(false), Some(false), Some(true), Some(false)],
+            booleans(is_null.evaluate(&batch).unwrap())
+        );
+
+        let date = Expr::Cast {
+            expr: Box::new(Expr::Literal(ScalarValue::Utf8(Some(
+                "1970-01-21".to_string(),
+            )))),
+            data_type: DataType::Date32,
+        };
+        let before = Expr::binary(d, BinaryOperator::LtEq, date);
+        assert_eq!(
+            vec![Some(true), Some(true), Some(false), Some(false)],
+            booleans(before.evaluate(&batch).unwrap())
+        );
+
+        let divide = Expr::binary(a, BinaryOperator::Divide, int(0));
+        assert!(matches!(divide.evaluate(&batch), Err(Error::Value(_))));
+    }
+
+    #[test]
+    fn test_overflow() {
+        let batch = batch();
+        let a = Expr::column("a", 0);
+        let max = Expr::binary(a.clone(), BinaryOperator::Plus, int(i32::MAX - 4));
+        let max = max.evaluate(&batch).unwrap();
+        let max = max.as_any().downcast_ref::<Int32Array>().unwrap();
+        assert_eq!(Some(i32::MAX), max.iter().last().unwrap());
+
+        for (left, op, right) in [
+            (int(i32::MAX), BinaryOperator::Plus, a.clone()),
+            (int(i32::MIN), BinaryOperator::Minus, a.clone()),
+            (int(i32::MAX), BinaryOperator::Multiply, a.clone()),
+            (int(i32::MIN), BinaryOperator::Divide, int(-1)),
+            (int(i32::MIN), BinaryOperator::Modulo, int(-1)),
+            (a.clone(), BinaryOperator::Modulo, int(0)),
+        ] {
+            let expr = Expr::binary(left, op, right);
+            assert!(matches!(expr.evaluate(&batch), Err(Error::Value(_))));
+        }
+        let negate = Expr::Negative(Box::new(int(i32::MIN)));
+        assert!(matches!(negate.evaluate(&batch), Err(Error::Value(_))));
+    }
+}
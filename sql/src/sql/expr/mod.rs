@@ -0,0 +1,318 @@
+use crate::sql::{Error, SqlResult};
+use datafusion::{
+    arrow::datatypes::{DataType, Field, Schema},
+    physical_plan::expressions::Column,
+    scalar::ScalarValue,
+};
+use std::fmt;
+
+mod eval;
+
+/// Expression evaluated against the rows of a [crate::sql::DataBlock], columns are
+/// referenced by their index in the input schema. Operands of binary operators are
+/// expected to have the same type, the planner inserts the needed casts
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Column(Column),
+    Literal(ScalarValue),
+    BinaryOp {
+        left: Box<Expr>,
+        op: BinaryOperator,
+        right: Box<Expr>,
+    },
+    Not(Box<Expr>),
+    Negative(Box<Expr>),
+    IsNull(Box<Expr>),
+    IsNotNull(Box<Expr>),
+    InList {
+        expr: Box<Expr>,
+        list: Vec<Expr>,
+        negated: bool,
+    },
+    Like {
+        expr: Box<Expr>,
+        pattern: Box<Expr>,
+        negated: bool,
+    },
+    Cast {
+        expr: Box<Expr>,
+        data_type: DataType,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BinaryOperator {
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+    Plus,
+    Minus,
+    Multiply,
+    Divide,
+    Modulo,
+    And,
+    Or,
+}
+
+impl BinaryOperator {
+    pub fn is_comparison(&self) -> bool {
+        matches!(
+            self,
+            BinaryOperator::Eq
+                | BinaryOperator::NotEq
+                | BinaryOperator::Lt
+                | BinaryOperator::LtEq
+                | BinaryOperator::Gt
+                | BinaryOperator::GtEq
+        )
+    }
+
+    pub fn is_arithmetic(&self) -> bool {
+        matches!(
+            self,
+            BinaryOperator::Plus
+                | BinaryOperator::Minus
+                | BinaryOperator::Multiply
+                | BinaryOperator::Divide
+                | BinaryOperator::Modulo
+        )
+    }
+}
+
+impl fmt::Display for BinaryOperator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = match self {
+            BinaryOperator::Eq => "=",
+            BinaryOperator::NotEq => "!=",
+            BinaryOperator::Lt => "<",
+            BinaryOperator::LtEq => "<=",
+            BinaryOperator::Gt => ">",
+            BinaryOperator::GtEq => ">=",
+            BinaryOperator::Plus => "+",
+            BinaryOperator::Minus => "-",
+            BinaryOperator::Multiply => "*",
+            BinaryOperator::Divide => "/",
+            BinaryOperator::Modulo => "%",
+            BinaryOperator::And => "AND",
+            BinaryOperator::Or => "OR",
+        };
+        write!(f, "{}", op)
+    }
+}
+
+impl Expr {
+    pub fn column(name: &str, index: usize) -> Self {
+        Expr::Column(Column::new(name, index))
+    }
+
+    pub fn binary(left: Expr, op: BinaryOperator, right: Expr) -> Self {
+        Expr::BinaryOp {
+            left: Box::new(left),
+            op,
+            right: Box::new(right),
+        }
+    }
+
+    pub fn data_type(&self, input: &Schema) -> SqlResult<DataType> {
+        let data_type = match self {
+            Expr::Column(col) => Self::input_field(col, input)?.data_type().clone(),
+            Expr::Literal(value) => value.get_datatype(),
+            Expr::BinaryOp { left, op, .. } => {
+                if op.is_arithmetic() {
+                    left.data_type(input)?
+                } else {
+                    DataType::Boolean
+                }
+            }
+            Expr::Negative(expr) => expr.data_type(input)?,
+            Expr::Cast { data_type, .. } => data_type.clone(),
+            Expr::Not(_)
+            | Expr::IsNull(_)
+            | Expr::IsNotNull(_)
+            | Expr::InList { .. }
+            | Expr::Like { .. } => DataType::Boolean,
+        };
+        Ok(data_type)
+    }
+
+    pub fn nullable(&self, input: &Schema) -> SqlResult<bool> {
+        let nullable = match self {
+            Expr::Column(col) => Self::input_field(col, input)?.is_nullable(),
+            Expr::Literal(value) => value.is_null(),
+            Expr::IsNull(_) | Expr::IsNotNull(_) => false,
+            Expr::BinaryOp { left, right, .. }
+            | Expr::Like {
+                expr: left,
+                pattern: right,
+                ..
+            } => left.nullable(input)? || right.nullable(input)?,
+            Expr::InList { expr, list, .. } => {
+                let mut nullable = expr.nullable(input)?;
+                for item in list {
+                    nullable |= item.nullable(input)?;
+                }
+                nullable
+            }
+            Expr::Not(expr) | Expr::Negative(expr) | Expr::Cast { expr, .. } => {
+                expr.nullable(input)?
+            }
+        };
+        Ok(nullable)
+    }
+
+    /// Output field of the expression when it is projected under `name`
+    pub fn field(&self, name: &str, input: &Schema) -> SqlResult<Field> {
+        Ok(Field::new(
+            name,
+            self.data_type(input)?,
+            self.nullable(input)?,
+        ))
+    }
+
+    fn input_field<'a>(col: &Column, input: &'a Schema) -> SqlResult<&'a Field> {
+        if col.index() >= input.fields().len() {
+            return Err(Error::Internal(format!(
+                "column {} at index {} is out of bound of the input schema",
+                col.name(),
+                col.index()
+            )));
+        }
+        Ok(input.field(col.index()))
+    }
+}
+
+/// Common type that both operands of a comparison or arithmetic operator are cast to,
+/// None if the types cannot be compared
+pub fn common_type(left: &DataType, right: &DataType) -> Option<DataType> {
+    use DataType::*;
+    if left == right {
+        return Some(left.clone());
+    }
+    match (left, right) {
+        (Null, other) | (other, Null) => Some(other.clone()),
+        (Utf8, LargeUtf8) | (LargeUtf8, Utf8) => Some(LargeUtf8),
+        (Utf8 | LargeUtf8, Date32 | Date64 | Timestamp(_, _)) => Some(right.clone()),
+        (Date32 | Date64 | Timestamp(_, _), Utf8 | LargeUtf8) => Some(left.clone()),
+        (Date32, Date64) | (Date64, Date32) => Some(Date64),
+        _ if is_numeric(left) && is_numeric(right) => Some(numeric_common_type(left, right)),
+        _ => None,
+    }
+}
+
+pub fn is_numeric(data_type: &DataType) -> bool {
+    use DataType::*;
+    matches!(
+        data_type,
+        Int8 | Int16 | Int32 | Int64 | UInt8 | UInt16 | UInt32 | UInt64 | Float32 | Float64
+    )
+}
+
+fn numeric_common_type(left: &DataType, right: &DataType) -> DataType {
+    use DataType::*;
+    match (left, right) {
+        (Float64, _) | (_, Float64) => Float64,
+        (Float32, _) | (_, Float32) => Float32,
+        _ => {
+            let signed = is_signed(left) || is_signed(right);
+            let width = int_width(left, signed).max(int_width(right, signed));
+            match (signed, width) {
+                (true, 1) => Int8,
+                (true, 2) => Int16,
+                (true, 4) => Int32,
+                (true, _) => Int64,
+                (false, 1) => UInt8,
+                (false, 2) => UInt16,
+                (false, 4) => UInt32,
+                (false, _) => UInt64,
+            }
+        }
+    }
+}
+
+fn is_signed(data_type: &DataType) -> bool {
+    matches!(
+        data_type,
+        DataType::Int8 | DataType::Int16 | DataType::Int32 | DataType::Int64
+    )
+}
+
+/// Byte width of an integer type, unsigned types are widened when they have to fit
+/// into a signed common type
+fn int_width(data_type: &DataType, signed: bool) -> usize {
+    let width = match data_type {
+        DataType::Int8 | DataType::UInt8 => 1,
+        DataType::Int16 | DataType::UInt16 => 2,
+        DataType::Int32 | DataType::UInt32 => 4,
+        _ => 8,
+    };
+    if signed && !is_signed(data_type) {
+        (width * 2).min(8)
+    } else {
+        width
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{common_type, BinaryOperator, Expr};
+    use datafusion::{
+        arrow::datatypes::{DataType, Field, Schema},
+        scalar::ScalarValue,
+    };
+
+    #[test]
+    fn test_common_type() {
+        assert_eq!(
+            Some(DataType::Int64),
+            common_type(&DataType::Int32, &DataType::Int64)
+        );
+        assert_eq!(
+            Some(DataType::Int32),
+            common_type(&DataType::UInt16, &DataType::Int8)
+        );
+        assert_eq!(
+            Some(DataType::Float64),
+            common_type(&DataType::Int64, &DataType::Float64)
+        );
+        assert_eq!(
+            Some(DataType::Date32),
+            common_type(&DataType::Utf8, &DataType::Date32)
+        );
+        assert_eq!(None, common_type(&DataType::Utf8, &DataType::Int32));
+    }
+
+    #[test]
+    fn test_expr_field() {
+        let schema = Schema::new(vec![
+            Field::new("a", DataType::Int32, false),
+            Field::new("b", DataType::Utf8, true),
+        ]);
+        let sum = Expr::binary(
+            Expr::column("a", 0),
+            BinaryOperator::Plus,
+            Expr::Literal(ScalarValue::Int32(Some(1))),
+        );
+        assert_eq!(
+            Field::new("a + 1", DataType::Int32, false),
+            sum.field("a + 1", &schema).unwrap()
+        );
+        let is_null = Expr::IsNull(Box::new(Expr::column("b", 1)));
+        assert_eq!(
+            Field::new("b IS NULL", DataType::Boolean, false),
+            is_null.field("b IS NULL", &schema).unwrap()
+        );
+        let cmp = Expr::binary(
+            Expr::column("b", 1),
+            BinaryOperator::Eq,
+            Expr::Literal(ScalarValue::Utf8(Some("x".to_string()))),
+        );
+        assert_eq!(
+            Field::new("c", DataType::Boolean, true),
+            cmp.field("c", &schema).unwrap()
+        );
+        assert!(Expr::column("c", 2).data_type(&schema).is_err());
+    }
+}
@@ -0,0 +1,66 @@
+use crate::sql::{
+    exe::{BoxedDataIter, Executor, Operator, PlanType, SchemaDataIter},
+    expr::Expr,
+    DataBlock, Error, ExecutionContext, SqlResult,
+};
+use datafusion::arrow::{array::BooleanArray, compute::filter_record_batch, datatypes::SchemaRef};
+
+/// Rows of the child for which predicate evaluates to true, output schema is
+/// the schema of the child
+pub struct FilterPlan {
+    pub predicate: Expr,
+    pub source_plan: Box<PlanType>,
+}
+
+#[derive(Debug)]
+pub struct Filter {
+    predicate: Expr,
+    input: Box<dyn Operator>,
+}
+
+impl Filter {
+    pub fn from_plan(plan: FilterPlan, ctx: ExecutionContext) -> SqlResult<Self> {
+        let input = Executor::create_from_subplan_operator(*plan.source_plan, ctx)?;
+        Ok(Filter {
+            predicate: plan.predicate,
+            input,
+        })
+    }
+}
+
+/// Rows for which predicate is null are removed as well
+pub fn filter_batch(batch: &DataBlock, predicate: &Expr) -> SqlResult<DataBlock> {
+    let mask = predicate.evaluate(batch)?;
+    let mask = mask
+        .as_any()
+        .downcast_ref::<BooleanArray>()
+        .ok_or_else(|| {
+            Error::Value(format!(
+                "predicate must be a boolean, got type {}",
+                mask.data_type()
+            ))
+        })?;
+    Ok(filter_record_batch(batch, mask)?)
+}
+
+/// Batches of input filtered by predicate, batches left without rows are skipped
+pub fn filter_stream(schema: SchemaRef, input: BoxedDataIter, predicate: Expr) -> BoxedDataIter {
+    let filtered = input.filter_map(move |batch| {
+        match batch.and_then(|batch| filter_batch(&batch, &predicate)) {
+            Ok(batch) if batch.num_rows() == 0 => None,
+            ret => Some(ret),
+        }
+    });
+    SchemaDataIter::new(schema, Box::new(filtered))
+}
+
+impl Operator for Filter {
+    fn execute_sync(&mut self, ctx: ExecutionContext) -> SqlResult<BoxedDataIter> {
+        let input = self.input.execute_sync(ctx)?;
+        Ok(filter_stream(self.schema(), input, self.predicate.clone()))
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.input.schema()
+    }
+}
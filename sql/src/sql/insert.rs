@@ -1,41 +1,49 @@
-use super::exe::Operator;
-use super::exe::PlanType;
-// use crate::sql::exe::Executor;
-// use crate::sql::exe::Tuple;
-// use crate::sql::Error;
-use crate::sql::ExecutionContext;
-// use crate::sql::PartialResult;
+use super::exe::{
+    affected_rows_block, affected_rows_schema, collect_rids, rid_schema, BoxedDataIter, Executor,
+    Operator, PlanType, SchemaDataIter,
+};
+use crate::sql::{ExecutionContext, SqlResult};
+use datafusion::arrow::datatypes::SchemaRef;
 
 pub struct InsertPlan {
     pub table: String,
     pub source_plan: Box<PlanType>,
 }
+
+/// Inserts every row of its child into the table, output is a one row batch
+/// containing the number of inserted rows
+#[derive(Debug)]
 pub struct Insert {
     table: String,
     source: Box<dyn Operator>,
-    ctx: ExecutionContext,
 }
 
-// impl Insert {
-//     pub fn new(plan: InsertPlan, ctx: ExecutionContext) -> Self {
-//         let source = Executor::create_operator(*plan.source_plan, ctx.clone());
-//         Insert {
-//             table: plan.table,
-//             source,
-//             ctx,
-//         }
-//     }
-// }
+impl Insert {
+    pub fn from_plan(plan: InsertPlan, ctx: ExecutionContext) -> SqlResult<Self> {
+        let source = Executor::create_from_subplan_operator(*plan.source_plan, ctx)?;
+        Ok(Insert {
+            table: plan.table,
+            source,
+        })
+    }
+}
+
+impl Operator for Insert {
+    fn execute_sync(&mut self, ctx: ExecutionContext) -> SqlResult<BoxedDataIter> {
+        let input = self.source.execute_sync(ctx.clone())?;
+        ctx.lock_for_write(&self.table)?;
+        let rids = ctx
+            .get_storage()
+            .insert_tuples(&self.table, input, &ctx.get_txn())?;
+        let inserted = collect_rids(ctx.lock_rows(rid_schema(), rids))?.len();
+        let batch = affected_rows_block(inserted)?;
+        Ok(SchemaDataIter::new(
+            self.schema(),
+            Box::new(std::iter::once(Ok(batch))),
+        ))
+    }
 
-// impl Operator for Insert {
-//     fn next(&mut self) -> Result<PartialResult, Error> {
-//         let storage = self.ctx.get_storage();
-//         let ret = self.source.next()?;
-//         if ret.done {
-//             return Ok(PartialResult::new_done());
-//         }
-//         let tuples = Tuple::new_multi(ret.inner);
-//         storage.insert_tuples(&self.table, tuples, &self.ctx.txn)?;
-//         Ok(PartialResult::new(vec![]))
-//     }
-// }
+    fn schema(&self) -> SchemaRef {
+        affected_rows_schema()
+    }
+}
@@ -1,7 +1,11 @@
-use super::inmem::HashJoinOp;
+use super::{
+    inmem::{build_left_rows, HashJoinOp},
+    JoinType,
+};
 use crate::sql::{
     exe::{BoxedDataIter, DataIter, Executor, Operator, PlanType, SchemaDataIter},
     join::hash_util::hash_to_buckets,
+    memory::{batch_memory_size, MemoryReservation},
     util::GeneratorIteratorAdapter,
     DataBlock, ExecutionContext, SqlResult,
 };
@@ -11,26 +15,34 @@ use datafusion::{
     arrow::{
         array::{Array, PrimitiveArray},
         compute::kernels::take::take,
-        datatypes::{Schema, SchemaRef, UInt64Type},
+        datatypes::{Field, Schema, SchemaRef, UInt64Type},
     },
     physical_plan::{
         expressions::Column,
         join_utils::{ColumnIndex, JoinSide},
     },
 };
+use parking_lot::Mutex;
 use std::{collections::HashMap, fmt::Debug, sync::Arc};
 
-#[allow(dead_code)]
 pub struct GraceHashJoinPlan {
-    on_left: Vec<Column>,
-    on_right: Vec<Column>,
-    left_plan: Box<PlanType>,
-    right_plan: Box<PlanType>,
+    pub on_left: Vec<Column>,
+    pub on_right: Vec<Column>,
+    pub left_plan: Box<PlanType>,
+    pub right_plan: Box<PlanType>,
+    pub join_type: JoinType,
 }
 // type JoinedTable = RawTable<(u64, SmallVec<[u64; 1]>)>;
 
-// TODO: add fallback to merge join, if partition contain duplicate joined rows count
-// that takes more than inmem partition
+/// Partitions still larger than max_size_per_partition at this level are not
+/// partitioned again, but joined with the fallback strategy
+const MAX_RECURSION_LEVEL: usize = 8;
+
+/// A partition is joined in memory if its inner side is at most max_size_per_partition
+/// bytes and can be reserved from the memory pool of the [ExecutionContext], otherwise it
+/// is partitioned again. If a partition contains duplicate joined rows count that takes
+/// more than inmem partition, re-hashing does not shrink it. Such partition is marked as
+/// fallback and joined by [GraceHashJoinOp::block_nested_hash_join]
 #[derive(Debug)]
 pub struct GraceHashJoinOp {
     config: Config,
@@ -64,13 +76,56 @@ impl Operator for GraceHashJoinOp {
         };
         let mut left_stream = self.left_op.execute_sync(ctx.clone())?;
         let mut right_stream = self.right_op.execute_sync(ctx.clone())?;
-        Self::partition_batch(
+        let inner_stats = Self::partition_batch(
             &mut right_stream,
             &mut first_level_partitions,
             &self.config,
             true,
         )?;
 
+        // null-aware anti join depends on the whole inner side, which is known only now
+        let mut config = self.config.clone();
+        if config.join_type == JoinType::NullAwareLeftAnti {
+            // x NOT IN (.., null, ..) is never true
+            if inner_stats.null_keys {
+                return Ok(SchemaDataIter::new(
+                    self.schema.clone(),
+                    Box::new(std::iter::empty()),
+                ));
+            }
+            // x NOT IN () is true even if x is null
+            if inner_stats.rows == 0 {
+                config.join_type = JoinType::LeftAnti;
+            }
+        }
+
+        // partitions are driven by the inner side, without inner rows none would be joined
+        // and the left rows would be lost, so they are returned without matches directly
+        if inner_stats.rows == 0 {
+            let schema = self.schema.clone();
+            let column_indices = self.join_column_indices.clone();
+            let unmatched = left_stream.filter_map(move |batch| {
+                let batch = match batch {
+                    Ok(batch) => batch,
+                    Err(e) => return Some(Err(e)),
+                };
+                let matched = vec![false; batch.num_rows()];
+                build_left_rows(
+                    config.join_type,
+                    &schema,
+                    &batch,
+                    &config.on_left,
+                    &matched,
+                    &column_indices,
+                )
+                .transpose()
+            });
+            return Ok(SchemaDataIter::new(
+                self.schema.clone(),
+                Box::new(unmatched),
+            ));
+        }
+
         Self::partition_batch(
             &mut left_stream,
             &mut first_level_partitions,
@@ -83,8 +138,8 @@ impl Operator for GraceHashJoinOp {
         let moved_ctx = ctx.clone();
         let schema = self.schema.clone();
         // let schema_cloned = self.schema.clone();
-        let config = self.config.clone();
         let joined_column_indices = self.join_column_indices.clone();
+        let mut reservation = ctx.new_reservation("grace hash join");
 
         let gen = move || {
             'recursiveloop: while stack.len() > 0 {
@@ -93,6 +148,7 @@ impl Operator for GraceHashJoinOp {
                     Self::_find_next_inmem_sized_partition(
                         &mut stack,
                         config.max_size_per_partition,
+                        &mut reservation,
                     )
                 {
                     let mut inmem_joiner = Self::_new_hash_joiner(
@@ -118,18 +174,47 @@ impl Operator for GraceHashJoinOp {
                         }
                     }); */
 
-                    let mut inmem_stream = inmem_joiner.execute_sync(moved_ctx.clone()).unwrap();
-                    while let Some(batch_ret) = inmem_stream.next() {
+                    let inmem_stream = match inmem_joiner.execute_sync(moved_ctx.clone()) {
+                        Ok(inmem_stream) => inmem_stream,
+                        Err(e) => {
+                            yield Err(e);
+                            return;
+                        }
+                    };
+                    for batch_ret in inmem_stream {
                         yield batch_ret;
                     }
+                    reservation.free();
                 }
-                while let Some((p_index, outer_queue, inner_queue)) =
-                    Self::_find_next_fallback_partition(&mut stack)
+                while let Some(fallback_partition) = Self::_find_next_fallback_partition(&mut stack)
                 {
                     // p_index is a partition that even if partition one more time, it may not fit in
-                    // memory, so we use fallback strategy like merge join instead
+                    // memory, so we use fallback strategy instead
                     // make this fallback operation as late as possible
-                    panic!("unimplemented")
+                    let cur_level = stack.last().unwrap().level;
+                    let fallback_stream = Self::block_nested_hash_join(
+                        moved_ctx.clone(),
+                        config.clone(),
+                        schema.clone(),
+                        joined_column_indices.clone(),
+                        fallback_partition,
+                        cur_level,
+                    );
+                    match fallback_stream {
+                        Ok(fallback_stream) => {
+                            for batch_ret in fallback_stream {
+                                let failed = batch_ret.is_err();
+                                yield batch_ret;
+                                if failed {
+                                    return;
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            yield Err(e);
+                            return;
+                        }
+                    }
                 }
 
                 let cur_partitions = stack.last_mut().unwrap();
@@ -137,12 +222,16 @@ impl Operator for GraceHashJoinOp {
                 // recursive partition
                 if cur_partitions.map.len() > 0 {
                     // let st = cur_partitions.last_mut();
-                    if let Some(next_recursive_p) =
-                        Self::recursive_partition(moved_ctx.clone(), config, cur_partitions)
-                            .unwrap()
-                    {
-                        stack.push(next_recursive_p);
-                        continue 'recursiveloop;
+                    match Self::recursive_partition(moved_ctx.clone(), config, cur_partitions) {
+                        Ok(Some(next_recursive_p)) => {
+                            stack.push(next_recursive_p);
+                            continue 'recursiveloop;
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            yield Err(e);
+                            return;
+                        }
                     }
                 }
                 stack.pop();
@@ -175,42 +264,65 @@ pub trait PartitionedQueue: Sync + Send + Debug {
 #[derive(Debug)]
 struct PInfo {
     parent_size: usize,
+    /// Bytes of the inner side of the partition
     memsize: usize,
 }
 
 #[derive(Clone, Debug)]
 struct Config {
     bucket_size: usize,
+    /// In bytes, see [GraceHashJoinOp]
     max_size_per_partition: usize,
     batch_size: usize,
     on_left: Vec<Column>,
     on_right: Vec<Column>,
     outer_schema: SchemaRef,
     inner_schema: SchemaRef,
+    join_type: JoinType,
 }
-fn build_join_schema(left: &Schema, right: &Schema) -> (Schema, Vec<ColumnIndex>) {
-    let left_fields = left
-        .fields()
-        .iter()
-        .cloned()
-        .enumerate()
-        .map(|(index, field)| {
-            (
-                field,
-                ColumnIndex {
-                    index,
-                    side: JoinSide::Left,
-                },
-            )
-        });
+
+/// Rows and keys seen while partitioning one side of the join
+#[derive(Debug, Default)]
+struct PartitionStats {
+    rows: usize,
+    null_keys: bool,
+}
+
+fn nullable_field(field: &Field, nullable: bool) -> Field {
+    if !nullable || field.is_nullable() {
+        return field.clone();
+    }
+    let mut ret = Field::new(field.name(), field.data_type().clone(), true);
+    ret.set_metadata(field.metadata().clone());
+    ret
+}
+
+/// Fields of the side that may be padded with nulls become nullable, semi and
+/// anti join only output the left side
+pub(super) fn build_join_schema(
+    left: &Schema,
+    right: &Schema,
+    join_type: JoinType,
+) -> (Schema, Vec<ColumnIndex>) {
+    let left_nullable = matches!(join_type, JoinType::RightOuter | JoinType::FullOuter);
+    let right_nullable = matches!(join_type, JoinType::LeftOuter | JoinType::FullOuter);
+    let left_fields = left.fields().iter().enumerate().map(|(index, field)| {
+        (
+            nullable_field(field, left_nullable),
+            ColumnIndex {
+                index,
+                side: JoinSide::Left,
+            },
+        )
+    });
     let right_fields = right
         .fields()
         .iter()
-        .cloned()
         .enumerate()
+        .filter(|_| !join_type.left_only())
         .map(|(index, field)| {
             (
-                field,
+                nullable_field(field, right_nullable),
                 ColumnIndex {
                     index,
                     side: JoinSide::Right,
@@ -222,31 +334,32 @@ fn build_join_schema(left: &Schema, right: &Schema) -> (Schema, Vec<ColumnIndex>
 }
 
 impl GraceHashJoinOp {
-    /// A big todo here
-    pub fn from_plan(plan: GraceHashJoinPlan, ctx: ExecutionContext) -> Self {
-        let left_op = Executor::create_from_subplan_operator(*plan.left_plan, ctx.clone());
-        let right_op = Executor::create_from_subplan_operator(*plan.right_plan, ctx.clone());
+    pub fn from_plan(plan: GraceHashJoinPlan, ctx: ExecutionContext) -> SqlResult<Self> {
+        let left_op = Executor::create_from_subplan_operator(*plan.left_plan, ctx.clone())?;
+        let right_op = Executor::create_from_subplan_operator(*plan.right_plan, ctx.clone())?;
         let default_config = Config {
-            bucket_size: 10,
-            max_size_per_partition: 10,
-            batch_size: 10,
+            bucket_size: 16,
+            // a partition never takes more than the whole pool
+            max_size_per_partition: ctx.memory_pool().limit(),
+            batch_size: 1024,
             on_left: plan.on_left.clone(),
             on_right: plan.on_right.clone(),
             outer_schema: left_op.schema(),
             inner_schema: right_op.schema(),
+            join_type: plan.join_type,
         };
         let (left_schema, right_schema) = (left_op.schema(), right_op.schema());
 
-        let (schema, column_indices) = build_join_schema(&left_schema, &right_schema);
+        let (schema, column_indices) =
+            build_join_schema(&left_schema, &right_schema, plan.join_type);
 
-        return GraceHashJoinOp::new(
+        GraceHashJoinOp::new(
             default_config,
             left_op,
             right_op,
             column_indices,
             Arc::new(schema),
         )
-        .expect("failted to create grace hash joiner");
     }
 
     fn partition_batch<'b>(
@@ -254,7 +367,7 @@ impl GraceHashJoinOp {
         partition_infos: &mut PartitionLevel,
         c: &Config,
         is_inner: bool,
-    ) -> SqlResult<()> {
+    ) -> SqlResult<PartitionStats> {
         let mut on = &c.on_left;
         if is_inner {
             on = &c.on_right;
@@ -265,9 +378,11 @@ impl GraceHashJoinOp {
             false => &partition_infos.outer_queue,
         };
 
+        let mut stats = PartitionStats::default();
         let mut reused_buffer = Vec::new();
         while let Some(batch) = batch_stream.next() {
             let batch = batch?;
+            stats.rows += batch.num_rows();
             reused_buffer.clear();
             reused_buffer.resize(batch.num_rows(), 0);
             let batch: DataBlock = batch;
@@ -275,6 +390,7 @@ impl GraceHashJoinOp {
                 .iter()
                 .map(|col_info| batch.column(col_info.index()).clone())
                 .collect::<Vec<_>>();
+            stats.null_keys |= col_values.iter().any(|col| col.null_count() > 0);
 
             hash_to_buckets(
                 &col_values,
@@ -298,8 +414,8 @@ impl GraceHashJoinOp {
 
                 let output_batch = DataBlock::try_new(batch.schema(), columns)?;
 
-                let bucket_length = output_batch.num_rows();
-                queuer.enqueue(bucket_idx, output_batch).unwrap();
+                let bucket_length = batch_memory_size(&output_batch);
+                queuer.enqueue(bucket_idx, output_batch)?;
 
                 if is_inner {
                     match partition_infos.map.get_mut(&bucket_idx) {
@@ -319,7 +435,7 @@ impl GraceHashJoinOp {
                 }
             }
         }
-        Ok(())
+        Ok(stats)
     }
 
     fn _find_next_fallback_partition(
@@ -337,9 +453,12 @@ impl GraceHashJoinOp {
         ));
     }
 
+    /// The memory of the returned partition is reserved, it is freed once the partition
+    /// has been joined
     fn _find_next_inmem_sized_partition(
         stack: &mut Vec<PartitionLevel>,
         max_size_per_partition: usize,
+        reservation: &mut MemoryReservation,
     ) -> Option<(
         usize,
         usize,
@@ -349,7 +468,7 @@ impl GraceHashJoinOp {
         let mut found_index = None;
         let partition_infos = stack.last_mut().unwrap();
         for (index, item) in partition_infos.map.iter_mut() {
-            if item.memsize <= max_size_per_partition {
+            if item.memsize <= max_size_per_partition && reservation.try_grow(item.memsize) {
                 found_index = Some(*index);
                 break;
             }
@@ -387,10 +506,171 @@ impl GraceHashJoinOp {
             RandomState::with_seed(cur_level),
             config.batch_size,
             joined_column_indices.clone(),
+            config.join_type,
         );
         return st;
     }
 
+    /// Join a partition whose inner side does not fit in memory: the inner side is read
+    /// in chunks of at most max_size_per_partition bytes that can be reserved, each chunk is
+    /// joined in memory against the whole outer side, which is copied into a new queue
+    /// so that it can be read again for the next chunk. Left rows returned on their own
+    /// are only known once every chunk has been probed, so matches of the outer side are
+    /// recorded across chunks and such rows are returned by a last pass over the outer side
+    fn block_nested_hash_join(
+        ctx: ExecutionContext,
+        config: Config,
+        schema: SchemaRef,
+        joined_column_indices: Vec<ColumnIndex>,
+        (p_index, outer_queue, inner_queue): (
+            usize,
+            Arc<dyn PartitionedQueue>,
+            Arc<dyn PartitionedQueue>,
+        ),
+        cur_level: usize,
+    ) -> SqlResult<BoxedDataIter> {
+        let mut inner_stream = inner_queue.dequeue(p_index, config.batch_size)?.peekable();
+        let mut outer_stream = outer_queue.dequeue(p_index, config.batch_size)?;
+        let out_schema = schema.clone();
+        let track_left = config.join_type.returns_left_rows();
+        let mut reservation = ctx.new_reservation("grace hash join fallback");
+        let left_matches = Arc::new(Mutex::new(vec![]));
+
+        let gen = move || {
+            while inner_stream.peek().is_some() {
+                let chunk_queue = ctx.new_queue(config.inner_schema.clone());
+                let mut chunk_size = 0;
+                loop {
+                    let size = match inner_stream.peek() {
+                        Some(Ok(batch)) => batch_memory_size(batch),
+                        Some(Err(_)) => {
+                            if let Some(Err(e)) = inner_stream.next() {
+                                yield Err(e);
+                            }
+                            return;
+                        }
+                        None => break,
+                    };
+                    let reserved = chunk_size + size <= config.max_size_per_partition
+                        && reservation.try_grow(size);
+                    if !reserved {
+                        if chunk_size > 0 {
+                            break;
+                        }
+                        // a chunk has at least one batch
+                        if let Err(e) = reservation.grow(size) {
+                            yield Err(e);
+                            return;
+                        }
+                    }
+                    chunk_size += size;
+                    if let Some(Ok(batch)) = inner_stream.next() {
+                        if let Err(e) = chunk_queue.enqueue(0, batch) {
+                            yield Err(e);
+                            return;
+                        }
+                    }
+                }
+
+                // the outer side is needed again if there is another inner chunk,
+                // or to return left rows at the end
+                let probe_queue = ctx.new_queue(config.outer_schema.clone());
+                let next_queue = ctx.new_queue(config.outer_schema.clone());
+                let has_next_chunk = inner_stream.peek().is_some() || track_left;
+                let empty = DataBlock::new_empty(config.outer_schema.clone());
+                let enqueued = probe_queue
+                    .enqueue(0, empty.clone())
+                    .and_then(|_| next_queue.enqueue(0, empty));
+                if let Err(e) = enqueued {
+                    yield Err(e);
+                    return;
+                }
+                for batch in outer_stream {
+                    let enqueued = batch.and_then(|batch| {
+                        if has_next_chunk {
+                            next_queue.enqueue(0, batch.clone())?;
+                        }
+                        probe_queue.enqueue(0, batch)
+                    });
+                    if let Err(e) = enqueued {
+                        yield Err(e);
+                        return;
+                    }
+                }
+                outer_stream = match next_queue.dequeue(0, config.batch_size) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        yield Err(e);
+                        return;
+                    }
+                };
+
+                let mut inmem_joiner = Self::_new_hash_joiner(
+                    &config,
+                    schema.clone(),
+                    joined_column_indices.clone(),
+                    0,
+                    cur_level,
+                    probe_queue,
+                    chunk_queue,
+                );
+                if track_left {
+                    inmem_joiner = inmem_joiner.with_left_matches(left_matches.clone());
+                }
+                match inmem_joiner.execute_sync(ctx.clone()) {
+                    Ok(inmem_stream) => {
+                        for batch_ret in inmem_stream {
+                            yield batch_ret;
+                        }
+                    }
+                    Err(e) => {
+                        yield Err(e);
+                        return;
+                    }
+                }
+                reservation.free();
+            }
+
+            if !track_left {
+                return;
+            }
+            let mut offset = 0;
+            for batch in outer_stream {
+                let batch = match batch {
+                    Ok(batch) => batch,
+                    Err(e) => {
+                        yield Err(e);
+                        return;
+                    }
+                };
+                let matched = {
+                    let left_matches = left_matches.lock();
+                    (offset..offset + batch.num_rows())
+                        .map(|row| left_matches.get(row).copied().unwrap_or(false))
+                        .collect::<Vec<_>>()
+                };
+                offset += batch.num_rows();
+                match build_left_rows(
+                    config.join_type,
+                    &schema,
+                    &batch,
+                    &config.on_left,
+                    &matched,
+                    &joined_column_indices,
+                ) {
+                    Ok(Some(batch)) => yield Ok(batch),
+                    Ok(None) => {}
+                    Err(e) => {
+                        yield Err(e);
+                        return;
+                    }
+                }
+            }
+        };
+        let iter = GeneratorIteratorAdapter::new(gen);
+        Ok(SchemaDataIter::new(out_schema, Box::new(iter)))
+    }
+
     fn recursive_partition(
         ctx: ExecutionContext,
         config: Config,
@@ -425,7 +705,12 @@ impl GraceHashJoinOp {
                 .dequeue(*parent_p_index, batch_size)?;
             Self::partition_batch(&mut inner_stream, &mut new_level, &config, true)?;
             let mut fallbacks = vec![];
+            let max_level_reached = new_level.level >= MAX_RECURSION_LEVEL;
             new_level.map.retain(|idx, item| {
+                if max_level_reached && item.memsize > config.max_size_per_partition {
+                    fallbacks.push(*idx);
+                    return false;
+                }
                 let before_hash = parinfo.memsize as f64;
                 let after_hash = item.memsize as f64;
                 if before_hash > 0.0 {
@@ -479,19 +764,27 @@ pub mod tests {
     use crate::sql::{
         exe::{ExecutionContext, Operator},
         inmem_op::InMemOp,
-        join::queue::Inmem,
+        join::{
+            queue::{DiskAllocator, Inmem},
+            JoinType,
+        },
+        memory::MemoryPool,
         util::collect,
-        DataBlock,
+        DataBlock, Error,
     };
     use ahash::RandomState;
     use datafusion::{
         arrow::{
-            array::{Array, Int32Array},
-            datatypes::{DataType, Field, Schema},
+            array::{Array, DictionaryArray, Float64Array, Int32Array},
+            datatypes::{DataType, Field, Int32Type, Schema},
         },
         physical_plan::expressions::Column,
     };
     use std::sync::Arc;
+    use tempfile::TempDir;
+
+    /// Bytes of two rows of two non null int columns
+    const TWO_INT_ROWS: usize = 16;
 
     struct RowType(i64, Vec<u8>);
     fn build_i32_table_box(cols_and_values: Vec<(&str, Vec<i32>)>) -> Box<dyn Operator> {
@@ -526,6 +819,80 @@ pub mod tests {
         Arc::new(InMemOp::new(schema, vec![batch]))
     }
 
+    fn build_nullable_i32_table_box(
+        cols_and_values: Vec<(&str, Vec<Option<i32>>)>,
+    ) -> Box<dyn Operator> {
+        let field_vec = cols_and_values
+            .iter()
+            .map(|(col_name, _)| Field::new(col_name, DataType::Int32, true))
+            .collect::<Vec<_>>();
+        let schema = Arc::new(Schema::new(field_vec));
+        let columns = cols_and_values
+            .into_iter()
+            .map(|(_, values)| Arc::new(Int32Array::from(values)) as Arc<dyn Array>)
+            .collect::<Vec<_>>();
+        let batch = DataBlock::try_new(schema.clone(), columns).unwrap();
+        Box::new(InMemOp::new(schema, vec![batch]))
+    }
+
+    fn grace_join_with_type(
+        join_type: JoinType,
+        outer: Box<dyn Operator>,
+        inner: Box<dyn Operator>,
+        bucket_size: usize,
+    ) -> Vec<DataBlock> {
+        let ctx = ExecutionContext::new_for_test();
+        grace_join_with_ctx(ctx, join_type, outer, inner, bucket_size)
+    }
+
+    fn grace_join_with_ctx(
+        ctx: ExecutionContext,
+        join_type: JoinType,
+        outer: Box<dyn Operator>,
+        inner: Box<dyn Operator>,
+        bucket_size: usize,
+    ) -> Vec<DataBlock> {
+        let conf = Config {
+            bucket_size,
+            max_size_per_partition: TWO_INT_ROWS,
+            batch_size: 2,
+            on_left: vec![Column::new("col_a", 0)],
+            on_right: vec![Column::new("col_a", 0)],
+            outer_schema: outer.schema(),
+            inner_schema: inner.schema(),
+            join_type,
+        };
+        let (combined_schema, joined_column_indices) =
+            super::build_join_schema(&outer.schema(), &inner.schema(), join_type);
+        let mut hj = GraceHashJoinOp::new(
+            conf,
+            outer,
+            inner,
+            joined_column_indices,
+            Arc::new(combined_schema),
+        )
+        .expect("failted to create grace hash joiner");
+        let stream = hj.execute_sync(ctx).expect("executing join op");
+        collect(stream).expect("failed to collect from joined stream")
+    }
+
+    fn outer_with_nulls() -> Box<dyn Operator> {
+        build_nullable_i32_table_box(vec![
+            ("col_a", vec![Some(1), Some(2), Some(3), None]),
+            ("col_b", vec![Some(10), Some(20), Some(30), Some(40)]),
+        ])
+    }
+
+    fn inner_with_nulls() -> Box<dyn Operator> {
+        build_nullable_i32_table_box(vec![
+            ("col_a", vec![Some(1), Some(3), Some(3), Some(5), None]),
+            (
+                "col_c",
+                vec![Some(100), Some(300), Some(301), Some(500), Some(600)],
+            ),
+        ])
+    }
+
     fn collect_batch_from_op(mut op: Arc<dyn Operator>, ctx: ExecutionContext) -> Vec<DataBlock> {
         let outer_input_stream = Arc::get_mut(&mut op)
             .unwrap()
@@ -547,7 +914,7 @@ pub mod tests {
         let in_queue = Inmem::new(1, inner.schema());
         let out_queue = Inmem::new(2, outer.schema());
         let (combined_schema, joined_column_indices) =
-            super::build_join_schema(&outer.schema(), &inner.schema());
+            super::build_join_schema(&outer.schema(), &inner.schema(), JoinType::Inner);
 
         let ctx = ExecutionContext::new_for_test();
         let outer_batches = collect_batch_from_op(outer, ctx.clone());
@@ -572,6 +939,7 @@ pub mod tests {
             random_state,
             10,
             joined_column_indices,
+            JoinType::Inner,
         );
         let stream = joined_op
             .execute_sync(ctx.clone())
@@ -599,15 +967,16 @@ pub mod tests {
         let on_right = vec![Column::new("col_a", 0)];
         let conf = Config {
             bucket_size: 10,
-            max_size_per_partition: 2,
+            max_size_per_partition: TWO_INT_ROWS,
             batch_size: 2,
             on_left,
             on_right,
             outer_schema: outer.schema(),
             inner_schema: inner.schema(),
+            join_type: JoinType::Inner,
         };
         let (combined_schema, joined_column_indices) =
-            super::build_join_schema(&outer.schema(), &inner.schema());
+            super::build_join_schema(&outer.schema(), &inner.schema(), JoinType::Inner);
         let ctx = ExecutionContext::new_for_test();
 
         let mut hj = GraceHashJoinOp::new(
@@ -630,4 +999,533 @@ pub mod tests {
         ];
         crate::assert_batches_sorted_eq!(expected, &batches);
     }
+
+    #[test]
+    fn test_grace_hash_joiner_dictionary_and_float_keys() {
+        // each side has its own dictionary, -0.0 equals 0.0 and null never matches
+        let schema = |f: &str| {
+            let dict = DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8));
+            Arc::new(Schema::new(vec![
+                Field::new("col_a", dict, true),
+                Field::new(f, DataType::Float64, true),
+            ]))
+        };
+        let build = |f: &str, a: Vec<Option<&str>>, b: Vec<Option<f64>>| -> Box<dyn Operator> {
+            let schema = schema(f);
+            let dict = a.into_iter().collect::<DictionaryArray<Int32Type>>();
+            let batch = DataBlock::try_new(
+                schema.clone(),
+                vec![Arc::new(dict), Arc::new(Float64Array::from(b))],
+            )
+            .unwrap();
+            Box::new(InMemOp::new(schema, vec![batch]))
+        };
+        let outer = build(
+            "col_b",
+            vec![Some("a"), Some("b"), Some("c"), None, Some("a")],
+            vec![Some(0.0), Some(1.5), Some(2.0), Some(0.0), Some(3.0)],
+        );
+        let inner = build(
+            "col_c",
+            vec![Some("b"), Some("a"), None, Some("a"), Some("c")],
+            vec![Some(1.5), Some(-0.0), Some(0.0), Some(3.5), None],
+        );
+        let on = vec![Column::new("col_a", 0), Column::new("col_b", 1)];
+        let conf = Config {
+            bucket_size: 4,
+            max_size_per_partition: 1024,
+            batch_size: 2,
+            on_left: on.clone(),
+            on_right: on,
+            outer_schema: outer.schema(),
+            inner_schema: inner.schema(),
+            join_type: JoinType::Inner,
+        };
+        let (combined_schema, joined_column_indices) =
+            super::build_join_schema(&outer.schema(), &inner.schema(), JoinType::Inner);
+        let mut hj = GraceHashJoinOp::new(
+            conf,
+            outer,
+            inner,
+            joined_column_indices,
+            Arc::new(combined_schema),
+        )
+        .expect("failted to create grace hash joiner");
+        let stream = hj
+            .execute_sync(ExecutionContext::new_for_test())
+            .expect("executing join op");
+        let batches = collect(stream).expect("failed to collect from joined stream");
+        let expected = [
+            "+-------+-------+-------+-------+",
+            "| col_a | col_b | col_a | col_c |",
+            "+-------+-------+-------+-------+",
+            "| a     | 0     | a     | -0    |",
+            "| b     | 1.5   | b     | 1.5   |",
+            "+-------+-------+-------+-------+",
+        ];
+        crate::assert_batches_sorted_eq!(expected, &batches);
+    }
+
+    #[test]
+    fn test_grace_hash_joiner_disk_queue() {
+        let dir = TempDir::new().unwrap();
+        let ctx = ExecutionContext::new_for_test()
+            .with_queue_allocator(Arc::new(DiskAllocator::new(dir.path().to_path_buf())));
+        // skewed keys so that partitions are also re-partitioned and joined by fallback
+        let outer = build_i32_table_box(vec![
+            ("col_a", vec![1, 1, 1, 2, 3, 1, 7]),
+            ("col_b", vec![0, 1, 2, 3, 4, 5, 6]),
+        ]);
+        let inner = build_i32_table_box(vec![
+            ("col_a", vec![1, 1, 4, 1, 1, 3]),
+            ("col_c", vec![0, 1, 2, 3, 4, 5]),
+        ]);
+        let batches = grace_join_with_ctx(ctx, JoinType::LeftOuter, outer, inner, 4);
+        let expected = [
+            "+-------+-------+-------+-------+",
+            "| col_a | col_b | col_a | col_c |",
+            "+-------+-------+-------+-------+",
+            "| 1     | 0     | 1     | 0     |",
+            "| 1     | 0     | 1     | 1     |",
+            "| 1     | 0     | 1     | 3     |",
+            "| 1     | 0     | 1     | 4     |",
+            "| 1     | 1     | 1     | 0     |",
+            "| 1     | 1     | 1     | 1     |",
+            "| 1     | 1     | 1     | 3     |",
+            "| 1     | 1     | 1     | 4     |",
+            "| 1     | 2     | 1     | 0     |",
+            "| 1     | 2     | 1     | 1     |",
+            "| 1     | 2     | 1     | 3     |",
+            "| 1     | 2     | 1     | 4     |",
+            "| 1     | 5     | 1     | 0     |",
+            "| 1     | 5     | 1     | 1     |",
+            "| 1     | 5     | 1     | 3     |",
+            "| 1     | 5     | 1     | 4     |",
+            "| 2     | 3     |       |       |",
+            "| 3     | 4     | 3     | 5     |",
+            "| 7     | 6     |       |       |",
+            "+-------+-------+-------+-------+",
+        ];
+        crate::assert_batches_sorted_eq!(expected, &batches);
+    }
+
+    #[test]
+    fn test_grace_hash_joiner_memory_limit_exceeded() {
+        // partitions of key 1 are joined by fallback, whose chunks cannot even hold
+        // a single batch of 2 rows
+        let ctx = ExecutionContext::new_for_test()
+            .with_memory_pool(Arc::new(MemoryPool::new(TWO_INT_ROWS / 2)));
+        let outer = build_i32_table_box(vec![("col_a", vec![1, 1, 2]), ("col_b", vec![0, 1, 2])]);
+        let inner = build_i32_table_box(vec![
+            ("col_a", vec![1, 1, 1, 1, 1]),
+            ("col_c", vec![0, 1, 2, 3, 4]),
+        ]);
+        let conf = Config {
+            bucket_size: 4,
+            max_size_per_partition: TWO_INT_ROWS,
+            batch_size: 2,
+            on_left: vec![Column::new("col_a", 0)],
+            on_right: vec![Column::new("col_a", 0)],
+            outer_schema: outer.schema(),
+            inner_schema: inner.schema(),
+            join_type: JoinType::Inner,
+        };
+        let (combined_schema, joined_column_indices) =
+            super::build_join_schema(&outer.schema(), &inner.schema(), JoinType::Inner);
+        let mut hj = GraceHashJoinOp::new(
+            conf,
+            outer,
+            inner,
+            joined_column_indices,
+            Arc::new(combined_schema),
+        )
+        .expect("failted to create grace hash joiner");
+        let stream = hj.execute_sync(ctx.clone()).expect("executing join op");
+        let ret = collect(stream);
+        assert!(matches!(ret, Err(Error::ResourceExhausted(_))));
+        assert_eq!(0, ctx.memory_pool().used());
+    }
+
+    #[test]
+    fn test_grace_hash_joiner_skewed_partition() {
+        // key 1 is duplicated far more than max_size_per_partition, re-hashing
+        // never shrinks its partition
+        let outer_keys = vec![1, 1, 1, 2, 3, 1];
+        let inner_keys = vec![1, 1, 4, 1, 1, 1, 2, 1, 1];
+        let outer = build_i32_table_box(vec![
+            ("col_a", outer_keys.clone()),
+            ("col_b", (0..outer_keys.len() as i32).collect()),
+        ]);
+        let inner = build_i32_table_box(vec![
+            ("col_a", inner_keys.clone()),
+            ("col_c", (0..inner_keys.len() as i32).collect()),
+        ]);
+        let conf = Config {
+            bucket_size: 4,
+            max_size_per_partition: TWO_INT_ROWS,
+            batch_size: 2,
+            on_left: vec![Column::new("col_a", 0)],
+            on_right: vec![Column::new("col_a", 0)],
+            outer_schema: outer.schema(),
+            inner_schema: inner.schema(),
+            join_type: JoinType::Inner,
+        };
+        let (combined_schema, joined_column_indices) =
+            super::build_join_schema(&outer.schema(), &inner.schema(), JoinType::Inner);
+        let ctx = ExecutionContext::new_for_test();
+        let mut hj = GraceHashJoinOp::new(
+            conf,
+            outer,
+            inner,
+            joined_column_indices,
+            Arc::new(combined_schema),
+        )
+        .expect("failted to create grace hash joiner");
+        let stream = hj.execute_sync(ctx).expect("executing join op");
+        let batches = collect(stream).expect("failed to collect from joined stream");
+
+        let mut got = vec![];
+        for batch in &batches {
+            let cols = (0..4)
+                .map(|i| {
+                    batch
+                        .column(i)
+                        .as_any()
+                        .downcast_ref::<Int32Array>()
+                        .unwrap()
+                        .clone()
+                })
+                .collect::<Vec<_>>();
+            for row in 0..batch.num_rows() {
+                got.push((
+                    cols[0].value(row),
+                    cols[1].value(row),
+                    cols[2].value(row),
+                    cols[3].value(row),
+                ));
+            }
+        }
+        got.sort_unstable();
+
+        let mut expected = vec![];
+        for (outer_row, outer_key) in outer_keys.iter().enumerate() {
+            for (inner_row, inner_key) in inner_keys.iter().enumerate() {
+                if outer_key == inner_key {
+                    expected.push((*outer_key, outer_row as i32, *inner_key, inner_row as i32));
+                }
+            }
+        }
+        expected.sort_unstable();
+        assert_eq!(expected, got);
+    }
+
+    #[test]
+    fn test_grace_hash_joiner_left_outer() {
+        let batches = grace_join_with_type(
+            JoinType::LeftOuter,
+            outer_with_nulls(),
+            inner_with_nulls(),
+            4,
+        );
+        let expected = [
+            "+-------+-------+-------+-------+",
+            "| col_a | col_b | col_a | col_c |",
+            "+-------+-------+-------+-------+",
+            "|       | 40    |       |       |",
+            "| 1     | 10    | 1     | 100   |",
+            "| 2     | 20    |       |       |",
+            "| 3     | 30    | 3     | 300   |",
+            "| 3     | 30    | 3     | 301   |",
+            "+-------+-------+-------+-------+",
+        ];
+        crate::assert_batches_sorted_eq!(expected, &batches);
+        assert!(batches[0].schema().fields().iter().all(|f| f.is_nullable()));
+    }
+
+    #[test]
+    fn test_grace_hash_joiner_right_outer() {
+        let batches = grace_join_with_type(
+            JoinType::RightOuter,
+            outer_with_nulls(),
+            inner_with_nulls(),
+            4,
+        );
+        let expected = [
+            "+-------+-------+-------+-------+",
+            "| col_a | col_b | col_a | col_c |",
+            "+-------+-------+-------+-------+",
+            "|       |       |       | 600   |",
+            "|       |       | 5     | 500   |",
+            "| 1     | 10    | 1     | 100   |",
+            "| 3     | 30    | 3     | 300   |",
+            "| 3     | 30    | 3     | 301   |",
+            "+-------+-------+-------+-------+",
+        ];
+        crate::assert_batches_sorted_eq!(expected, &batches);
+    }
+
+    #[test]
+    fn test_grace_hash_joiner_full_outer() {
+        let batches = grace_join_with_type(
+            JoinType::FullOuter,
+            outer_with_nulls(),
+            inner_with_nulls(),
+            4,
+        );
+        let expected = [
+            "+-------+-------+-------+-------+",
+            "| col_a | col_b | col_a | col_c |",
+            "+-------+-------+-------+-------+",
+            "|       |       |       | 600   |",
+            "|       |       | 5     | 500   |",
+            "|       | 40    |       |       |",
+            "| 1     | 10    | 1     | 100   |",
+            "| 2     | 20    |       |       |",
+            "| 3     | 30    | 3     | 300   |",
+            "| 3     | 30    | 3     | 301   |",
+            "+-------+-------+-------+-------+",
+        ];
+        crate::assert_batches_sorted_eq!(expected, &batches);
+    }
+
+    #[test]
+    fn test_grace_hash_joiner_left_semi() {
+        let batches = grace_join_with_type(
+            JoinType::LeftSemi,
+            outer_with_nulls(),
+            inner_with_nulls(),
+            4,
+        );
+        // 3 matches twice but is returned once
+        let expected = [
+            "+-------+-------+",
+            "| col_a | col_b |",
+            "+-------+-------+",
+            "| 1     | 10    |",
+            "| 3     | 30    |",
+            "+-------+-------+",
+        ];
+        crate::assert_batches_sorted_eq!(expected, &batches);
+    }
+
+    #[test]
+    fn test_grace_hash_joiner_left_anti() {
+        let batches = grace_join_with_type(
+            JoinType::LeftAnti,
+            outer_with_nulls(),
+            inner_with_nulls(),
+            4,
+        );
+        let expected = [
+            "+-------+-------+",
+            "| col_a | col_b |",
+            "+-------+-------+",
+            "|       | 40    |",
+            "| 2     | 20    |",
+            "+-------+-------+",
+        ];
+        crate::assert_batches_sorted_eq!(expected, &batches);
+    }
+
+    #[test]
+    fn test_grace_hash_joiner_null_aware_anti() {
+        // inner side has a null key: nothing is returned
+        let batches = grace_join_with_type(
+            JoinType::NullAwareLeftAnti,
+            outer_with_nulls(),
+            inner_with_nulls(),
+            4,
+        );
+        assert_eq!(0, batches.iter().map(|b| b.num_rows()).sum::<usize>());
+
+        // left rows with null key are excluded
+        let inner = build_nullable_i32_table_box(vec![
+            ("col_a", vec![Some(1), Some(3), Some(5)]),
+            ("col_c", vec![Some(100), Some(300), Some(500)]),
+        ]);
+        let batches =
+            grace_join_with_type(JoinType::NullAwareLeftAnti, outer_with_nulls(), inner, 4);
+        let expected = [
+            "+-------+-------+",
+            "| col_a | col_b |",
+            "+-------+-------+",
+            "| 2     | 20    |",
+            "+-------+-------+",
+        ];
+        crate::assert_batches_sorted_eq!(expected, &batches);
+
+        // unless the inner side is empty
+        let inner = build_nullable_i32_table_box(vec![("col_a", vec![]), ("col_c", vec![])]);
+        let batches =
+            grace_join_with_type(JoinType::NullAwareLeftAnti, outer_with_nulls(), inner, 4);
+        let expected = [
+            "+-------+-------+",
+            "| col_a | col_b |",
+            "+-------+-------+",
+            "|       | 40    |",
+            "| 1     | 10    |",
+            "| 2     | 20    |",
+            "| 3     | 30    |",
+            "+-------+-------+",
+        ];
+        crate::assert_batches_sorted_eq!(expected, &batches);
+    }
+
+    #[test]
+    fn test_grace_hash_joiner_empty_inner() {
+        let empty = || {
+            let schema = Arc::new(Schema::new(vec![
+                Field::new("col_a", DataType::Int32, true),
+                Field::new("col_c", DataType::Int32, true),
+            ]));
+            Box::new(InMemOp::new(schema, vec![])) as Box<dyn Operator>
+        };
+        let all_left = [
+            "+-------+-------+",
+            "| col_a | col_b |",
+            "+-------+-------+",
+            "|       | 40    |",
+            "| 1     | 10    |",
+            "| 2     | 20    |",
+            "| 3     | 30    |",
+            "+-------+-------+",
+        ];
+        for join_type in [JoinType::LeftAnti, JoinType::NullAwareLeftAnti] {
+            let batches = grace_join_with_type(join_type, outer_with_nulls(), empty(), 4);
+            crate::assert_batches_sorted_eq!(all_left, &batches);
+        }
+        for join_type in [JoinType::LeftOuter, JoinType::FullOuter] {
+            let batches = grace_join_with_type(join_type, outer_with_nulls(), empty(), 4);
+            let expected = [
+                "+-------+-------+-------+-------+",
+                "| col_a | col_b | col_a | col_c |",
+                "+-------+-------+-------+-------+",
+                "|       | 40    |       |       |",
+                "| 1     | 10    |       |       |",
+                "| 2     | 20    |       |       |",
+                "| 3     | 30    |       |       |",
+                "+-------+-------+-------+-------+",
+            ];
+            crate::assert_batches_sorted_eq!(expected, &batches);
+        }
+        for join_type in [JoinType::Inner, JoinType::RightOuter, JoinType::LeftSemi] {
+            let batches = grace_join_with_type(join_type, outer_with_nulls(), empty(), 4);
+            assert_eq!(0, batches.iter().map(|b| b.num_rows()).sum::<usize>());
+        }
+    }
+
+    #[test]
+    fn test_grace_hash_joiner_skewed_partition_join_types() {
+        // same skew as test_grace_hash_joiner_skewed_partition, the outer matches of
+        // key 1 are spread over multiple inner chunks
+        let outer_keys = vec![Some(1), Some(1), Some(1), Some(2), Some(3), Some(1), None];
+        let inner_keys = vec![
+            Some(1),
+            Some(1),
+            Some(4),
+            Some(1),
+            Some(1),
+            Some(1),
+            Some(2),
+            Some(1),
+            Some(1),
+        ];
+        let outer_rows = outer_keys
+            .iter()
+            .enumerate()
+            .map(|(i, k)| vec![*k, Some(i as i32)])
+            .collect::<Vec<_>>();
+        let inner_rows = inner_keys
+            .iter()
+            .enumerate()
+            .map(|(i, k)| vec![*k, Some(i as i32)])
+            .collect::<Vec<_>>();
+
+        for join_type in [
+            JoinType::Inner,
+            JoinType::LeftOuter,
+            JoinType::RightOuter,
+            JoinType::FullOuter,
+            JoinType::LeftSemi,
+            JoinType::LeftAnti,
+            JoinType::NullAwareLeftAnti,
+        ] {
+            let outer = build_nullable_i32_table_box(vec![
+                ("col_a", outer_keys.clone()),
+                ("col_b", (0..outer_keys.len() as i32).map(Some).collect()),
+            ]);
+            let inner = build_nullable_i32_table_box(vec![
+                ("col_a", inner_keys.clone()),
+                ("col_c", (0..inner_keys.len() as i32).map(Some).collect()),
+            ]);
+            let batches = grace_join_with_type(join_type, outer, inner, 4);
+            let mut got = vec![];
+            for batch in &batches {
+                for row in 0..batch.num_rows() {
+                    got.push(
+                        batch
+                            .columns()
+                            .iter()
+                            .map(|col| {
+                                let col = col.as_any().downcast_ref::<Int32Array>().unwrap();
+                                match col.is_null(row) {
+                                    true => None,
+                                    false => Some(col.value(row)),
+                                }
+                            })
+                            .collect::<Vec<_>>(),
+                    );
+                }
+            }
+            got.sort_unstable();
+
+            let mut expected = nested_loop_join(join_type, &outer_rows, &inner_rows);
+            expected.sort_unstable();
+            assert_eq!(expected, got, "join type {:?}", join_type);
+        }
+    }
+
+    /// Reference implementation joining on the first column
+    fn nested_loop_join(
+        join_type: JoinType,
+        outer: &[Vec<Option<i32>>],
+        inner: &[Vec<Option<i32>>],
+    ) -> Vec<Vec<Option<i32>>> {
+        let matches = |o: &Vec<Option<i32>>, i: &Vec<Option<i32>>| o[0].is_some() && o[0] == i[0];
+        let mut ret = vec![];
+        for o in outer {
+            let matched = inner.iter().filter(|i| matches(o, i)).collect::<Vec<_>>();
+            match join_type {
+                JoinType::LeftSemi if !matched.is_empty() => ret.push(o.clone()),
+                JoinType::LeftAnti if matched.is_empty() => ret.push(o.clone()),
+                JoinType::NullAwareLeftAnti
+                    if matched.is_empty() && !inner.iter().any(|i| i[0].is_none()) =>
+                {
+                    if o[0].is_some() || inner.is_empty() {
+                        ret.push(o.clone())
+                    }
+                }
+                JoinType::LeftOuter | JoinType::FullOuter if matched.is_empty() => {
+                    ret.push([o.clone(), vec![None, None]].concat())
+                }
+                JoinType::Inner
+                | JoinType::LeftOuter
+                | JoinType::RightOuter
+                | JoinType::FullOuter => {
+                    for i in matched {
+                        ret.push([o.clone(), i.clone()].concat());
+                    }
+                }
+                _ => {}
+            }
+        }
+        if matches!(join_type, JoinType::RightOuter | JoinType::FullOuter) {
+            for i in inner {
+                if !outer.iter().any(|o| matches(o, i)) {
+                    ret.push([vec![None, None], i.clone()].concat());
+                }
+            }
+        }
+        ret
+    }
 }
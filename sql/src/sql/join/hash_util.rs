@@ -21,10 +21,16 @@ use crate::sql::SqlResult;
 use ahash::{CallHasher, RandomState};
 use datafusion::arrow::{
     array::{
-        Array, ArrayRef, BooleanArray, DecimalArray, DictionaryArray, Int16Array, Int32Array,
-        Int64Array, Int8Array, UInt16Array, UInt32Array, UInt64Array, UInt8Array,
+        Array, ArrayRef, BooleanArray, Date32Array, Date64Array, DecimalArray, DictionaryArray,
+        Float32Array, Float64Array, Int16Array, Int32Array, Int64Array, Int8Array,
+        LargeStringArray, StringArray, TimestampMicrosecondArray, TimestampMillisecondArray,
+        TimestampNanosecondArray, TimestampSecondArray, UInt16Array, UInt32Array, UInt64Array,
+        UInt8Array,
+    },
+    datatypes::{
+        ArrowDictionaryKeyType, ArrowNativeType, DataType, Int16Type, Int32Type, Int64Type,
+        Int8Type, TimeUnit, UInt16Type, UInt32Type, UInt64Type, UInt8Type,
     },
-    datatypes::{ArrowDictionaryKeyType, ArrowNativeType, DataType},
 };
 use std::sync::Arc;
 
@@ -132,6 +138,7 @@ macro_rules! hash_array_primitive {
     };
 }
 
+/// Floats are hashed by their bits, `value + 0.0` turns -0.0 into 0.0 since both are equal
 macro_rules! hash_array_float {
     ($array_type:ident, $column: ident, $ty: ident, $hashes: ident, $random_state: ident, $multi_col: ident) => {
         let array = $column.as_any().downcast_ref::<$array_type>().unwrap();
@@ -141,13 +148,19 @@ macro_rules! hash_array_float {
             if $multi_col {
                 for (hash, value) in $hashes.iter_mut().zip(values.iter()) {
                     *hash = combine_hashes(
-                        $ty::get_hash(&$ty::from_le_bytes(value.to_le_bytes()), $random_state),
+                        $ty::get_hash(
+                            &$ty::from_le_bytes((value + 0.0).to_le_bytes()),
+                            $random_state,
+                        ),
                         *hash,
                     );
                 }
             } else {
                 for (hash, value) in $hashes.iter_mut().zip(values.iter()) {
-                    *hash = $ty::get_hash(&$ty::from_le_bytes(value.to_le_bytes()), $random_state)
+                    *hash = $ty::get_hash(
+                        &$ty::from_le_bytes((value + 0.0).to_le_bytes()),
+                        $random_state,
+                    )
                 }
             }
         } else {
@@ -155,7 +168,10 @@ macro_rules! hash_array_float {
                 for (i, (hash, value)) in $hashes.iter_mut().zip(values.iter()).enumerate() {
                     if !array.is_null(i) {
                         *hash = combine_hashes(
-                            $ty::get_hash(&$ty::from_le_bytes(value.to_le_bytes()), $random_state),
+                            $ty::get_hash(
+                                &$ty::from_le_bytes((value + 0.0).to_le_bytes()),
+                                $random_state,
+                            ),
                             *hash,
                         );
                     }
@@ -163,8 +179,10 @@ macro_rules! hash_array_float {
             } else {
                 for (i, (hash, value)) in $hashes.iter_mut().zip(values.iter()).enumerate() {
                     if !array.is_null(i) {
-                        *hash =
-                            $ty::get_hash(&$ty::from_le_bytes(value.to_le_bytes()), $random_state);
+                        *hash = $ty::get_hash(
+                            &$ty::from_le_bytes((value + 0.0).to_le_bytes()),
+                            $random_state,
+                        );
                     }
                 }
             }
@@ -220,22 +238,6 @@ fn create_hashes_dictionary<K: ArrowDictionaryKeyType>(
     Ok(())
 }
 
-/// Test version of `create_hashes` that produces the same value for
-/// all hashes (to test collisions)
-///
-/// See comments on `hashes_buffer` for more details
-#[cfg(feature = "force_hash_collisions")]
-pub fn create_hashes<'a>(
-    _arrays: &[ArrayRef],
-    _random_state: RandomState,
-    hashes_buffer: &'a mut Vec<u64>,
-) -> Result<&'a mut Vec<u64>> {
-    for hash in hashes_buffer.iter_mut() {
-        *hash = 0
-    }
-    return Ok(hashes_buffer);
-}
-
 /// Return vector of bucket index
 pub fn hash_to_buckets<'a>(
     arrays: &[ArrayRef],
@@ -243,13 +245,33 @@ pub fn hash_to_buckets<'a>(
     hashes_buffer: &'a mut Vec<u64>,
     buckets: usize,
 ) -> SqlResult<()> {
+    create_hashes(arrays, random_state, hashes_buffer)?;
+    for item in hashes_buffer.iter_mut() {
+        *item %= buckets as u64;
+    }
+    Ok(())
+}
+
+/// Creates hash values for every row, based on the values in the
+/// columns.
+///
+/// The number of rows to hash is determined by `hashes_buffer.len()`.
+/// `hashes_buffer` should be pre-sized appropriately. Null values leave the hash
+/// of their row unchanged, so it should be zeroed as well. Values that are equal
+/// according to `equal_rows` in [super::inmem] have the same hash, e.g -0.0 and 0.0
+pub fn create_hashes<'a>(
+    arrays: &[ArrayRef],
+    random_state: &RandomState,
+    hashes_buffer: &'a mut Vec<u64>,
+) -> SqlResult<&'a mut Vec<u64>> {
     // combine hashes with `combine_hashes` if we have more than 1 column
     let multi_col = arrays.len() > 1;
 
     for col in arrays {
         match col.data_type() {
+            DataType::Null => {}
             DataType::Decimal(_, _) => {
-                hash_decimal128(col, &random_state, hashes_buffer, multi_col);
+                hash_decimal128(col, random_state, hashes_buffer, multi_col);
             }
             DataType::UInt8 => {
                 hash_array_primitive!(UInt8Array, col, u8, hashes_buffer, random_state, multi_col);
@@ -296,96 +318,85 @@ pub fn hash_to_buckets<'a>(
             DataType::Int64 => {
                 hash_array_primitive!(Int64Array, col, i64, hashes_buffer, random_state, multi_col);
             }
-            DataType::Boolean => {
-                hash_array!(
-                    BooleanArray,
+            DataType::Float32 => {
+                hash_array_float!(
+                    Float32Array,
                     col,
-                    u8,
+                    u32,
                     hashes_buffer,
                     random_state,
                     multi_col
                 );
             }
-
-            _ => {
-                // This is internal because we should have caught this before.
-                return Err(
-                    format!("Unsupported data type in hasher: {}", col.data_type())
-                        .to_string()
-                        .into(),
+            DataType::Float64 => {
+                hash_array_float!(
+                    Float64Array,
+                    col,
+                    u64,
+                    hashes_buffer,
+                    random_state,
+                    multi_col
                 );
             }
-        }
-    }
-    for item in hashes_buffer.iter_mut() {
-        *item = *item % buckets as u64;
-    }
-    Ok(())
-}
-
-/// Creates hash values for every row, based on the values in the
-/// columns.
-///
-/// The number of rows to hash is determined by `hashes_buffer.len()`.
-/// `hashes_buffer` should be pre-sized appropriately
-#[cfg(not(feature = "force_hash_collisions"))]
-pub fn create_hashes<'a>(
-    arrays: &[ArrayRef],
-    random_state: &RandomState,
-    hashes_buffer: &'a mut Vec<u64>,
-) -> SqlResult<&'a mut Vec<u64>> {
-    // combine hashes with `combine_hashes` if we have more than 1 column
-    let multi_col = arrays.len() > 1;
-
-    for col in arrays {
-        match col.data_type() {
-            DataType::Decimal(_, _) => {
-                hash_decimal128(col, &random_state, hashes_buffer, multi_col);
-            }
-            DataType::UInt8 => {
-                hash_array_primitive!(UInt8Array, col, u8, hashes_buffer, random_state, multi_col);
-            }
-            DataType::UInt16 => {
+            DataType::Date32 => {
                 hash_array_primitive!(
-                    UInt16Array,
+                    Date32Array,
                     col,
-                    u16,
+                    i32,
                     hashes_buffer,
                     random_state,
                     multi_col
                 );
             }
-            DataType::UInt32 => {
+            DataType::Date64 => {
                 hash_array_primitive!(
-                    UInt32Array,
+                    Date64Array,
                     col,
-                    u32,
+                    i64,
                     hashes_buffer,
                     random_state,
                     multi_col
                 );
             }
-            DataType::UInt64 => {
+            DataType::Timestamp(TimeUnit::Second, _) => {
                 hash_array_primitive!(
-                    UInt64Array,
+                    TimestampSecondArray,
                     col,
-                    u64,
+                    i64,
                     hashes_buffer,
                     random_state,
                     multi_col
                 );
             }
-            DataType::Int8 => {
-                hash_array_primitive!(Int8Array, col, i8, hashes_buffer, random_state, multi_col);
-            }
-            DataType::Int16 => {
-                hash_array_primitive!(Int16Array, col, i16, hashes_buffer, random_state, multi_col);
+            DataType::Timestamp(TimeUnit::Millisecond, _) => {
+                hash_array_primitive!(
+                    TimestampMillisecondArray,
+                    col,
+                    i64,
+                    hashes_buffer,
+                    random_state,
+                    multi_col
+                );
             }
-            DataType::Int32 => {
-                hash_array_primitive!(Int32Array, col, i32, hashes_buffer, random_state, multi_col);
+            DataType::Timestamp(TimeUnit::Microsecond, _) => {
+                hash_array_primitive!(
+                    TimestampMicrosecondArray,
+                    col,
+                    i64,
+                    hashes_buffer,
+                    random_state,
+                    multi_col
+                );
             }
-            DataType::Int64 => {
-                hash_array_primitive!(Int64Array, col, i64, hashes_buffer, random_state, multi_col);
+            DataType::Timestamp(TimeUnit::Nanosecond, _) => {
+                hash_array_primitive!(
+                    TimestampNanosecondArray,
+                    col,
+                    i64,
+                    hashes_buffer,
+                    random_state,
+                    multi_col
+                );
             }
             DataType::Boolean => {
                 hash_array!(
@@ -397,7 +408,99 @@ pub fn create_hashes<'a>(
                     multi_col
                 );
             }
-
+            DataType::Utf8 => {
+                hash_array!(
+                    StringArray,
+                    col,
+                    str,
+                    hashes_buffer,
+                    random_state,
+                    multi_col
+                );
+            }
+            DataType::LargeUtf8 => {
+                hash_array!(
+                    LargeStringArray,
+                    col,
+                    str,
+                    hashes_buffer,
+                    random_state,
+                    multi_col
+                );
+            }
+            DataType::Dictionary(key_type, _) => match **key_type {
+                DataType::Int8 => {
+                    create_hashes_dictionary::<Int8Type>(
+                        col,
+                        random_state,
+                        hashes_buffer,
+                        multi_col,
+                    )?;
+                }
+                DataType::Int16 => {
+                    create_hashes_dictionary::<Int16Type>(
+                        col,
+                        random_state,
+                        hashes_buffer,
+                        multi_col,
+                    )?;
+                }
+                DataType::Int32 => {
+                    create_hashes_dictionary::<Int32Type>(
+                        col,
+                        random_state,
+                        hashes_buffer,
+                        multi_col,
+                    )?;
+                }
+                DataType::Int64 => {
+                    create_hashes_dictionary::<Int64Type>(
+                        col,
+                        random_state,
+                        hashes_buffer,
+                        multi_col,
+                    )?;
+                }
+                DataType::UInt8 => {
+                    create_hashes_dictionary::<UInt8Type>(
+                        col,
+                        random_state,
+                        hashes_buffer,
+                        multi_col,
+                    )?;
+                }
+                DataType::UInt16 => {
+                    create_hashes_dictionary::<UInt16Type>(
+                        col,
+                        random_state,
+                        hashes_buffer,
+                        multi_col,
+                    )?;
+                }
+                DataType::UInt32 => {
+                    create_hashes_dictionary::<UInt32Type>(
+                        col,
+                        random_state,
+                        hashes_buffer,
+                        multi_col,
+                    )?;
+                }
+                DataType::UInt64 => {
+                    create_hashes_dictionary::<UInt64Type>(
+                        col,
+                        random_state,
+                        hashes_buffer,
+                        multi_col,
+                    )?;
+                }
+                _ => {
+                    return Err(format!(
+                        "Unsupported dictionary key type in hasher: {}",
+                        col.data_type()
+                    )
+                    .into());
+                }
+            },
             _ => {
                 // This is internal because we should have caught this before.
                 return Err(format!("Unsupported data type in hasher: {}", col.data_type()).into());
@@ -406,3 +509,94 @@ pub fn create_hashes<'a>(
     }
     Ok(hashes_buffer)
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::{create_hashes, hash_to_buckets};
+    use ahash::RandomState;
+    use datafusion::arrow::{
+        array::{
+            ArrayRef, Date32Array, DictionaryArray, Float32Array, Float64Array, Int32Array,
+            LargeStringArray, StringArray, TimestampMillisecondArray, TimestampNanosecondArray,
+        },
+        datatypes::{Int8Type, UInt32Type},
+    };
+    use std::sync::Arc;
+
+    fn hashes(arrays: &[ArrayRef]) -> Vec<u64> {
+        let mut hashes_buffer = vec![0; arrays[0].len()];
+        create_hashes(arrays, &RandomState::with_seed(0), &mut hashes_buffer).unwrap();
+        hashes_buffer
+    }
+
+    #[test]
+    fn test_hash_equal_values() {
+        // the first and last values are equal
+        let arrays: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from(vec!["a", "b", "a"])),
+            Arc::new(LargeStringArray::from(vec!["a", "b", "a"])),
+            Arc::new(Float32Array::from(vec![0.0, 1.5, -0.0])),
+            Arc::new(Float64Array::from(vec![0.0, 1.5, -0.0])),
+            Arc::new(Date32Array::from(vec![19000, 19001, 19000])),
+            Arc::new(TimestampMillisecondArray::from_vec(vec![1, 2, 1], None)),
+            Arc::new(TimestampNanosecondArray::from_vec(
+                vec![1, 2, 1],
+                Some("+00:00".to_string()),
+            )),
+            Arc::new(
+                vec!["a", "b", "a"]
+                    .into_iter()
+                    .collect::<DictionaryArray<Int8Type>>(),
+            ),
+        ];
+        for array in arrays {
+            let hashes = hashes(&[array.clone()]);
+            assert_eq!(hashes[0], hashes[2], "{:?}", array.data_type());
+            assert_ne!(hashes[0], hashes[1], "{:?}", array.data_type());
+        }
+    }
+
+    #[test]
+    fn test_hash_dictionary_as_values() {
+        // different dictionaries with the same values hash the same as the plain values
+        let values: ArrayRef = Arc::new(StringArray::from(vec!["x", "y", "x", "z"]));
+        let dict: ArrayRef = Arc::new(
+            vec!["x", "y", "x", "z"]
+                .into_iter()
+                .collect::<DictionaryArray<UInt32Type>>(),
+        );
+        let other_dict: ArrayRef = Arc::new(
+            vec!["z", "x", "y", "x", "z"]
+                .into_iter()
+                .collect::<DictionaryArray<Int8Type>>(),
+        );
+        assert_eq!(hashes(&[values.clone()]), hashes(&[dict]));
+        assert_eq!(hashes(&[values])[..], hashes(&[other_dict])[1..]);
+    }
+
+    #[test]
+    fn test_hash_nulls() {
+        let ints: ArrayRef = Arc::new(Int32Array::from(vec![1, 2, 3]));
+        let strings: ArrayRef = Arc::new(StringArray::from(vec![Some("a"), None, None]));
+        let single = hashes(&[strings.clone()]);
+        assert_eq!(0, single[1]);
+        // a null value leaves the hash of the previous columns unchanged
+        let all_nulls: ArrayRef = Arc::new(StringArray::from(vec![None::<&str>; 3]));
+        let multi = hashes(&[ints.clone(), strings]);
+        let ints_only = hashes(&[ints, all_nulls]);
+        assert_ne!(ints_only[0], multi[0]);
+        assert_eq!(ints_only[1..], multi[1..]);
+    }
+
+    #[test]
+    fn test_hash_to_buckets() {
+        let arrays: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from(vec!["a", "b", "c", "a", "b", "c"])),
+            Arc::new(Float64Array::from(vec![1.0, 2.0, 3.0, 1.0, 2.0, 3.0])),
+        ];
+        let mut buckets = vec![0; 6];
+        hash_to_buckets(&arrays, &RandomState::with_seed(1), &mut buckets, 4).unwrap();
+        assert!(buckets.iter().all(|b| *b < 4));
+        assert_eq!(buckets[..3], buckets[3..]);
+    }
+}
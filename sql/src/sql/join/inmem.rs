@@ -3,6 +3,7 @@ use crate::sql::{
     join::{
         grace::PartitionedQueue,
         hash_util::{create_hashes, hash_to_buckets},
+        JoinType,
     },
     DataBlock, Error as SqlError, SqlResult,
 };
@@ -10,14 +11,18 @@ use ahash::RandomState;
 use datafusion::{
     arrow::{
         array::{
-            Array, ArrayData, ArrayRef, BooleanArray, Float32Array, Float64Array, Int16Array,
-            Int32Array, Int64Array, Int8Array, LargeStringArray, PrimitiveArray, StringArray,
+            new_null_array, Array, ArrayData, ArrayRef, BooleanArray, Date32Array, Date64Array,
+            DecimalArray, DictionaryArray, Float32Array, Float64Array, Int16Array, Int32Array,
+            Int64Array, Int8Array, LargeStringArray, PrimitiveArray, StringArray,
             TimestampMicrosecondArray, TimestampMillisecondArray, TimestampNanosecondArray,
             TimestampSecondArray, UInt16Array, UInt32Array, UInt64Array, UInt64BufferBuilder,
             UInt8Array,
         },
         compute::take,
-        datatypes::{DataType, SchemaRef, TimeUnit, UInt64Type},
+        datatypes::{
+            ArrowDictionaryKeyType, ArrowNativeType, DataType, Int16Type, Int32Type, Int64Type,
+            Int8Type, SchemaRef, TimeUnit, UInt16Type, UInt32Type, UInt64Type, UInt8Type,
+        },
     },
     physical_plan::{
         expressions::Column,
@@ -28,6 +33,7 @@ use datafusion::{
 use futures_core::Stream;
 use futures_util::StreamExt;
 use hashbrown::raw::RawTable;
+use parking_lot::Mutex;
 use smallvec::{smallvec, SmallVec};
 use std::{
     fmt::Debug,
@@ -36,8 +42,11 @@ use std::{
     task::{Context, Poll},
 };
 
-type JoinedTable = RawTable<(u64, SmallVec<[u64; 1]>)>;
+pub(crate) type JoinedTable = RawTable<(u64, SmallVec<[u64; 1]>)>;
 
+/// Joins one partition of the outer and inner queue in memory. Null-aware
+/// anti join only excludes left rows with null key here, the cases depending
+/// on the whole right side are handled by the caller
 #[derive(Debug)]
 pub struct HashJoinOp {
     on_left: Vec<Column>,
@@ -50,6 +59,8 @@ pub struct HashJoinOp {
     schema: SchemaRef,
     join_column_indices: Vec<ColumnIndex>,
     random_state: RandomState,
+    join_type: JoinType,
+    left_matches: Option<Arc<Mutex<Vec<bool>>>>,
 }
 
 impl Operator for HashJoinOp {
@@ -81,11 +92,16 @@ impl Operator for HashJoinOp {
             outer_stream,
             on_left: self.on_left.clone(),
             on_right: self.on_right.clone(),
+            inner_matched: vec![false; inner_data.num_rows()],
             inner_data,
             inner_table,
             hash_state: self.random_state.clone(),
             join_column_indices: self.join_column_indices.clone(),
             schema: self.schema.clone(),
+            join_type: self.join_type,
+            left_matches: self.left_matches.clone(),
+            outer_offset: 0,
+            finished: false,
         }));
     }
 }
@@ -96,9 +112,15 @@ pub struct HashJoiner {
     on_right: Vec<Column>,
     inner_data: DataBlock,
     inner_table: JoinedTable,
+    inner_matched: Vec<bool>,
     hash_state: RandomState,
     join_column_indices: Vec<ColumnIndex>,
     schema: SchemaRef,
+    join_type: JoinType,
+    left_matches: Option<Arc<Mutex<Vec<bool>>>>,
+    /// Number of outer rows probed so far
+    outer_offset: usize,
+    finished: bool,
 }
 unsafe impl Sync for HashJoiner {}
 unsafe impl Send for HashJoiner {}
@@ -114,77 +136,126 @@ impl Iterator for HashJoiner {
 
     fn next(&mut self) -> Option<Self::Item> {
         match self.outer_stream.next() {
-            Some(batch_ret) => {
-                match batch_ret {
-                    Ok(outer_batch) => {
-                        let outer_joined_values = self
-                            .on_left
-                            .iter()
-                            .map(|c| outer_batch.column(c.index()).clone())
-                            .collect::<Vec<_>>();
-                        let inner_join_values = self
-                            .on_right
-                            .iter()
-                            .map(|c| self.inner_data.column(c.index()).clone())
-                            .collect::<Vec<_>>();
-                        let mut hash_buffer = vec![0; outer_joined_values[0].len()];
-                        let outer_hash_values =
-                            create_hashes(&outer_joined_values, &self.hash_state, &mut hash_buffer)
-                                .unwrap();
-                        let mut outer_indices = UInt64BufferBuilder::new(0);
-                        let mut inner_indices = UInt64BufferBuilder::new(0);
+            Some(Ok(outer_batch)) => Some(self.probe(outer_batch)),
+            Some(Err(err)) => Some(Err(err)),
+            None => {
+                // every outer row has been probed, the inner rows that are still
+                // unmatched will never be
+                if self.finished || !self.join_type.emits_unmatched_right() {
+                    return None;
+                }
+                self.finished = true;
+                Some(self.unmatched_inner())
+            }
+        }
+    }
+}
 
-                        for (outer_row, hash_value) in outer_hash_values.iter().enumerate() {
-                            if let Some((_, indices)) = self
-                                .inner_table
-                                .get(*hash_value, |(hash, _)| *hash_value == *hash)
-                            {
-                                // equal hash, need to check real value
-                                for inner_row in indices {
-                                    if equal_rows(
-                                        *inner_row as usize,
-                                        outer_row,
-                                        &inner_join_values,
-                                        &outer_joined_values,
-                                        false,
-                                    )
-                                    .unwrap()
-                                    {
-                                        outer_indices.append(outer_row as u64);
-                                        inner_indices.append(*inner_row as u64);
-                                    }
-                                }
-                            }
-                        }
-                        let inner = ArrayData::builder(DataType::UInt64)
-                            .len(inner_indices.len())
-                            .add_buffer(inner_indices.finish())
-                            .build()
-                            .unwrap();
-                        let inner_indices = PrimitiveArray::<UInt64Type>::from(inner);
+impl HashJoiner {
+    fn probe(&mut self, outer_batch: DataBlock) -> SqlResult<DataBlock> {
+        let outer_joined_values = HashJoinOp::make_joined_columes(&self.on_left, &outer_batch);
+        let inner_join_values = HashJoinOp::make_joined_columes(&self.on_right, &self.inner_data);
+        let mut hash_buffer = vec![0; outer_batch.num_rows()];
+        let outer_hash_values =
+            create_hashes(&outer_joined_values, &self.hash_state, &mut hash_buffer)?;
+        let mut outer_indices = UInt64BufferBuilder::new(0);
+        let mut inner_indices = UInt64BufferBuilder::new(0);
+        let mut outer_matched = vec![false; outer_batch.num_rows()];
 
-                        let outer = ArrayData::builder(DataType::UInt64)
-                            .len(outer_indices.len())
-                            .add_buffer(outer_indices.finish())
-                            .build()
-                            .unwrap();
-                        let outer_indices = PrimitiveArray::<UInt64Type>::from(outer);
-                        let next_batch = build_batch_from_indices(
-                            &self.schema,
-                            &outer_batch,
-                            &self.inner_data,
-                            outer_indices,
-                            inner_indices,
-                            &self.join_column_indices,
-                        )
-                        .unwrap();
-                        Some(Ok(next_batch))
+        for (outer_row, hash_value) in outer_hash_values.iter().enumerate() {
+            if let Some((_, indices)) = self
+                .inner_table
+                .get(*hash_value, |(hash, _)| *hash_value == *hash)
+            {
+                // equal hash, need to check real value
+                for inner_row in indices {
+                    if equal_rows(
+                        *inner_row as usize,
+                        outer_row,
+                        &inner_join_values,
+                        &outer_joined_values,
+                        false,
+                    )? {
+                        outer_indices.append(outer_row as u64);
+                        inner_indices.append(*inner_row as u64);
+                        outer_matched[outer_row] = true;
+                        self.inner_matched[*inner_row as usize] = true;
+                    }
+                }
+            }
+        }
+
+        let mut blocks = vec![];
+        if self.join_type.emits_pairs() {
+            let inner = ArrayData::builder(DataType::UInt64)
+                .len(inner_indices.len())
+                .add_buffer(inner_indices.finish())
+                .build()?;
+            let inner_indices = PrimitiveArray::<UInt64Type>::from(inner);
+
+            let outer = ArrayData::builder(DataType::UInt64)
+                .len(outer_indices.len())
+                .add_buffer(outer_indices.finish())
+                .build()?;
+            let outer_indices = PrimitiveArray::<UInt64Type>::from(outer);
+            blocks.push(build_batch_from_indices(
+                &self.schema,
+                &outer_batch,
+                &self.inner_data,
+                outer_indices,
+                inner_indices,
+                &self.join_column_indices,
+            )?);
+        }
+
+        match &self.left_matches {
+            // the owner of left_matches returns the left rows once the outer side
+            // has been probed against every inner chunk
+            Some(left_matches) => {
+                let mut left_matches = left_matches.lock();
+                let end = self.outer_offset + outer_matched.len();
+                if left_matches.len() < end {
+                    left_matches.resize(end, false);
+                }
+                for (row, matched) in outer_matched.iter().enumerate() {
+                    if *matched {
+                        left_matches[self.outer_offset + row] = true;
                     }
-                    Err(err) => Some(Err(err)),
                 }
             }
-            None => None,
+            None => {
+                if let Some(batch) = build_left_rows(
+                    self.join_type,
+                    &self.schema,
+                    &outer_batch,
+                    &self.on_left,
+                    &outer_matched,
+                    &self.join_column_indices,
+                )? {
+                    blocks.push(batch);
+                }
+            }
         }
+        self.outer_offset += outer_matched.len();
+
+        Ok(DataBlock::concat(&self.schema, &blocks)?)
+    }
+
+    fn unmatched_inner(&self) -> SqlResult<DataBlock> {
+        let rows = self
+            .inner_matched
+            .iter()
+            .enumerate()
+            .filter(|(_, matched)| !**matched)
+            .map(|(row, _)| row as u64)
+            .collect::<Vec<_>>();
+        build_batch_with_null_side(
+            &self.schema,
+            &self.inner_data,
+            &rows.into(),
+            &self.join_column_indices,
+            false,
+        )
     }
 }
 
@@ -199,6 +270,7 @@ impl HashJoinOp {
         random_state: RandomState,
         batch_size: usize,
         join_column_indices: Vec<ColumnIndex>,
+        join_type: JoinType,
     ) -> Self {
         HashJoinOp {
             on_left,
@@ -211,9 +283,20 @@ impl HashJoinOp {
             built: false,
             join_column_indices,
             schema,
+            join_type,
+            left_matches: None,
         }
     }
 
+    /// Instead of returning left rows on their own (unmatched rows for outer and
+    /// anti join, matched rows for semi join), record which outer rows have matched
+    /// into left_matches, indexed by their position in the outer stream. Used when
+    /// the same outer rows are probed against multiple inner chunks
+    pub fn with_left_matches(mut self, left_matches: Arc<Mutex<Vec<bool>>>) -> Self {
+        self.left_matches = Some(left_matches);
+        self
+    }
+
     fn make_joined_columes(joined_on: &[Column], data: &DataBlock) -> Vec<ArrayRef> {
         joined_on
             .iter()
@@ -278,7 +361,7 @@ impl HashJoinOp {
 /// inner_indices:  [0,1,2] (since 2,3,4 match)
 /// column_indices: [(0,left),(1,left),(2,left),(0,right),(1,right)]:
 /// => Result:  
-fn build_batch_from_indices(
+pub(super) fn build_batch_from_indices(
     schema: &SchemaRef,
     outer: &DataBlock,
     inner: &DataBlock,
@@ -303,6 +386,56 @@ fn build_batch_from_indices(
     Ok(DataBlock::try_new(schema.clone(), columns)?)
 }
 
+/// Like [build_batch_from_indices], but rows are only taken from one side,
+/// columns of the other side are padded with nulls
+fn build_batch_with_null_side(
+    schema: &SchemaRef,
+    batch: &DataBlock,
+    indices: &UInt64Array,
+    column_indices: &[ColumnIndex],
+    from_left: bool,
+) -> SqlResult<DataBlock> {
+    let mut columns: Vec<Arc<dyn Array>> = Vec::with_capacity(schema.fields().len());
+    for (col_index, field) in column_indices.iter().zip(schema.fields()) {
+        let taken = match col_index.side {
+            JoinSide::Left => from_left,
+            JoinSide::Right => !from_left,
+        };
+        if taken {
+            columns.push(take(batch.column(col_index.index).as_ref(), indices, None)?);
+        } else {
+            columns.push(new_null_array(field.data_type(), indices.len()));
+        }
+    }
+    Ok(DataBlock::try_new(schema.clone(), columns)?)
+}
+
+/// Left rows that are returned on their own given whether they have matched any
+/// inner row, right columns are padded with nulls if the output has them
+pub(super) fn build_left_rows(
+    join_type: JoinType,
+    schema: &SchemaRef,
+    outer: &DataBlock,
+    on_left: &[Column],
+    matched: &[bool],
+    column_indices: &[ColumnIndex],
+) -> SqlResult<Option<DataBlock>> {
+    let keys = HashJoinOp::make_joined_columes(on_left, outer);
+    let rows = matched
+        .iter()
+        .enumerate()
+        .filter(|(row, matched)| {
+            let null_key = keys.iter().any(|key| key.is_null(*row));
+            join_type.keeps_left_row(**matched, null_key)
+        })
+        .map(|(row, _)| row as u64)
+        .collect::<Vec<_>>();
+    if rows.is_empty() {
+        return Ok(None);
+    }
+    build_batch_with_null_side(schema, outer, &rows.into(), column_indices, true).map(Some)
+}
+
 macro_rules! equal_rows_elem {
     ($array_type:ident, $l: ident, $r: ident, $left: ident, $right: ident, $null_equals_null: ident) => {{
         let left_array = $l.as_any().downcast_ref::<$array_type>().unwrap();
@@ -316,7 +449,7 @@ macro_rules! equal_rows_elem {
     }};
 }
 
-fn equal_rows(
+pub(crate) fn equal_rows(
     left: usize,
     right: usize,
     left_arrays: &[ArrayRef],
@@ -362,7 +495,16 @@ fn equal_rows(
             DataType::Float64 => {
                 equal_rows_elem!(Float64Array, l, r, left, right, null_equals_null)
             }
-            DataType::Timestamp(time_unit, None) => match time_unit {
+            DataType::Date32 => {
+                equal_rows_elem!(Date32Array, l, r, left, right, null_equals_null)
+            }
+            DataType::Date64 => {
+                equal_rows_elem!(Date64Array, l, r, left, right, null_equals_null)
+            }
+            DataType::Decimal(_, _) => {
+                equal_rows_elem!(DecimalArray, l, r, left, right, null_equals_null)
+            }
+            DataType::Timestamp(time_unit, _) => match time_unit {
                 TimeUnit::Second => {
                     equal_rows_elem!(TimestampSecondArray, l, r, left, right, null_equals_null)
                 }
@@ -403,6 +545,42 @@ fn equal_rows(
             DataType::LargeUtf8 => {
                 equal_rows_elem!(LargeStringArray, l, r, left, right, null_equals_null)
             }
+            DataType::Dictionary(key_type, _) => {
+                let ret = match **key_type {
+                    DataType::Int8 => {
+                        equal_dict_rows::<Int8Type>(l, r, left, right, null_equals_null)
+                    }
+                    DataType::Int16 => {
+                        equal_dict_rows::<Int16Type>(l, r, left, right, null_equals_null)
+                    }
+                    DataType::Int32 => {
+                        equal_dict_rows::<Int32Type>(l, r, left, right, null_equals_null)
+                    }
+                    DataType::Int64 => {
+                        equal_dict_rows::<Int64Type>(l, r, left, right, null_equals_null)
+                    }
+                    DataType::UInt8 => {
+                        equal_dict_rows::<UInt8Type>(l, r, left, right, null_equals_null)
+                    }
+                    DataType::UInt16 => {
+                        equal_dict_rows::<UInt16Type>(l, r, left, right, null_equals_null)
+                    }
+                    DataType::UInt32 => {
+                        equal_dict_rows::<UInt32Type>(l, r, left, right, null_equals_null)
+                    }
+                    DataType::UInt64 => {
+                        equal_dict_rows::<UInt64Type>(l, r, left, right, null_equals_null)
+                    }
+                    _ => Err(SqlError::Value(format!(
+                        "unsupported dictionary key type {}",
+                        l.data_type()
+                    ))),
+                };
+                ret.unwrap_or_else(|e| {
+                    err = Some(Err(e));
+                    false
+                })
+            }
             _ => {
                 // This is internal because we should have caught this before.
                 err = Some(Err(SqlError::Value("something wrong".to_string())));
@@ -412,3 +590,33 @@ fn equal_rows(
 
     err.unwrap_or(Ok(res))
 }
+
+/// Dictionary rows are compared by their values, both sides may have different dictionaries
+fn equal_dict_rows<K: ArrowDictionaryKeyType>(
+    l: &ArrayRef,
+    r: &ArrayRef,
+    left: usize,
+    right: usize,
+    null_equals_null: bool,
+) -> SqlResult<bool> {
+    let left_array = l.as_any().downcast_ref::<DictionaryArray<K>>().unwrap();
+    let right_array = r.as_any().downcast_ref::<DictionaryArray<K>>().unwrap();
+    match (left_array.is_null(left), right_array.is_null(right)) {
+        (false, false) => {
+            let key = |array: &DictionaryArray<K>, row: usize| {
+                let key = array.keys().value(row);
+                key.to_usize()
+                    .ok_or_else(|| SqlError::Value(format!("invalid dictionary key {:?}", key)))
+            };
+            equal_rows(
+                key(left_array, left)?,
+                key(right_array, right)?,
+                &[left_array.values().clone()],
+                &[right_array.values().clone()],
+                null_equals_null,
+            )
+        }
+        (true, true) => Ok(null_equals_null),
+        _ => Ok(false),
+    }
+}
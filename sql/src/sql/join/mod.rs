@@ -1,4 +1,56 @@
 pub mod grace;
-mod hash_util;
-mod inmem;
+pub mod hash_util;
+pub(crate) mod inmem;
+pub mod nested_loop;
 pub mod queue;
+
+/// Left is the outer (probe) side, right is the inner (build) side of a join
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum JoinType {
+    Inner,
+    LeftOuter,
+    RightOuter,
+    FullOuter,
+    /// Left rows having at least one match, each returned once
+    LeftSemi,
+    /// Left rows having no match
+    LeftAnti,
+    /// Semantic of `NOT IN`: if any right key is null the output is empty,
+    /// left rows with null key are only returned if the right side is empty
+    NullAwareLeftAnti,
+}
+
+impl JoinType {
+    /// Output only contains columns of the left side
+    pub fn left_only(&self) -> bool {
+        matches!(
+            self,
+            JoinType::LeftSemi | JoinType::LeftAnti | JoinType::NullAwareLeftAnti
+        )
+    }
+
+    /// Output contains a row for each matched pair
+    fn emits_pairs(&self) -> bool {
+        !self.left_only()
+    }
+
+    /// Some left rows are returned on their own, see [JoinType::keeps_left_row]
+    fn returns_left_rows(&self) -> bool {
+        !matches!(self, JoinType::Inner | JoinType::RightOuter)
+    }
+
+    /// Unmatched right rows are padded with nulls and returned
+    fn emits_unmatched_right(&self) -> bool {
+        matches!(self, JoinType::RightOuter | JoinType::FullOuter)
+    }
+
+    /// Whether a left row is returned on its own, regardless of its matched pairs
+    fn keeps_left_row(&self, matched: bool, null_key: bool) -> bool {
+        match self {
+            JoinType::Inner | JoinType::RightOuter => false,
+            JoinType::LeftOuter | JoinType::FullOuter | JoinType::LeftAnti => !matched,
+            JoinType::LeftSemi => matched,
+            JoinType::NullAwareLeftAnti => !matched && !null_key,
+        }
+    }
+}
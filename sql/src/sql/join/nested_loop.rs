@@ -0,0 +1,418 @@
+use super::{
+    grace::build_join_schema,
+    inmem::{build_batch_from_indices, build_left_rows},
+    JoinType,
+};
+use crate::sql::{
+    exe::{BoxedDataIter, Executor, Operator, PlanType, SchemaDataIter},
+    expr::Expr,
+    memory::batch_memory_size,
+    util::GeneratorIteratorAdapter,
+    DataBlock, Error, ExecutionContext, SqlResult,
+};
+use datafusion::{
+    arrow::{
+        array::{Array, BooleanArray, UInt64Array},
+        compute::filter_record_batch,
+        datatypes::SchemaRef,
+    },
+    physical_plan::join_utils::ColumnIndex,
+};
+use std::sync::Arc;
+
+/// Joins every pair of rows for which predicate is true, columns of the predicate
+/// are indexed in the joined schema. Without predicate every pair is returned
+pub struct NestedLoopJoinPlan {
+    pub predicate: Option<Expr>,
+    pub left_plan: Box<PlanType>,
+    pub right_plan: Box<PlanType>,
+    pub join_type: JoinType,
+}
+
+#[derive(Clone, Debug)]
+struct Config {
+    /// Pairs evaluated at once are at most batch_size, unless the inner batch is larger
+    batch_size: usize,
+}
+
+/// Block nested loop join, only inner and left outer joins are supported.
+///
+/// The outer (left) side is read in chunks as large as the memory pool of the
+/// [ExecutionContext] allows, the inner side is scanned once per chunk. If there
+/// is more than one chunk, the inner side is spilled to a queue while it is
+/// scanned so it can be scanned again
+#[derive(Debug)]
+pub struct NestedLoopJoinOp {
+    config: Config,
+    predicate: Option<Expr>,
+    join_type: JoinType,
+    left: Box<dyn Operator>,
+    right: Box<dyn Operator>,
+    schema: SchemaRef,
+    column_indices: Vec<ColumnIndex>,
+}
+
+impl NestedLoopJoinOp {
+    pub fn from_plan(plan: NestedLoopJoinPlan, ctx: ExecutionContext) -> SqlResult<Self> {
+        let left = Executor::create_from_subplan_operator(*plan.left_plan, ctx.clone())?;
+        let right = Executor::create_from_subplan_operator(*plan.right_plan, ctx)?;
+        let config = Config { batch_size: 1024 };
+        Self::new(config, left, right, plan.predicate, plan.join_type)
+    }
+
+    fn new(
+        config: Config,
+        left: Box<dyn Operator>,
+        right: Box<dyn Operator>,
+        predicate: Option<Expr>,
+        join_type: JoinType,
+    ) -> SqlResult<Self> {
+        if !matches!(join_type, JoinType::Inner | JoinType::LeftOuter) {
+            return Err(Error::Value(format!(
+                "nested loop join does not support {:?} join",
+                join_type
+            )));
+        }
+        let (schema, column_indices) =
+            build_join_schema(&left.schema(), &right.schema(), join_type);
+        Ok(NestedLoopJoinOp {
+            config,
+            predicate,
+            join_type,
+            left,
+            right,
+            schema: Arc::new(schema),
+            column_indices,
+        })
+    }
+}
+
+/// Joins one outer chunk with the inner batches
+struct ChunkJoiner {
+    batch_size: usize,
+    schema: SchemaRef,
+    column_indices: Vec<ColumnIndex>,
+    predicate: Option<Expr>,
+    outer: DataBlock,
+    matched: Vec<bool>,
+}
+
+impl ChunkJoiner {
+    /// Pairs of the chunk and the inner batch satisfying the predicate, evaluated
+    /// for a few outer rows at a time
+    fn join(&mut self, inner: &DataBlock) -> SqlResult<Vec<DataBlock>> {
+        let inner_rows = inner.num_rows();
+        if inner_rows == 0 {
+            return Ok(vec![]);
+        }
+        let step = (self.batch_size / inner_rows).max(1);
+        let mut joined = vec![];
+        let mut start = 0;
+        while start < self.outer.num_rows() {
+            let end = (start + step).min(self.outer.num_rows());
+            let outer_indices = (start..end)
+                .flat_map(|row| std::iter::repeat(row as u64).take(inner_rows))
+                .collect::<Vec<_>>();
+            let inner_indices = (start..end)
+                .flat_map(|_| 0..inner_rows as u64)
+                .collect::<Vec<_>>();
+            let pairs = build_batch_from_indices(
+                &self.schema,
+                &self.outer,
+                inner,
+                UInt64Array::from(outer_indices.clone()),
+                UInt64Array::from(inner_indices),
+                &self.column_indices,
+            )?;
+            start = end;
+
+            let pairs = match &self.predicate {
+                None => {
+                    for row in &outer_indices {
+                        self.matched[*row as usize] = true;
+                    }
+                    pairs
+                }
+                Some(predicate) => {
+                    let mask = predicate.evaluate(&pairs)?;
+                    let mask = mask
+                        .as_any()
+                        .downcast_ref::<BooleanArray>()
+                        .ok_or_else(|| {
+                            Error::Value(format!(
+                                "join predicate must be a boolean, got type {}",
+                                mask.data_type()
+                            ))
+                        })?;
+                    for (row, outer_row) in outer_indices.iter().enumerate() {
+                        if mask.is_valid(row) && mask.value(row) {
+                            self.matched[*outer_row as usize] = true;
+                        }
+                    }
+                    filter_record_batch(&pairs, mask)?
+                }
+            };
+            if pairs.num_rows() > 0 {
+                joined.push(pairs);
+            }
+        }
+        Ok(joined)
+    }
+}
+
+impl Operator for NestedLoopJoinOp {
+    fn execute_sync(&mut self, ctx: ExecutionContext) -> SqlResult<BoxedDataIter> {
+        let mut outer_stream = self.left.execute_sync(ctx.clone())?.peekable();
+        let mut inner_stream: BoxedDataIter = self.right.execute_sync(ctx.clone())?;
+        let outer_schema = self.left.schema();
+        let inner_schema = self.right.schema();
+        let config = self.config.clone();
+        let predicate = self.predicate.clone();
+        let join_type = self.join_type;
+        let schema = self.schema.clone();
+        let column_indices = self.column_indices.clone();
+        let mut reservation = ctx.new_reservation("nested loop join");
+
+        let gen = move || {
+            while outer_stream.peek().is_some() {
+                let mut chunk = vec![];
+                loop {
+                    let size = match outer_stream.peek() {
+                        Some(Ok(batch)) => batch_memory_size(batch),
+                        Some(Err(_)) => {
+                            if let Some(Err(e)) = outer_stream.next() {
+                                yield Err(e);
+                            }
+                            return;
+                        }
+                        None => break,
+                    };
+                    if !reservation.try_grow(size) {
+                        if !chunk.is_empty() {
+                            break;
+                        }
+                        // a chunk has at least one batch
+                        if let Err(e) = reservation.grow(size) {
+                            yield Err(e);
+                            return;
+                        }
+                    }
+                    if let Some(Ok(batch)) = outer_stream.next() {
+                        chunk.push(batch);
+                    }
+                }
+                let outer = match DataBlock::concat(&outer_schema, &chunk) {
+                    Ok(outer) => outer,
+                    Err(e) => {
+                        yield Err(e.into());
+                        return;
+                    }
+                };
+                drop(chunk);
+                let mut joiner = ChunkJoiner {
+                    batch_size: config.batch_size,
+                    schema: schema.clone(),
+                    column_indices: column_indices.clone(),
+                    predicate: predicate.clone(),
+                    matched: vec![false; outer.num_rows()],
+                    outer,
+                };
+
+                // the inner side is scanned again for the next chunk
+                let next_queue = match outer_stream.peek() {
+                    Some(_) => {
+                        let queue = ctx.new_queue(inner_schema.clone());
+                        let empty = DataBlock::new_empty(inner_schema.clone());
+                        if let Err(e) = queue.enqueue(0, empty) {
+                            yield Err(e);
+                            return;
+                        }
+                        Some(queue)
+                    }
+                    None => None,
+                };
+                for inner in inner_stream {
+                    let inner = match inner {
+                        Ok(inner) => inner,
+                        Err(e) => {
+                            yield Err(e);
+                            return;
+                        }
+                    };
+                    if let Some(queue) = &next_queue {
+                        if let Err(e) = queue.enqueue(0, inner.clone()) {
+                            yield Err(e);
+                            return;
+                        }
+                    }
+                    match joiner.join(&inner) {
+                        Ok(joined) => {
+                            for batch in joined {
+                                yield Ok(batch);
+                            }
+                        }
+                        Err(e) => {
+                            yield Err(e);
+                            return;
+                        }
+                    }
+                }
+                inner_stream = match &next_queue {
+                    Some(queue) => match queue.dequeue(0, config.batch_size) {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            yield Err(e);
+                            return;
+                        }
+                    },
+                    None => SchemaDataIter::new(inner_schema.clone(), Box::new(std::iter::empty())),
+                };
+
+                match build_left_rows(
+                    join_type,
+                    &schema,
+                    &joiner.outer,
+                    &[],
+                    &joiner.matched,
+                    &column_indices,
+                ) {
+                    Ok(Some(batch)) => yield Ok(batch),
+                    Ok(None) => {}
+                    Err(e) => {
+                        yield Err(e);
+                        return;
+                    }
+                }
+                reservation.free();
+            }
+        };
+        let iter = GeneratorIteratorAdapter::new(gen);
+        Ok(SchemaDataIter::new(self.schema.clone(), Box::new(iter)))
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{Config, NestedLoopJoinOp};
+    use crate::sql::{
+        exe::{ExecutionContext, Operator},
+        expr::{BinaryOperator, Expr},
+        inmem_op::InMemOp,
+        join::JoinType,
+        memory::MemoryPool,
+        util::collect,
+        DataBlock, Error,
+    };
+    use datafusion::arrow::{
+        array::Int32Array,
+        datatypes::{DataType, Field, Schema},
+    };
+    use std::sync::Arc;
+
+    /// Bytes of a batch of two rows of one non null int column
+    const TWO_INT_ROWS: usize = 8;
+
+    fn build_i32_batches(col: &str, batches: Vec<Vec<i32>>) -> Box<dyn Operator> {
+        let schema = Arc::new(Schema::new(vec![Field::new(col, DataType::Int32, false)]));
+        let batches = batches
+            .into_iter()
+            .map(|values| {
+                DataBlock::try_new(schema.clone(), vec![Arc::new(Int32Array::from(values))])
+                    .unwrap()
+            })
+            .collect();
+        Box::new(InMemOp::new(schema, batches))
+    }
+
+    fn build_op(predicate: Option<Expr>, join_type: JoinType) -> NestedLoopJoinOp {
+        let left = build_i32_batches("a", vec![vec![1, 2], vec![3, 4]]);
+        let right = build_i32_batches("b", vec![vec![2], vec![3]]);
+        NestedLoopJoinOp::new(Config { batch_size: 2 }, left, right, predicate, join_type).unwrap()
+    }
+
+    fn a_less_than_b() -> Option<Expr> {
+        Some(Expr::binary(
+            Expr::column("a", 0),
+            BinaryOperator::Lt,
+            Expr::column("b", 1),
+        ))
+    }
+
+    #[test]
+    fn test_nested_loop_range_join() {
+        for pool in [MemoryPool::unbounded(), MemoryPool::new(TWO_INT_ROWS)] {
+            let ctx = ExecutionContext::new_for_test().with_memory_pool(Arc::new(pool));
+            let mut op = build_op(a_less_than_b(), JoinType::Inner);
+            let batches = collect(op.execute_sync(ctx.clone()).unwrap()).unwrap();
+            let expected = [
+                "+---+---+",
+                "| a | b |",
+                "+---+---+",
+                "| 1 | 2 |",
+                "| 1 | 3 |",
+                "| 2 | 3 |",
+                "+---+---+",
+            ];
+            crate::assert_batches_sorted_eq!(expected, &batches);
+
+            let mut op = build_op(a_less_than_b(), JoinType::LeftOuter);
+            let batches = collect(op.execute_sync(ctx).unwrap()).unwrap();
+            let expected = [
+                "+---+---+",
+                "| a | b |",
+                "+---+---+",
+                "| 1 | 2 |",
+                "| 1 | 3 |",
+                "| 2 | 3 |",
+                "| 3 |   |",
+                "| 4 |   |",
+                "+---+---+",
+            ];
+            crate::assert_batches_sorted_eq!(expected, &batches);
+        }
+    }
+
+    #[test]
+    fn test_nested_loop_cross_join() {
+        let ctx = ExecutionContext::new_for_test()
+            .with_memory_pool(Arc::new(MemoryPool::new(TWO_INT_ROWS)));
+        let mut op = build_op(None, JoinType::Inner);
+        let batches = collect(op.execute_sync(ctx).unwrap()).unwrap();
+        let expected = [
+            "+---+---+",
+            "| a | b |",
+            "+---+---+",
+            "| 1 | 2 |",
+            "| 1 | 3 |",
+            "| 2 | 2 |",
+            "| 2 | 3 |",
+            "| 3 | 2 |",
+            "| 3 | 3 |",
+            "| 4 | 2 |",
+            "| 4 | 3 |",
+            "+---+---+",
+        ];
+        crate::assert_batches_sorted_eq!(expected, &batches);
+    }
+
+    #[test]
+    fn test_nested_loop_join_errors() {
+        let left = build_i32_batches("a", vec![vec![1]]);
+        let right = build_i32_batches("b", vec![vec![1]]);
+        let config = Config { batch_size: 2 };
+        assert!(matches!(
+            NestedLoopJoinOp::new(config, left, right, None, JoinType::RightOuter),
+            Err(Error::Value(_))
+        ));
+
+        let ctx = ExecutionContext::new_for_test()
+            .with_memory_pool(Arc::new(MemoryPool::new(TWO_INT_ROWS / 2)));
+        let mut op = build_op(a_less_than_b(), JoinType::Inner);
+        let ret = collect(op.execute_sync(ctx).unwrap());
+        assert!(matches!(ret, Err(Error::ResourceExhausted(_))));
+    }
+}
@@ -3,14 +3,30 @@ use crate::sql::{
     join::grace::PartitionedQueue,
     DataBlock, SqlResult,
 };
-use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::{
+    datatypes::SchemaRef,
+    ipc::{reader::StreamReader, writer::StreamWriter},
+};
 use parking_lot::Mutex;
 use std::{
     cell::RefCell,
-    collections::{HashMap, VecDeque},
-    sync::Arc,
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    fmt,
+    fs::File,
+    io::{BufReader, Seek, SeekFrom},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
 };
 
+/// Creates the queues operators spill their partitions into, selected through
+/// [crate::sql::exe::ExecutionContext::with_queue_allocator]
+pub trait QueueAllocator: Send + Sync {
+    fn alloc(&self, schema: SchemaRef) -> Arc<dyn PartitionedQueue>;
+}
+
 #[derive(Debug)]
 pub struct Inmem {
     inner: RefCell<HashMap<usize, VecDeque<DataBlock>>>,
@@ -38,6 +54,12 @@ impl MemoryAllocator {
         self.inner.lock().alloc(schema)
     }
 }
+impl QueueAllocator for MemoryAllocator {
+    fn alloc(&self, schema: SchemaRef) -> Arc<dyn PartitionedQueue> {
+        self.inner.lock().alloc(schema)
+    }
+}
+
 impl RawMemoryAllocator {
     pub fn new() -> Self {
         RawMemoryAllocator {
@@ -127,8 +149,225 @@ impl PartitionedQueue for Inmem {
             None => Err(format!("partition {} does not exist", partition_idx))?,
             Some(exist) => {
                 let fut = DequeueFut { all: exist };
-                Ok(SchemaDataIter::new(self.schema.clone(), Box::new(fut)))
+                let batches = SizedBatches::new(self.schema.clone(), Box::new(fut), size);
+                Ok(SchemaDataIter::new(self.schema.clone(), Box::new(batches)))
             }
         }
     }
 }
+
+/// Allocates [Disk] queues whose files are created inside dir
+pub struct DiskAllocator {
+    dir: PathBuf,
+    cur_id: AtomicUsize,
+}
+
+impl DiskAllocator {
+    pub fn new(dir: PathBuf) -> Self {
+        DiskAllocator {
+            dir,
+            cur_id: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl QueueAllocator for DiskAllocator {
+    fn alloc(&self, schema: SchemaRef) -> Arc<dyn PartitionedQueue> {
+        let id = self.cur_id.fetch_add(1, Ordering::SeqCst);
+        Arc::new(Disk::new(id, schema, self.dir.clone()))
+    }
+}
+
+/// Each partition is appended to its own anonymous temp file in the Arrow IPC
+/// stream format, the file is removed by the os once the partition is dequeued
+/// and its reader dropped
+pub struct Disk {
+    partitions: Mutex<HashMap<usize, StreamWriter<File>>>,
+    id: usize,
+    schema: SchemaRef,
+    dir: PathBuf,
+}
+
+impl fmt::Debug for Disk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Disk")
+            .field("id", &self.id)
+            .field("dir", &self.dir)
+            .field("partitions", &self.partitions.lock().keys())
+            .finish()
+    }
+}
+
+impl Disk {
+    pub fn new(id: usize, schema: SchemaRef, dir: PathBuf) -> Self {
+        Disk {
+            partitions: Mutex::new(HashMap::new()),
+            id,
+            schema,
+            dir,
+        }
+    }
+
+    /// Finishes writing the partition and returns a reader positioned at its
+    /// first batch, the partition no longer exists after this call
+    fn open_reader(&self, partition_idx: usize) -> SqlResult<StreamReader<BufReader<File>>> {
+        let writer = match self.partitions.lock().remove(&partition_idx) {
+            None => Err(format!("partition {} does not exist", partition_idx))?,
+            Some(writer) => writer,
+        };
+        let mut file = writer.into_inner()?;
+        file.seek(SeekFrom::Start(0))
+            .map_err(|e| format!("failed seeking spilled partition: {}", e))?;
+        Ok(StreamReader::try_new(BufReader::new(file))?)
+    }
+}
+
+impl PartitionedQueue for Disk {
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn enqueue(&self, partition_idx: usize, data: DataBlock) -> SqlResult<()> {
+        let mut partitions = self.partitions.lock();
+        let writer = match partitions.entry(partition_idx) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let file = tempfile::tempfile_in(&self.dir)
+                    .map_err(|e| format!("failed creating spill file: {}", e))?;
+                entry.insert(StreamWriter::try_new(file, &self.schema)?)
+            }
+        };
+        writer.write(&data)?;
+        Ok(())
+    }
+
+    fn dequeue_all(&self, partition_idx: usize) -> SqlResult<DataBlock> {
+        let batches = self
+            .open_reader(partition_idx)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(DataBlock::concat(&self.schema, &batches)?)
+    }
+
+    /// Batches are read from the file only when the returned iterator is polled
+    fn dequeue(&self, partition_idx: usize, size: usize) -> SqlResult<BoxedDataIter> {
+        let reader = self
+            .open_reader(partition_idx)?
+            .map(|batch| batch.map_err(|e| e.into()));
+        let batches = SizedBatches::new(self.schema.clone(), Box::new(reader), size);
+        Ok(SchemaDataIter::new(self.schema.clone(), Box::new(batches)))
+    }
+}
+
+/// Re-chunks a stream of batches into batches of size rows, except the last one
+struct SizedBatches {
+    inner: Box<dyn Iterator<Item = SqlResult<DataBlock>>>,
+    pending: Option<DataBlock>,
+    schema: SchemaRef,
+    size: usize,
+}
+
+impl SizedBatches {
+    fn new(
+        schema: SchemaRef,
+        inner: Box<dyn Iterator<Item = SqlResult<DataBlock>>>,
+        size: usize,
+    ) -> Self {
+        SizedBatches {
+            inner,
+            pending: None,
+            schema,
+            size: size.max(1),
+        }
+    }
+}
+
+impl Iterator for SizedBatches {
+    type Item = SqlResult<DataBlock>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut parts = vec![];
+        let mut rows = 0;
+        while rows < self.size {
+            let batch = match self.pending.take() {
+                Some(batch) => batch,
+                None => match self.inner.next() {
+                    None => break,
+                    Some(Ok(batch)) => batch,
+                    Some(Err(e)) => return Some(Err(e)),
+                },
+            };
+            let needed = self.size - rows;
+            if batch.num_rows() > needed {
+                self.pending = Some(batch.slice(needed, batch.num_rows() - needed));
+                parts.push(batch.slice(0, needed));
+                rows += needed;
+            } else {
+                rows += batch.num_rows();
+                parts.push(batch);
+            }
+        }
+        if parts.is_empty() {
+            return None;
+        }
+        Some(DataBlock::concat(&self.schema, &parts).map_err(|e| e.into()))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{DiskAllocator, MemoryAllocator, QueueAllocator};
+    use crate::sql::{util::collect, DataBlock};
+    use datafusion::arrow::{
+        array::{Array, Int32Array},
+        datatypes::{DataType, Field, Schema, SchemaRef},
+    };
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    fn build_batch(schema: &SchemaRef, values: Vec<i32>) -> DataBlock {
+        DataBlock::try_new(schema.clone(), vec![Arc::new(Int32Array::from(values))]).unwrap()
+    }
+
+    fn values(batch: &DataBlock) -> Vec<i32> {
+        let col = batch
+            .column(0)
+            .as_any()
+            .downcast_ref::<Int32Array>()
+            .unwrap();
+        (0..col.len()).map(|i| col.value(i)).collect()
+    }
+
+    fn check_queue(allocator: &dyn QueueAllocator) {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let queue = allocator.alloc(schema.clone());
+        queue
+            .enqueue(0, build_batch(&schema, vec![1, 2, 3]))
+            .unwrap();
+        queue.enqueue(1, build_batch(&schema, vec![10])).unwrap();
+        queue.enqueue(0, build_batch(&schema, vec![])).unwrap();
+        queue
+            .enqueue(0, build_batch(&schema, vec![4, 5, 6, 7]))
+            .unwrap();
+
+        let batches = collect(queue.dequeue(0, 2).unwrap()).unwrap();
+        let sizes = batches.iter().map(|b| b.num_rows()).collect::<Vec<_>>();
+        assert_eq!(vec![2, 2, 2, 1], sizes);
+        let all = batches.iter().flat_map(values).collect::<Vec<_>>();
+        assert_eq!(vec![1, 2, 3, 4, 5, 6, 7], all);
+        assert!(queue.dequeue(0, 2).is_err());
+
+        assert_eq!(vec![10], values(&queue.dequeue_all(1).unwrap()));
+        assert!(queue.dequeue(2, 2).is_err());
+    }
+
+    #[test]
+    fn test_inmem_queue() {
+        check_queue(&MemoryAllocator::new());
+    }
+
+    #[test]
+    fn test_disk_queue() {
+        let dir = TempDir::new().unwrap();
+        check_queue(&DiskAllocator::new(dir.path().to_path_buf()));
+    }
+}
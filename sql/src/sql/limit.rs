@@ -0,0 +1,153 @@
+use crate::sql::{
+    exe::{BoxedDataIter, Executor, Operator, PlanType, SchemaDataIter},
+    util::GeneratorIteratorAdapter,
+    ExecutionContext, SqlResult,
+};
+use datafusion::arrow::datatypes::SchemaRef;
+
+/// Skips the first offset rows of the child and returns at most limit of the rest
+pub struct LimitPlan {
+    pub limit: Option<usize>,
+    pub offset: usize,
+    pub source_plan: Box<PlanType>,
+}
+
+/// Batches are sliced, the child is not pulled anymore once limit rows are returned
+#[derive(Debug)]
+pub struct Limit {
+    limit: Option<usize>,
+    offset: usize,
+    input: Box<dyn Operator>,
+}
+
+impl Limit {
+    pub fn from_plan(plan: LimitPlan, ctx: ExecutionContext) -> SqlResult<Self> {
+        let input = Executor::create_from_subplan_operator(*plan.source_plan, ctx)?;
+        Ok(Self::new(input, plan.limit, plan.offset))
+    }
+
+    pub fn new(input: Box<dyn Operator>, limit: Option<usize>, offset: usize) -> Self {
+        Limit {
+            limit,
+            offset,
+            input,
+        }
+    }
+}
+
+impl Operator for Limit {
+    fn execute_sync(&mut self, ctx: ExecutionContext) -> SqlResult<BoxedDataIter> {
+        let mut input = self.input.execute_sync(ctx)?;
+        let mut remaining = self.limit;
+        let mut skip = self.offset;
+        let gen = move || {
+            while remaining != Some(0) {
+                let batch = match input.next() {
+                    None => return,
+                    Some(Ok(batch)) => batch,
+                    Some(Err(e)) => {
+                        yield Err(e);
+                        return;
+                    }
+                };
+                let rows = batch.num_rows();
+                if skip >= rows {
+                    skip -= rows;
+                    continue;
+                }
+                let mut len = rows - skip;
+                if let Some(remaining) = remaining.as_mut() {
+                    len = len.min(*remaining);
+                    *remaining -= len;
+                }
+                yield Ok(batch.slice(skip, len));
+                skip = 0;
+            }
+        };
+        let iter = GeneratorIteratorAdapter::new(gen);
+        Ok(SchemaDataIter::new(self.schema(), Box::new(iter)))
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.input.schema()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::Limit;
+    use crate::sql::{
+        exe::{BoxedDataIter, Operator, SchemaDataIter},
+        inmem_op::InMemOp,
+        util::collect,
+        DataBlock, ExecutionContext, SqlResult,
+    };
+    use datafusion::arrow::{
+        array::Int32Array,
+        datatypes::{DataType, Field, Schema, SchemaRef},
+    };
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    /// Counts the batches pulled from the wrapped operator
+    #[derive(Debug)]
+    struct CountingOp {
+        inner: InMemOp,
+        pulled: Arc<AtomicUsize>,
+    }
+
+    impl Operator for CountingOp {
+        fn execute_sync(&mut self, ctx: ExecutionContext) -> SqlResult<BoxedDataIter> {
+            let pulled = self.pulled.clone();
+            let stream = self.inner.execute_sync(ctx)?.inspect(move |_| {
+                pulled.fetch_add(1, Ordering::SeqCst);
+            });
+            Ok(SchemaDataIter::new(self.schema(), Box::new(stream)))
+        }
+
+        fn schema(&self) -> SchemaRef {
+            self.inner.schema()
+        }
+    }
+
+    fn run(limit: Option<usize>, offset: usize) -> (Vec<i32>, usize) {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let batches = [vec![1, 2, 3], vec![4, 5], vec![6, 7, 8]]
+            .into_iter()
+            .map(|values| {
+                DataBlock::try_new(schema.clone(), vec![Arc::new(Int32Array::from(values))])
+                    .unwrap()
+            })
+            .collect();
+        let pulled = Arc::new(AtomicUsize::new(0));
+        let input = CountingOp {
+            inner: InMemOp::new(schema, batches),
+            pulled: pulled.clone(),
+        };
+        let mut op = Limit::new(Box::new(input), limit, offset);
+        let batches = collect(op.execute_sync(ExecutionContext::new_for_test()).unwrap()).unwrap();
+        let values = batches
+            .iter()
+            .flat_map(|batch| {
+                let column = batch
+                    .column(0)
+                    .as_any()
+                    .downcast_ref::<Int32Array>()
+                    .unwrap();
+                column.values().to_vec()
+            })
+            .collect();
+        (values, pulled.load(Ordering::SeqCst))
+    }
+
+    #[test]
+    fn test_limit() {
+        assert_eq!((vec![1, 2], 1), run(Some(2), 0));
+        assert_eq!((vec![3, 4, 5], 2), run(Some(3), 2));
+        assert_eq!((vec![5, 6, 7, 8], 3), run(None, 4));
+        assert_eq!((vec![], 0), run(Some(0), 1));
+        assert_eq!((vec![], 3), run(Some(2), 10));
+    }
+}
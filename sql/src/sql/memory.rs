@@ -0,0 +1,179 @@
+use crate::sql::{DataBlock, Error, SqlResult};
+use datafusion::arrow::array::ArrayData;
+use parking_lot::Mutex;
+use std::sync::Arc;
+
+/// Bytes that the operators of one query may hold in memory. Operators reserve from
+/// the pool of their [crate::sql::exe::ExecutionContext] through a [MemoryReservation]
+/// and spill once a reservation cannot grow anymore
+#[derive(Debug)]
+pub struct MemoryPool {
+    limit: usize,
+    used: Mutex<usize>,
+}
+
+impl MemoryPool {
+    pub fn new(limit: usize) -> Self {
+        MemoryPool {
+            limit,
+            used: Mutex::new(0),
+        }
+    }
+
+    pub fn unbounded() -> Self {
+        Self::new(usize::MAX)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn used(&self) -> usize {
+        *self.used.lock()
+    }
+
+    fn try_reserve(&self, bytes: usize) -> bool {
+        let mut used = self.used.lock();
+        if self.limit - *used < bytes {
+            return false;
+        }
+        *used += bytes;
+        true
+    }
+
+    fn release(&self, bytes: usize) {
+        *self.used.lock() -= bytes;
+    }
+}
+
+/// Bytes reserved by one consumer, given back to the pool when dropped
+#[derive(Debug)]
+pub struct MemoryReservation {
+    pool: Arc<MemoryPool>,
+    consumer: String,
+    size: usize,
+}
+
+impl MemoryReservation {
+    pub fn new(pool: Arc<MemoryPool>, consumer: &str) -> Self {
+        MemoryReservation {
+            pool,
+            consumer: consumer.to_string(),
+            size: 0,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns false if the pool does not have bytes left, the caller is expected
+    /// to spill and try again
+    pub fn try_grow(&mut self, bytes: usize) -> bool {
+        if !self.pool.try_reserve(bytes) {
+            return false;
+        }
+        self.size += bytes;
+        true
+    }
+
+    /// Like [MemoryReservation::try_grow], for memory that cannot be spilled
+    pub fn grow(&mut self, bytes: usize) -> SqlResult<()> {
+        if self.try_grow(bytes) {
+            return Ok(());
+        }
+        Err(Error::ResourceExhausted(format!(
+            "{} failed to reserve {} bytes, {} of {} bytes of the query memory limit are in use",
+            self.consumer,
+            bytes,
+            self.pool.used(),
+            self.pool.limit()
+        )))
+    }
+
+    pub fn shrink(&mut self, bytes: usize) {
+        let bytes = bytes.min(self.size);
+        self.pool.release(bytes);
+        self.size -= bytes;
+    }
+
+    pub fn free(&mut self) {
+        self.shrink(self.size)
+    }
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        self.free()
+    }
+}
+
+/// Bytes of the Arrow buffers referenced by the batch. Buffers are shared by slices,
+/// so a sliced batch is accounted for the whole buffers it keeps alive
+pub fn batch_memory_size(batch: &DataBlock) -> usize {
+    batch
+        .columns()
+        .iter()
+        .map(|column| array_data_size(column.data()))
+        .sum()
+}
+
+fn array_data_size(data: &ArrayData) -> usize {
+    let buffers = data.buffers().iter().map(|b| b.len()).sum::<usize>();
+    let nulls = data.null_buffer().map_or(0, |b| b.len());
+    let children = data.child_data().iter().map(array_data_size).sum::<usize>();
+    buffers + nulls + children
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{batch_memory_size, MemoryPool, MemoryReservation};
+    use crate::sql::{DataBlock, Error};
+    use datafusion::arrow::{
+        array::{Array, Int32Array, StringArray},
+        datatypes::{DataType, Field, Schema},
+    };
+    use std::sync::Arc;
+
+    #[test]
+    fn test_reservation() {
+        let pool = Arc::new(MemoryPool::new(100));
+        let mut join = MemoryReservation::new(pool.clone(), "join");
+        let mut sort = MemoryReservation::new(pool.clone(), "sort");
+        assert!(join.try_grow(60));
+        assert!(!sort.try_grow(50));
+        assert!(sort.try_grow(40));
+        assert_eq!(100, pool.used());
+
+        match join.grow(1) {
+            Err(Error::ResourceExhausted(msg)) => assert!(msg.starts_with("join failed")),
+            other => panic!("unexpected {:?}", other),
+        }
+        join.shrink(10);
+        assert_eq!(50, join.size());
+        join.grow(10).unwrap();
+
+        drop(sort);
+        assert_eq!(60, pool.used());
+        join.free();
+        assert_eq!(0, pool.used());
+    }
+
+    #[test]
+    fn test_batch_memory_size() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int32, true),
+            Field::new("b", DataType::Utf8, false),
+        ]));
+        let batch = DataBlock::try_new(
+            schema,
+            vec![
+                Arc::new(Int32Array::from(vec![Some(1), None, Some(3)])) as Arc<dyn Array>,
+                Arc::new(StringArray::from(vec!["a", "bc", "def"])) as Arc<dyn Array>,
+            ],
+        )
+        .unwrap();
+        // values and validity of a, offsets and values of b
+        assert_eq!(3 * 4 + 1 + 4 * 4 + 6, batch_memory_size(&batch));
+    }
+}
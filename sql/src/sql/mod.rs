@@ -2,16 +2,31 @@ use self::exe::ExecutionContext;
 // pub use self::schema::{ColumnInfo, TableMeta};
 use core::fmt::Debug;
 pub use datafusion::arrow::record_batch::RecordBatch;
-use datafusion::arrow::{datatypes::DataType as ArrowDataType, error::ArrowError};
+use datafusion::{
+    arrow::{datatypes::DataType as ArrowDataType, error::ArrowError},
+    error::DataFusionError,
+};
 use serde_derive::{Deserialize, Serialize};
 
+pub mod agg;
+pub mod ddl;
+pub mod delete;
+pub mod distinct;
 pub mod exe;
+pub mod expr;
+pub mod filter;
 pub mod inmem_op;
 pub mod insert;
 pub mod join;
+pub mod limit;
+pub mod memory;
+pub mod planner;
+pub mod projection;
 pub mod scan;
+pub mod sort;
+pub mod update;
 // pub mod schema;
-// pub mod cc;
+pub mod cc;
 pub mod common;
 pub mod table_gen;
 pub mod tx;
@@ -26,10 +41,14 @@ pub type SqlResult<T> = std::result::Result<T, Error>;
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Error {
     Abort,
+    AlreadyExists(String),
     Config(String),
     Internal(String),
+    NotFound(String),
     Parse(String),
     ReadOnly,
+    /// The query exceeded its memory limit
+    ResourceExhausted(String),
     Serialization,
     Value(String),
 }
@@ -50,3 +69,8 @@ impl From<ArrowError> for Error {
         Error::Value(s.to_string())
     }
 }
+impl From<DataFusionError> for Error {
+    fn from(s: DataFusionError) -> Error {
+        Error::Value(s.to_string())
+    }
+}
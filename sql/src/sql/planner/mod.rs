@@ -0,0 +1,2244 @@
+use crate::sql::{
+    agg::{AggregateCall, AggregationPlan},
+    ddl::CreateIndexPlan,
+    delete::DeletePlan,
+    distinct::DistinctPlan,
+    exe::{rid_schema, with_rid_schema, PlanType, Storage, RID_COLUMN},
+    expr::{common_type, is_numeric, BinaryOperator, Expr},
+    filter::FilterPlan,
+    insert::InsertPlan,
+    join::{grace::GraceHashJoinPlan, nested_loop::NestedLoopJoinPlan, JoinType},
+    limit::LimitPlan,
+    projection::ProjectionPlan,
+    scan::{
+        index::{IndexRange, IndexScanPlan},
+        SeqScanPlan,
+    },
+    sort::{SortKey, SortPlan},
+    update::UpdatePlan,
+    util::RawInput,
+    DataBlock, Error, SqlResult,
+};
+use datafusion::{
+    arrow::{
+        array::ArrayRef,
+        compute::cast,
+        datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit},
+    },
+    physical_plan::{
+        aggregates::{create_aggregate_expr, AggregateFunction},
+        expressions::{lit, Column},
+        PhysicalExpr,
+    },
+    scalar::ScalarValue,
+};
+use sqlparser::{ast, dialect::GenericDialect, parser::Parser};
+use std::{ops::Bound, str::FromStr, sync::Arc};
+
+/// Turns SQL text into a [PlanType] tree that [crate::sql::exe::Executor] can run.
+/// Table and column names are resolved against the catalog, every error is
+/// returned as [Error::Parse]
+pub struct Planner {
+    catalog: Arc<dyn Storage>,
+}
+
+/// A planned relation and the names its output columns can be referenced by
+struct Relation {
+    plan: PlanType,
+    scope: Scope,
+}
+
+#[derive(Clone, Debug)]
+struct ScopeColumn {
+    /// Table name or alias the column comes from
+    qualifier: Option<String>,
+    field: Field,
+}
+
+/// Output columns of a relation, in the order of its schema
+#[derive(Clone, Debug, Default)]
+struct Scope {
+    columns: Vec<ScopeColumn>,
+}
+
+impl Scope {
+    fn from_schema(qualifier: Option<&str>, schema: &Schema) -> Self {
+        let columns = schema
+            .fields()
+            .iter()
+            .map(|field| ScopeColumn {
+                qualifier: qualifier.map(str::to_string),
+                field: field.clone(),
+            })
+            .collect();
+        Scope { columns }
+    }
+
+    fn schema(&self) -> Schema {
+        Schema::new(self.columns.iter().map(|c| c.field.clone()).collect())
+    }
+
+    fn requalify(mut self, qualifier: Option<&str>) -> Self {
+        for column in &mut self.columns {
+            column.qualifier = qualifier.map(str::to_string);
+        }
+        self
+    }
+
+    /// Columns of the side that may not have a match become nullable
+    fn join(self, right: Scope, join_type: JoinType) -> Self {
+        let left_nullable = matches!(join_type, JoinType::RightOuter | JoinType::FullOuter);
+        let right_nullable = matches!(join_type, JoinType::LeftOuter | JoinType::FullOuter);
+        let nullable = |mut column: ScopeColumn, nullable: bool| {
+            if nullable {
+                column.field =
+                    Field::new(column.field.name(), column.field.data_type().clone(), true);
+            }
+            column
+        };
+        let mut columns: Vec<_> = self
+            .columns
+            .into_iter()
+            .map(|c| nullable(c, left_nullable))
+            .collect();
+        if !join_type.left_only() {
+            columns.extend(
+                right
+                    .columns
+                    .into_iter()
+                    .map(|c| nullable(c, right_nullable)),
+            );
+        }
+        Scope { columns }
+    }
+
+    /// Returns None if no column has the name, an error if more than one does
+    fn try_resolve(&self, qualifier: Option<&str>, name: &str) -> SqlResult<Option<Column>> {
+        let mut found = None;
+        for (index, column) in self.columns.iter().enumerate() {
+            if column.field.name() != name {
+                continue;
+            }
+            if qualifier.is_some() && column.qualifier.as_deref() != qualifier {
+                continue;
+            }
+            if found.is_some() {
+                return Err(Error::Parse(format!(
+                    "column reference {} is ambiguous",
+                    display_name(qualifier, name)
+                )));
+            }
+            found = Some(Column::new(name, index));
+        }
+        Ok(found)
+    }
+
+    fn resolve(&self, qualifier: Option<&str>, name: &str) -> SqlResult<Column> {
+        self.try_resolve(qualifier, name)?.ok_or_else(|| {
+            Error::Parse(format!(
+                "column {} does not exist",
+                display_name(qualifier, name)
+            ))
+        })
+    }
+}
+
+fn display_name(qualifier: Option<&str>, name: &str) -> String {
+    match qualifier {
+        Some(qualifier) => format!("{}.{}", qualifier, name),
+        None => name.to_string(),
+    }
+}
+
+fn unsupported<T>(what: impl std::fmt::Display) -> SqlResult<T> {
+    Err(Error::Parse(format!("unsupported {}", what)))
+}
+
+/// Resolves SQL expressions against the columns of a scope
+struct ExprPlanner<'a> {
+    scope: &'a Scope,
+    schema: Schema,
+    /// Set when scope is the output of an aggregation over these input columns,
+    /// aggregate calls are then resolved to the output columns named after them
+    aggregated_input: Option<&'a Scope>,
+}
+
+impl<'a> ExprPlanner<'a> {
+    fn new(scope: &'a Scope) -> Self {
+        ExprPlanner {
+            scope,
+            schema: scope.schema(),
+            aggregated_input: None,
+        }
+    }
+
+    fn aggregated(scope: &'a Scope, input: &'a Scope) -> Self {
+        ExprPlanner {
+            aggregated_input: Some(input),
+            ..Self::new(scope)
+        }
+    }
+
+    fn data_type(&self, expr: &Expr) -> SqlResult<DataType> {
+        expr.data_type(&self.schema)
+    }
+
+    fn column(&self, qualifier: Option<&str>, name: &str) -> SqlResult<Expr> {
+        if let Some(col) = self.scope.try_resolve(qualifier, name)? {
+            return Ok(Expr::Column(col));
+        }
+        if let Some(input) = self.aggregated_input {
+            if input.try_resolve(qualifier, name)?.is_some() {
+                return Err(Error::Parse(format!(
+                    "column {} must appear in the GROUP BY clause or be used in an aggregate function",
+                    display_name(qualifier, name)
+                )));
+            }
+        }
+        Err(Error::Parse(format!(
+            "column {} does not exist",
+            display_name(qualifier, name)
+        )))
+    }
+
+    fn plan(&self, expr: &ast::Expr) -> SqlResult<Expr> {
+        match expr {
+            ast::Expr::Identifier(ident) => self.column(None, &ident.value),
+            ast::Expr::CompoundIdentifier(idents) => match idents.as_slice() {
+                [qualifier, name] => self.column(Some(&qualifier.value), &name.value),
+                _ => unsupported(format!("column reference {}", expr)),
+            },
+            ast::Expr::Value(value) => Ok(Expr::Literal(literal(value)?)),
+            ast::Expr::TypedString { data_type, value } => Ok(Expr::Literal(cast_scalar(
+                ScalarValue::Utf8(Some(value.clone())),
+                &convert_data_type(data_type)?,
+            )?)),
+            ast::Expr::Nested(expr) => self.plan(expr),
+            ast::Expr::UnaryOp { op, expr } => self.plan_unary(op, expr),
+            ast::Expr::BinaryOp { left, op, right } => self.plan_binary(left, op, right),
+            ast::Expr::IsNull(expr) => Ok(Expr::IsNull(Box::new(self.plan(expr)?))),
+            ast::Expr::IsNotNull(expr) => Ok(Expr::IsNotNull(Box::new(self.plan(expr)?))),
+            ast::Expr::InList {
+                expr,
+                list,
+                negated,
+            } => {
+                let mut exprs = vec![self.plan(expr)?];
+                for item in list {
+                    exprs.push(self.plan(item)?);
+                }
+                let mut exprs = self.coerce_all(exprs)?.into_iter();
+                Ok(Expr::InList {
+                    expr: Box::new(exprs.next().unwrap()),
+                    list: exprs.collect(),
+                    negated: *negated,
+                })
+            }
+            ast::Expr::Between {
+                expr,
+                negated,
+                low,
+                high,
+            } => {
+                let expr = self.plan(expr)?;
+                let (low_expr, low) = self.coerce(expr.clone(), self.plan(low)?)?;
+                let (high_expr, high) = self.coerce(expr, self.plan(high)?)?;
+                let between = Expr::binary(
+                    Expr::binary(low_expr, BinaryOperator::GtEq, low),
+                    BinaryOperator::And,
+                    Expr::binary(high_expr, BinaryOperator::LtEq, high),
+                );
+                if *negated {
+                    Ok(Expr::Not(Box::new(between)))
+                } else {
+                    Ok(between)
+                }
+            }
+            ast::Expr::Cast { expr, data_type } => {
+                let data_type = convert_data_type(data_type)?;
+                match self.plan(expr)? {
+                    Expr::Literal(value) => Ok(Expr::Literal(cast_scalar(value, &data_type)?)),
+                    expr => Ok(Expr::Cast {
+                        expr: Box::new(expr),
+                        data_type,
+                    }),
+                }
+            }
+            ast::Expr::Function(func) => self.plan_function(func),
+            _ => unsupported(format!("expression {}", expr)),
+        }
+    }
+
+    fn plan_unary(&self, op: &ast::UnaryOperator, expr: &ast::Expr) -> SqlResult<Expr> {
+        let expr = self.plan(expr)?;
+        match op {
+            ast::UnaryOperator::Not => Ok(Expr::Not(Box::new(self.boolean(expr)?))),
+            ast::UnaryOperator::Plus => Ok(expr),
+            ast::UnaryOperator::Minus => match expr {
+                Expr::Literal(value) => Ok(Expr::Literal(negate(value)?)),
+                expr if is_numeric(&self.data_type(&expr)?) => Ok(Expr::Negative(Box::new(expr))),
+                expr => Err(Error::Parse(format!(
+                    "cannot negate a value of type {}",
+                    self.data_type(&expr)?
+                ))),
+            },
+            _ => unsupported(format!("operator {}", op)),
+        }
+    }
+
+    fn plan_binary(
+        &self,
+        left: &ast::Expr,
+        op: &ast::BinaryOperator,
+        right: &ast::Expr,
+    ) -> SqlResult<Expr> {
+        let (left, right) = (self.plan(left)?, self.plan(right)?);
+        let op = match op {
+            ast::BinaryOperator::Like | ast::BinaryOperator::NotLike => {
+                return Ok(Expr::Like {
+                    expr: Box::new(self.string(left)?),
+                    pattern: Box::new(self.string(right)?),
+                    negated: *op == ast::BinaryOperator::NotLike,
+                })
+            }
+            ast::BinaryOperator::And => BinaryOperator::And,
+            ast::BinaryOperator::Or => BinaryOperator::Or,
+            ast::BinaryOperator::Eq => BinaryOperator::Eq,
+            ast::BinaryOperator::NotEq => BinaryOperator::NotEq,
+            ast::BinaryOperator::Lt => BinaryOperator::Lt,
+            ast::BinaryOperator::LtEq => BinaryOperator::LtEq,
+            ast::BinaryOperator::Gt => BinaryOperator::Gt,
+            ast::BinaryOperator::GtEq => BinaryOperator::GtEq,
+            ast::BinaryOperator::Plus => BinaryOperator::Plus,
+            ast::BinaryOperator::Minus => BinaryOperator::Minus,
+            ast::BinaryOperator::Multiply => BinaryOperator::Multiply,
+            ast::BinaryOperator::Divide => BinaryOperator::Divide,
+            ast::BinaryOperator::Modulo => BinaryOperator::Modulo,
+            op => return unsupported(format!("operator {}", op)),
+        };
+        if !op.is_comparison() && !op.is_arithmetic() {
+            return Ok(Expr::binary(self.boolean(left)?, op, self.boolean(right)?));
+        }
+        let (left, right) = self.coerce(left, right)?;
+        if op.is_arithmetic() && !is_numeric(&self.data_type(&left)?) {
+            return Err(Error::Parse(format!(
+                "operator {} is not defined for type {}",
+                op,
+                self.data_type(&left)?
+            )));
+        }
+        Ok(Expr::binary(left, op, right))
+    }
+
+    fn plan_function(&self, func: &ast::Function) -> SqlResult<Expr> {
+        let name = func.name.to_string().to_lowercase();
+        if AggregateFunction::from_str(&name).is_err() {
+            return unsupported(format!("function {}", func.name));
+        }
+        let output = func.to_string();
+        match self.aggregated_input {
+            Some(_) => Ok(Expr::Column(self.scope.resolve(None, &output)?)),
+            None => Err(Error::Parse(format!(
+                "aggregate function {} is not allowed here",
+                output
+            ))),
+        }
+    }
+
+    /// Null literals take the type of the other side, other literals are converted
+    /// to the type of the other side if the conversion does not lose information
+    fn coerce(&self, left: Expr, right: Expr) -> SqlResult<(Expr, Expr)> {
+        let mut coerced = self.coerce_all(vec![left, right])?.into_iter();
+        Ok((coerced.next().unwrap(), coerced.next().unwrap()))
+    }
+
+    fn coerce_all(&self, exprs: Vec<Expr>) -> SqlResult<Vec<Expr>> {
+        let is_literal = |e: &Expr| matches!(e, Expr::Literal(_));
+        let mut target: Option<DataType> = None;
+        // non literal operands decide the type first
+        for pass_literals in [false, true] {
+            for expr in exprs.iter().filter(|e| is_literal(e) == pass_literals) {
+                if let Expr::Literal(value) = expr {
+                    if value.is_null() {
+                        continue;
+                    }
+                    if let Some(data_type) = &target {
+                        if cast_lossless(value, data_type).is_some() {
+                            continue;
+                        }
+                    }
+                }
+                let data_type = self.data_type(expr)?;
+                target = Some(match target {
+                    None => data_type,
+                    Some(target) => common_type(&target, &data_type).ok_or_else(|| {
+                        Error::Parse(format!(
+                            "cannot compare values of type {} and {}",
+                            target, data_type
+                        ))
+                    })?,
+                });
+            }
+        }
+        let target = match target {
+            Some(target) => target,
+            None => return Ok(exprs),
+        };
+        exprs
+            .into_iter()
+            .map(|expr| {
+                if self.data_type(&expr)? == target {
+                    return Ok(expr);
+                }
+                match expr {
+                    Expr::Literal(value) => Ok(Expr::Literal(cast_scalar(value, &target)?)),
+                    expr => Ok(Expr::Cast {
+                        expr: Box::new(expr),
+                        data_type: target.clone(),
+                    }),
+                }
+            })
+            .collect()
+    }
+
+    fn boolean(&self, expr: Expr) -> SqlResult<Expr> {
+        match expr {
+            Expr::Literal(value) if value.is_null() => {
+                Ok(Expr::Literal(ScalarValue::Boolean(None)))
+            }
+            expr => match self.data_type(&expr)? {
+                DataType::Boolean => Ok(expr),
+                other => Err(Error::Parse(format!(
+                    "expected a boolean expression, got type {}",
+                    other
+                ))),
+            },
+        }
+    }
+
+    fn string(&self, expr: Expr) -> SqlResult<Expr> {
+        match expr {
+            Expr::Literal(value) if value.is_null() => Ok(Expr::Literal(ScalarValue::Utf8(None))),
+            expr => match self.data_type(&expr)? {
+                DataType::Utf8 => Ok(expr),
+                DataType::LargeUtf8 => Ok(Expr::Cast {
+                    expr: Box::new(expr),
+                    data_type: DataType::Utf8,
+                }),
+                other => Err(Error::Parse(format!(
+                    "expected a string expression, got type {}",
+                    other
+                ))),
+            },
+        }
+    }
+}
+
+/// Untyped NULL is planned as a boolean null and converted once its type is known
+fn literal(value: &ast::Value) -> SqlResult<ScalarValue> {
+    match value {
+        ast::Value::Number(number, _) => {
+            if let Ok(int) = number.parse::<i64>() {
+                return Ok(ScalarValue::Int64(Some(int)));
+            }
+            number
+                .parse::<f64>()
+                .map(|float| ScalarValue::Float64(Some(float)))
+                .map_err(|_| Error::Parse(format!("invalid number {}", number)))
+        }
+        ast::Value::SingleQuotedString(s) | ast::Value::NationalStringLiteral(s) => {
+            Ok(ScalarValue::Utf8(Some(s.clone())))
+        }
+        ast::Value::Boolean(b) => Ok(ScalarValue::Boolean(Some(*b))),
+        ast::Value::Null => Ok(ScalarValue::Boolean(None)),
+        _ => unsupported(format!("literal {}", value)),
+    }
+}
+
+fn negate(value: ScalarValue) -> SqlResult<ScalarValue> {
+    match value {
+        ScalarValue::Int64(v) => Ok(ScalarValue::Int64(v.map(|v| -v))),
+        ScalarValue::Float64(v) => Ok(ScalarValue::Float64(v.map(|v| -v))),
+        other => Err(Error::Parse(format!("cannot negate {}", other))),
+    }
+}
+
+fn cast_scalar(value: ScalarValue, to: &DataType) -> SqlResult<ScalarValue> {
+    if value.is_null() {
+        return ScalarValue::try_from(to).map_err(|e| Error::Parse(e.to_string()));
+    }
+    if &value.get_datatype() == to {
+        return Ok(value);
+    }
+    let array: ArrayRef = value.to_array_of_size(1);
+    let casted = cast(&array, to).map_err(|e| Error::Parse(e.to_string()))?;
+    if casted.is_null(0) {
+        return Err(Error::Parse(format!("cannot cast {} to {}", value, to)));
+    }
+    ScalarValue::try_from_array(&casted, 0).map_err(|e| Error::Parse(e.to_string()))
+}
+
+/// The value casted to the type, if it can be casted back to the same value
+fn cast_lossless(value: &ScalarValue, to: &DataType) -> Option<ScalarValue> {
+    let casted = cast_scalar(value.clone(), to).ok()?;
+    let back = cast_scalar(casted.clone(), &value.get_datatype()).ok()?;
+    if &back == value {
+        Some(casted)
+    } else {
+        None
+    }
+}
+
+fn convert_data_type(data_type: &ast::DataType) -> SqlResult<DataType> {
+    let converted = match data_type {
+        ast::DataType::Char(_)
+        | ast::DataType::Varchar(_)
+        | ast::DataType::Text
+        | ast::DataType::String => DataType::Utf8,
+        ast::DataType::TinyInt(_) => DataType::Int8,
+        ast::DataType::SmallInt(_) => DataType::Int16,
+        ast::DataType::Int(_) => DataType::Int32,
+        ast::DataType::BigInt(_) => DataType::Int64,
+        ast::DataType::Real | ast::DataType::Float(Some(0..=24)) => DataType::Float32,
+        ast::DataType::Float(_) | ast::DataType::Double => DataType::Float64,
+        ast::DataType::Boolean => DataType::Boolean,
+        ast::DataType::Date => DataType::Date32,
+        ast::DataType::Timestamp => DataType::Timestamp(TimeUnit::Nanosecond, None),
+        other => return unsupported(format!("data type {}", other)),
+    };
+    Ok(converted)
+}
+
+/// Name of a select item without alias
+fn output_name(expr: &ast::Expr) -> String {
+    match expr {
+        ast::Expr::Identifier(ident) => ident.value.clone(),
+        ast::Expr::CompoundIdentifier(idents) => idents.last().unwrap().value.clone(),
+        expr => expr.to_string(),
+    }
+}
+
+fn parse_usize(expr: &ast::Expr) -> SqlResult<usize> {
+    match expr {
+        ast::Expr::Value(ast::Value::Number(number, _)) => number
+            .parse::<usize>()
+            .map_err(|_| Error::Parse(format!("expected a non negative integer, got {}", number))),
+        other => Err(Error::Parse(format!(
+            "expected a non negative integer, got {}",
+            other
+        ))),
+    }
+}
+
+/// Aggregate calls in expr, in the order they appear
+fn find_aggregates<'a>(expr: &'a ast::Expr, found: &mut Vec<&'a ast::Function>) {
+    match expr {
+        ast::Expr::Function(func) => {
+            if AggregateFunction::from_str(&func.name.to_string().to_lowercase()).is_ok() {
+                found.push(func);
+            }
+        }
+        ast::Expr::Nested(expr)
+        | ast::Expr::UnaryOp { expr, .. }
+        | ast::Expr::IsNull(expr)
+        | ast::Expr::IsNotNull(expr)
+        | ast::Expr::Cast { expr, .. } => find_aggregates(expr, found),
+        ast::Expr::BinaryOp { left, right, .. } => {
+            find_aggregates(left, found);
+            find_aggregates(right, found);
+        }
+        ast::Expr::InList { expr, list, .. } => {
+            find_aggregates(expr, found);
+            list.iter().for_each(|item| find_aggregates(item, found));
+        }
+        ast::Expr::Between {
+            expr, low, high, ..
+        } => {
+            find_aggregates(expr, found);
+            find_aggregates(low, found);
+            find_aggregates(high, found);
+        }
+        _ => {}
+    }
+}
+
+/// Conjuncts of an AND chain
+fn split_conjunction<'a>(expr: &'a ast::Expr, conjuncts: &mut Vec<&'a ast::Expr>) {
+    match expr {
+        ast::Expr::BinaryOp {
+            left,
+            op: ast::BinaryOperator::And,
+            right,
+        } => {
+            split_conjunction(left, conjuncts);
+            split_conjunction(right, conjuncts);
+        }
+        ast::Expr::Nested(expr) => split_conjunction(expr, conjuncts),
+        expr => conjuncts.push(expr),
+    }
+}
+
+impl Planner {
+    pub fn new(catalog: Arc<dyn Storage>) -> Self {
+        Planner { catalog }
+    }
+
+    /// sql must contain exactly one statement
+    pub fn plan(&self, sql: &str) -> SqlResult<PlanType> {
+        let mut statements =
+            Parser::parse_sql(&GenericDialect {}, sql).map_err(|e| Error::Parse(e.to_string()))?;
+        if statements.len() != 1 {
+            return Err(Error::Parse(format!(
+                "expected exactly one statement, got {}",
+                statements.len()
+            )));
+        }
+        self.plan_statement(statements.remove(0))
+    }
+
+    fn plan_statement(&self, statement: ast::Statement) -> SqlResult<PlanType> {
+        match statement {
+            ast::Statement::Query(query) => Ok(self.plan_query(&query)?.plan),
+            ast::Statement::Insert {
+                table_name,
+                columns,
+                source,
+                ..
+            } => self.plan_insert(&table_name.to_string(), &columns, &source),
+            ast::Statement::Update {
+                table,
+                assignments,
+                selection,
+            } => self.plan_update(&table, &assignments, selection.as_ref()),
+            ast::Statement::Delete {
+                table_name,
+                selection,
+            } => self.plan_delete(&table_name.to_string(), selection.as_ref()),
+            ast::Statement::CreateIndex {
+                name,
+                table_name,
+                columns,
+                unique,
+                if_not_exists,
+            } => {
+                if unique {
+                    return unsupported("UNIQUE index");
+                }
+                self.plan_create_index(
+                    &name.to_string(),
+                    &table_name.to_string(),
+                    &columns,
+                    if_not_exists,
+                )
+            }
+            other => unsupported(format!("statement {}", other)),
+        }
+    }
+
+    fn table_schema(&self, table: &str) -> SqlResult<SchemaRef> {
+        match self.catalog.get_table(table) {
+            Ok(meta) => Ok(meta.schema_ref()),
+            Err(Error::NotFound(_)) => Err(Error::Parse(format!("table {} does not exist", table))),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn plan_query(&self, query: &ast::Query) -> SqlResult<Relation> {
+        if query.with.is_some() || query.fetch.is_some() {
+            return unsupported(format!("query {}", query));
+        }
+        let mut relation = match &query.body {
+            ast::SetExpr::Select(select) => {
+                let mut relation = self.plan_select(select)?;
+                if select.distinct {
+                    relation.plan = PlanType::Distinct(DistinctPlan {
+                        source_plan: Box::new(relation.plan),
+                    });
+                }
+                relation
+            }
+            ast::SetExpr::Query(query) => self.plan_query(query)?,
+            ast::SetExpr::Values(values) => self.plan_values(values)?,
+            other => return unsupported(format!("query {}", other)),
+        };
+
+        let limit = query.limit.as_ref().map(parse_usize).transpose()?;
+        let offset = match &query.offset {
+            Some(offset) => parse_usize(&offset.value)?,
+            None => 0,
+        };
+        if !query.order_by.is_empty() {
+            let planner = ExprPlanner::new(&relation.scope);
+            let mut order_by = Vec::with_capacity(query.order_by.len());
+            for item in &query.order_by {
+                let column = match &item.expr {
+                    // ORDER BY 1 refers to the first output column
+                    ast::Expr::Value(ast::Value::Number(..)) => {
+                        let position = parse_usize(&item.expr)?;
+                        match relation.scope.columns.get(position.wrapping_sub(1)) {
+                            Some(c) => Column::new(c.field.name(), position - 1),
+                            None => {
+                                return Err(Error::Parse(format!(
+                                    "ORDER BY position {} is not in select list",
+                                    position
+                                )))
+                            }
+                        }
+                    }
+                    expr => match planner.plan(expr)? {
+                        Expr::Column(column) => column,
+                        _ => return unsupported(format!("ORDER BY expression {}", expr)),
+                    },
+                };
+                let descending = item.asc == Some(false);
+                order_by.push(SortKey {
+                    column,
+                    descending,
+                    nulls_first: item.nulls_first.unwrap_or(descending),
+                });
+            }
+            relation.plan = PlanType::Sort(SortPlan {
+                order_by,
+                limit: limit.map(|limit| limit + offset),
+                source_plan: Box::new(relation.plan),
+            });
+            if offset == 0 {
+                return Ok(relation);
+            }
+        }
+        if limit.is_some() || offset > 0 {
+            relation.plan = PlanType::Limit(LimitPlan {
+                limit,
+                offset,
+                source_plan: Box::new(relation.plan),
+            });
+        }
+        Ok(relation)
+    }
+
+    fn plan_select(&self, select: &ast::Select) -> SqlResult<Relation> {
+        if select.top.is_some() || !select.lateral_views.is_empty() {
+            return unsupported(format!("query {}", select));
+        }
+        let mut relation = match select.from.split_first() {
+            Some((first, rest)) => {
+                let mut relation = self.plan_from(first)?;
+                for from in rest {
+                    let right = self.plan_from(from)?;
+                    relation =
+                        self.plan_nested_loop_join(relation, right, JoinType::Inner, None)?;
+                }
+                relation
+            }
+            None => return unsupported("SELECT without FROM"),
+        };
+
+        if let Some(selection) = &select.selection {
+            let planner = ExprPlanner::new(&relation.scope);
+            let predicate = planner.boolean(planner.plan(selection)?)?;
+            relation.plan = match relation.plan {
+                // rows are filtered as they are scanned
+                PlanType::SeqScan(scan) => match self.choose_index(&scan.table, &predicate)? {
+                    Some((index, range)) => PlanType::IndexScan(IndexScanPlan {
+                        table: scan.table,
+                        index,
+                        range,
+                        predicate: Some(predicate),
+                    }),
+                    None => PlanType::SeqScan(SeqScanPlan {
+                        predicate: Some(predicate),
+                        ..scan
+                    }),
+                },
+                plan => PlanType::Filter(FilterPlan {
+                    predicate,
+                    source_plan: Box::new(plan),
+                }),
+            };
+        }
+
+        let mut aggregates = vec![];
+        for item in &select.projection {
+            if let ast::SelectItem::UnnamedExpr(expr)
+            | ast::SelectItem::ExprWithAlias { expr, .. } = item
+            {
+                find_aggregates(expr, &mut aggregates);
+            }
+        }
+        if let Some(having) = &select.having {
+            find_aggregates(having, &mut aggregates);
+        }
+        if aggregates.is_empty() && select.group_by.is_empty() && select.having.is_none() {
+            return self.plan_projection(relation, &select.projection, None);
+        }
+
+        let input_scope = relation.scope.clone();
+        let mut relation = self.plan_aggregation(relation, &select.group_by, &aggregates)?;
+        if let Some(having) = &select.having {
+            let planner = ExprPlanner::aggregated(&relation.scope, &input_scope);
+            let predicate = planner.boolean(planner.plan(having)?)?;
+            relation.plan = PlanType::Filter(FilterPlan {
+                predicate,
+                source_plan: Box::new(relation.plan),
+            });
+        }
+        self.plan_projection(relation, &select.projection, Some(&input_scope))
+    }
+
+    fn plan_from(&self, from: &ast::TableWithJoins) -> SqlResult<Relation> {
+        let mut relation = self.plan_table_factor(&from.relation)?;
+        for join in &from.joins {
+            let right = self.plan_table_factor(&join.relation)?;
+            relation = self.plan_join(relation, right, &join.join_operator)?;
+        }
+        Ok(relation)
+    }
+
+    fn plan_table_factor(&self, factor: &ast::TableFactor) -> SqlResult<Relation> {
+        match factor {
+            ast::TableFactor::Table { name, alias, .. } => {
+                let table = name.to_string();
+                let schema = self.table_schema(&table)?;
+                let qualifier = alias
+                    .as_ref()
+                    .map_or(table.clone(), |a| a.name.value.clone());
+                Ok(Relation {
+                    plan: PlanType::SeqScan(SeqScanPlan {
+                        table,
+                        predicate: None,
+                        with_rids: false,
+                    }),
+                    scope: Scope::from_schema(Some(&qualifier), &schema),
+                })
+            }
+            ast::TableFactor::Derived {
+                lateral: false,
+                subquery,
+                alias,
+            } => {
+                let relation = self.plan_query(subquery)?;
+                let qualifier = alias.as_ref().map(|a| a.name.value.as_str());
+                Ok(Relation {
+                    plan: relation.plan,
+                    scope: relation.scope.requalify(qualifier),
+                })
+            }
+            other => unsupported(format!("relation {}", other)),
+        }
+    }
+
+    /// Equality conditions between columns of both sides become the join keys,
+    /// the other conditions of an inner join filter the joined rows. Joins without
+    /// keys, and left joins with other conditions, are nested loop joins
+    fn plan_join(
+        &self,
+        left: Relation,
+        right: Relation,
+        operator: &ast::JoinOperator,
+    ) -> SqlResult<Relation> {
+        let (join_type, constraint) = match operator {
+            ast::JoinOperator::Inner(c) => (JoinType::Inner, c),
+            ast::JoinOperator::LeftOuter(c) => (JoinType::LeftOuter, c),
+            ast::JoinOperator::RightOuter(c) => (JoinType::RightOuter, c),
+            ast::JoinOperator::FullOuter(c) => (JoinType::FullOuter, c),
+            ast::JoinOperator::CrossJoin => {
+                return self.plan_nested_loop_join(left, right, JoinType::Inner, None)
+            }
+            _ => return unsupported(format!("join {:?}", operator)),
+        };
+        let mut on_left = vec![];
+        let mut on_right = vec![];
+        let mut residual = vec![];
+        match constraint {
+            ast::JoinConstraint::On(expr) => {
+                let mut conjuncts = vec![];
+                split_conjunction(expr, &mut conjuncts);
+                let left_planner = ExprPlanner::new(&left.scope);
+                let right_planner = ExprPlanner::new(&right.scope);
+                let side_column = |planner: &ExprPlanner, expr: &ast::Expr| match planner.plan(expr)
+                {
+                    Ok(Expr::Column(col)) => Some(col),
+                    _ => None,
+                };
+                for conjunct in conjuncts {
+                    let key = match conjunct {
+                        ast::Expr::BinaryOp {
+                            left: l,
+                            op: ast::BinaryOperator::Eq,
+                            right: r,
+                        } => match (
+                            side_column(&left_planner, l),
+                            side_column(&right_planner, r),
+                        ) {
+                            (Some(l), Some(r)) => Some((l, r)),
+                            _ => side_column(&left_planner, r).zip(side_column(&right_planner, l)),
+                        },
+                        _ => None,
+                    };
+                    match key {
+                        Some((l, r)) => {
+                            on_left.push(l);
+                            on_right.push(r);
+                        }
+                        None => residual.push(conjunct),
+                    }
+                }
+            }
+            ast::JoinConstraint::Using(idents) => {
+                for ident in idents {
+                    on_left.push(left.scope.resolve(None, &ident.value)?);
+                    on_right.push(right.scope.resolve(None, &ident.value)?);
+                }
+            }
+            _ => return unsupported("join without ON condition"),
+        }
+        if on_left.is_empty() || (!residual.is_empty() && join_type == JoinType::LeftOuter) {
+            let on = match constraint {
+                ast::JoinConstraint::On(expr) => Some(expr),
+                _ => None,
+            };
+            return self.plan_nested_loop_join(left, right, join_type, on);
+        }
+        let (left_schema, right_schema) = (left.scope.schema(), right.scope.schema());
+        for (l, r) in on_left.iter().zip(&on_right) {
+            let (lt, rt) = (
+                left_schema.field(l.index()).data_type(),
+                right_schema.field(r.index()).data_type(),
+            );
+            if lt != rt {
+                return Err(Error::Parse(format!(
+                    "join keys {} and {} have different types {} and {}",
+                    l.name(),
+                    r.name(),
+                    lt,
+                    rt
+                )));
+            }
+        }
+        if !residual.is_empty() && join_type != JoinType::Inner {
+            return unsupported("non equality condition in outer join");
+        }
+
+        let mut relation = Relation {
+            plan: PlanType::GraceHashJoin(GraceHashJoinPlan {
+                on_left,
+                on_right,
+                left_plan: Box::new(left.plan),
+                right_plan: Box::new(right.plan),
+                join_type,
+            }),
+            scope: left.scope.join(right.scope, join_type),
+        };
+        if !residual.is_empty() {
+            let planner = ExprPlanner::new(&relation.scope);
+            let mut predicate: Option<Expr> = None;
+            for conjunct in residual {
+                let expr = planner.boolean(planner.plan(conjunct)?)?;
+                predicate = Some(match predicate {
+                    Some(p) => Expr::binary(p, BinaryOperator::And, expr),
+                    None => expr,
+                });
+            }
+            relation.plan = PlanType::Filter(FilterPlan {
+                predicate: predicate.unwrap(),
+                source_plan: Box::new(relation.plan),
+            });
+        }
+        Ok(relation)
+    }
+
+    /// Every pair of rows is checked against the condition, only inner and left
+    /// outer joins can be planned this way
+    fn plan_nested_loop_join(
+        &self,
+        left: Relation,
+        right: Relation,
+        join_type: JoinType,
+        on: Option<&ast::Expr>,
+    ) -> SqlResult<Relation> {
+        if !matches!(join_type, JoinType::Inner | JoinType::LeftOuter) {
+            return unsupported(format!(
+                "{:?} join without an equality condition between both sides",
+                join_type
+            ));
+        }
+        let scope = left.scope.join(right.scope, join_type);
+        let predicate = match on {
+            Some(on) => {
+                let planner = ExprPlanner::new(&scope);
+                Some(planner.boolean(planner.plan(on)?)?)
+            }
+            None => None,
+        };
+        Ok(Relation {
+            plan: PlanType::NestedLoopJoin(NestedLoopJoinPlan {
+                predicate,
+                left_plan: Box::new(left.plan),
+                right_plan: Box::new(right.plan),
+                join_type,
+            }),
+            scope,
+        })
+    }
+
+    /// Output is the group by columns followed by one column per distinct aggregate
+    /// call, named after the call
+    fn plan_aggregation(
+        &self,
+        input: Relation,
+        group_by: &[ast::Expr],
+        aggregates: &[&ast::Function],
+    ) -> SqlResult<Relation> {
+        let planner = ExprPlanner::new(&input.scope);
+        let input_schema = Arc::new(input.scope.schema());
+        let mut group_columns = Vec::with_capacity(group_by.len());
+        let mut columns = Vec::with_capacity(group_by.len() + aggregates.len());
+        for expr in group_by {
+            match planner.plan(expr)? {
+                Expr::Column(col) => {
+                    columns.push(input.scope.columns[col.index()].clone());
+                    group_columns.push(col);
+                }
+                _ => return unsupported(format!("GROUP BY expression {}", expr)),
+            }
+        }
+
+        let mut calls: Vec<AggregateCall> = vec![];
+        for func in aggregates {
+            let name = func.to_string();
+            if calls.iter().any(|call| call.name == name) {
+                continue;
+            }
+            let agg = AggregateFunction::from_str(&func.name.to_string().to_lowercase())
+                .map_err(|e| Error::Parse(e.to_string()))?;
+            let arg = match func.args.as_slice() {
+                [ast::FunctionArg::Unnamed(ast::FunctionArgExpr::Wildcard)] => None,
+                [ast::FunctionArg::Unnamed(ast::FunctionArgExpr::Expr(expr))] => {
+                    match planner.plan(expr)? {
+                        Expr::Column(col) => Some(col),
+                        _ => return unsupported(format!("aggregate argument {}", expr)),
+                    }
+                }
+                _ => return unsupported(format!("aggregate call {}", func)),
+            };
+            let physical_arg: Arc<dyn PhysicalExpr> = match &arg {
+                Some(col) => Arc::new(col.clone()),
+                None => lit(ScalarValue::UInt8(Some(1))),
+            };
+            let field = create_aggregate_expr(
+                &agg,
+                func.distinct,
+                &[physical_arg],
+                &input_schema,
+                name.clone(),
+            )
+            .map_err(|e| Error::Parse(e.to_string()))?
+            .field()
+            .map_err(|e| Error::Parse(e.to_string()))?;
+            columns.push(ScopeColumn {
+                qualifier: None,
+                field,
+            });
+            calls.push(AggregateCall {
+                func: agg,
+                distinct: func.distinct,
+                arg,
+                name,
+            });
+        }
+
+        Ok(Relation {
+            plan: PlanType::Aggregation(AggregationPlan {
+                group_by: group_columns,
+                aggregates: calls,
+                source_plan: Box::new(input.plan),
+            }),
+            scope: Scope { columns },
+        })
+    }
+
+    /// Projection is omitted if the select list returns the input columns unchanged
+    fn plan_projection(
+        &self,
+        input: Relation,
+        items: &[ast::SelectItem],
+        aggregated_input: Option<&Scope>,
+    ) -> SqlResult<Relation> {
+        let planner = match aggregated_input {
+            Some(aggregated) => ExprPlanner::aggregated(&input.scope, aggregated),
+            None => ExprPlanner::new(&input.scope),
+        };
+        let mut exprs = vec![];
+        let mut columns = vec![];
+        for item in items {
+            match item {
+                ast::SelectItem::Wildcard | ast::SelectItem::QualifiedWildcard(_) => {
+                    let qualifier = match item {
+                        ast::SelectItem::QualifiedWildcard(name) => Some(name.to_string()),
+                        _ => None,
+                    };
+                    let before = exprs.len();
+                    for (index, column) in input.scope.columns.iter().enumerate() {
+                        if qualifier.is_some() && column.qualifier != qualifier {
+                            continue;
+                        }
+                        exprs.push(Expr::column(column.field.name(), index));
+                        columns.push(column.clone());
+                    }
+                    if let (Some(qualifier), true) = (&qualifier, before == exprs.len()) {
+                        return Err(Error::Parse(format!(
+                            "relation {} does not exist",
+                            qualifier
+                        )));
+                    }
+                }
+                ast::SelectItem::UnnamedExpr(expr)
+                | ast::SelectItem::ExprWithAlias { expr, .. } => {
+                    let name = match item {
+                        ast::SelectItem::ExprWithAlias { alias, .. } => alias.value.clone(),
+                        _ => output_name(expr),
+                    };
+                    let planned = planner.plan(expr)?;
+                    let qualifier = match &planned {
+                        Expr::Column(col) => input.scope.columns[col.index()].qualifier.clone(),
+                        _ => None,
+                    };
+                    columns.push(ScopeColumn {
+                        qualifier,
+                        field: planned.field(&name, &planner.schema)?,
+                    });
+                    exprs.push(planned);
+                }
+            }
+        }
+
+        let unchanged = exprs.len() == input.scope.columns.len()
+            && exprs
+                .iter()
+                .zip(&columns)
+                .enumerate()
+                .all(|(i, (expr, column))| {
+                    matches!(expr, Expr::Column(col) if col.index() == i)
+                        && column.field == input.scope.columns[i].field
+                });
+        if unchanged {
+            return Ok(Relation {
+                plan: input.plan,
+                scope: Scope { columns },
+            });
+        }
+        let scope = Scope { columns };
+        Ok(Relation {
+            plan: PlanType::Projection(ProjectionPlan {
+                exprs,
+                schema: Arc::new(scope.schema()),
+                source_plan: Box::new(input.plan),
+            }),
+            scope,
+        })
+    }
+
+    /// Every row must only contain constants, column i is named columni
+    fn plan_values(&self, values: &ast::Values) -> SqlResult<Relation> {
+        let rows = self.constant_rows(values)?;
+        let width = rows.first().map_or(0, Vec::len);
+        let mut fields = Vec::with_capacity(width);
+        for i in 0..width {
+            let mut data_type: Option<DataType> = None;
+            for row in &rows {
+                if row[i].is_null() {
+                    continue;
+                }
+                let value_type = row[i].get_datatype();
+                data_type = Some(match data_type {
+                    None => value_type,
+                    Some(t) => common_type(&t, &value_type).ok_or_else(|| {
+                        Error::Parse(format!(
+                            "VALUES column {} has values of type {} and {}",
+                            i + 1,
+                            t,
+                            value_type
+                        ))
+                    })?,
+                });
+            }
+            let nullable = rows.iter().any(|row| row[i].is_null());
+            let data_type = data_type.unwrap_or(DataType::Boolean);
+            fields.push(Field::new(&format!("column{}", i + 1), data_type, nullable));
+        }
+        let schema = Arc::new(Schema::new(fields));
+        let block = Self::build_block(schema.clone(), rows)?;
+        Ok(Relation {
+            plan: PlanType::RawInput(RawInput::new(block)),
+            scope: Scope::from_schema(None, &schema),
+        })
+    }
+
+    fn constant_rows(&self, values: &ast::Values) -> SqlResult<Vec<Vec<ScalarValue>>> {
+        let empty = Scope::default();
+        let planner = ExprPlanner::new(&empty);
+        let width = values.0.first().map_or(0, Vec::len);
+        values
+            .0
+            .iter()
+            .map(|row| {
+                if row.len() != width {
+                    return Err(Error::Parse(format!(
+                        "VALUES rows must have the same length, expected {} got {}",
+                        width,
+                        row.len()
+                    )));
+                }
+                row.iter()
+                    .map(|expr| match planner.plan(expr)? {
+                        Expr::Literal(value) => Ok(value),
+                        _ => unsupported(format!(
+                            "VALUES expression {}, only constants are allowed",
+                            expr
+                        )),
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Values are casted to the types of the schema
+    fn build_block(schema: SchemaRef, rows: Vec<Vec<ScalarValue>>) -> SqlResult<DataBlock> {
+        let mut columns = Vec::with_capacity(schema.fields().len());
+        for (i, field) in schema.fields().iter().enumerate() {
+            let mut values = Vec::with_capacity(rows.len());
+            for row in &rows {
+                let value = cast_scalar(row[i].clone(), field.data_type())?;
+                if value.is_null() && !field.is_nullable() {
+                    return Err(Error::Parse(format!(
+                        "null value in column {} that is not nullable",
+                        field.name()
+                    )));
+                }
+                values.push(value);
+            }
+            let column = if values.is_empty() {
+                datafusion::arrow::array::new_empty_array(field.data_type())
+            } else {
+                ScalarValue::iter_to_array(values).map_err(|e| Error::Parse(e.to_string()))?
+            };
+            columns.push(column);
+        }
+        Ok(DataBlock::try_new(schema, columns)?)
+    }
+
+    /// Rows of the source are inserted into the given columns, the remaining columns
+    /// of the table are null
+    fn plan_insert(
+        &self,
+        table: &str,
+        columns: &[ast::Ident],
+        source: &ast::Query,
+    ) -> SqlResult<PlanType> {
+        let schema = self.table_schema(table)?;
+        let targets: Vec<usize> = if columns.is_empty() {
+            (0..schema.fields().len()).collect()
+        } else {
+            let mut targets = Vec::with_capacity(columns.len());
+            for ident in columns {
+                let index = schema.index_of(&ident.value).map_err(|_| {
+                    Error::Parse(format!(
+                        "column {} of table {} does not exist",
+                        ident, table
+                    ))
+                })?;
+                if targets.contains(&index) {
+                    return Err(Error::Parse(format!("column {} is specified twice", ident)));
+                }
+                targets.push(index);
+            }
+            targets
+        };
+        for (i, field) in schema.fields().iter().enumerate() {
+            if !targets.contains(&i) && !field.is_nullable() {
+                return Err(Error::Parse(format!(
+                    "column {} is not nullable and must be specified",
+                    field.name()
+                )));
+            }
+        }
+        let source_position = |i: usize| targets.iter().position(|&t| t == i);
+
+        // VALUES are converted to the table types while planning
+        if let (ast::SetExpr::Values(values), true, None, None) = (
+            &source.body,
+            source.order_by.is_empty(),
+            &source.limit,
+            &source.offset,
+        ) {
+            let rows = self.constant_rows(values)?;
+            if let Some(row) = rows.first() {
+                if row.len() != targets.len() {
+                    return Err(Error::Parse(format!(
+                        "INSERT has {} target columns but {} values",
+                        targets.len(),
+                        row.len()
+                    )));
+                }
+            }
+            let rows = rows
+                .into_iter()
+                .map(|row| {
+                    (0..schema.fields().len())
+                        .map(|i| match source_position(i) {
+                            Some(position) => row[position].clone(),
+                            None => ScalarValue::Boolean(None),
+                        })
+                        .collect()
+                })
+                .collect();
+            let block = Self::build_block(schema, rows)?;
+            return Ok(PlanType::Insert(InsertPlan {
+                table: table.to_string(),
+                source_plan: Box::new(PlanType::RawInput(RawInput::new(block))),
+            }));
+        }
+
+        let relation = self.plan_query(source)?;
+        let source_schema = relation.scope.schema();
+        if source_schema.fields().len() != targets.len() {
+            return Err(Error::Parse(format!(
+                "INSERT has {} target columns but the query returns {}",
+                targets.len(),
+                source_schema.fields().len()
+            )));
+        }
+        if source_schema.fields() == schema.fields() {
+            return Ok(PlanType::Insert(InsertPlan {
+                table: table.to_string(),
+                source_plan: Box::new(relation.plan),
+            }));
+        }
+        let mut exprs = Vec::with_capacity(schema.fields().len());
+        for (i, field) in schema.fields().iter().enumerate() {
+            let expr = match source_position(i) {
+                Some(position) => {
+                    let source_field = source_schema.field(position);
+                    let col = Expr::column(source_field.name(), position);
+                    if source_field.data_type() == field.data_type() {
+                        col
+                    } else {
+                        Expr::Cast {
+                            expr: Box::new(col),
+                            data_type: field.data_type().clone(),
+                        }
+                    }
+                }
+                None => Expr::Literal(cast_scalar(ScalarValue::Boolean(None), field.data_type())?),
+            };
+            exprs.push(expr);
+        }
+        Ok(PlanType::Insert(InsertPlan {
+            table: table.to_string(),
+            source_plan: Box::new(PlanType::Projection(ProjectionPlan {
+                exprs,
+                schema,
+                source_plan: Box::new(relation.plan),
+            })),
+        }))
+    }
+
+    fn plan_create_index(
+        &self,
+        index: &str,
+        table: &str,
+        columns: &[ast::OrderByExpr],
+        if_not_exists: bool,
+    ) -> SqlResult<PlanType> {
+        let schema = self.table_schema(table)?;
+        let mut names = Vec::with_capacity(columns.len());
+        for column in columns {
+            let name = match (&column.expr, column.asc, column.nulls_first) {
+                (ast::Expr::Identifier(ident), None | Some(true), None) => ident.value.clone(),
+                _ => return unsupported(format!("index on {}", column)),
+            };
+            if schema.field_with_name(&name).is_err() {
+                return Err(Error::Parse(format!(
+                    "column {} of table {} does not exist",
+                    name, table
+                )));
+            }
+            names.push(name);
+        }
+        Ok(PlanType::CreateIndex(CreateIndexPlan {
+            index: index.to_string(),
+            table: table.to_string(),
+            columns: names,
+            if_not_exists,
+        }))
+    }
+
+    /// Index of the table matching the most conjuncts of predicate, either equalities on
+    /// its first columns or a range on the column after them. The whole predicate is
+    /// still evaluated on the fetched rows
+    fn choose_index(
+        &self,
+        table: &str,
+        predicate: &Expr,
+    ) -> SqlResult<Option<(String, IndexRange)>> {
+        let schema = self.table_schema(table)?;
+        let mut conjuncts = vec![];
+        split_expr_conjunction(predicate, &mut conjuncts);
+        let bounds = conjuncts
+            .into_iter()
+            .filter_map(|conjunct| column_bound(conjunct, &schema))
+            .collect::<Vec<_>>();
+        if bounds.is_empty() {
+            return Ok(None);
+        }
+
+        let mut best: Option<(usize, String, IndexRange)> = None;
+        for index in self.catalog.list_indexes(table)? {
+            let mut range = IndexRange::point(vec![]);
+            for column in &index.columns {
+                let column = schema.index_of(column)?;
+                let column_bounds = bounds.iter().filter(|(c, _, _)| *c == column);
+                let eq = column_bounds
+                    .clone()
+                    .find(|(_, op, _)| *op == BinaryOperator::Eq);
+                if let Some((_, _, value)) = eq {
+                    range.eq.push(value.clone());
+                    continue;
+                }
+                for (_, op, value) in column_bounds {
+                    match op {
+                        BinaryOperator::Gt => range.lower = Bound::Excluded(value.clone()),
+                        BinaryOperator::GtEq => range.lower = Bound::Included(value.clone()),
+                        BinaryOperator::Lt => range.upper = Bound::Excluded(value.clone()),
+                        BinaryOperator::LtEq => range.upper = Bound::Included(value.clone()),
+                        _ => {}
+                    }
+                }
+                break;
+            }
+            let bounded = range.lower != Bound::Unbounded || range.upper != Bound::Unbounded;
+            let score = range.eq.len() * 2 + bounded as usize;
+            if score > best.as_ref().map_or(0, |(best, _, _)| *best) {
+                best = Some((score, index.name, range));
+            }
+        }
+        Ok(best.map(|(_, index, range)| (index, range)))
+    }
+
+    /// Scan of the rows matching selection, each followed by its RID. The scope only
+    /// covers the columns of the table, the RID is the column after them
+    fn plan_rid_scan(
+        &self,
+        table: &str,
+        qualifier: &str,
+        selection: Option<&ast::Expr>,
+    ) -> SqlResult<Relation> {
+        let schema = self.table_schema(table)?;
+        let scope = Scope::from_schema(Some(qualifier), &schema);
+        let predicate = match selection {
+            Some(selection) => {
+                let planner = ExprPlanner::new(&scope);
+                Some(planner.boolean(planner.plan(selection)?)?)
+            }
+            None => None,
+        };
+        Ok(Relation {
+            plan: PlanType::SeqScan(SeqScanPlan {
+                table: table.to_string(),
+                predicate,
+                with_rids: true,
+            }),
+            scope,
+        })
+    }
+
+    fn plan_delete(&self, table: &str, selection: Option<&ast::Expr>) -> SqlResult<PlanType> {
+        let relation = self.plan_rid_scan(table, table, selection)?;
+        let rid_index = relation.scope.schema().fields().len();
+        Ok(PlanType::Delete(DeletePlan {
+            table: table.to_string(),
+            source_plan: Box::new(PlanType::Projection(ProjectionPlan {
+                exprs: vec![Expr::column(RID_COLUMN, rid_index)],
+                schema: rid_schema(),
+                source_plan: Box::new(relation.plan),
+            })),
+        }))
+    }
+
+    /// Every column of the table is projected, either to its assigned value or to its
+    /// current value, followed by the RID
+    fn plan_update(
+        &self,
+        table: &ast::TableWithJoins,
+        assignments: &[ast::Assignment],
+        selection: Option<&ast::Expr>,
+    ) -> SqlResult<PlanType> {
+        let (table, qualifier) = match &table.relation {
+            ast::TableFactor::Table { name, alias, .. } if table.joins.is_empty() => {
+                let table = name.to_string();
+                let qualifier = alias
+                    .as_ref()
+                    .map_or(table.clone(), |a| a.name.value.clone());
+                (table, qualifier)
+            }
+            _ => return unsupported(format!("UPDATE of {}", table)),
+        };
+        let relation = self.plan_rid_scan(&table, &qualifier, selection)?;
+        let input_schema = relation.scope.schema();
+        let mut exprs = input_schema
+            .fields()
+            .iter()
+            .enumerate()
+            .map(|(i, field)| Expr::column(field.name(), i))
+            .collect::<Vec<_>>();
+        let mut assigned = vec![false; exprs.len()];
+        let planner = ExprPlanner::new(&relation.scope);
+        for assignment in assignments {
+            let col = match assignment.id.as_slice() {
+                [name] => relation.scope.resolve(None, &name.value)?,
+                [q, name] => relation.scope.resolve(Some(&q.value), &name.value)?,
+                _ => return unsupported(format!("assignment to {:?}", assignment.id)),
+            };
+            if assigned[col.index()] {
+                return Err(Error::Parse(format!(
+                    "column {} is assigned more than once",
+                    col.name()
+                )));
+            }
+            assigned[col.index()] = true;
+            let field = input_schema.field(col.index());
+            let value = planner.plan(&assignment.value)?;
+            exprs[col.index()] = assigned_value(value, field, &input_schema)?;
+        }
+        exprs.push(Expr::column(RID_COLUMN, exprs.len()));
+        Ok(PlanType::Update(UpdatePlan {
+            table: table.to_string(),
+            source_plan: Box::new(PlanType::Projection(ProjectionPlan {
+                exprs,
+                schema: with_rid_schema(&input_schema),
+                source_plan: Box::new(relation.plan),
+            })),
+        }))
+    }
+}
+
+fn split_expr_conjunction<'a>(expr: &'a Expr, conjuncts: &mut Vec<&'a Expr>) {
+    match expr {
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::And,
+            right,
+        } => {
+            split_expr_conjunction(left, conjuncts);
+            split_expr_conjunction(right, conjuncts);
+        }
+        other => conjuncts.push(other),
+    }
+}
+
+/// Comparison of a column with a non null literal of the same type, as the column
+/// index, the operator with the column on the left and the literal
+fn column_bound(expr: &Expr, schema: &Schema) -> Option<(usize, BinaryOperator, ScalarValue)> {
+    let (column, op, value) = match expr {
+        Expr::BinaryOp { left, op, right } => match (left.as_ref(), right.as_ref()) {
+            (Expr::Column(col), Expr::Literal(value)) => (col, *op, value),
+            (Expr::Literal(value), Expr::Column(col)) => {
+                let op = match op {
+                    BinaryOperator::Lt => BinaryOperator::Gt,
+                    BinaryOperator::LtEq => BinaryOperator::GtEq,
+                    BinaryOperator::Gt => BinaryOperator::Lt,
+                    BinaryOperator::GtEq => BinaryOperator::LtEq,
+                    op => *op,
+                };
+                (col, op, value)
+            }
+            _ => return None,
+        },
+        _ => return None,
+    };
+    let indexable = matches!(
+        op,
+        BinaryOperator::Eq
+            | BinaryOperator::Lt
+            | BinaryOperator::LtEq
+            | BinaryOperator::Gt
+            | BinaryOperator::GtEq
+    );
+    let data_type = schema.field(column.index()).data_type();
+    if !indexable || value.is_null() || &value.get_datatype() != data_type {
+        return None;
+    }
+    Some((column.index(), op, value.clone()))
+}
+
+/// Value converted to the type of the assigned column
+fn assigned_value(value: Expr, field: &Field, input: &Schema) -> SqlResult<Expr> {
+    let data_type = value.data_type(input)?;
+    let nullable = value.nullable(input)?;
+    let value = match value {
+        _ if &data_type == field.data_type() => value,
+        Expr::Literal(value) => Expr::Literal(cast_scalar(value, field.data_type())?),
+        value if common_type(&data_type, field.data_type()).is_some() => Expr::Cast {
+            expr: Box::new(value),
+            data_type: field.data_type().clone(),
+        },
+        _ => {
+            return Err(Error::Parse(format!(
+                "cannot assign a value of type {} to column {} of type {}",
+                data_type,
+                field.name(),
+                field.data_type()
+            )))
+        }
+    };
+    if nullable && !field.is_nullable() {
+        return Err(Error::Parse(format!(
+            "column {} is not nullable and cannot be assigned a nullable value",
+            field.name()
+        )));
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::Planner;
+    use crate::sql::{
+        exe::{Executor, PlanType},
+        expr::{BinaryOperator, Expr},
+        scan::index::IndexRange,
+        util::collect,
+        DataBlock, Error, ExecutionContext,
+    };
+    use datafusion::{
+        arrow::{
+            array::{Array, Int32Array},
+            datatypes::{DataType, Field, Schema},
+            record_batch::RecordBatch,
+        },
+        physical_plan::expressions::Column,
+        scalar::ScalarValue,
+    };
+    use std::ops::Bound;
+
+    fn setup() -> ExecutionContext {
+        let ctx = ExecutionContext::new_for_test();
+        let storage = ctx.get_storage();
+        storage
+            .create_table(
+                "t",
+                Schema::new(vec![
+                    Field::new("a", DataType::Int32, false),
+                    Field::new("b", DataType::Utf8, true),
+                ]),
+            )
+            .unwrap();
+        storage
+            .create_table(
+                "u",
+                Schema::new(vec![
+                    Field::new("a", DataType::Int32, false),
+                    Field::new("c", DataType::Int64, false),
+                ]),
+            )
+            .unwrap();
+        run(&ctx, "INSERT INTO t VALUES (1, 'x'), (2, NULL), (3, 'x')");
+        run(
+            &ctx,
+            "INSERT INTO u (c, a) VALUES (10, 1), (30, 3), (40, 4)",
+        );
+        ctx
+    }
+
+    fn run(ctx: &ExecutionContext, sql: &str) -> Vec<DataBlock> {
+        let stream = Executor::execute_sql(sql, ctx.clone()).expect(sql);
+        collect(stream).expect(sql)
+    }
+
+    fn plan(ctx: &ExecutionContext, sql: &str) -> PlanType {
+        Planner::new(ctx.get_storage()).plan(sql).expect(sql)
+    }
+
+    fn plan_err(ctx: &ExecutionContext, sql: &str) -> String {
+        match Planner::new(ctx.get_storage()).plan(sql) {
+            Err(Error::Parse(msg)) => msg,
+            Err(other) => panic!("{}: unexpected error {:?}", sql, other),
+            Ok(_) => panic!("{}: expected an error", sql),
+        }
+    }
+
+    #[test]
+    fn test_insert_select() {
+        let ctx = setup();
+        let batches = run(&ctx, "INSERT INTO t (a) VALUES (4)");
+        assert_eq!(1, batches[0].num_rows());
+        let expected = [
+            "+---+---+",
+            "| a | b |",
+            "+---+---+",
+            "| 1 | x |",
+            "| 2 |   |",
+            "| 3 | x |",
+            "| 4 |   |",
+            "+---+---+",
+        ];
+        crate::assert_batches_sorted_eq!(expected, &run(&ctx, "SELECT * FROM t"));
+        let expected = [
+            "+---+----+",
+            "| a | c  |",
+            "+---+----+",
+            "| 1 | 10 |",
+            "| 3 | 30 |",
+            "| 4 | 40 |",
+            "+---+----+",
+        ];
+        crate::assert_batches_sorted_eq!(expected, &run(&ctx, "SELECT u.* FROM u"));
+
+        run(&ctx, "INSERT INTO u SELECT a, c FROM u");
+        let expected = [
+            "+---+----+",
+            "| a | c  |",
+            "+---+----+",
+            "| 1 | 10 |",
+            "| 1 | 10 |",
+            "| 3 | 30 |",
+            "| 3 | 30 |",
+            "| 4 | 40 |",
+            "| 4 | 40 |",
+            "+---+----+",
+        ];
+        crate::assert_batches_sorted_eq!(expected, &run(&ctx, "SELECT * FROM u"));
+    }
+
+    #[test]
+    fn test_where_projection_limit() {
+        let ctx = setup();
+        let expected = [
+            "+---+-------+",
+            "| a | twice |",
+            "+---+-------+",
+            "| 1 | 2     |",
+            "| 3 | 6     |",
+            "+---+-------+",
+        ];
+        let batches = run(&ctx, "SELECT a, a * 2 AS twice FROM t WHERE b = 'x'");
+        crate::assert_batches_sorted_eq!(expected, &batches);
+        let batches = run(&ctx, "SELECT a FROM t WHERE a > 1 LIMIT 1");
+        assert_eq!(1, batches.iter().map(|b| b.num_rows()).sum::<usize>());
+    }
+
+    #[test]
+    fn test_join() {
+        let ctx = setup();
+        let expected = [
+            "+---+---+---+----+",
+            "| a | b | a | c  |",
+            "+---+---+---+----+",
+            "| 1 | x | 1 | 10 |",
+            "| 3 | x | 3 | 30 |",
+            "+---+---+---+----+",
+        ];
+        let batches = run(&ctx, "SELECT * FROM t JOIN u ON t.a = u.a");
+        crate::assert_batches_sorted_eq!(expected, &batches);
+        let batches = run(&ctx, "SELECT * FROM t AS l JOIN u AS r ON r.a = l.a");
+        crate::assert_batches_sorted_eq!(expected, &batches);
+
+        let expected = [
+            "+---+---+---+----+",
+            "| a | b | a | c  |",
+            "+---+---+---+----+",
+            "| 1 | x | 1 | 10 |",
+            "| 2 |   |   |    |",
+            "| 3 | x | 3 | 30 |",
+            "+---+---+---+----+",
+        ];
+        let batches = run(&ctx, "SELECT * FROM t LEFT JOIN u USING (a)");
+        crate::assert_batches_sorted_eq!(expected, &batches);
+
+        // every left row is kept when the right table is empty
+        ctx.get_storage()
+            .create_table(
+                "e",
+                Schema::new(vec![Field::new("a", DataType::Int32, false)]),
+            )
+            .unwrap();
+        let expected = [
+            "+---+---+---+",
+            "| a | b | a |",
+            "+---+---+---+",
+            "| 1 | x |   |",
+            "| 2 |   |   |",
+            "| 3 | x |   |",
+            "+---+---+---+",
+        ];
+        let batches = run(&ctx, "SELECT * FROM t LEFT JOIN e ON t.a = e.a");
+        crate::assert_batches_sorted_eq!(expected, &batches);
+    }
+
+    #[test]
+    fn test_nested_loop_join() {
+        let ctx = setup();
+        let expected = [
+            "+---+----+",
+            "| a | c  |",
+            "+---+----+",
+            "| 1 |    |",
+            "| 2 | 10 |",
+            "| 3 | 10 |",
+            "+---+----+",
+        ];
+        let batches = run(&ctx, "SELECT t.a, u.c FROM t LEFT JOIN u ON t.a > u.a");
+        crate::assert_batches_sorted_eq!(expected, &batches);
+
+        let expected = [
+            "+---+----+",
+            "| a | c  |",
+            "+---+----+",
+            "| 1 |    |",
+            "| 2 |    |",
+            "| 3 | 30 |",
+            "+---+----+",
+        ];
+        let batches = run(
+            &ctx,
+            "SELECT t.a, u.c FROM t LEFT JOIN u ON t.a = u.a AND u.c > 10",
+        );
+        crate::assert_batches_sorted_eq!(expected, &batches);
+
+        let expected = [
+            "+----------+",
+            "| COUNT(*) |",
+            "+----------+",
+            "| 9        |",
+            "+----------+",
+        ];
+        let batches = run(&ctx, "SELECT COUNT(*) FROM t, u");
+        crate::assert_batches_sorted_eq!(expected, &batches);
+        let batches = run(&ctx, "SELECT COUNT(*) FROM t CROSS JOIN u");
+        crate::assert_batches_sorted_eq!(expected, &batches);
+    }
+
+    #[test]
+    fn test_update_delete() {
+        let ctx = setup();
+        let expected = [
+            "+-------+",
+            "| count |",
+            "+-------+",
+            "| 2     |",
+            "+-------+",
+        ];
+        let batches = run(&ctx, "UPDATE t SET a = a * 10, b = 'y' WHERE b = 'x'");
+        crate::assert_batches_sorted_eq!(expected, &batches);
+        let batches = run(&ctx, "SELECT * FROM t");
+        let expected = [
+            "+----+---+",
+            "| a  | b |",
+            "+----+---+",
+            "| 10 | y |",
+            "| 2  |   |",
+            "| 30 | y |",
+            "+----+---+",
+        ];
+        crate::assert_batches_sorted_eq!(expected, &batches);
+
+        let batches = run(&ctx, "UPDATE u AS r SET c = c + 1, a = r.c WHERE r.a > 3");
+        let expected = [
+            "+-------+",
+            "| count |",
+            "+-------+",
+            "| 1     |",
+            "+-------+",
+        ];
+        crate::assert_batches_sorted_eq!(expected, &batches);
+        let batches = run(&ctx, "SELECT * FROM u WHERE c > 40");
+        let expected = [
+            "+----+----+",
+            "| a  | c  |",
+            "+----+----+",
+            "| 40 | 41 |",
+            "+----+----+",
+        ];
+        crate::assert_batches_sorted_eq!(expected, &batches);
+
+        let batches = run(&ctx, "DELETE FROM t WHERE a > 5");
+        let expected = [
+            "+-------+",
+            "| count |",
+            "+-------+",
+            "| 2     |",
+            "+-------+",
+        ];
+        crate::assert_batches_sorted_eq!(expected, &batches);
+        let batches = run(&ctx, "DELETE FROM u");
+        let expected = [
+            "+-------+",
+            "| count |",
+            "+-------+",
+            "| 3     |",
+            "+-------+",
+        ];
+        crate::assert_batches_sorted_eq!(expected, &batches);
+        let batches = run(&ctx, "SELECT * FROM t");
+        let expected = [
+            "+---+---+",
+            "| a | b |",
+            "+---+---+",
+            "| 2 |   |",
+            "+---+---+",
+        ];
+        crate::assert_batches_sorted_eq!(expected, &batches);
+    }
+
+    #[test]
+    fn test_index_scan() {
+        let ctx = setup();
+        assert!(run(&ctx, "CREATE INDEX t_a ON t (a)").is_empty());
+        run(&ctx, "CREATE INDEX IF NOT EXISTS t_a ON t (b)");
+        run(&ctx, "CREATE INDEX t_b_a ON t (b, a)");
+
+        match plan(&ctx, "SELECT * FROM t WHERE 2 <= a AND b IS NULL") {
+            PlanType::IndexScan(scan) => {
+                assert_eq!("t_a", scan.index);
+                assert_eq!(
+                    IndexRange {
+                        eq: vec![],
+                        lower: Bound::Included(ScalarValue::Int32(Some(2))),
+                        upper: Bound::Unbounded,
+                    },
+                    scan.range
+                );
+            }
+            _ => panic!("expected an index scan"),
+        }
+        match plan(&ctx, "SELECT * FROM t WHERE a < 3 AND b = 'x'") {
+            PlanType::IndexScan(scan) => {
+                assert_eq!("t_b_a", scan.index);
+                assert_eq!(
+                    IndexRange {
+                        eq: vec![ScalarValue::Utf8(Some("x".to_string()))],
+                        lower: Bound::Unbounded,
+                        upper: Bound::Excluded(ScalarValue::Int32(Some(3))),
+                    },
+                    scan.range
+                );
+            }
+            _ => panic!("expected an index scan"),
+        }
+        assert!(matches!(
+            plan(&ctx, "SELECT * FROM t WHERE a + 1 = 2 OR a = 3"),
+            PlanType::SeqScan(_)
+        ));
+
+        let expected = [
+            "+---+---+",
+            "| a | b |",
+            "+---+---+",
+            "| 2 |   |",
+            "+---+---+",
+        ];
+        let batches = run(&ctx, "SELECT * FROM t WHERE 2 <= a AND b IS NULL");
+        crate::assert_batches_sorted_eq!(expected, &batches);
+
+        // the index follows modified rows
+        run(&ctx, "UPDATE t SET a = 5 WHERE a = 1");
+        run(&ctx, "DELETE FROM t WHERE a = 3");
+        run(&ctx, "INSERT INTO t VALUES (4, 'x')");
+        let expected = [
+            "+---+---+",
+            "| a | b |",
+            "+---+---+",
+            "| 4 | x |",
+            "| 5 | x |",
+            "+---+---+",
+        ];
+        let batches = run(&ctx, "SELECT * FROM t WHERE b = 'x' AND a > 2");
+        crate::assert_batches_sorted_eq!(expected, &batches);
+        let batches = run(&ctx, "SELECT * FROM t WHERE a BETWEEN 3 AND 9");
+        crate::assert_batches_sorted_eq!(expected, &batches);
+    }
+
+    #[test]
+    fn test_index_scan_floats() {
+        let ctx = setup();
+        ctx.get_storage()
+            .create_table(
+                "f",
+                Schema::new(vec![Field::new("x", DataType::Float64, true)]),
+            )
+            .unwrap();
+        run(
+            &ctx,
+            "INSERT INTO f VALUES (-0.0), (0.0), (1.0), (-1.0), (CAST('NaN' AS DOUBLE)), (NULL)",
+        );
+        let queries = [
+            "SELECT * FROM f WHERE x = 0.0",
+            "SELECT * FROM f WHERE x = -0.0",
+            "SELECT * FROM f WHERE x >= 0.0",
+            "SELECT * FROM f WHERE x > -0.0",
+            "SELECT * FROM f WHERE x <= -0.0",
+            "SELECT * FROM f WHERE x < 0.0",
+            "SELECT * FROM f WHERE x > 0.5",
+            "SELECT * FROM f WHERE x < 2.0",
+        ];
+        let seq_scans = queries
+            .iter()
+            .map(|sql| {
+                assert!(matches!(plan(&ctx, sql), PlanType::SeqScan(_)));
+                run(&ctx, sql)
+            })
+            .collect::<Vec<_>>();
+        run(&ctx, "CREATE INDEX f_x ON f (x)");
+        for (sql, seq_scan) in queries.iter().zip(seq_scans) {
+            assert!(matches!(plan(&ctx, sql), PlanType::IndexScan(_)), "{}", sql);
+            let expected = datafusion::arrow::util::pretty::pretty_format_batches(&seq_scan)
+                .unwrap()
+                .to_string();
+            let mut expected = expected.lines().collect::<Vec<_>>();
+            let index_scan =
+                datafusion::arrow::util::pretty::pretty_format_batches(&run(&ctx, sql))
+                    .unwrap()
+                    .to_string();
+            let mut got = index_scan.lines().collect::<Vec<_>>();
+            expected.sort_unstable();
+            got.sort_unstable();
+            assert_eq!(expected, got, "{}", sql);
+        }
+        let batches = run(&ctx, "SELECT * FROM f WHERE x >= 0.0");
+        assert_eq!(3, batches.iter().map(|b| b.num_rows()).sum::<usize>());
+    }
+
+    #[test]
+    fn test_aggregate_order_by_limit() {
+        let ctx = setup();
+        let expected = [
+            "+---+----------+--------+",
+            "| b | COUNT(*) | MAX(a) |",
+            "+---+----------+--------+",
+            "|   | 1        | 2      |",
+            "| x | 2        | 3      |",
+            "+---+----------+--------+",
+        ];
+        let batches = run(&ctx, "SELECT b, COUNT(*), MAX(a) FROM t GROUP BY b");
+        crate::assert_batches_sorted_eq!(expected, &batches);
+
+        let batches = run(&ctx, "SELECT * FROM t ORDER BY a DESC LIMIT 2");
+        let a = RecordBatch::concat(&batches[0].schema(), &batches).unwrap();
+        let a = a.column(0).as_any().downcast_ref::<Int32Array>().unwrap();
+        assert_eq!(vec![Some(3), Some(2)], a.iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_filter_projection() {
+        let ctx = setup();
+        let expected = [
+            "+---+-------+--------+",
+            "| a | twice | b_is_x |",
+            "+---+-------+--------+",
+            "| 1 | 2     | true   |",
+            "| 3 | 6     | true   |",
+            "+---+-------+--------+",
+        ];
+        let batches = run(
+            &ctx,
+            "SELECT a, a * 2 AS twice, b = 'x' AS b_is_x FROM t WHERE b LIKE 'x%' OR a > 5",
+        );
+        crate::assert_batches_sorted_eq!(expected, &batches);
+
+        // filter above a join and a projection of aggregates
+        let expected = [
+            "+---+----+-----------+",
+            "| a | c  | c_minus_a |",
+            "+---+----+-----------+",
+            "| 3 | 30 | 27        |",
+            "+---+----+-----------+",
+        ];
+        let batches = run(
+            &ctx,
+            "SELECT t.a, c, c - t.a AS c_minus_a FROM t JOIN u ON t.a = u.a \
+             WHERE c BETWEEN 20 AND 30 AND b IS NOT NULL",
+        );
+        crate::assert_batches_sorted_eq!(expected, &batches);
+        let expected = [
+            "+-------+---+",
+            "| total | b |",
+            "+-------+---+",
+            "| 4     | x |",
+            "+-------+---+",
+        ];
+        let batches = run(
+            &ctx,
+            "SELECT SUM(a) AS total, b FROM t GROUP BY b HAVING COUNT(*) > 1",
+        );
+        crate::assert_batches_sorted_eq!(expected, &batches);
+
+        let batches = run(
+            &ctx,
+            "INSERT INTO u SELECT a + 10, a FROM t WHERE a IN (1, 2)",
+        );
+        assert_eq!(1, batches[0].num_rows());
+        let expected = [
+            "+----+---+",
+            "| a  | c |",
+            "+----+---+",
+            "| 11 | 1 |",
+            "| 12 | 2 |",
+            "+----+---+",
+        ];
+        let batches = run(&ctx, "SELECT * FROM u WHERE a > 10");
+        crate::assert_batches_sorted_eq!(expected, &batches);
+    }
+
+    #[test]
+    fn test_distinct_limit() {
+        let ctx = setup();
+        let expected = ["+---+", "| b |", "+---+", "|   |", "| x |", "+---+"];
+        crate::assert_batches_sorted_eq!(expected, &run(&ctx, "SELECT DISTINCT b FROM t"));
+        let expected = [
+            "+---+---+",
+            "| a | b |",
+            "+---+---+",
+            "| 2 |   |",
+            "+---+---+",
+        ];
+        let batches = run(&ctx, "SELECT * FROM t ORDER BY a LIMIT 1 OFFSET 1");
+        crate::assert_batches_sorted_eq!(expected, &batches);
+        let batches = run(&ctx, "SELECT a FROM t LIMIT 2");
+        assert_eq!(2, batches.iter().map(|b| b.num_rows()).sum::<usize>());
+    }
+
+    #[test]
+    fn test_plan_tree() {
+        let ctx = setup();
+        let projection = plan(&ctx, "SELECT a + 1 AS c FROM t WHERE b = 'x' AND a > -1");
+        let (exprs, schema, scan) = match projection {
+            PlanType::Projection(p) => (p.exprs, p.schema, *p.source_plan),
+            _ => panic!("expected a projection"),
+        };
+        // literals take the type of the column they are compared with
+        let a = Expr::column("a", 0);
+        assert_eq!(
+            vec![Expr::binary(
+                a.clone(),
+                BinaryOperator::Plus,
+                Expr::Literal(ScalarValue::Int32(Some(1)))
+            )],
+            exprs
+        );
+        assert_eq!(&Field::new("c", DataType::Int32, false), schema.field(0));
+        // the predicate is pushed into the scan
+        let (table, predicate) = match scan {
+            PlanType::SeqScan(scan) => (scan.table, scan.predicate),
+            _ => panic!("expected a scan"),
+        };
+        assert_eq!("t", table);
+        let expected = Expr::binary(
+            Expr::binary(
+                Expr::column("b", 1),
+                BinaryOperator::Eq,
+                Expr::Literal(ScalarValue::Utf8(Some("x".to_string()))),
+            ),
+            BinaryOperator::And,
+            Expr::binary(
+                a,
+                BinaryOperator::Gt,
+                Expr::Literal(ScalarValue::Int32(Some(-1))),
+            ),
+        );
+        assert_eq!(Some(expected), predicate);
+
+        // a value that does not fit into the column type widens the column instead
+        match plan(&ctx, "SELECT * FROM t WHERE a IN (1, 3000000000)") {
+            PlanType::SeqScan(scan) => match scan.predicate.unwrap() {
+                Expr::InList { expr, .. } => assert_eq!(
+                    Expr::Cast {
+                        expr: Box::new(Expr::column("a", 0)),
+                        data_type: DataType::Int64
+                    },
+                    *expr
+                ),
+                other => panic!("unexpected {:?}", other),
+            },
+            _ => panic!("expected a scan"),
+        }
+
+        match plan(&ctx, "SELECT * FROM t ORDER BY a LIMIT 2 OFFSET 1") {
+            PlanType::Limit(limit) => {
+                assert_eq!((Some(2), 1), (limit.limit, limit.offset));
+                assert!(
+                    matches!(*limit.source_plan, PlanType::Sort(sort) if sort.limit == Some(3))
+                );
+            }
+            _ => panic!("expected a limit"),
+        }
+
+        match plan(&ctx, "SELECT b, COUNT(*) FROM t GROUP BY b ORDER BY 2 DESC") {
+            PlanType::Sort(sort) => {
+                assert_eq!(1, sort.order_by.len());
+                let key = &sort.order_by[0];
+                assert_eq!(Column::new("COUNT(*)", 1), key.column);
+                assert!(key.descending && key.nulls_first);
+                match *sort.source_plan {
+                    PlanType::Aggregation(agg) => {
+                        assert_eq!(vec![Column::new("b", 1)], agg.group_by);
+                        assert_eq!(1, agg.aggregates.len());
+                        assert!(agg.aggregates[0].arg.is_none());
+                        assert!(matches!(*agg.source_plan, PlanType::SeqScan(s) if s.table == "t"));
+                    }
+                    _ => panic!("expected an aggregation"),
+                }
+            }
+            _ => panic!("expected a sort"),
+        }
+
+        match plan(&ctx, "SELECT * FROM t JOIN u ON u.a = t.a") {
+            PlanType::GraceHashJoin(join) => {
+                assert_eq!(vec![Column::new("a", 0)], join.on_left);
+                assert_eq!(vec![Column::new("a", 0)], join.on_right);
+                assert!(matches!(*join.left_plan, PlanType::SeqScan(s) if s.table == "t"));
+                assert!(matches!(*join.right_plan, PlanType::SeqScan(s) if s.table == "u"));
+            }
+            _ => panic!("expected a join"),
+        }
+    }
+
+    #[test]
+    fn test_plan_errors() {
+        let ctx = setup();
+        assert!(plan_err(&ctx, "SELEC * FROM t").contains("Expected"));
+        assert_eq!("table v does not exist", plan_err(&ctx, "SELECT * FROM v"));
+        assert_eq!("column d does not exist", plan_err(&ctx, "SELECT d FROM t"));
+        assert_eq!(
+            "column reference a is ambiguous",
+            plan_err(&ctx, "SELECT a FROM t JOIN u ON t.a = u.a")
+        );
+        assert_eq!(
+            "column a must appear in the GROUP BY clause or be used in an aggregate function",
+            plan_err(&ctx, "SELECT a, COUNT(*) FROM t GROUP BY b")
+        );
+        assert_eq!(
+            "cannot compare values of type Int32 and Utf8",
+            plan_err(&ctx, "SELECT * FROM t WHERE a = b")
+        );
+        assert_eq!(
+            "column a is not nullable and must be specified",
+            plan_err(&ctx, "INSERT INTO t (b) VALUES ('y')")
+        );
+        assert_eq!(
+            "join keys a and c have different types Int32 and Int64",
+            plan_err(&ctx, "SELECT * FROM t JOIN u ON t.a = u.c")
+        );
+        assert_eq!(
+            "column d of table t does not exist",
+            plan_err(&ctx, "CREATE INDEX t_d ON t (d)")
+        );
+        assert_eq!(
+            "column a is not nullable and cannot be assigned a nullable value",
+            plan_err(&ctx, "UPDATE t SET a = NULL")
+        );
+        assert_eq!(
+            "cannot assign a value of type Utf8 to column a of type Int32",
+            plan_err(&ctx, "UPDATE t SET a = b WHERE b IS NOT NULL")
+        );
+    }
+}
@@ -0,0 +1,54 @@
+use crate::sql::{
+    exe::{BoxedDataIter, Executor, Operator, PlanType, SchemaDataIter},
+    expr::Expr,
+    DataBlock, ExecutionContext, SqlResult,
+};
+use datafusion::arrow::datatypes::SchemaRef;
+
+/// Field i of schema is the result of exprs[i] evaluated against the rows of the child
+pub struct ProjectionPlan {
+    pub exprs: Vec<Expr>,
+    pub schema: SchemaRef,
+    pub source_plan: Box<PlanType>,
+}
+
+#[derive(Debug)]
+pub struct Projection {
+    exprs: Vec<Expr>,
+    schema: SchemaRef,
+    input: Box<dyn Operator>,
+}
+
+impl Projection {
+    pub fn from_plan(plan: ProjectionPlan, ctx: ExecutionContext) -> SqlResult<Self> {
+        let input = Executor::create_from_subplan_operator(*plan.source_plan, ctx)?;
+        Ok(Projection {
+            exprs: plan.exprs,
+            schema: plan.schema,
+            input,
+        })
+    }
+
+    fn project(batch: &DataBlock, exprs: &[Expr], schema: &SchemaRef) -> SqlResult<DataBlock> {
+        let columns = exprs
+            .iter()
+            .map(|expr| expr.evaluate(batch))
+            .collect::<SqlResult<Vec<_>>>()?;
+        Ok(DataBlock::try_new(schema.clone(), columns)?)
+    }
+}
+
+impl Operator for Projection {
+    fn execute_sync(&mut self, ctx: ExecutionContext) -> SqlResult<BoxedDataIter> {
+        let input = self.input.execute_sync(ctx)?;
+        let exprs = self.exprs.clone();
+        let schema = self.schema.clone();
+        let projected =
+            input.map(move |batch| batch.and_then(|batch| Self::project(&batch, &exprs, &schema)));
+        Ok(SchemaDataIter::new(self.schema(), Box::new(projected)))
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+}
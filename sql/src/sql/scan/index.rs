@@ -0,0 +1,150 @@
+use crate::{
+    sql::{
+        exe::{BoxedDataIter, Operator},
+        expr::Expr,
+        filter::filter_stream,
+        ExecutionContext, SqlResult,
+    },
+    storage::keycode::{canonical, encode_scalar, prefix_end, Order, NOT_NULL},
+};
+use datafusion::{arrow::datatypes::SchemaRef, scalar::ScalarValue};
+use std::ops::Bound;
+
+/// Rows whose first indexed columns are equal to `eq` and whose next indexed column is
+/// between `lower` and `upper`. Values have the types of the indexed columns
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexRange {
+    pub eq: Vec<ScalarValue>,
+    pub lower: Bound<ScalarValue>,
+    pub upper: Bound<ScalarValue>,
+}
+
+impl IndexRange {
+    pub fn point(values: Vec<ScalarValue>) -> Self {
+        IndexRange {
+            eq: values,
+            lower: Bound::Unbounded,
+            upper: Bound::Unbounded,
+        }
+    }
+
+    /// Start (inclusive) and end (exclusive) of the encoded index keys in the range,
+    /// see [crate::storage::keycode]. Null values of the bounded column are excluded.
+    /// Values are made canonical as the keys of the index entries are
+    pub fn key_range(&self) -> SqlResult<(Vec<u8>, Option<Vec<u8>>)> {
+        let mut prefix = vec![];
+        for value in &self.eq {
+            encode_scalar(&canonical(value.clone()), Order::Asc, &mut prefix)?;
+        }
+        let with_bound = |value: &ScalarValue| -> SqlResult<Vec<u8>> {
+            let mut key = prefix.clone();
+            encode_scalar(&canonical(value.clone()), Order::Asc, &mut key)?;
+            Ok(key)
+        };
+        let start = match &self.lower {
+            Bound::Included(value) => with_bound(value)?,
+            Bound::Excluded(value) => prefix_end(&with_bound(value)?).unwrap(),
+            Bound::Unbounded if self.upper != Bound::Unbounded => {
+                let mut key = prefix.clone();
+                key.push(NOT_NULL);
+                key
+            }
+            Bound::Unbounded => prefix.clone(),
+        };
+        let end = match &self.upper {
+            Bound::Included(value) => prefix_end(&with_bound(value)?),
+            Bound::Excluded(value) => Some(with_bound(value)?),
+            Bound::Unbounded => prefix_end(&prefix),
+        };
+        Ok((start, end))
+    }
+}
+
+/// Output schema is the schema of the table, see [crate::sql::scan::SeqScanPlan]
+pub struct IndexScanPlan {
+    pub table: String,
+    pub index: String,
+    pub range: IndexRange,
+    /// Only rows for which predicate is true are returned
+    pub predicate: Option<Expr>,
+}
+
+/// Looks up the RIDs in range from the index, then fetches their rows
+#[derive(Debug)]
+pub struct IndexScan {
+    table: String,
+    index: String,
+    range: IndexRange,
+    predicate: Option<Expr>,
+    schema: SchemaRef,
+}
+
+impl IndexScan {
+    pub fn from_plan(plan: IndexScanPlan, ctx: ExecutionContext) -> SqlResult<Self> {
+        let table_meta = ctx.get_storage().get_table(&plan.table)?;
+        Ok(IndexScan {
+            table: plan.table,
+            index: plan.index,
+            range: plan.range,
+            predicate: plan.predicate,
+            schema: table_meta.schema_ref(),
+        })
+    }
+}
+
+impl Operator for IndexScan {
+    fn execute_sync(&mut self, ctx: ExecutionContext) -> SqlResult<BoxedDataIter> {
+        ctx.lock_for_read(&self.table)?;
+        let storage = ctx.get_storage();
+        let txn = ctx.get_txn();
+        let rids = storage.index_scan(&self.index, &self.range, &txn)?;
+        let rows = storage.get_tuples(&self.table, rids, &txn)?;
+        match &self.predicate {
+            Some(predicate) => Ok(filter_stream(self.schema(), rows, predicate.clone())),
+            None => Ok(rows),
+        }
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::IndexRange;
+    use datafusion::scalar::ScalarValue;
+    use std::ops::Bound;
+
+    #[test]
+    fn test_key_range() {
+        let int = |v| ScalarValue::Int32(Some(v));
+        let all = IndexRange::point(vec![]);
+        assert_eq!((vec![], None), all.key_range().unwrap());
+
+        // [1, 0x80, 0, 0, 7] is the key of 7
+        let point = IndexRange::point(vec![int(7)]);
+        assert_eq!(
+            (vec![1, 0x80, 0, 0, 7], Some(vec![1, 0x80, 0, 0, 8])),
+            point.key_range().unwrap()
+        );
+        let less = IndexRange {
+            eq: vec![int(7)],
+            lower: Bound::Unbounded,
+            upper: Bound::Excluded(int(9)),
+        };
+        assert_eq!(
+            (
+                vec![1, 0x80, 0, 0, 7, 1],
+                Some(vec![1, 0x80, 0, 0, 7, 1, 0x80, 0, 0, 9])
+            ),
+            less.key_range().unwrap()
+        );
+        let greater = IndexRange {
+            eq: vec![],
+            lower: Bound::Excluded(int(7)),
+            upper: Bound::Unbounded,
+        };
+        assert_eq!((vec![1, 0x80, 0, 0, 8], None), greater.key_range().unwrap());
+    }
+}
@@ -1,37 +1,47 @@
-use crate::sql::{exe::Operator, ExecutionContext, SqlResult};
-use datafusion::arrow::datatypes::{Schema, SchemaRef};
-use std::sync::Arc;
+use crate::sql::{exe::Operator, expr::Expr, filter::filter_stream, ExecutionContext, SqlResult};
+use datafusion::arrow::datatypes::SchemaRef;
 
-use super::exe::BoxedDataIter;
+use super::exe::{with_rid_schema, BoxedDataIter};
 
+pub mod index;
+
+/// Output schema is resolved from the catalog when the operator is created
 pub struct SeqScanPlan {
     pub table: String,
-    pub out_schema: Schema,
+    /// Only rows for which predicate is true are returned
+    pub predicate: Option<Expr>,
+    /// Every row is followed by its RID, see [crate::sql::exe::Storage::scan_with_rids]
+    pub with_rids: bool,
 }
 
 // todo: batch size
 #[derive(Debug)]
 pub struct SeqScanner {
-    predicate: Predicate,
+    predicate: Option<Expr>,
     // ctx: ExecutionContext,
     init: bool,
+    with_rids: bool,
     table: String,
     schema: SchemaRef,
     // leftover: Option<Box<dyn Iterator<Item = Tuple>>>,
 }
 
-#[derive(Debug)]
-struct Predicate {}
-
 impl SeqScanner {
-    pub fn from_plan(plan: SeqScanPlan, ctx: ExecutionContext) -> Self {
-        SeqScanner {
-            predicate: Predicate {}, //todo
+    pub fn from_plan(plan: SeqScanPlan, ctx: ExecutionContext) -> SqlResult<Self> {
+        let table_meta = ctx.get_storage().get_table(&plan.table)?;
+        let schema = if plan.with_rids {
+            with_rid_schema(&table_meta.schema)
+        } else {
+            table_meta.schema_ref()
+        };
+        Ok(SeqScanner {
+            predicate: plan.predicate,
             // ctx,
             init: false,
+            with_rids: plan.with_rids,
             table: plan.table,
-            schema: Arc::new(plan.out_schema),
-        }
+            schema,
+        })
     }
 }
 
@@ -41,7 +51,17 @@ impl Operator for SeqScanner {
         todo!()
     } */
     fn execute_sync(&mut self, ctx: ExecutionContext) -> SqlResult<BoxedDataIter> {
-        ctx.get_storage().scan(&self.table, ctx.get_txn())
+        ctx.lock_for_read(&self.table)?;
+        let storage = ctx.get_storage();
+        let rows = if self.with_rids {
+            storage.scan_with_rids(&self.table, &ctx.get_txn())?
+        } else {
+            storage.scan(&self.table, &ctx.get_txn())?
+        };
+        match &self.predicate {
+            Some(predicate) => Ok(filter_stream(self.schema(), rows, predicate.clone())),
+            None => Ok(rows),
+        }
     }
 
     fn schema(&self) -> SchemaRef {
@@ -0,0 +1,579 @@
+use crate::sql::{
+    exe::{BoxedDataIter, Executor, Operator, PlanType, SchemaDataIter},
+    join::grace::PartitionedQueue,
+    memory::batch_memory_size,
+    util::GeneratorIteratorAdapter,
+    DataBlock, ExecutionContext, SqlResult,
+};
+use datafusion::{
+    arrow::{
+        array::{build_compare, make_array, Array, ArrayRef, DynComparator, MutableArrayData},
+        compute::{
+            kernels::sort::{lexsort_to_indices, SortColumn, SortOptions},
+            take,
+        },
+        datatypes::SchemaRef,
+    },
+    physical_plan::expressions::Column,
+};
+use std::{cmp::Ordering, sync::Arc};
+
+pub struct SortPlan {
+    pub order_by: Vec<SortKey>,
+    /// Only the first `limit` rows are returned, sort then runs in Top-N mode
+    pub limit: Option<usize>,
+    pub source_plan: Box<PlanType>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SortKey {
+    pub column: Column,
+    pub descending: bool,
+    pub nulls_first: bool,
+}
+
+impl SortKey {
+    fn options(&self) -> SortOptions {
+        SortOptions {
+            descending: self.descending,
+            nulls_first: self.nulls_first,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Config {
+    batch_size: usize,
+}
+
+/// External merge sort.
+///
+/// Input is buffered as long as its batches can be reserved from the memory pool of the
+/// [ExecutionContext], the buffer is then sorted with [lexsort_to_indices] and spilled as a
+/// run into its own partition of a queue allocated from the context. Once the input is
+/// exhausted, all runs are merged k-way. If nothing was spilled, the sorted buffer is
+/// returned directly.
+///
+/// In Top-N mode (`limit` is set), a full buffer is first truncated to its first `limit`
+/// rows, it is only spilled if those rows still do not leave room for the next batch
+#[derive(Debug)]
+pub struct SortOp {
+    config: Config,
+    order_by: Vec<SortKey>,
+    limit: Option<usize>,
+    input: Box<dyn Operator>,
+}
+
+impl SortOp {
+    pub fn from_plan(plan: SortPlan, ctx: ExecutionContext) -> SqlResult<Self> {
+        let input = Executor::create_from_subplan_operator(*plan.source_plan, ctx)?;
+        let config = Config { batch_size: 1024 };
+        Ok(Self::new(config, input, plan.order_by, plan.limit))
+    }
+
+    fn new(
+        config: Config,
+        input: Box<dyn Operator>,
+        order_by: Vec<SortKey>,
+        limit: Option<usize>,
+    ) -> Self {
+        SortOp {
+            config,
+            order_by,
+            limit,
+            input,
+        }
+    }
+
+    fn sort_batch(
+        schema: &SchemaRef,
+        batches: &[DataBlock],
+        order_by: &[SortKey],
+        limit: Option<usize>,
+    ) -> SqlResult<DataBlock> {
+        let batch = DataBlock::concat(schema, batches)?;
+        let sort_columns = order_by
+            .iter()
+            .map(|key| SortColumn {
+                values: batch.column(key.column.index()).clone(),
+                options: Some(key.options()),
+            })
+            .collect::<Vec<_>>();
+        let indices = lexsort_to_indices(&sort_columns, limit)?;
+        let columns = batch
+            .columns()
+            .iter()
+            .map(|c| take(c.as_ref(), &indices, None))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(DataBlock::try_new(schema.clone(), columns)?)
+    }
+
+    fn spill_run(
+        queue: &Arc<dyn PartitionedQueue>,
+        run_idx: usize,
+        run: &DataBlock,
+        batch_size: usize,
+    ) -> SqlResult<()> {
+        let mut offset = 0;
+        while offset < run.num_rows() {
+            let len = batch_size.min(run.num_rows() - offset);
+            queue.enqueue(run_idx, run.slice(offset, len))?;
+            offset += len;
+        }
+        Ok(())
+    }
+}
+
+impl Operator for SortOp {
+    fn schema(&self) -> SchemaRef {
+        self.input.schema()
+    }
+
+    fn execute_sync(&mut self, ctx: ExecutionContext) -> SqlResult<BoxedDataIter> {
+        let schema = self.schema();
+        let config = self.config.clone();
+        let order_by = self.order_by.clone();
+        let limit = self.limit;
+        let mut reservation = ctx.new_reservation("sort");
+
+        let mut buffered = vec![];
+        let mut queue = None;
+        let mut run_count = 0;
+        for batch in self.input.execute_sync(ctx.clone())? {
+            let batch = batch?;
+            let size = batch_memory_size(&batch);
+            if !reservation.try_grow(size) {
+                if buffered.is_empty() {
+                    // nothing to spill, a single batch does not fit
+                    reservation.grow(size)?;
+                } else {
+                    let sorted = Self::sort_batch(&schema, &buffered, &order_by, limit)?;
+                    buffered.clear();
+                    reservation.free();
+                    let keep_top_n =
+                        limit.is_some() && reservation.try_grow(batch_memory_size(&sorted) + size);
+                    if keep_top_n {
+                        buffered.push(sorted);
+                    } else {
+                        let queue = queue.get_or_insert_with(|| ctx.new_queue(schema.clone()));
+                        Self::spill_run(queue, run_count, &sorted, config.batch_size)?;
+                        run_count += 1;
+                        reservation.grow(size)?;
+                    }
+                }
+            }
+            buffered.push(batch);
+        }
+        let last_run = Self::sort_batch(&schema, &buffered, &order_by, limit)?;
+        drop(buffered);
+
+        let queue = match queue {
+            None => {
+                let output = MergeIter::chunk(last_run, config.batch_size, limit);
+                let gen = move || {
+                    // the sorted rows are held until the output is consumed
+                    let _reservation = reservation;
+                    for batch in output {
+                        yield Ok(batch);
+                    }
+                };
+                let iter = GeneratorIteratorAdapter::new(gen);
+                return Ok(SchemaDataIter::new(schema, Box::new(iter)));
+            }
+            Some(queue) => queue,
+        };
+        let mut runs = Vec::with_capacity(run_count + 1);
+        for run_idx in 0..run_count {
+            runs.push(queue.dequeue(run_idx, config.batch_size)?);
+        }
+        let last_run_stream: BoxedDataIter = SchemaDataIter::new(
+            schema.clone(),
+            Box::new(
+                MergeIter::chunk(last_run, config.batch_size, None)
+                    .into_iter()
+                    .map(Ok),
+            ),
+        );
+        runs.push(last_run_stream);
+
+        let mut merger = MergeIter::new(schema.clone(), runs, order_by, config.batch_size)?;
+        let mut remaining = self.limit;
+        let gen = move || {
+            let _reservation = reservation;
+            while remaining != Some(0) {
+                match merger.next_batch(remaining) {
+                    Ok(None) => return,
+                    Ok(Some(batch)) => {
+                        remaining = remaining.map(|n| n - batch.num_rows());
+                        yield Ok(batch);
+                    }
+                    Err(e) => {
+                        yield Err(e);
+                        return;
+                    }
+                }
+            }
+        };
+        let iter = GeneratorIteratorAdapter::new(gen);
+        Ok(SchemaDataIter::new(self.schema(), Box::new(iter)))
+    }
+}
+
+/// Current position inside one sorted run
+struct Cursor {
+    stream: BoxedDataIter,
+    batch: Option<DataBlock>,
+    row: usize,
+}
+
+impl Cursor {
+    fn new(mut stream: BoxedDataIter) -> SqlResult<Self> {
+        let batch = Self::next_non_empty(&mut stream)?;
+        Ok(Cursor {
+            stream,
+            batch,
+            row: 0,
+        })
+    }
+
+    fn next_non_empty(stream: &mut BoxedDataIter) -> SqlResult<Option<DataBlock>> {
+        for batch in stream {
+            let batch = batch?;
+            if batch.num_rows() > 0 {
+                return Ok(Some(batch));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns true if the cursor moved to a new batch
+    fn advance(&mut self) -> SqlResult<bool> {
+        self.row += 1;
+        let exhausted = match &self.batch {
+            Some(batch) => self.row >= batch.num_rows(),
+            None => false,
+        };
+        if exhausted {
+            self.batch = Self::next_non_empty(&mut self.stream)?;
+            self.row = 0;
+        }
+        Ok(exhausted)
+    }
+}
+
+/// K-way merge of sorted runs, the run with the smallest current row is found with a
+/// linear scan since the number of runs is small
+struct MergeIter {
+    schema: SchemaRef,
+    cursors: Vec<Cursor>,
+    order_by: Vec<SortKey>,
+    batch_size: usize,
+    /// Comparators of the sort columns between the current batches of two cursors,
+    /// built on first use and dropped once either cursor moves to a new batch
+    comparators: Vec<Vec<Option<Vec<DynComparator>>>>,
+}
+
+impl MergeIter {
+    fn new(
+        schema: SchemaRef,
+        runs: Vec<BoxedDataIter>,
+        order_by: Vec<SortKey>,
+        batch_size: usize,
+    ) -> SqlResult<Self> {
+        let cursors = runs
+            .into_iter()
+            .map(Cursor::new)
+            .collect::<SqlResult<Vec<Cursor>>>()?;
+        let comparators = cursors
+            .iter()
+            .map(|_| cursors.iter().map(|_| None).collect())
+            .collect();
+        Ok(MergeIter {
+            schema,
+            cursors,
+            order_by,
+            batch_size,
+            comparators,
+        })
+    }
+
+    fn chunk(batch: DataBlock, batch_size: usize, limit: Option<usize>) -> Vec<DataBlock> {
+        let total = limit.map_or(batch.num_rows(), |n| n.min(batch.num_rows()));
+        let mut ret = vec![];
+        let mut offset = 0;
+        while offset < total {
+            let len = batch_size.min(total - offset);
+            ret.push(batch.slice(offset, len));
+            offset += len;
+        }
+        ret
+    }
+
+    fn compare(&mut self, left_idx: usize, right_idx: usize) -> SqlResult<Ordering> {
+        let (left, right) = (&self.cursors[left_idx], &self.cursors[right_idx]);
+        let (left_batch, right_batch) = match (&left.batch, &right.batch) {
+            (Some(l), Some(r)) => (l, r),
+            _ => unreachable!("exhausted cursors are never compared"),
+        };
+        let comparators = match &mut self.comparators[left_idx][right_idx] {
+            Some(comparators) => comparators,
+            slot => {
+                let comparators = self
+                    .order_by
+                    .iter()
+                    .map(|key| {
+                        let l = left_batch.column(key.column.index());
+                        let r = right_batch.column(key.column.index());
+                        build_compare(l.as_ref(), r.as_ref())
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                slot.insert(comparators)
+            }
+        };
+        for (key, comparator) in self.order_by.iter().zip(comparators.iter()) {
+            let l = left_batch.column(key.column.index());
+            let r = right_batch.column(key.column.index());
+            let ord = compare_values(l, left.row, r, right.row, key, comparator);
+            if ord != Ordering::Equal {
+                return Ok(ord);
+            }
+        }
+        Ok(Ordering::Equal)
+    }
+
+    /// Drops the comparators built against the batch the cursor moved away from
+    fn clear_comparators(&mut self, idx: usize) {
+        for other in 0..self.cursors.len() {
+            self.comparators[idx][other] = None;
+            self.comparators[other][idx] = None;
+        }
+    }
+
+    fn next_batch(&mut self, limit: Option<usize>) -> SqlResult<Option<DataBlock>> {
+        let capacity = limit.map_or(self.batch_size, |n| n.min(self.batch_size));
+        // batches referenced by the output and the (source, row) of every output row
+        let mut sources: Vec<DataBlock> = vec![];
+        let mut source_of_cursor: Vec<Option<usize>> = vec![None; self.cursors.len()];
+        let mut picks: Vec<(usize, usize)> = Vec::with_capacity(capacity);
+
+        while picks.len() < capacity {
+            let mut min_idx: Option<usize> = None;
+            for idx in 0..self.cursors.len() {
+                if self.cursors[idx].batch.is_none() {
+                    continue;
+                }
+                min_idx = match min_idx {
+                    None => Some(idx),
+                    Some(cur) => match self.compare(idx, cur)? {
+                        Ordering::Less => Some(idx),
+                        _ => Some(cur),
+                    },
+                };
+            }
+            let idx = match min_idx {
+                None => break,
+                Some(idx) => idx,
+            };
+            let source = match source_of_cursor[idx] {
+                Some(source) => source,
+                None => {
+                    sources.push(self.cursors[idx].batch.clone().unwrap());
+                    source_of_cursor[idx] = Some(sources.len() - 1);
+                    sources.len() - 1
+                }
+            };
+            picks.push((source, self.cursors[idx].row));
+            if self.cursors[idx].advance()? {
+                source_of_cursor[idx] = None;
+                self.clear_comparators(idx);
+            }
+        }
+        if picks.is_empty() {
+            return Ok(None);
+        }
+
+        let mut columns: Vec<ArrayRef> = Vec::with_capacity(self.schema.fields().len());
+        for col_idx in 0..self.schema.fields().len() {
+            let arrays = sources
+                .iter()
+                .map(|batch| batch.column(col_idx).data())
+                .collect::<Vec<_>>();
+            let mut mutable = MutableArrayData::new(arrays, true, picks.len());
+            for (source, row) in &picks {
+                mutable.extend(*source, *row, *row + 1);
+            }
+            columns.push(make_array(mutable.freeze()));
+        }
+        Ok(Some(DataBlock::try_new(self.schema.clone(), columns)?))
+    }
+}
+
+fn compare_values(
+    left: &ArrayRef,
+    left_row: usize,
+    right: &ArrayRef,
+    right_row: usize,
+    key: &SortKey,
+    comparator: &DynComparator,
+) -> Ordering {
+    let ord = match (left.is_null(left_row), right.is_null(right_row)) {
+        (true, true) => return Ordering::Equal,
+        (true, false) if key.nulls_first => return Ordering::Less,
+        (true, false) => return Ordering::Greater,
+        (false, true) if key.nulls_first => return Ordering::Greater,
+        (false, true) => return Ordering::Less,
+        (false, false) => comparator(left_row, right_row),
+    };
+    match key.descending {
+        true => ord.reverse(),
+        false => ord,
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{Config, SortKey, SortOp};
+    use crate::sql::{
+        exe::{ExecutionContext, Operator},
+        inmem_op::InMemOp,
+        memory::MemoryPool,
+        util::collect,
+        DataBlock, Error, SqlResult,
+    };
+    use datafusion::{
+        arrow::{
+            array::{Array, Int32Array, StringArray},
+            datatypes::{DataType, Field, Schema},
+        },
+        physical_plan::expressions::Column,
+    };
+    use std::sync::Arc;
+
+    /// Columns: a int nullable, b utf8, split into batches of 2 rows. Values of b are
+    /// one character, so a batch of 2 rows takes 23 bytes
+    fn build_input(a: Vec<Option<i32>>, b: Vec<&str>) -> Box<dyn Operator> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int32, true),
+            Field::new("b", DataType::Utf8, false),
+        ]));
+        let batches = a
+            .chunks(2)
+            .zip(b.chunks(2))
+            .map(|(a, b)| {
+                DataBlock::try_new(
+                    schema.clone(),
+                    vec![
+                        Arc::new(Int32Array::from(a.to_vec())) as Arc<dyn Array>,
+                        Arc::new(StringArray::from(b.to_vec())) as Arc<dyn Array>,
+                    ],
+                )
+                .unwrap()
+            })
+            .collect();
+        Box::new(InMemOp::new(schema, batches))
+    }
+
+    fn key(name: &str, index: usize, descending: bool, nulls_first: bool) -> SortKey {
+        SortKey {
+            column: Column::new(name, index),
+            descending,
+            nulls_first,
+        }
+    }
+
+    fn try_run(
+        memory_limit: usize,
+        order_by: Vec<SortKey>,
+        limit: Option<usize>,
+    ) -> SqlResult<Vec<DataBlock>> {
+        let input = build_input(
+            vec![Some(3), None, Some(1), Some(3), Some(2), None, Some(1)],
+            vec!["c", "x", "a", "d", "b", "y", "z"],
+        );
+        let config = Config { batch_size: 3 };
+        let mut op = SortOp::new(config, input, order_by, limit);
+        let ctx = ExecutionContext::new_for_test()
+            .with_memory_pool(Arc::new(MemoryPool::new(memory_limit)));
+        let stream = op.execute_sync(ctx.clone())?;
+        let batches = collect(stream)?;
+        assert_eq!(0, ctx.memory_pool().used());
+        Ok(batches)
+    }
+
+    fn run(memory_limit: usize, order_by: Vec<SortKey>, limit: Option<usize>) -> Vec<DataBlock> {
+        try_run(memory_limit, order_by, limit).expect("running sort")
+    }
+
+    fn rows(batches: &[DataBlock]) -> Vec<String> {
+        let table = crate::sql::util::create_pretty_print_table(batches)
+            .unwrap()
+            .to_string();
+        table
+            .lines()
+            .skip(3)
+            .filter(|l| !l.starts_with('+'))
+            .map(|l| l.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_inmem_sort() {
+        let batches = run(
+            1000,
+            vec![key("a", 0, false, false), key("b", 1, true, false)],
+            None,
+        );
+        assert_eq!(
+            vec![
+                "| 1 | z |",
+                "| 1 | a |",
+                "| 2 | b |",
+                "| 3 | d |",
+                "| 3 | c |",
+                "|   | y |",
+                "|   | x |",
+            ],
+            rows(&batches)
+        );
+    }
+
+    #[test]
+    fn test_external_sort() {
+        // only one input batch fits, each is spilled as a sorted run
+        let batches = run(
+            30,
+            vec![key("a", 0, true, true), key("b", 1, false, false)],
+            None,
+        );
+        assert!(batches.iter().all(|b| b.num_rows() <= 3));
+        assert_eq!(
+            vec![
+                "|   | x |",
+                "|   | y |",
+                "| 3 | c |",
+                "| 3 | d |",
+                "| 2 | b |",
+                "| 1 | a |",
+                "| 1 | z |",
+            ],
+            rows(&batches)
+        );
+    }
+
+    #[test]
+    fn test_top_n() {
+        let order_by = || vec![key("a", 0, false, true), key("b", 1, false, false)];
+        // the buffer is truncated to the first 2 rows each time it is full
+        let expected = vec!["|   | x |", "|   | y |"];
+        assert_eq!(expected, rows(&run(50, order_by(), Some(2))));
+        // limit does not fit in memory, external sort stops after the limit
+        let expected = vec!["|   | x |", "|   | y |", "| 1 | a |", "| 1 | z |"];
+        assert_eq!(expected, rows(&run(30, order_by(), Some(4))));
+    }
+
+    #[test]
+    fn test_sort_memory_limit_exceeded() {
+        let ret = try_run(10, vec![key("a", 0, false, false)], None);
+        assert!(matches!(ret, Err(Error::ResourceExhausted(_))));
+    }
+}
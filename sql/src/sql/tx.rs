@@ -1,19 +1,44 @@
-use super::common::RID;
+use super::{
+    cc::{DeadlockPolicy, LockMgr, LockMode, LockTarget},
+    exe::RID,
+    Error, SqlResult,
+};
+use crate::storage::mvcc::{Transaction, MVCC};
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 
-#[derive(Copy, Clone)]
+pub type TxnID = u64;
+
+/// A transaction handed out by [TxnManager], shared between the operators of its
+/// queries. A Txn begun on a storage with MVCC carries the storage's MVCC transaction,
+/// storages run the operations of a Txn without one in their own transactions
 pub struct Txn {
+    id: TxnID,
     lv: IsolationLevel,
+    inner: Mutex<TxnInner>,
+    mvcc: Option<Transaction>,
+}
+
+struct TxnInner {
     two2pl: TwoPLState,
+    locks: HashMap<LockTarget, LockMode>,
+    writes: Vec<WriteRecord>,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum IsolationLevel {
     ReadUncommitted,
     ReadComitted,
     RepeatableRead,
     Serializable,
 }
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum TwoPLState {
     Growing,
     Shrinking,
@@ -21,26 +46,260 @@ pub enum TwoPLState {
     Aborted,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WriteKind {
+    Insert,
+    Delete,
+    Update,
+}
+
+/// A row written by a txn
+#[derive(Clone, Debug, PartialEq)]
+pub struct WriteRecord {
+    pub table: String,
+    pub rid: RID,
+    pub kind: WriteKind,
+}
+
+impl Default for Txn {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Txn {
+    /// A txn with id 0 that is not tracked by any [TxnManager], each storage operation
+    /// made with it commits on its own
     pub fn new() -> Self {
+        Self::with_id(0, IsolationLevel::ReadComitted, None)
+    }
+    fn with_id(id: TxnID, lv: IsolationLevel, mvcc: Option<Transaction>) -> Self {
         Txn {
-            lv: IsolationLevel::ReadComitted,
-            two2pl: TwoPLState::Growing,
+            id,
+            lv,
+            inner: Mutex::new(TxnInner {
+                two2pl: TwoPLState::Growing,
+                locks: HashMap::new(),
+                writes: vec![],
+            }),
+            mvcc,
         }
     }
+    pub fn id(&self) -> TxnID {
+        self.id
+    }
+    pub fn mvcc(&self) -> Option<&Transaction> {
+        self.mvcc.as_ref()
+    }
     pub fn isolation_level(&self) -> IsolationLevel {
         self.lv
     }
-    pub fn abort(&mut self) {
-        self.two2pl = TwoPLState::Aborted;
+    /// Marks the txn as aborted, its writes are undone by [TxnManager::abort]
+    pub fn abort(&self) {
+        self.set_state(TwoPLState::Aborted);
     }
     pub fn state(&self) -> TwoPLState {
-        self.two2pl
+        self.inner.lock().two2pl
+    }
+    pub fn set_state(&self, state: TwoPLState) {
+        self.inner.lock().two2pl = state;
     }
     pub fn s_locked(&self, rid: RID) -> bool {
-        false
+        self.lock_mode(&LockTarget::Row(rid)) == Some(LockMode::Shared)
     }
     pub fn x_locked(&self, rid: RID) -> bool {
-        false
+        self.lock_mode(&LockTarget::Row(rid)) == Some(LockMode::Exclusive)
+    }
+    pub fn lock_mode(&self, target: &LockTarget) -> Option<LockMode> {
+        self.inner.lock().locks.get(target).copied()
+    }
+    /// Records a lock granted by [LockMgr], replacing the lock held on target if any
+    pub fn add_lock(&self, target: LockTarget, mode: LockMode) {
+        self.inner.lock().locks.insert(target, mode);
+    }
+    pub fn remove_lock(&self, target: &LockTarget) {
+        self.inner.lock().locks.remove(target);
+    }
+    /// Targets locked by the txn in any mode
+    pub fn locked(&self) -> Vec<LockTarget> {
+        self.inner.lock().locks.keys().cloned().collect()
+    }
+    pub fn record_write(&self, table: &str, rid: RID, kind: WriteKind) {
+        self.inner.lock().writes.push(WriteRecord {
+            table: table.to_string(),
+            rid,
+            kind,
+        });
+    }
+    /// Rows written by the txn, in the order of the writes
+    pub fn write_set(&self) -> Vec<WriteRecord> {
+        self.inner.lock().writes.clone()
+    }
+}
+
+/// Hands out transactions and finishes them, releasing their locks. Ids are increasing,
+/// with MVCC they are the ids of the MVCC transactions so they keep increasing across
+/// restarts
+pub struct TxnManager {
+    next_id: AtomicU64,
+    mvcc: Option<MVCC>,
+    lock_mgr: Arc<LockMgr>,
+    active: Mutex<HashMap<TxnID, Arc<Txn>>>,
+}
+
+impl Default for TxnManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TxnManager {
+    pub fn new() -> Self {
+        TxnManager {
+            next_id: AtomicU64::new(1),
+            mvcc: None,
+            lock_mgr: LockMgr::new(DeadlockPolicy::default()),
+            active: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Transactions of the manager read and write through MVCC transactions
+    pub fn with_mvcc(mvcc: MVCC) -> Self {
+        TxnManager {
+            mvcc: Some(mvcc),
+            ..Self::new()
+        }
+    }
+
+    /// Deadlocks are detected in the background by default
+    pub fn with_deadlock_policy(mut self, policy: DeadlockPolicy) -> Self {
+        self.lock_mgr = LockMgr::new(policy);
+        self
+    }
+
+    pub fn begin(&self, lv: IsolationLevel) -> SqlResult<Arc<Txn>> {
+        let txn = match &self.mvcc {
+            Some(mvcc) => {
+                let mvcc = mvcc.begin()?;
+                Txn::with_id(mvcc.id(), lv, Some(mvcc))
+            }
+            None => Txn::with_id(self.next_id.fetch_add(1, Ordering::SeqCst), lv, None),
+        };
+        let txn = Arc::new(txn);
+        self.active.lock().insert(txn.id(), txn.clone());
+        Ok(txn)
+    }
+
+    /// Resumes an MVCC transaction left active, e.g. by a restart
+    pub fn resume(&self, id: TxnID, lv: IsolationLevel) -> SqlResult<Arc<Txn>> {
+        if let Some(txn) = self.get(id) {
+            return Ok(txn);
+        }
+        let mvcc = match &self.mvcc {
+            Some(mvcc) => mvcc.resume(id)?,
+            None => return Err(Error::NotFound(format!("transaction {} is not active", id))),
+        };
+        let txn = Arc::new(Txn::with_id(id, lv, Some(mvcc)));
+        self.active.lock().insert(id, txn.clone());
+        Ok(txn)
+    }
+
+    pub fn lock_mgr(&self) -> Arc<LockMgr> {
+        self.lock_mgr.clone()
+    }
+
+    pub fn get(&self, id: TxnID) -> Option<Arc<Txn>> {
+        self.active.lock().get(&id).cloned()
+    }
+
+    /// Ids of the transactions begun by the manager that have not finished
+    pub fn active(&self) -> Vec<TxnID> {
+        let mut ids = self.active.lock().keys().copied().collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Commits the txn, a txn marked as aborted is rolled back instead and
+    /// [Error::Abort] is returned
+    pub fn commit(&self, txn: &Txn) -> SqlResult<()> {
+        match txn.state() {
+            TwoPLState::Committed => return Ok(()),
+            TwoPLState::Aborted => {
+                self.abort(txn)?;
+                return Err(Error::Abort);
+            }
+            TwoPLState::Growing | TwoPLState::Shrinking => {}
+        }
+        if let Some(mvcc) = txn.mvcc() {
+            mvcc.commit()?;
+        }
+        self.finish(txn, TwoPLState::Committed)
+    }
+
+    /// Aborts the txn and undoes its writes
+    pub fn abort(&self, txn: &Txn) -> SqlResult<()> {
+        if txn.state() == TwoPLState::Committed {
+            return Err(Error::Value(format!(
+                "transaction {} is already committed",
+                txn.id()
+            )));
+        }
+        if let Some(mvcc) = txn.mvcc() {
+            mvcc.rollback()?;
+        }
+        self.finish(txn, TwoPLState::Aborted)
+    }
+
+    /// Locks are released once the state is final, as strict 2PL requires
+    fn finish(&self, txn: &Txn, state: TwoPLState) -> SqlResult<()> {
+        txn.set_state(state);
+        self.active.lock().remove(&txn.id());
+        self.lock_mgr.unlock_all(txn)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{IsolationLevel, TwoPLState, TxnManager, WriteKind, WriteRecord};
+    use crate::sql::{
+        cc::{LockMode, LockTarget},
+        Error,
+    };
+
+    #[test]
+    fn test_txn_manager() {
+        let mgr = TxnManager::new();
+        let t1 = mgr.begin(IsolationLevel::RepeatableRead).unwrap();
+        let t2 = mgr.begin(IsolationLevel::Serializable).unwrap();
+        assert!(t1.id() < t2.id());
+        assert_eq!(IsolationLevel::Serializable, t2.isolation_level());
+        assert_eq!(vec![t1.id(), t2.id()], mgr.active());
+
+        t1.add_lock(LockTarget::Row(1), LockMode::Shared);
+        t1.add_lock(LockTarget::Row(2), LockMode::Shared);
+        t1.add_lock(LockTarget::Row(2), LockMode::Exclusive);
+        assert!(t1.s_locked(1) && !t1.x_locked(1));
+        assert!(t1.x_locked(2) && !t1.s_locked(2));
+        t1.record_write("t", 2, WriteKind::Update);
+        assert_eq!(
+            vec![WriteRecord {
+                table: "t".to_string(),
+                rid: 2,
+                kind: WriteKind::Update
+            }],
+            t1.write_set()
+        );
+
+        mgr.commit(&t1).unwrap();
+        assert_eq!(TwoPLState::Committed, t1.state());
+        assert!(t1.locked().is_empty());
+        assert!(mgr.abort(&t1).is_err());
+
+        // a txn marked as aborted cannot commit
+        t2.abort();
+        assert_eq!(Err(Error::Abort), mgr.commit(&t2));
+        assert_eq!(TwoPLState::Aborted, t2.state());
+        assert!(mgr.active().is_empty());
+        assert!(mgr.get(t2.id()).is_none());
     }
 }
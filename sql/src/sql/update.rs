@@ -0,0 +1,51 @@
+use super::exe::{
+    affected_rows_block, affected_rows_schema, BoxedDataIter, Executor, Operator, PlanType,
+    SchemaDataIter,
+};
+use crate::sql::{ExecutionContext, SqlResult};
+use datafusion::arrow::datatypes::SchemaRef;
+
+/// Child returns the new values of every column of the table followed by the RID of
+/// the replaced row, see [crate::sql::exe::with_rid_schema]
+pub struct UpdatePlan {
+    pub table: String,
+    pub source_plan: Box<PlanType>,
+}
+
+/// Rewrites every row returned by its child, output is a one row batch containing
+/// the number of updated rows
+#[derive(Debug)]
+pub struct Update {
+    table: String,
+    source: Box<dyn Operator>,
+}
+
+impl Update {
+    pub fn from_plan(plan: UpdatePlan, ctx: ExecutionContext) -> SqlResult<Self> {
+        let source = Executor::create_from_subplan_operator(*plan.source_plan, ctx)?;
+        Ok(Update {
+            table: plan.table,
+            source,
+        })
+    }
+}
+
+impl Operator for Update {
+    fn execute_sync(&mut self, ctx: ExecutionContext) -> SqlResult<BoxedDataIter> {
+        let input = self.source.execute_sync(ctx.clone())?;
+        ctx.lock_for_write(&self.table)?;
+        let input = ctx.lock_rows(self.source.schema(), input);
+        let updated = ctx
+            .get_storage()
+            .update(&self.table, input, &ctx.get_txn())?;
+        let batch = affected_rows_block(updated)?;
+        Ok(SchemaDataIter::new(
+            self.schema(),
+            Box::new(std::iter::once(Ok(batch))),
+        ))
+    }
+
+    fn schema(&self) -> SchemaRef {
+        affected_rows_schema()
+    }
+}
@@ -3,17 +3,41 @@ use std::{
     pin::Pin,
 };
 
-use super::{exe::BoxedDataIter, SqlResult};
-use crate::sql::DataBlock;
+use super::{
+    exe::{BoxedDataIter, Operator, SchemaDataIter},
+    SqlResult,
+};
+use crate::sql::{DataBlock, ExecutionContext};
 use comfy_table::{Cell, Table};
-use datafusion::arrow::util::display::array_value_to_string;
+use datafusion::arrow::{datatypes::SchemaRef, util::display::array_value_to_string};
 // use futures::TryStreamExt;
 
 /// Util struct to create impl of Operator from raw input
+#[derive(Debug)]
 pub struct RawInput {
     inner: DataBlock,
 }
 
+impl RawInput {
+    pub fn new(inner: DataBlock) -> Self {
+        RawInput { inner }
+    }
+}
+
+impl Operator for RawInput {
+    fn execute_sync(&mut self, _: ExecutionContext) -> SqlResult<BoxedDataIter> {
+        let batch = self.inner.clone();
+        Ok(SchemaDataIter::new(
+            self.schema(),
+            Box::new(std::iter::once(Ok(batch))),
+        ))
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.inner.schema()
+    }
+}
+
 /// Create a vector of record batches from a stream
 pub fn collect(stream: BoxedDataIter) -> SqlResult<Vec<DataBlock>> {
     stream.collect()
@@ -50,6 +74,25 @@ pub fn create_pretty_print_table(batches: &[DataBlock]) -> SqlResult<Table> {
     Ok(table)
 }
 
+#[macro_export]
+macro_rules! assert_batches_eq {
+    ($EXPECTED_LINES: expr, $CHUNKS: expr) => {
+        let expected_lines: Vec<String> = $EXPECTED_LINES.iter().map(|&s| s.into()).collect();
+
+        let formatted = $crate::sql::util::create_pretty_print_table($CHUNKS)
+            .unwrap()
+            .to_string();
+
+        let actual_lines: Vec<&str> = formatted.trim().lines().collect();
+
+        assert_eq!(
+            expected_lines, actual_lines,
+            "\n\nexpected:\n\n{:#?}\nactual:\n\n{:#?}\n\n",
+            expected_lines, actual_lines
+        );
+    };
+}
+
 #[macro_export]
 macro_rules! assert_batches_sorted_eq {
     ($EXPECTED_LINES: expr, $CHUNKS: expr) => {
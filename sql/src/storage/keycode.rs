@@ -0,0 +1,535 @@
+use crate::sql::{Error, SqlResult};
+use datafusion::{
+    arrow::{
+        array::ArrayRef,
+        datatypes::{DataType, TimeUnit},
+    },
+    scalar::ScalarValue,
+};
+
+/// Tag of a null value, sorts before every non null value
+const NULL: u8 = 0x00;
+/// Tag preceding every non null value
+pub const NOT_NULL: u8 = 0x01;
+
+/// Direction in which encoded values sort. Descending encodings are the ascending
+/// ones with every byte inverted, so nulls sort last
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+/// Appends the value to buf so that comparing the encoded bytes gives the same order
+/// as comparing the values, floats are ordered as by [f64::total_cmp]. Encoded values
+/// are self delimiting, so the encodings of several values can be concatenated into
+/// one key that sorts by the first value first
+pub fn encode_scalar(value: &ScalarValue, order: Order, buf: &mut Vec<u8>) -> SqlResult<()> {
+    let start = buf.len();
+    encode_asc(value, buf)?;
+    if order == Order::Desc {
+        buf[start..].iter_mut().for_each(|b| *b = !*b);
+    }
+    Ok(())
+}
+
+fn encode_asc(value: &ScalarValue, buf: &mut Vec<u8>) -> SqlResult<()> {
+    // ScalarValue::is_null does not cover binary values
+    let is_null = matches!(
+        value,
+        ScalarValue::Binary(None) | ScalarValue::LargeBinary(None)
+    );
+    if is_null || value.is_null() {
+        buf.push(NULL);
+        return Ok(());
+    }
+    buf.push(NOT_NULL);
+    match value {
+        ScalarValue::Boolean(Some(v)) => buf.push(*v as u8),
+        // signed integers have their sign bit flipped so that negative values sort first
+        ScalarValue::Int8(Some(v)) => buf.push(*v as u8 ^ (1 << 7)),
+        ScalarValue::Int16(Some(v)) => {
+            buf.extend_from_slice(&(*v as u16 ^ (1 << 15)).to_be_bytes())
+        }
+        ScalarValue::Int32(Some(v)) | ScalarValue::Date32(Some(v)) => {
+            buf.extend_from_slice(&(*v as u32 ^ (1 << 31)).to_be_bytes())
+        }
+        ScalarValue::Int64(Some(v))
+        | ScalarValue::Date64(Some(v))
+        | ScalarValue::TimestampSecond(Some(v), _)
+        | ScalarValue::TimestampMillisecond(Some(v), _)
+        | ScalarValue::TimestampMicrosecond(Some(v), _)
+        | ScalarValue::TimestampNanosecond(Some(v), _) => {
+            buf.extend_from_slice(&(*v as u64 ^ (1 << 63)).to_be_bytes())
+        }
+        ScalarValue::UInt8(Some(v)) => buf.push(*v),
+        ScalarValue::UInt16(Some(v)) => buf.extend_from_slice(&v.to_be_bytes()),
+        ScalarValue::UInt32(Some(v)) => buf.extend_from_slice(&v.to_be_bytes()),
+        ScalarValue::UInt64(Some(v)) => buf.extend_from_slice(&v.to_be_bytes()),
+        // negative floats have every bit flipped so that larger magnitudes sort first
+        ScalarValue::Float32(Some(v)) => {
+            let bits = v.to_bits();
+            let bits = if bits >> 31 == 1 {
+                !bits
+            } else {
+                bits ^ (1 << 31)
+            };
+            buf.extend_from_slice(&bits.to_be_bytes())
+        }
+        ScalarValue::Float64(Some(v)) => {
+            let bits = v.to_bits();
+            let bits = if bits >> 63 == 1 {
+                !bits
+            } else {
+                bits ^ (1 << 63)
+            };
+            buf.extend_from_slice(&bits.to_be_bytes())
+        }
+        ScalarValue::Utf8(Some(v)) | ScalarValue::LargeUtf8(Some(v)) => {
+            encode_bytes(v.as_bytes(), buf)
+        }
+        ScalarValue::Binary(Some(v)) | ScalarValue::LargeBinary(Some(v)) => encode_bytes(v, buf),
+        other => {
+            return Err(Error::Value(format!(
+                "values of type {} cannot be encoded into a key",
+                other.get_datatype()
+            )))
+        }
+    }
+    Ok(())
+}
+
+/// 0x00 is escaped as 0x00 0xff and the bytes are terminated by 0x00 0x00, so a
+/// prefix sorts before the longer byte strings
+fn encode_bytes(bytes: &[u8], buf: &mut Vec<u8>) {
+    for b in bytes {
+        buf.push(*b);
+        if *b == 0x00 {
+            buf.push(0xff);
+        }
+    }
+    buf.extend_from_slice(&[0x00, 0x00]);
+}
+
+/// Reads bytes of an encoded key, inverting them back for descending values
+struct Reader<'a, 'b> {
+    input: &'a mut &'b [u8],
+    mask: u8,
+}
+
+impl<'a, 'b> Reader<'a, 'b> {
+    fn byte(&mut self) -> SqlResult<u8> {
+        let (first, rest) = self
+            .input
+            .split_first()
+            .ok_or_else(|| Error::Value("key is truncated".to_string()))?;
+        *self.input = rest;
+        Ok(first ^ self.mask)
+    }
+
+    fn array<const N: usize>(&mut self) -> SqlResult<[u8; N]> {
+        let mut ret = [0; N];
+        for b in ret.iter_mut() {
+            *b = self.byte()?;
+        }
+        Ok(ret)
+    }
+
+    fn bytes(&mut self) -> SqlResult<Vec<u8>> {
+        let mut ret = vec![];
+        loop {
+            match self.byte()? {
+                0x00 => match self.byte()? {
+                    0x00 => return Ok(ret),
+                    0xff => ret.push(0x00),
+                    other => {
+                        return Err(Error::Value(format!(
+                            "invalid escaped byte {:#x} in key",
+                            other
+                        )))
+                    }
+                },
+                b => ret.push(b),
+            }
+        }
+    }
+}
+
+/// Reads one value of the type encoded by [encode_scalar] with the same order from the
+/// start of input, and advances input past it
+pub fn decode_scalar(
+    data_type: &DataType,
+    order: Order,
+    input: &mut &[u8],
+) -> SqlResult<ScalarValue> {
+    let mut reader = Reader {
+        input,
+        mask: if order == Order::Desc { 0xff } else { 0x00 },
+    };
+    let is_null = match reader.byte()? {
+        NULL => true,
+        NOT_NULL => false,
+        other => {
+            return Err(Error::Value(format!(
+                "invalid null tag {:#x} in key",
+                other
+            )))
+        }
+    };
+    if is_null {
+        return match data_type {
+            DataType::Binary => Ok(ScalarValue::Binary(None)),
+            DataType::LargeBinary => Ok(ScalarValue::LargeBinary(None)),
+            _ => ScalarValue::try_from(data_type).map_err(|e| e.into()),
+        };
+    }
+    let int64 = |reader: &mut Reader| -> SqlResult<i64> {
+        Ok((u64::from_be_bytes(reader.array()?) ^ (1 << 63)) as i64)
+    };
+    let value = match data_type {
+        DataType::Boolean => ScalarValue::Boolean(Some(reader.byte()? != 0)),
+        DataType::Int8 => ScalarValue::Int8(Some((reader.byte()? ^ (1 << 7)) as i8)),
+        DataType::Int16 => ScalarValue::Int16(Some(
+            (u16::from_be_bytes(reader.array()?) ^ (1 << 15)) as i16,
+        )),
+        DataType::Int32 | DataType::Date32 => {
+            let v = (u32::from_be_bytes(reader.array()?) ^ (1 << 31)) as i32;
+            match data_type {
+                DataType::Int32 => ScalarValue::Int32(Some(v)),
+                _ => ScalarValue::Date32(Some(v)),
+            }
+        }
+        DataType::Int64 => ScalarValue::Int64(Some(int64(&mut reader)?)),
+        DataType::Date64 => ScalarValue::Date64(Some(int64(&mut reader)?)),
+        DataType::Timestamp(unit, tz) => {
+            let v = Some(int64(&mut reader)?);
+            match unit {
+                TimeUnit::Second => ScalarValue::TimestampSecond(v, tz.clone()),
+                TimeUnit::Millisecond => ScalarValue::TimestampMillisecond(v, tz.clone()),
+                TimeUnit::Microsecond => ScalarValue::TimestampMicrosecond(v, tz.clone()),
+                TimeUnit::Nanosecond => ScalarValue::TimestampNanosecond(v, tz.clone()),
+            }
+        }
+        DataType::UInt8 => ScalarValue::UInt8(Some(reader.byte()?)),
+        DataType::UInt16 => ScalarValue::UInt16(Some(u16::from_be_bytes(reader.array()?))),
+        DataType::UInt32 => ScalarValue::UInt32(Some(u32::from_be_bytes(reader.array()?))),
+        DataType::UInt64 => ScalarValue::UInt64(Some(u64::from_be_bytes(reader.array()?))),
+        DataType::Float32 => {
+            let bits = u32::from_be_bytes(reader.array()?);
+            let bits = if bits >> 31 == 1 {
+                bits ^ (1 << 31)
+            } else {
+                !bits
+            };
+            ScalarValue::Float32(Some(f32::from_bits(bits)))
+        }
+        DataType::Float64 => {
+            let bits = u64::from_be_bytes(reader.array()?);
+            let bits = if bits >> 63 == 1 {
+                bits ^ (1 << 63)
+            } else {
+                !bits
+            };
+            ScalarValue::Float64(Some(f64::from_bits(bits)))
+        }
+        DataType::Utf8 | DataType::LargeUtf8 => {
+            let v = String::from_utf8(reader.bytes()?).map_err(|e| Error::Value(e.to_string()))?;
+            match data_type {
+                DataType::Utf8 => ScalarValue::Utf8(Some(v)),
+                _ => ScalarValue::LargeUtf8(Some(v)),
+            }
+        }
+        DataType::Binary => ScalarValue::Binary(Some(reader.bytes()?)),
+        DataType::LargeBinary => ScalarValue::LargeBinary(Some(reader.bytes()?)),
+        other => {
+            return Err(Error::Value(format!(
+                "values of type {} cannot be decoded from a key",
+                other
+            )))
+        }
+    };
+    Ok(value)
+}
+
+/// Encodes a tuple of values, each one in its own order
+pub fn encode_key(values: &[ScalarValue], orders: &[Order]) -> SqlResult<Vec<u8>> {
+    let mut buf = vec![];
+    for (value, order) in values.iter().zip(orders) {
+        encode_scalar(value, *order, &mut buf)?;
+    }
+    Ok(buf)
+}
+
+/// Decodes a tuple encoded by [encode_key], the whole key must be consumed
+pub fn decode_key(types: &[DataType], orders: &[Order], key: &[u8]) -> SqlResult<Vec<ScalarValue>> {
+    let mut input = key;
+    let values = types
+        .iter()
+        .zip(orders)
+        .map(|(data_type, order)| decode_scalar(data_type, *order, &mut input))
+        .collect::<SqlResult<Vec<_>>>()?;
+    if !input.is_empty() {
+        return Err(Error::Value(format!(
+            "{} trailing bytes after the key",
+            input.len()
+        )));
+    }
+    Ok(values)
+}
+
+/// Whether values of the type can be encoded by [encode_scalar]
+pub fn is_encodable(data_type: &DataType) -> bool {
+    use DataType::*;
+    matches!(
+        data_type,
+        Boolean
+            | Int8
+            | Int16
+            | Int32
+            | Int64
+            | UInt8
+            | UInt16
+            | UInt32
+            | UInt64
+            | Float32
+            | Float64
+            | Date32
+            | Date64
+            | Timestamp(_, _)
+            | Utf8
+            | LargeUtf8
+            | Binary
+            | LargeBinary
+    )
+}
+
+/// Encodes the values of one row of the given columns in ascending order, floats are
+/// made [canonical] first
+pub fn encode_row(columns: &[ArrayRef], row: usize) -> SqlResult<Vec<u8>> {
+    let mut buf = vec![];
+    for column in columns {
+        let value = ScalarValue::try_from_array(column, row)?;
+        encode_scalar(&canonical(value), Order::Asc, &mut buf)?;
+    }
+    Ok(buf)
+}
+
+/// Turns -0.0 into 0.0 and every NaN into the same NaN. Keys of values that are equal
+/// by IEEE comparisons, as predicates compare them, are then equal as well, which
+/// [f64::total_cmp] alone does not give
+pub fn canonical(value: ScalarValue) -> ScalarValue {
+    match value {
+        ScalarValue::Float32(Some(v)) if v == 0.0 => ScalarValue::Float32(Some(0.0)),
+        ScalarValue::Float32(Some(v)) if v.is_nan() => ScalarValue::Float32(Some(f32::NAN)),
+        ScalarValue::Float64(Some(v)) if v == 0.0 => ScalarValue::Float64(Some(0.0)),
+        ScalarValue::Float64(Some(v)) if v.is_nan() => ScalarValue::Float64(Some(f64::NAN)),
+        value => value,
+    }
+}
+
+/// Smallest byte string greater than every string starting with prefix, None if
+/// there is no such string
+pub fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{decode_key, decode_scalar, encode_key, encode_scalar, prefix_end, Order};
+    use datafusion::{
+        arrow::datatypes::{DataType, TimeUnit},
+        scalar::ScalarValue,
+    };
+    use proptest::prelude::*;
+    use std::cmp::Ordering;
+
+    fn encode(value: &ScalarValue, order: Order) -> Vec<u8> {
+        let mut buf = vec![];
+        encode_scalar(value, order, &mut buf).unwrap();
+        buf
+    }
+
+    fn order_strategy() -> impl Strategy<Value = Order> {
+        prop_oneof![Just(Order::Asc), Just(Order::Desc)]
+    }
+
+    /// Values of one type, with the order of the values
+    #[derive(Clone, Debug)]
+    enum Typed {
+        Boolean(Option<bool>),
+        Int8(Option<i8>),
+        Int32(Option<i32>),
+        Int64(Option<i64>),
+        UInt16(Option<u16>),
+        Float32(Option<f32>),
+        Float64(Option<f64>),
+        Utf8(Option<String>),
+        Binary(Option<Vec<u8>>),
+    }
+
+    impl Typed {
+        fn scalar(&self) -> ScalarValue {
+            match self.clone() {
+                Typed::Boolean(v) => ScalarValue::Boolean(v),
+                Typed::Int8(v) => ScalarValue::Int8(v),
+                Typed::Int32(v) => ScalarValue::Int32(v),
+                Typed::Int64(v) => ScalarValue::Int64(v),
+                Typed::UInt16(v) => ScalarValue::UInt16(v),
+                Typed::Float32(v) => ScalarValue::Float32(v),
+                Typed::Float64(v) => ScalarValue::Float64(v),
+                Typed::Utf8(v) => ScalarValue::Utf8(v),
+                Typed::Binary(v) => ScalarValue::Binary(v),
+            }
+        }
+
+        /// None if the values have different types
+        fn cmp(&self, other: &Typed) -> Option<Ordering> {
+            fn total<T>(l: &Option<T>, r: &Option<T>, cmp: fn(&T, &T) -> Ordering) -> Ordering {
+                match (l, r) {
+                    (Some(l), Some(r)) => cmp(l, r),
+                    _ => l.is_some().cmp(&r.is_some()),
+                }
+            }
+            let ordering = match (self, other) {
+                (Typed::Boolean(l), Typed::Boolean(r)) => l.cmp(r),
+                (Typed::Int8(l), Typed::Int8(r)) => l.cmp(r),
+                (Typed::Int32(l), Typed::Int32(r)) => l.cmp(r),
+                (Typed::Int64(l), Typed::Int64(r)) => l.cmp(r),
+                (Typed::UInt16(l), Typed::UInt16(r)) => l.cmp(r),
+                (Typed::Float32(l), Typed::Float32(r)) => total(l, r, f32::total_cmp),
+                (Typed::Float64(l), Typed::Float64(r)) => total(l, r, f64::total_cmp),
+                (Typed::Utf8(l), Typed::Utf8(r)) => l.cmp(r),
+                (Typed::Binary(l), Typed::Binary(r)) => l.cmp(r),
+                _ => return None,
+            };
+            Some(ordering)
+        }
+    }
+
+    /// Pairs of values of the same type, strings and bytes are drawn from few
+    /// characters so that they often share prefixes and contain escaped bytes
+    fn pair_strategy() -> impl Strategy<Value = (Typed, Typed)> {
+        let bytes = || prop::collection::vec(prop_oneof![Just(0u8), Just(1), Just(0xff)], 0..5);
+        prop_oneof![
+            (any::<Option<bool>>(), any::<Option<bool>>())
+                .prop_map(|(l, r)| (Typed::Boolean(l), Typed::Boolean(r))),
+            (any::<Option<i8>>(), any::<Option<i8>>())
+                .prop_map(|(l, r)| (Typed::Int8(l), Typed::Int8(r))),
+            (any::<Option<i32>>(), any::<Option<i32>>())
+                .prop_map(|(l, r)| (Typed::Int32(l), Typed::Int32(r))),
+            (any::<Option<i64>>(), any::<Option<i64>>())
+                .prop_map(|(l, r)| (Typed::Int64(l), Typed::Int64(r))),
+            (any::<Option<u16>>(), any::<Option<u16>>())
+                .prop_map(|(l, r)| (Typed::UInt16(l), Typed::UInt16(r))),
+            (any::<Option<f32>>(), any::<Option<f32>>())
+                .prop_map(|(l, r)| (Typed::Float32(l), Typed::Float32(r))),
+            (any::<Option<f64>>(), any::<Option<f64>>())
+                .prop_map(|(l, r)| (Typed::Float64(l), Typed::Float64(r))),
+            (
+                prop::option::of("[\\x00ab\u{e9}]{0,4}"),
+                prop::option::of("[\\x00ab\u{e9}]{0,4}")
+            )
+                .prop_map(|(l, r)| (Typed::Utf8(l), Typed::Utf8(r))),
+            (prop::option::of(bytes()), prop::option::of(bytes()))
+                .prop_map(|(l, r)| (Typed::Binary(l), Typed::Binary(r))),
+        ]
+    }
+
+    fn assert_round_trip(value: &ScalarValue, order: Order) {
+        let encoded = encode(value, order);
+        let mut input = encoded.as_slice();
+        let decoded = decode_scalar(&value.get_datatype(), order, &mut input).unwrap();
+        assert!(input.is_empty());
+        // compared as bits, NaN is not equal to itself
+        match (value, &decoded) {
+            (ScalarValue::Float32(Some(l)), ScalarValue::Float32(Some(r))) => {
+                assert_eq!(l.to_bits(), r.to_bits())
+            }
+            (ScalarValue::Float64(Some(l)), ScalarValue::Float64(Some(r))) => {
+                assert_eq!(l.to_bits(), r.to_bits())
+            }
+            _ => assert_eq!(value, &decoded),
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_encoded_order((left, right) in pair_strategy(), order in order_strategy()) {
+            let expected = left.cmp(&right).unwrap();
+            let expected = if order == Order::Desc { expected.reverse() } else { expected };
+            let encoded = encode(&left.scalar(), order).cmp(&encode(&right.scalar(), order));
+            prop_assert_eq!(expected, encoded);
+            assert_round_trip(&left.scalar(), order);
+            assert_round_trip(&right.scalar(), order);
+        }
+
+        #[test]
+        fn test_composite_key_order(
+            pairs in prop::collection::vec(pair_strategy(), 1..4),
+            orders in prop::collection::vec(order_strategy(), 4),
+        ) {
+            let (left, right): (Vec<_>, Vec<_>) = pairs.into_iter().unzip();
+            let expected = left
+                .iter()
+                .zip(&right)
+                .zip(&orders)
+                .map(|((l, r), order)| {
+                    let ordering = l.cmp(r).unwrap();
+                    if *order == Order::Desc { ordering.reverse() } else { ordering }
+                })
+                .find(|ordering| *ordering != Ordering::Equal)
+                .unwrap_or(Ordering::Equal);
+            let left = left.iter().map(Typed::scalar).collect::<Vec<_>>();
+            let right = right.iter().map(Typed::scalar).collect::<Vec<_>>();
+            let encoded_left = encode_key(&left, &orders).unwrap();
+            let encoded_right = encode_key(&right, &orders).unwrap();
+            prop_assert_eq!(expected, encoded_left.cmp(&encoded_right));
+
+            let types = left.iter().map(|v| v.get_datatype()).collect::<Vec<_>>();
+            let decoded = decode_key(&types, &orders, &encoded_left).unwrap();
+            prop_assert_eq!(encode_key(&decoded, &orders).unwrap(), encoded_left);
+        }
+    }
+
+    #[test]
+    fn test_decode_key() {
+        let timestamp = ScalarValue::TimestampMillisecond(Some(-5), Some("UTC".to_string()));
+        let values = vec![
+            ScalarValue::Date32(Some(19000)),
+            timestamp.clone(),
+            ScalarValue::LargeUtf8(None),
+        ];
+        let orders = [Order::Desc, Order::Asc, Order::Desc];
+        let types = [
+            DataType::Date32,
+            DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".to_string())),
+            DataType::LargeUtf8,
+        ];
+        let key = encode_key(&values, &orders).unwrap();
+        assert_eq!(values, decode_key(&types, &orders, &key).unwrap());
+
+        assert!(decode_key(&types[..2], &orders, &key).is_err());
+        assert!(decode_key(&types, &orders, &key[..key.len() - 1]).is_err());
+        let mut buf = vec![];
+        assert!(encode_scalar(
+            &ScalarValue::Decimal128(Some(1), 10, 2),
+            Order::Asc,
+            &mut buf
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_prefix_end() {
+        assert_eq!(Some(vec![1, 3]), prefix_end(&[1, 2]));
+        assert_eq!(Some(vec![2]), prefix_end(&[1, 0xff]));
+        assert_eq!(None, prefix_end(&[0xff, 0xff]));
+    }
+}
@@ -0,0 +1,60 @@
+use super::mvcc::{Range, Scan, Store};
+use crate::sql::SqlResult;
+use std::collections::BTreeMap;
+
+/// In-memory Store backed by a BTreeMap, nothing survives a restart so it is mostly
+/// useful for tests
+#[derive(Default)]
+pub struct Memory {
+    data: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl Memory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Store for Memory {
+    fn delete(&mut self, key: &[u8]) -> SqlResult<()> {
+        self.data.remove(key);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> SqlResult<()> {
+        Ok(())
+    }
+
+    fn get(&self, key: &[u8]) -> SqlResult<Option<Vec<u8>>> {
+        Ok(self.data.get(key).cloned())
+    }
+
+    fn scan(&self, range: Range) -> Scan {
+        if range.is_empty() {
+            return Box::new(std::iter::empty());
+        }
+        // Scan must not borrow the store, so the matching entries are copied out
+        let items: Vec<_> = self
+            .data
+            .range(range.bounds())
+            .map(|(k, v)| Ok((k.clone(), v.clone())))
+            .collect();
+        Box::new(items.into_iter())
+    }
+
+    fn set(&mut self, key: &[u8], value: Vec<u8>) -> SqlResult<()> {
+        self.data.insert(key.to_vec(), value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::Memory;
+    use crate::storage::mvcc::tests::test_store;
+
+    #[test]
+    fn test_memory_store() {
+        test_store(&mut Memory::new());
+    }
+}
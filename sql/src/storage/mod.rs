@@ -1,5 +1,7 @@
 // This folder contains implementation of Storage trait
 // mod btree;
 // mod bustub;
+pub mod keycode;
+pub mod memory;
 pub mod mvcc;
 pub mod sled;
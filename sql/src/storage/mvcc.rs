@@ -1,5 +1,14 @@
-use crate::sql::SqlResult;
-use std::ops::{Bound, RangeBounds};
+use super::keycode::{decode_scalar, encode_scalar, prefix_end, Order};
+use crate::sql::{Error, SqlResult};
+use datafusion::{arrow::datatypes::DataType, scalar::ScalarValue};
+use parking_lot::RwLock;
+use std::{
+    collections::HashSet,
+    convert::TryInto,
+    iter::Peekable,
+    ops::{Bound, RangeBounds},
+    sync::Arc,
+};
 
 /// Store is unaware of the version of the key, this logic must be decided inside the
 /// implementation of MVCC
@@ -43,6 +52,23 @@ impl Range {
         }
     }
 
+    /// Returns the bounds of the range, for stores that can seek to them directly.
+    pub(crate) fn bounds(&self) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+        (self.start.clone(), self.end.clone())
+    }
+
+    /// Checks if no key can fall into the range. Range APIs of the underlying stores may
+    /// panic on such ranges (e.g. start after end), so they are short-circuited instead.
+    pub(crate) fn is_empty(&self) -> bool {
+        match (&self.start, &self.end) {
+            (Bound::Included(start), Bound::Included(end)) => start > end,
+            (Bound::Included(start), Bound::Excluded(end))
+            | (Bound::Excluded(start), Bound::Included(end))
+            | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+            _ => false,
+        }
+    }
+
     /// Checks if the given value is contained in the range.
     fn contains(&self, v: &[u8]) -> bool {
         (match &self.start {
@@ -57,3 +83,733 @@ impl Range {
     }
 }
 pub type Scan = Box<dyn DoubleEndedIterator<Item = SqlResult<(Vec<u8>, Vec<u8>)>>>;
+
+/// Ids of transactions, also used as the version of the keys they write
+pub type TxnID = u64;
+
+/// Keys under which [MVCC] keeps its state in the underlying Store. Each kind starts with
+/// its own tag byte, below any printable character, so a Store can be shared with
+/// users that only write printable prefixes
+enum Key<'a> {
+    /// Id of the next transaction
+    TxnNext,
+    /// Marks a transaction as active, the set of these keys is the active set
+    TxnActive(TxnID),
+    /// Active set at the time a transaction began
+    TxnSnapshot(TxnID),
+    /// A key written by a transaction, so its versions can be removed on rollback
+    TxnUpdate(TxnID, &'a [u8]),
+    /// A version of a user key, the key is escaped so all versions of a key are adjacent
+    /// and ordered by version
+    Record(&'a [u8], TxnID),
+}
+
+const TXN_NEXT: u8 = 0x01;
+const TXN_ACTIVE: u8 = 0x02;
+const TXN_SNAPSHOT: u8 = 0x03;
+const TXN_UPDATE: u8 = 0x04;
+const RECORD: u8 = 0x05;
+
+impl<'a> Key<'a> {
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            Key::TxnNext => vec![TXN_NEXT],
+            Key::TxnActive(id) => [&[TXN_ACTIVE][..], &id.to_be_bytes()].concat(),
+            Key::TxnSnapshot(id) => [&[TXN_SNAPSHOT][..], &id.to_be_bytes()].concat(),
+            Key::TxnUpdate(id, key) => [&[TXN_UPDATE][..], &id.to_be_bytes(), key].concat(),
+            Key::Record(key, version) => {
+                let mut buf = vec![RECORD];
+                // encoding a binary value never fails
+                encode_scalar(
+                    &ScalarValue::Binary(Some(key.to_vec())),
+                    Order::Asc,
+                    &mut buf,
+                )
+                .unwrap();
+                buf.extend_from_slice(&version.to_be_bytes());
+                buf
+            }
+        }
+    }
+
+    /// Reads the id following the tag of TxnActive and TxnUpdate keys
+    fn decode_id(key: &[u8]) -> SqlResult<TxnID> {
+        Ok(TxnID::from_be_bytes(key[1..9].try_into()?))
+    }
+
+    /// Splits an encoded Record key into the user key and the version
+    fn decode_record(mut key: &[u8]) -> SqlResult<(Vec<u8>, TxnID)> {
+        key = &key[1..];
+        let user_key = match decode_scalar(&DataType::Binary, Order::Asc, &mut key)? {
+            ScalarValue::Binary(Some(user_key)) => user_key,
+            other => {
+                return Err(Error::Internal(format!(
+                    "invalid mvcc record key {:?}",
+                    other
+                )))
+            }
+        };
+        Ok((user_key, TxnID::from_be_bytes(key.try_into()?)))
+    }
+}
+
+/// Range of the keys starting with the tag
+fn tag_range(tag: u8) -> Range {
+    Range::from(vec![tag]..vec![tag + 1])
+}
+
+/// Multi version concurrency control over a Store, giving snapshot isolation to its
+/// transactions. Every write creates a new version of the key tagged with the id of the
+/// writer, and a transaction reads the latest version written by transactions that
+/// committed before it began. Two transactions writing the same key conflict, the one
+/// writing last fails with [Error::Serialization] and must be rolled back.
+///
+/// The active set is kept in the Store, so transactions left active by a crash stay
+/// invisible to later transactions until they are resumed and finished.
+#[derive(Clone)]
+pub struct MVCC {
+    store: Arc<RwLock<Box<dyn Store + Send + Sync>>>,
+}
+
+impl MVCC {
+    pub fn new(store: Box<dyn Store + Send + Sync>) -> Self {
+        MVCC {
+            store: Arc::new(RwLock::new(store)),
+        }
+    }
+
+    /// Begins a new transaction, which sees the data committed so far
+    pub fn begin(&self) -> SqlResult<Transaction> {
+        let mut store = self.store.write();
+        let id = match store.get(&Key::TxnNext.encode())? {
+            Some(raw) => TxnID::from_be_bytes(raw.as_slice().try_into()?),
+            None => 1,
+        };
+        store.set(&Key::TxnNext.encode(), (id + 1).to_be_bytes().to_vec())?;
+
+        let mut snapshot = HashSet::new();
+        for item in store.scan(tag_range(TXN_ACTIVE)) {
+            let (key, _) = item?;
+            snapshot.insert(Key::decode_id(&key)?);
+        }
+        store.set(
+            &Key::TxnSnapshot(id).encode(),
+            serde_json::to_vec(&snapshot)?,
+        )?;
+        store.set(&Key::TxnActive(id).encode(), vec![])?;
+        Ok(Transaction {
+            store: self.store.clone(),
+            id,
+            snapshot,
+        })
+    }
+
+    /// Resumes an active transaction, e.g. one that was active before a restart
+    pub fn resume(&self, id: TxnID) -> SqlResult<Transaction> {
+        let store = self.store.read();
+        if store.get(&Key::TxnActive(id).encode())?.is_none() {
+            return Err(Error::NotFound(format!("transaction {} is not active", id)));
+        }
+        let snapshot = match store.get(&Key::TxnSnapshot(id).encode())? {
+            Some(raw) => serde_json::from_slice(&raw)?,
+            None => {
+                return Err(Error::Internal(format!(
+                    "snapshot of transaction {} not found",
+                    id
+                )))
+            }
+        };
+        Ok(Transaction {
+            store: self.store.clone(),
+            id,
+            snapshot,
+        })
+    }
+
+    /// Ids of the active transactions
+    pub fn active(&self) -> SqlResult<Vec<TxnID>> {
+        let store = self.store.read();
+        let mut ret = vec![];
+        for item in store.scan(tag_range(TXN_ACTIVE)) {
+            let (key, _) = item?;
+            ret.push(Key::decode_id(&key)?);
+        }
+        Ok(ret)
+    }
+}
+
+/// A transaction of [MVCC]. It stays active until commit or rollback is called, dropping
+/// it keeps it active so it can be resumed
+#[derive(Clone)]
+pub struct Transaction {
+    store: Arc<RwLock<Box<dyn Store + Send + Sync>>>,
+    id: TxnID,
+    /// Transactions that were active when this one began
+    snapshot: HashSet<TxnID>,
+}
+
+impl Transaction {
+    pub fn id(&self) -> TxnID {
+        self.id
+    }
+
+    /// Checks if a version is visible to the transaction
+    fn is_visible(id: TxnID, snapshot: &HashSet<TxnID>, version: TxnID) -> bool {
+        version == id || (version < id && !snapshot.contains(&version))
+    }
+
+    /// Makes the writes of the transaction visible to transactions beginning after it.
+    /// Writes to the Store are not atomic, so removing the transaction from the active
+    /// set is the commit point. The TxnUpdate markers are removed afterwards, markers
+    /// left by a crash belong to a transaction that can no longer be resumed
+    pub fn commit(&self) -> SqlResult<()> {
+        let mut store = self.store.write();
+        store.delete(&Key::TxnActive(self.id).encode())?;
+        store.flush()?;
+        for (update, _) in Self::updates(&**store, self.id)? {
+            store.delete(&update)?;
+        }
+        store.delete(&Key::TxnSnapshot(self.id).encode())?;
+        store.flush()
+    }
+
+    /// Removes the writes of the transaction. Each version is removed before its marker
+    /// and the transaction stays active until all of them are gone, so a rollback cut
+    /// short by a crash can be resumed and rolled back again
+    pub fn rollback(&self) -> SqlResult<()> {
+        let mut store = self.store.write();
+        for (update, key) in Self::updates(&**store, self.id)? {
+            store.delete(&Key::Record(&key, self.id).encode())?;
+            store.delete(&update)?;
+        }
+        store.flush()?;
+        store.delete(&Key::TxnActive(self.id).encode())?;
+        store.delete(&Key::TxnSnapshot(self.id).encode())?;
+        store.flush()
+    }
+
+    /// TxnUpdate keys of the transaction, with the user keys they record
+    fn updates(store: &dyn Store, id: TxnID) -> SqlResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        let prefix = [&[TXN_UPDATE][..], &id.to_be_bytes()].concat();
+        let end = prefix_end(&prefix).unwrap();
+        store
+            .scan(Range::from(prefix.clone()..end))
+            .map(|item| {
+                let (update, _) = item?;
+                let key = update[prefix.len()..].to_vec();
+                Ok((update, key))
+            })
+            .collect()
+    }
+
+    /// Gets the latest version of the key visible to the transaction
+    pub fn get(&self, key: &[u8]) -> SqlResult<Option<Vec<u8>>> {
+        let store = self.store.read();
+        let range = Range::from(Key::Record(key, 0).encode()..=Key::Record(key, self.id).encode());
+        for item in store.scan(range).rev() {
+            let (record, value) = item?;
+            let (_, version) = Key::decode_record(&record)?;
+            if Self::is_visible(self.id, &self.snapshot, version) {
+                return Self::decode_value(value);
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn set(&self, key: &[u8], value: Vec<u8>) -> SqlResult<()> {
+        self.write(key, Some(value))
+    }
+
+    pub fn delete(&self, key: &[u8]) -> SqlResult<()> {
+        self.write(key, None)
+    }
+
+    /// Writes a new version of the key, a deleted key gets a tombstone version
+    fn write(&self, key: &[u8], value: Option<Vec<u8>>) -> SqlResult<()> {
+        let mut store = self.store.write();
+        // the latest version of the key must be visible, otherwise it was written by a
+        // transaction that is concurrent with this one
+        let range =
+            Range::from(Key::Record(key, 0).encode()..=Key::Record(key, TxnID::MAX).encode());
+        if let Some(item) = store.scan(range).next_back() {
+            let (record, _) = item?;
+            let (_, version) = Key::decode_record(&record)?;
+            if !Self::is_visible(self.id, &self.snapshot, version) {
+                return Err(Error::Serialization);
+            }
+        }
+        store.set(&Key::TxnUpdate(self.id, key).encode(), vec![])?;
+        store.set(
+            &Key::Record(key, self.id).encode(),
+            Self::encode_value(value),
+        )
+    }
+
+    /// Versions are tagged with 0x00 for tombstones and 0x01 for values
+    fn encode_value(value: Option<Vec<u8>>) -> Vec<u8> {
+        match value {
+            None => vec![0x00],
+            Some(value) => [&[0x01][..], &value].concat(),
+        }
+    }
+
+    fn decode_value(mut value: Vec<u8>) -> SqlResult<Option<Vec<u8>>> {
+        match value.first() {
+            Some(0x00) => Ok(None),
+            Some(0x01) => Ok(Some(value.split_off(1))),
+            _ => Err(Error::Internal("invalid mvcc value".to_string())),
+        }
+    }
+
+    /// Iterates over the keys of the range visible to the transaction, with their latest
+    /// visible values. Writes made by the transaction after the scan began may or may not
+    /// be returned
+    pub fn scan(&self, range: Range) -> Scan {
+        let start = match &range.start {
+            Bound::Included(k) => Bound::Included(Key::Record(k, 0).encode()),
+            Bound::Excluded(k) => Bound::Excluded(Key::Record(k, TxnID::MAX).encode()),
+            Bound::Unbounded => Bound::Included(vec![RECORD]),
+        };
+        let end = match &range.end {
+            Bound::Included(k) => Bound::Included(Key::Record(k, TxnID::MAX).encode()),
+            Bound::Excluded(k) => Bound::Excluded(Key::Record(k, 0).encode()),
+            Bound::Unbounded => Bound::Excluded(vec![RECORD + 1]),
+        };
+        let records = self.store.read().scan(Range::from((start, end)));
+        let id = self.id;
+        let snapshot = self.snapshot.clone();
+        let versions = records.filter_map(move |item| {
+            let result = item.and_then(|(record, value)| {
+                let (key, version) = Key::decode_record(&record)?;
+                if !Self::is_visible(id, &snapshot, version) {
+                    return Ok(None);
+                }
+                Ok(Some((key, Self::decode_value(value)?)))
+            });
+            result.transpose()
+        });
+        Box::new(MVCCScan {
+            versions: (Box::new(versions) as VersionScan).peekable(),
+            last_back: None,
+        })
+    }
+
+    /// Iterates over the keys starting with the prefix, see [Transaction::scan]
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Scan {
+        let end = match prefix_end(prefix) {
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        };
+        self.scan(Range::from((Bound::Included(prefix.to_vec()), end)))
+    }
+}
+
+type VersionScan = Box<dyn DoubleEndedIterator<Item = SqlResult<(Vec<u8>, Option<Vec<u8>>)>>>;
+
+/// Reduces the visible versions of each key to the latest one, skipping tombstones.
+/// Versions of a key are adjacent and ordered from oldest to latest
+struct MVCCScan {
+    versions: Peekable<VersionScan>,
+    /// Last key seen from the back, its versions are done with
+    last_back: Option<Vec<u8>>,
+}
+
+impl MVCCScan {
+    fn try_next(&mut self) -> SqlResult<Option<(Vec<u8>, Vec<u8>)>> {
+        while let Some((key, value)) = self.versions.next().transpose()? {
+            if self.last_back.as_ref() == Some(&key) {
+                continue;
+            }
+            let is_latest = match self.versions.peek() {
+                Some(Ok((next, _))) => *next != key,
+                Some(Err(e)) => return Err(e.clone()),
+                None => true,
+            };
+            if let (true, Some(value)) = (is_latest, value) {
+                return Ok(Some((key, value)));
+            }
+        }
+        Ok(None)
+    }
+
+    fn try_next_back(&mut self) -> SqlResult<Option<(Vec<u8>, Vec<u8>)>> {
+        while let Some((key, value)) = self.versions.next_back().transpose()? {
+            if self.last_back.as_ref() == Some(&key) {
+                continue;
+            }
+            self.last_back = Some(key.clone());
+            if let Some(value) = value {
+                return Ok(Some((key, value)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Iterator for MVCCScan {
+    type Item = SqlResult<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.try_next().transpose()
+    }
+}
+
+impl DoubleEndedIterator for MVCCScan {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.try_next_back().transpose()
+    }
+}
+
+/// Conformance suite shared by all Store implementations
+#[cfg(test)]
+pub mod tests {
+    use super::{Range, Scan, Store, MVCC};
+    use crate::{
+        sql::{Error, SqlResult},
+        storage::memory::Memory,
+    };
+    use std::{
+        ops::Bound,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    fn collect(scan: super::Scan) -> Vec<(Vec<u8>, Vec<u8>)> {
+        scan.collect::<SqlResult<_>>().unwrap()
+    }
+
+    /// Runs every check of the suite against an empty store
+    pub fn test_store(s: &mut dyn Store) {
+        test_point_ops(s);
+        test_scan(s);
+    }
+
+    fn test_point_ops(s: &mut dyn Store) {
+        assert_eq!(None, s.get(b"a").unwrap());
+
+        s.set(b"a", vec![1]).unwrap();
+        assert_eq!(Some(vec![1]), s.get(b"a").unwrap());
+        s.set(b"a", vec![2]).unwrap();
+        assert_eq!(Some(vec![2]), s.get(b"a").unwrap());
+
+        // empty keys and values are valid
+        s.set(b"", vec![]).unwrap();
+        assert_eq!(Some(vec![]), s.get(b"").unwrap());
+
+        s.delete(b"a").unwrap();
+        assert_eq!(None, s.get(b"a").unwrap());
+        s.delete(b"a").unwrap();
+        s.delete(b"").unwrap();
+        assert_eq!(None, s.get(b"").unwrap());
+        s.flush().unwrap();
+        assert!(collect(s.scan(Range::from(..))).is_empty());
+    }
+
+    fn test_scan(s: &mut dyn Store) {
+        let keys: Vec<Vec<u8>> = vec![
+            vec![0x00],
+            vec![0x01],
+            vec![0x01, 0x00],
+            vec![0x01, 0xff],
+            vec![0x02],
+            vec![0xff],
+            vec![0xff, 0xff],
+        ];
+        for (i, k) in keys.iter().enumerate() {
+            s.set(k, vec![i as u8]).unwrap();
+        }
+        s.flush().unwrap();
+        let all: Vec<_> = keys
+            .iter()
+            .enumerate()
+            .map(|(i, k)| (k.clone(), vec![i as u8]))
+            .collect();
+        assert_eq!(all, collect(s.scan(Range::from(..))));
+
+        // probes include keys both present and absent in the store
+        let mut probes = keys.clone();
+        probes.extend(vec![
+            vec![],
+            vec![0x01, 0x80],
+            vec![0x03],
+            vec![0xff, 0xff, 0x00],
+        ]);
+        let mut bounds = vec![Bound::Unbounded];
+        for p in &probes {
+            bounds.push(Bound::Included(p.clone()));
+            bounds.push(Bound::Excluded(p.clone()));
+        }
+
+        for start in &bounds {
+            for end in &bounds {
+                let range = Range::from((start.clone(), end.clone()));
+                let expected: Vec<_> = all
+                    .iter()
+                    .filter(|(k, _)| range.contains(k))
+                    .cloned()
+                    .collect();
+                let msg = format!("range {:?}..{:?}", start, end);
+
+                assert_eq!(expected, collect(s.scan(range)), "forward {}", msg);
+
+                let range = Range::from((start.clone(), end.clone()));
+                let mut reversed: Vec<_> = collect(Box::new(s.scan(range).rev()));
+                reversed.reverse();
+                assert_eq!(expected, reversed, "backward {}", msg);
+
+                // alternate both ends of the same iterator until they meet
+                let mut scan = s.scan(Range::from((start.clone(), end.clone())));
+                let (mut front, mut back) = (vec![], vec![]);
+                while let Some(item) = scan.next() {
+                    front.push(item.unwrap());
+                    match scan.next_back() {
+                        Some(item) => back.push(item.unwrap()),
+                        None => break,
+                    }
+                }
+                assert!(scan.next().is_none(), "exhausted {}", msg);
+                assert!(scan.next_back().is_none(), "exhausted {}", msg);
+                back.reverse();
+                front.extend(back);
+                assert_eq!(expected, front, "interleaved {}", msg);
+            }
+        }
+
+        s.delete(&[0xff]).unwrap();
+        s.set(&[0x03], vec![9]).unwrap();
+        assert_eq!(
+            vec![
+                (vec![0x02], vec![4]),
+                (vec![0x03], vec![9]),
+                (vec![0xff, 0xff], vec![6])
+            ],
+            collect(s.scan(Range::from(vec![0x02]..)))
+        );
+    }
+
+    /// Store running every operation in its own transaction, so keys get many versions
+    /// and tombstones
+    struct AutoCommit(MVCC);
+
+    impl Store for AutoCommit {
+        fn delete(&mut self, key: &[u8]) -> SqlResult<()> {
+            let txn = self.0.begin()?;
+            txn.delete(key)?;
+            txn.commit()
+        }
+
+        fn flush(&mut self) -> SqlResult<()> {
+            Ok(())
+        }
+
+        fn get(&self, key: &[u8]) -> SqlResult<Option<Vec<u8>>> {
+            let txn = self.0.begin()?;
+            let ret = txn.get(key)?;
+            txn.commit()?;
+            Ok(ret)
+        }
+
+        fn scan(&self, range: Range) -> Scan {
+            let txn = self.0.begin().unwrap();
+            let scan = txn.scan(range);
+            txn.commit().unwrap();
+            scan
+        }
+
+        fn set(&mut self, key: &[u8], value: Vec<u8>) -> SqlResult<()> {
+            let txn = self.0.begin()?;
+            txn.set(key, value)?;
+            txn.commit()
+        }
+    }
+
+    fn scan_all(txn: &super::Transaction) -> Vec<(Vec<u8>, Vec<u8>)> {
+        collect(txn.scan(Range::from(..)))
+    }
+
+    #[test]
+    fn test_mvcc_store() {
+        test_store(&mut AutoCommit(MVCC::new(Box::new(Memory::new()))));
+    }
+
+    #[test]
+    fn test_mvcc_isolation() {
+        let mvcc = MVCC::new(Box::new(Memory::new()));
+        let setup = mvcc.begin().unwrap();
+        setup.set(b"a", vec![1]).unwrap();
+        setup.set(b"b", vec![1]).unwrap();
+        setup.commit().unwrap();
+
+        let t1 = mvcc.begin().unwrap();
+        let t2 = mvcc.begin().unwrap();
+        t1.set(b"a", vec![2]).unwrap();
+        t1.delete(b"b").unwrap();
+        t1.set(b"c", vec![2]).unwrap();
+
+        // own writes are visible, concurrent ones are not
+        assert_eq!(Some(vec![2]), t1.get(b"a").unwrap());
+        assert_eq!(None, t1.get(b"b").unwrap());
+        assert_eq!(
+            vec![(b"a".to_vec(), vec![2]), (b"c".to_vec(), vec![2])],
+            scan_all(&t1)
+        );
+        assert_eq!(Some(vec![1]), t2.get(b"a").unwrap());
+        t1.commit().unwrap();
+
+        // t2 keeps reading its snapshot after t1 committed
+        assert_eq!(Some(vec![1]), t2.get(b"a").unwrap());
+        assert_eq!(
+            vec![(b"a".to_vec(), vec![1]), (b"b".to_vec(), vec![1])],
+            scan_all(&t2)
+        );
+        let t3 = mvcc.begin().unwrap();
+        assert_eq!(
+            vec![(b"a".to_vec(), vec![2]), (b"c".to_vec(), vec![2])],
+            scan_all(&t3)
+        );
+
+        // rolled back writes are never visible
+        t3.set(b"d", vec![3]).unwrap();
+        t3.delete(b"a").unwrap();
+        t3.rollback().unwrap();
+        let t4 = mvcc.begin().unwrap();
+        assert_eq!(
+            vec![(b"a".to_vec(), vec![2]), (b"c".to_vec(), vec![2])],
+            scan_all(&t4)
+        );
+        assert_eq!(vec![t2.id(), t4.id()], mvcc.active().unwrap(),);
+    }
+
+    #[test]
+    fn test_mvcc_conflict() {
+        let mvcc = MVCC::new(Box::new(Memory::new()));
+        let t1 = mvcc.begin().unwrap();
+        let t2 = mvcc.begin().unwrap();
+        let t3 = mvcc.begin().unwrap();
+        t1.set(b"a", vec![1]).unwrap();
+
+        // active and later transactions both conflict
+        assert_eq!(Err(Error::Serialization), t2.set(b"a", vec![2]));
+        assert_eq!(Err(Error::Serialization), t3.delete(b"a"));
+        t1.commit().unwrap();
+        assert_eq!(Err(Error::Serialization), t2.set(b"a", vec![2]));
+        t2.rollback().unwrap();
+
+        // t1 committed before t4 began
+        let t4 = mvcc.begin().unwrap();
+        t4.set(b"a", vec![4]).unwrap();
+        t4.set(b"a", vec![5]).unwrap();
+        t4.commit().unwrap();
+
+        // keys written by rolled back transactions are free again
+        t3.set(b"b", vec![3]).unwrap();
+        t3.rollback().unwrap();
+        let t5 = mvcc.begin().unwrap();
+        t5.set(b"b", vec![5]).unwrap();
+        assert_eq!(
+            vec![(b"a".to_vec(), vec![5]), (b"b".to_vec(), vec![5])],
+            scan_all(&t5)
+        );
+    }
+
+    #[test]
+    fn test_mvcc_resume() {
+        let mvcc = MVCC::new(Box::new(Memory::new()));
+        let t1 = mvcc.begin().unwrap();
+        t1.set(b"a", vec![1]).unwrap();
+        let id = t1.id();
+        drop(t1);
+
+        // a dropped transaction stays active and invisible
+        let t2 = mvcc.begin().unwrap();
+        assert_eq!(None, t2.get(b"a").unwrap());
+        let t1 = mvcc.resume(id).unwrap();
+        assert_eq!(Some(vec![1]), t1.get(b"a").unwrap());
+        t1.commit().unwrap();
+        assert_eq!(None, t2.get(b"a").unwrap());
+        assert_eq!(Some(vec![1]), mvcc.begin().unwrap().get(b"a").unwrap());
+
+        assert!(matches!(mvcc.resume(id), Err(Error::NotFound(_))));
+    }
+
+    /// Fails every write once its budget of deletes is used up, as if the process
+    /// crashed there
+    struct Crashing {
+        inner: Memory,
+        deletes: Arc<AtomicUsize>,
+    }
+
+    impl Crashing {
+        fn check(&self) -> SqlResult<()> {
+            match self.deletes.load(Ordering::SeqCst) {
+                0 => Err(Error::Internal("crashed".to_string())),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    impl Store for Crashing {
+        fn delete(&mut self, key: &[u8]) -> SqlResult<()> {
+            self.check()?;
+            self.deletes.fetch_sub(1, Ordering::SeqCst);
+            self.inner.delete(key)
+        }
+
+        fn flush(&mut self) -> SqlResult<()> {
+            self.check()
+        }
+
+        fn get(&self, key: &[u8]) -> SqlResult<Option<Vec<u8>>> {
+            self.inner.get(key)
+        }
+
+        fn scan(&self, range: Range) -> Scan {
+            self.inner.scan(range)
+        }
+
+        fn set(&mut self, key: &[u8], value: Vec<u8>) -> SqlResult<()> {
+            self.check()?;
+            self.inner.set(key, value)
+        }
+    }
+
+    #[test]
+    fn test_mvcc_crash() {
+        for budget in 0..8 {
+            for commit in [true, false] {
+                let deletes = Arc::new(AtomicUsize::new(usize::MAX));
+                let mvcc = MVCC::new(Box::new(Crashing {
+                    inner: Memory::new(),
+                    deletes: deletes.clone(),
+                }));
+                let t1 = mvcc.begin().unwrap();
+                t1.set(b"a", vec![1]).unwrap();
+                t1.set(b"b", vec![1]).unwrap();
+                deletes.store(budget, Ordering::SeqCst);
+                let ret = if commit { t1.commit() } else { t1.rollback() };
+                deletes.store(usize::MAX, Ordering::SeqCst);
+
+                // a txn cut short is either finished or still active and can be rolled
+                // back, its writes are never visible in part
+                if ret.is_err() {
+                    if let Ok(t1) = mvcc.resume(t1.id()) {
+                        t1.rollback().unwrap();
+                    }
+                }
+                let visible = scan_all(&mvcc.begin().unwrap());
+                if visible.is_empty() {
+                    assert!(!commit || ret.is_err());
+                } else {
+                    assert!(commit);
+                    assert_eq!(
+                        vec![(b"a".to_vec(), vec![1]), (b"b".to_vec(), vec![1])],
+                        visible
+                    );
+                }
+                assert!(matches!(mvcc.resume(t1.id()), Err(Error::NotFound(_))));
+            }
+        }
+    }
+}
@@ -1,13 +1,54 @@
+use super::{
+    keycode::{encode_row, is_encodable, prefix_end},
+    mvcc::{Range, Scan, Store, Transaction, MVCC},
+};
 use crate::sql::{
-    exe::{BoxedDataIter, Catalog, Storage},
-    tx::Txn,
-    util, Error, SqlResult,
+    exe::{
+        collect_rids, rids_to_block, with_rid_schema, BoxedDataIter, Catalog, IndexMeta,
+        SchemaDataIter, Storage, TableMeta, OID, RID,
+    },
+    scan::index::IndexRange,
+    tx::{Txn, WriteKind},
+    util::GeneratorIteratorAdapter,
+    DataBlock, Error, SqlResult,
+};
+use datafusion::arrow::{
+    array::{Array, UInt64Array},
+    datatypes::{Schema, SchemaRef},
+    json::{reader::Decoder, writer::record_batches_to_json_rows},
 };
-use sled::{Db, IVec};
-use std::{array::TryFromSliceError, convert::TryInto};
+use serde_json::Value;
+use sled::Db;
+use std::{array::TryFromSliceError, convert::TryInto, sync::Arc};
+
+/// Pairs of position in a batch and RID of a row
+type RowPositions = Vec<(usize, RID)>;
+
+/// Number of rows decoded into one DataBlock during scan
+const SCAN_BATCH_SIZE: usize = 128;
+
+const NEXT_OID_KEY: &[u8] = b"meta/next_oid";
+const CATALOG_PREFIX: &[u8] = b"catalog/";
+const INDEX_CATALOG_PREFIX: &[u8] = b"index_catalog/";
 
+/// Key layout:
+/// - meta/next_oid: oid of the next created table
+/// - catalog/{table}: json encoded [TableMeta]
+/// - index_catalog/{index}: json encoded [IndexMeta]
+///
+/// Rows and index entries are read and written through [MVCC], which stores them
+/// versioned in the same tree:
+/// - data/{oid}/{rid}: json encoded row, rid is big endian so rows are scanned in
+/// insertion order
+/// - index/{oid}/{index}/{values}{rid}: empty value, values of the indexed columns are
+/// encoded with [keycode] so entries are scanned in the order of the values
+///
+/// Catalog changes are applied immediately and are not part of any transaction. Rows
+/// and index entries are keyed by the oid of their table, so a table created with the
+/// name of a dropped one never sees the rows left over from it.
 pub struct Sled {
     pub tree: Db,
+    mvcc: MVCC,
 }
 impl From<sled::Error> for Error {
     fn from(e: sled::Error) -> Error {
@@ -23,31 +64,262 @@ impl From<TryFromSliceError> for Error {
 impl Sled {
     pub fn new(filename: String) -> SqlResult<Self> {
         let tree = sled::open(filename)?;
-        Ok(Sled { tree })
+        let mvcc = MVCC::new(Box::new(SledStore { tree: tree.clone() }));
+        Ok(Sled { tree, mvcc })
     }
-}
-/* impl From<sled::transaction::TransactionError> for Error {
-    fn from(e: sled::transaction::TransactionError) -> Error {
-        Error::Value(e.to_string())
+
+    /// MVCC of rows and index entries, for [crate::sql::tx::TxnManager] to begin
+    /// transactions isolated from each other
+    pub fn mvcc(&self) -> MVCC {
+        self.mvcc.clone()
     }
-}
-impl From<&Tuple> for IVec {
-    fn from(tuple: &Tuple) -> IVec {
-        let mut ret = IVec::from(vec![0; 8 + tuple.data.len()]);
-        ret[..8].clone_from_slice(&tuple.rid.to_be_bytes());
-        ret[8..].clone_from_slice(&tuple.data);
-        ret
+
+    /// Runs f in the MVCC transaction of txn, or in a transaction of its own that is
+    /// committed when f succeeds if txn has none
+    fn in_txn<T>(&self, txn: &Txn, f: impl FnOnce(&Transaction) -> SqlResult<T>) -> SqlResult<T> {
+        if let Some(t) = txn.mvcc() {
+            return f(t);
+        }
+        let t = self.mvcc.begin()?;
+        match f(&t) {
+            Ok(ret) => {
+                t.commit()?;
+                Ok(ret)
+            }
+            Err(e) => {
+                t.rollback()?;
+                Err(e)
+            }
+        }
+    }
+
+    fn catalog_key(table: &str) -> Vec<u8> {
+        let mut key = CATALOG_PREFIX.to_vec();
+        key.extend_from_slice(table.as_bytes());
+        key
+    }
+
+    fn data_prefix(oid: OID) -> Vec<u8> {
+        format!("data/{}/", oid).into_bytes()
+    }
+
+    fn data_key(oid: OID, rid: RID) -> Vec<u8> {
+        let mut key = Self::data_prefix(oid);
+        key.extend_from_slice(&rid.to_be_bytes());
+        key
+    }
+
+    fn index_catalog_key(index: &str) -> Vec<u8> {
+        let mut key = INDEX_CATALOG_PREFIX.to_vec();
+        key.extend_from_slice(index.as_bytes());
+        key
+    }
+
+    fn index_prefix(oid: OID, index: &str) -> Vec<u8> {
+        format!("index/{}/{}/", oid, index).into_bytes()
+    }
+
+    /// Key prefixes of the indexes of the table with the positions of their columns in
+    /// the table schema
+    fn table_indexes(&self, table: &TableMeta) -> SqlResult<Vec<(Vec<u8>, Vec<usize>)>> {
+        let mut ret = vec![];
+        for meta in self.list_indexes(&table.name)? {
+            let columns = meta
+                .columns
+                .iter()
+                .map(|name| table.schema.index_of(name))
+                .collect::<Result<Vec<_>, _>>()?;
+            ret.push((Self::index_prefix(table.oid, &meta.name), columns));
+        }
+        Ok(ret)
+    }
+
+    /// Keys of the index entries of the given rows of batch, rows are pairs of
+    /// position in the batch and RID
+    fn index_keys(
+        indexes: &[(Vec<u8>, Vec<usize>)],
+        batch: &DataBlock,
+        rows: &[(usize, RID)],
+    ) -> SqlResult<Vec<Vec<u8>>> {
+        let mut keys = vec![];
+        for (prefix, columns) in indexes {
+            let columns = columns
+                .iter()
+                .map(|i| batch.column(*i).clone())
+                .collect::<Vec<_>>();
+            for (row, rid) in rows {
+                let mut key = prefix.clone();
+                key.extend(encode_row(&columns, *row)?);
+                key.extend_from_slice(&rid.to_be_bytes());
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Current rows of the RIDs that exist, with their position in rids
+    fn existing_rows(
+        t: &Transaction,
+        oid: OID,
+        rids: &[RID],
+    ) -> SqlResult<(RowPositions, Vec<Vec<u8>>)> {
+        let mut existing = vec![];
+        let mut rows = vec![];
+        for (i, rid) in rids.iter().enumerate() {
+            if let Some(row) = t.get(&Self::data_key(oid, *rid))? {
+                existing.push((i, *rid));
+                rows.push(row);
+            }
+        }
+        Ok((existing, rows))
+    }
+
+    /// Removes the index entries of the rows, rows are given as returned by
+    /// [Sled::existing_rows]
+    fn remove_index_entries(
+        t: &Transaction,
+        schema: SchemaRef,
+        indexes: &[(Vec<u8>, Vec<usize>)],
+        existing: &[(usize, RID)],
+        rows: Vec<Vec<u8>>,
+    ) -> SqlResult<()> {
+        if indexes.is_empty() || rows.is_empty() {
+            return Ok(());
+        }
+        let old = Self::decode_rows(schema, rows)?;
+        let positions = existing
+            .iter()
+            .enumerate()
+            .map(|(row, (_, rid))| (row, *rid))
+            .collect::<Vec<_>>();
+        for key in Self::index_keys(indexes, &old, &positions)? {
+            t.delete(&key)?;
+        }
+        Ok(())
+    }
+
+    fn next_oid(&self) -> SqlResult<OID> {
+        let mut ret = 0;
+        self.tree.fetch_and_update(NEXT_OID_KEY, |old| {
+            ret = match old.map(|raw| raw.try_into()) {
+                Some(Ok(raw)) => OID::from_be_bytes(raw),
+                _ => 0,
+            };
+            Some((ret + 1).to_be_bytes().to_vec())
+        })?;
+        Ok(ret)
+    }
+
+    /// Key of a row ends with its RID
+    fn rid_of_key(key: &[u8]) -> SqlResult<RID> {
+        let rid = key[key.len() - std::mem::size_of::<RID>()..].try_into()?;
+        Ok(RID::from_be_bytes(rid))
+    }
+
+    fn scan_rows(
+        &self,
+        t: &Transaction,
+        table: &TableMeta,
+        with_rids: bool,
+    ) -> SqlResult<BoxedDataIter> {
+        let schema = table.schema_ref();
+        let mut iter = t.scan_prefix(&Self::data_prefix(table.oid));
+        let moved_schema = schema.clone();
+        let gen = move || loop {
+            let mut rows = Vec::with_capacity(SCAN_BATCH_SIZE);
+            let mut rids = Vec::with_capacity(SCAN_BATCH_SIZE);
+            while rows.len() < SCAN_BATCH_SIZE {
+                match iter.next() {
+                    None => break,
+                    Some(Ok((key, row))) => {
+                        if with_rids {
+                            match Self::rid_of_key(&key) {
+                                Ok(rid) => rids.push(rid),
+                                Err(e) => {
+                                    yield Err(e);
+                                    return;
+                                }
+                            }
+                        }
+                        rows.push(row)
+                    }
+                    Some(Err(e)) => {
+                        yield Err(e);
+                        return;
+                    }
+                }
+            }
+            if rows.is_empty() {
+                return;
+            }
+            let batch = Self::decode_rows(moved_schema.clone(), rows);
+            if !with_rids {
+                yield batch;
+                continue;
+            }
+            yield batch.and_then(|batch| {
+                let mut columns = batch.columns().to_vec();
+                columns.push(Arc::new(UInt64Array::from(rids)));
+                Ok(DataBlock::try_new(with_rid_schema(&moved_schema), columns)?)
+            });
+        };
+        let iter = GeneratorIteratorAdapter::new(gen);
+        let schema = if with_rids {
+            with_rid_schema(&schema)
+        } else {
+            schema
+        };
+        Ok(SchemaDataIter::new(schema, Box::new(iter)))
+    }
+
+    fn decode_rows(schema: SchemaRef, rows: Vec<Vec<u8>>) -> SqlResult<DataBlock> {
+        let values = rows
+            .iter()
+            .map(|raw| serde_json::from_slice::<Value>(raw).map_err(|e| e.into()))
+            .collect::<SqlResult<Vec<_>>>()?;
+        let decoder = Decoder::new(schema.clone(), values.len(), None);
+        match decoder.next_batch(&mut values.into_iter().map(Ok))? {
+            Some(batch) => Ok(batch),
+            None => Ok(DataBlock::new_empty(schema)),
+        }
     }
 }
- */
 
 impl Storage for Sled {
     fn get_tuples(&self, table: &str, rids: BoxedDataIter, txn: &Txn) -> SqlResult<BoxedDataIter> {
-        todo!()
+        let meta = self.get_table(table)?;
+        let schema = meta.schema_ref();
+        let rids = collect_rids(rids)?;
+        let rows = self.in_txn(txn, |t| {
+            let mut rows = Vec::with_capacity(rids.len());
+            for rid in rids {
+                match t.get(&Self::data_key(meta.oid, rid))? {
+                    None => {
+                        return Err(Error::Value(format!(
+                            "rid {} not found in table {}",
+                            rid, table
+                        )))
+                    }
+                    Some(row) => rows.push(row),
+                }
+            }
+            Ok(rows)
+        })?;
+        let batch = Self::decode_rows(schema.clone(), rows)?;
+        Ok(SchemaDataIter::new(
+            schema,
+            Box::new(std::iter::once(Ok(batch))),
+        ))
     }
 
     fn scan(&self, table: &str, txn: &Txn) -> SqlResult<BoxedDataIter> {
-        todo!()
+        let meta = self.get_table(table)?;
+        self.in_txn(txn, |t| self.scan_rows(t, &meta, false))
+    }
+
+    fn scan_with_rids(&self, table: &str, txn: &Txn) -> SqlResult<BoxedDataIter> {
+        let meta = self.get_table(table)?;
+        self.in_txn(txn, |t| self.scan_rows(t, &meta, true))
     }
 
     fn insert_tuples(
@@ -56,62 +328,730 @@ impl Storage for Sled {
         data: BoxedDataIter,
         txn: &Txn,
     ) -> SqlResult<BoxedDataIter> {
-        todo!()
-        /* let datablocks = util::collect(data).await?;
-        let res = tokio::task::spawn_blocking(move || {
-            let rid = self.tree.transaction(move |tree| {
-                let rid = tree.generate_id()?;
-                let id = rid.to_be_bytes();
-                for item in &tuples {
-                    let prefix = format!("data/{}/", table.to_string());
-                    let key_bytes = prefix
-                        .into_bytes()
-                        .into_iter()
-                        .chain(id.iter().copied())
-                        .collect::<Vec<_>>();
-                    tree.insert(IVec::from(key_bytes), item.data.clone())?;
+        let meta = self.get_table(table)?;
+        let schema = meta.schema_ref();
+        let indexes = self.table_indexes(&meta)?;
+        let inserted_rids = self.in_txn(txn, |t| {
+            let mut inserted_rids = vec![];
+            for batch in data {
+                let batch = batch?;
+                if batch.schema().fields() != schema.fields() {
+                    return Err(Error::Value(format!(
+                        "schema of inserted data does not match table {}",
+                        table
+                    )));
+                }
+                let rows = record_batches_to_json_rows(&[batch.clone()])?;
+                let mut rids = Vec::with_capacity(rows.len());
+                for (i, row) in rows.into_iter().enumerate() {
+                    let rid = self.tree.generate_id()?;
+                    t.set(&Self::data_key(meta.oid, rid), serde_json::to_vec(&row)?)?;
+                    rids.push((i, rid));
+                }
+                for key in Self::index_keys(&indexes, &batch, &rids)? {
+                    t.set(&key, vec![])?;
+                }
+                for (_, rid) in rids {
+                    txn.record_write(table, rid, WriteKind::Insert);
+                    inserted_rids.push(rid);
                 }
+            }
+            Ok(inserted_rids)
+        })?;
+        let block = rids_to_block(inserted_rids)?;
+        Ok(SchemaDataIter::new(
+            block.schema(),
+            Box::new(std::iter::once(Ok(block))),
+        ))
+    }
 
-                tree.flush();
-                Ok(rid)
-            })?;
+    fn delete(&self, table: &str, data: BoxedDataIter, txn: &Txn) -> SqlResult<usize> {
+        let meta = self.get_table(table)?;
+        let schema = meta.schema_ref();
+        let indexes = self.table_indexes(&meta)?;
+        let rids = collect_rids(data)?;
+        self.in_txn(txn, |t| {
+            let (existing, rows) = Self::existing_rows(t, meta.oid, &rids)?;
+            for (_, rid) in &existing {
+                t.delete(&Self::data_key(meta.oid, *rid))?;
+                txn.record_write(table, *rid, WriteKind::Delete);
+            }
+            Self::remove_index_entries(t, schema, &indexes, &existing, rows)?;
+            Ok(existing.len())
         })
-        .await?;
-        // let tuple_ref = &tuple;
-        let rid = self.tree.transaction(move |tree| {
-            let rid = tree.generate_id()?;
-            let id = rid.to_be_bytes();
-            for item in &tuples {
-                let prefix = format!("data/{}/", table.to_string());
-                let key_bytes = prefix
-                    .into_bytes()
-                    .into_iter()
-                    .chain(id.iter().copied())
-                    .collect::<Vec<_>>();
-                tree.insert(IVec::from(key_bytes), item.data.clone())?;
+    }
+
+    fn update(&self, table: &str, data: BoxedDataIter, txn: &Txn) -> SqlResult<usize> {
+        let meta = self.get_table(table)?;
+        let schema = meta.schema_ref();
+        let indexes = self.table_indexes(&meta)?;
+        self.in_txn(txn, |t| {
+            let mut updated = 0;
+            for batch in data {
+                let batch = batch?;
+                if batch.schema().fields() != with_rid_schema(&schema).fields() {
+                    return Err(Error::Value(format!(
+                        "schema of updated data does not match table {}",
+                        table
+                    )));
+                }
+                let mut columns = batch.columns().to_vec();
+                let rids = columns.pop().unwrap();
+                let rids = rids.as_any().downcast_ref::<UInt64Array>().unwrap();
+                let values = DataBlock::try_new(schema.clone(), columns)?;
+                let rows = record_batches_to_json_rows(&[values.clone()])?;
+                let (existing, old_rows) = Self::existing_rows(t, meta.oid, rids.values())?;
+
+                for (i, rid) in &existing {
+                    t.set(
+                        &Self::data_key(meta.oid, *rid),
+                        serde_json::to_vec(&rows[*i])?,
+                    )?;
+                    txn.record_write(table, *rid, WriteKind::Update);
+                }
+                Self::remove_index_entries(t, schema.clone(), &indexes, &existing, old_rows)?;
+                for key in Self::index_keys(&indexes, &values, &existing)? {
+                    t.set(&key, vec![])?;
+                }
+                updated += existing.len();
+            }
+            Ok(updated)
+        })
+    }
+
+    fn index_scan(&self, index: &str, range: &IndexRange, txn: &Txn) -> SqlResult<BoxedDataIter> {
+        let meta: IndexMeta = match self.tree.get(Self::index_catalog_key(index))? {
+            Some(raw) => serde_json::from_slice(&raw)?,
+            None => return Err(Error::NotFound(format!("index {} does not exist", index))),
+        };
+        let prefix = Self::index_prefix(self.get_table(&meta.table)?.oid, index);
+        let (start, end) = range.key_range()?;
+        let start = [prefix.as_slice(), &start].concat();
+        let end = match end {
+            Some(end) => [prefix.as_slice(), &end].concat(),
+            None => prefix_end(&prefix).unwrap(),
+        };
+        let rids = self.in_txn(txn, |t| {
+            let mut rids = vec![];
+            for item in t.scan(Range::from(start..end)) {
+                let (key, _) = item?;
+                rids.push(Self::rid_of_key(&key)?);
+            }
+            Ok(rids)
+        })?;
+        let block = rids_to_block(rids)?;
+        Ok(SchemaDataIter::new(
+            block.schema(),
+            Box::new(std::iter::once(Ok(block))),
+        ))
+    }
+}
+
+impl Catalog for Sled {
+    fn create_table(&self, tablename: &str, schema: Schema) -> SqlResult<TableMeta> {
+        // checked before allocating an oid so that it is not wasted, the swap below
+        // still catches a table created concurrently
+        if self.tree.contains_key(Self::catalog_key(tablename))? {
+            return Err(Error::AlreadyExists(format!(
+                "table {} already exists",
+                tablename
+            )));
+        }
+        let meta = TableMeta {
+            oid: self.next_oid()?,
+            name: tablename.to_string(),
+            schema,
+        };
+        let encoded = serde_json::to_vec(&meta)?;
+        let swapped = self.tree.compare_and_swap(
+            Self::catalog_key(tablename),
+            None as Option<&[u8]>,
+            Some(encoded),
+        )?;
+        if swapped.is_err() {
+            return Err(Error::AlreadyExists(format!(
+                "table {} already exists",
+                tablename
+            )));
+        }
+        self.tree.flush()?;
+        Ok(meta)
+    }
+
+    fn get_table(&self, tablename: &str) -> SqlResult<TableMeta> {
+        match self.tree.get(Self::catalog_key(tablename))? {
+            None => Err(Error::NotFound(format!(
+                "table {} does not exist",
+                tablename
+            ))),
+            Some(raw) => Ok(serde_json::from_slice(&raw)?),
+        }
+    }
+
+    fn drop_table(&self, tablename: &str) -> SqlResult<()> {
+        let meta: TableMeta = match self.tree.remove(Self::catalog_key(tablename))? {
+            Some(raw) => serde_json::from_slice(&raw)?,
+            None => {
+                return Err(Error::NotFound(format!(
+                    "table {} does not exist",
+                    tablename
+                )))
+            }
+        };
+        let mut prefixes = vec![Self::data_prefix(meta.oid)];
+        for index in self.list_indexes(tablename)? {
+            self.tree.remove(Self::index_catalog_key(&index.name))?;
+            prefixes.push(Self::index_prefix(meta.oid, &index.name));
+        }
+        self.tree.flush()?;
+        // the table is gone once its catalog entry is, rows left behind by a failure
+        // below are unreachable since no other table gets the same oid
+        self.in_txn(&Txn::new(), |t| {
+            for prefix in prefixes {
+                for item in t.scan_prefix(&prefix) {
+                    let (key, _) = item?;
+                    t.delete(&key)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn list_tables(&self) -> SqlResult<Vec<TableMeta>> {
+        let mut ret = vec![];
+        for item in self.tree.scan_prefix(CATALOG_PREFIX) {
+            let (_, raw) = item?;
+            ret.push(serde_json::from_slice(&raw)?);
+        }
+        Ok(ret)
+    }
+
+    fn create_index(&self, index: &str, table: &str, columns: &[String]) -> SqlResult<IndexMeta> {
+        let table_meta = self.get_table(table)?;
+        let schema = table_meta.schema_ref();
+        for name in columns {
+            let field = schema.field_with_name(name).map_err(|_| {
+                Error::NotFound(format!("column {} of table {} does not exist", name, table))
+            })?;
+            if !is_encodable(field.data_type()) {
+                return Err(Error::Value(format!(
+                    "column {} of type {} cannot be indexed",
+                    name,
+                    field.data_type()
+                )));
             }
+        }
+        let meta = IndexMeta {
+            name: index.to_string(),
+            table: table.to_string(),
+            columns: columns.to_vec(),
+        };
+        let encoded = serde_json::to_vec(&meta)?;
+        let swapped = self.tree.compare_and_swap(
+            Self::index_catalog_key(index),
+            None as Option<&[u8]>,
+            Some(encoded),
+        )?;
+        if swapped.is_err() {
+            return Err(Error::AlreadyExists(format!(
+                "index {} already exists",
+                index
+            )));
+        }
 
-            tree.flush();
-            Ok(rid)
+        let positions = columns
+            .iter()
+            .map(|name| schema.index_of(name))
+            .collect::<Result<Vec<_>, _>>()?;
+        let indexes = [(Self::index_prefix(table_meta.oid, index), positions)];
+        self.in_txn(&Txn::new(), |t| {
+            for batch in self.scan_rows(t, &table_meta, true)? {
+                let batch = batch?;
+                let rids = batch
+                    .column(schema.fields().len())
+                    .as_any()
+                    .downcast_ref::<UInt64Array>()
+                    .unwrap();
+                let rows = rids
+                    .values()
+                    .iter()
+                    .copied()
+                    .enumerate()
+                    .collect::<Vec<_>>();
+                for key in Self::index_keys(&indexes, &batch, &rows)? {
+                    t.set(&key, vec![])?;
+                }
+            }
+            Ok(())
         })?;
-        Ok(rid as RID) */
+        Ok(meta)
+    }
+
+    fn list_indexes(&self, table: &str) -> SqlResult<Vec<IndexMeta>> {
+        let mut ret = vec![];
+        for item in self.tree.scan_prefix(INDEX_CATALOG_PREFIX) {
+            let (_, raw) = item?;
+            let meta: IndexMeta = serde_json::from_slice(&raw)?;
+            if meta.table == table {
+                ret.push(meta);
+            }
+        }
+        Ok(ret)
     }
+}
 
-    fn delete(&self, table: &str, data: BoxedDataIter, txn: &Txn) -> SqlResult<()> {
-        todo!()
+/// Raw key/value [Store] over a sled database, unlike [Sled] it has no notion of tables
+/// and leaves the key layout to its user
+pub struct SledStore {
+    tree: Db,
+}
+
+impl SledStore {
+    pub fn new(filename: String) -> SqlResult<Self> {
+        let tree = sled::open(filename)?;
+        Ok(SledStore { tree })
     }
 }
 
-impl Catalog for Sled {}
+impl Store for SledStore {
+    fn delete(&mut self, key: &[u8]) -> SqlResult<()> {
+        self.tree.remove(key)?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> SqlResult<()> {
+        self.tree.flush()?;
+        Ok(())
+    }
+
+    fn get(&self, key: &[u8]) -> SqlResult<Option<Vec<u8>>> {
+        Ok(self.tree.get(key)?.map(|v| v.to_vec()))
+    }
+
+    fn scan(&self, range: Range) -> Scan {
+        if range.is_empty() {
+            return Box::new(std::iter::empty());
+        }
+        Box::new(self.tree.range(range.bounds()).map(|item| {
+            let (k, v) = item?;
+            Ok((k.to_vec(), v.to_vec()))
+        }))
+    }
+
+    fn set(&mut self, key: &[u8], value: Vec<u8>) -> SqlResult<()> {
+        self.tree.insert(key, value)?;
+        Ok(())
+    }
+}
 
 #[cfg(test)]
 pub mod tests {
+    use super::{Sled, SledStore};
+    use crate::{
+        sql::{
+            exe::{
+                collect_rids, rids_to_block, with_rid_schema, Catalog, SchemaDataIter, Storage, RID,
+            },
+            scan::index::IndexRange,
+            tx::{IsolationLevel, Txn, TxnManager},
+            util::collect,
+            DataBlock, Error,
+        },
+        storage::mvcc::{tests::test_store, Range, Store},
+    };
+    use datafusion::{
+        arrow::{
+            array::{Array, Int32Array, StringArray, UInt64Array},
+            datatypes::{DataType, Field, Schema},
+        },
+        scalar::ScalarValue,
+    };
+    use std::{ops::Bound, sync::Arc};
+    use tempfile::TempDir;
+
+    fn build_table(ids: Vec<i32>, names: Vec<&str>) -> DataBlock {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int32, false),
+            Field::new("name", DataType::Utf8, true),
+        ]));
+        let columns = vec![
+            Arc::new(Int32Array::from(ids)) as Arc<dyn Array>,
+            Arc::new(StringArray::from(names)) as Arc<dyn Array>,
+        ];
+        DataBlock::try_new(schema, columns).unwrap()
+    }
+
+    fn one_block(block: DataBlock) -> Box<SchemaDataIter> {
+        SchemaDataIter::new(block.schema(), Box::new(std::iter::once(Ok(block))))
+    }
+
+    /// The background flusher of a dropped sled may hold the file lock for a moment
+    fn reopen(path: String) -> Sled {
+        for _ in 0..100 {
+            if let Ok(db) = Sled::new(path.clone()) {
+                return db;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        Sled::new(path).expect("failed reopening sled")
+    }
 
     #[test]
-    fn test_sled_catalog() {}
+    fn test_sled_catalog() {
+        let dir = TempDir::new().expect("failed creating temp dir");
+        let path = dir.path().join("db").to_str().unwrap().to_string();
+        let txn = Txn::new();
+        let schema = build_table(vec![], vec![]).schema().as_ref().clone();
+        {
+            let db = Sled::new(path.clone()).expect("failed creating sled");
+            let t1 = db.create_table("t1", schema.clone()).expect("creating t1");
+            let t12 = db
+                .create_table("t12", schema.clone())
+                .expect("creating t12");
+            assert_ne!(t1.oid, t12.oid);
+            assert!(matches!(
+                db.create_table("t1", schema.clone()),
+                Err(Error::AlreadyExists(_))
+            ));
+            let t3 = db.create_table("t3", schema.clone()).expect("creating t3");
+            assert_eq!(t12.oid + 1, t3.oid);
+            db.insert_tuples(
+                "t1",
+                one_block(build_table(vec![1, 2], vec!["a", "b"])),
+                &txn,
+            )
+            .expect("inserting into t1");
+            db.insert_tuples("t12", one_block(build_table(vec![3], vec!["c"])), &txn)
+                .expect("inserting into t12");
+            assert!(matches!(db.scan("t2", &txn), Err(Error::NotFound(_))));
+        }
+
+        // catalog and rows survive reopening, tables sharing a name prefix do not mix
+        let db = reopen(path);
+        let t1 = db.get_table("t1").expect("getting t1");
+        assert_eq!(schema, t1.schema);
+        let batches = collect(db.scan("t1", &txn).expect("scanning t1")).unwrap();
+        let expected = [
+            "+----+------+",
+            "| id | name |",
+            "+----+------+",
+            "| 1  | a    |",
+            "| 2  | b    |",
+            "+----+------+",
+        ];
+        crate::assert_batches_sorted_eq!(expected, &batches);
+
+        db.drop_table("t1").expect("dropping t1");
+        assert!(matches!(db.drop_table("t1"), Err(Error::NotFound(_))));
+        let names = db
+            .list_tables()
+            .unwrap()
+            .into_iter()
+            .map(|t| t.name)
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["t12".to_string(), "t3".to_string()], names);
+
+        // recreated table does not see rows of the dropped one
+        let recreated = db
+            .create_table("t1", schema.clone())
+            .expect("recreating t1");
+        assert_ne!(t1.oid, recreated.oid);
+        let batches = collect(db.scan("t1", &txn).unwrap()).unwrap();
+        assert!(batches.is_empty());
+
+        // not even when the rows of the dropped table could not be deleted
+        db.tree.remove(Sled::catalog_key("t12")).unwrap();
+        db.create_table("t12", schema).expect("recreating t12");
+        let batches = collect(db.scan("t12", &txn).unwrap()).unwrap();
+        assert!(batches.is_empty());
+    }
 
     /// Insert a bunch, then seq scan and compare values
     /// Don't care about order (using hashmap)
     #[test]
-    fn test_sled_raw_insert() {}
+    fn test_sled_raw_insert() {
+        let dir = TempDir::new().expect("failed creating temp dir");
+        let path = dir.path().join("db").to_str().unwrap().to_string();
+        let db = Sled::new(path).expect("failed creating sled");
+        let txn = Txn::new();
+
+        let wrong_schema = Schema::new(vec![Field::new("id", DataType::Int64, false)]);
+        db.create_table("wrong", wrong_schema).unwrap();
+        let rejected = db.insert_tuples("wrong", one_block(build_table(vec![1], vec!["a"])), &txn);
+        assert!(rejected.is_err());
+
+        let ids = (0..300).collect::<Vec<i32>>();
+        let names = ids
+            .iter()
+            .map(|i| format!("name_{}", i))
+            .collect::<Vec<_>>();
+        let batch = build_table(ids, names.iter().map(|s| s.as_str()).collect());
+        db.create_table("t", batch.schema().as_ref().clone())
+            .unwrap();
+        let rids = collect_rids(
+            db.insert_tuples("t", one_block(batch.clone()), &txn)
+                .expect("inserting tuples"),
+        )
+        .unwrap();
+        assert_eq!(300, rids.len());
+
+        let scanned = collect(db.scan("t", &txn).expect("scanning")).unwrap();
+        assert_eq!(3, scanned.len());
+        let concatenated = DataBlock::concat(&batch.schema(), &scanned).unwrap();
+        assert_eq!(batch, concatenated);
+
+        let picked = vec![rids[42], rids[7]];
+        let rid_stream = one_block(rids_to_block(picked.clone()).unwrap());
+        let fetched = collect(
+            db.get_tuples("t", rid_stream, &txn)
+                .expect("getting tuples"),
+        )
+        .unwrap();
+        let expected = [
+            "+----+---------+",
+            "| id | name    |",
+            "+----+---------+",
+            "| 42 | name_42 |",
+            "| 7  | name_7  |",
+            "+----+---------+",
+        ];
+        // in the order of the given rids
+        crate::assert_batches_eq!(expected, &fetched);
+
+        let rid_stream = one_block(rids_to_block(picked).unwrap());
+        let deleted = db.delete("t", rid_stream, &txn).expect("deleting tuples");
+        assert_eq!(2, deleted);
+        let remaining = collect(db.scan("t", &txn).unwrap()).unwrap();
+        let remaining_rows: usize = remaining.iter().map(|b| b.num_rows()).sum();
+        assert_eq!(298, remaining_rows);
+
+        let rid_stream = one_block(rids_to_block(vec![rids[42]]).unwrap());
+        assert!(db.get_tuples("t", rid_stream, &txn).is_err());
+    }
+
+    #[test]
+    fn test_sled_update() {
+        let dir = TempDir::new().expect("failed creating temp dir");
+        let path = dir.path().join("db").to_str().unwrap().to_string();
+        let db = Sled::new(path).expect("failed creating sled");
+        let txn = Txn::new();
+
+        let batch = build_table(vec![1, 2, 3], vec!["a", "b", "c"]);
+        db.create_table("t", batch.schema().as_ref().clone())
+            .unwrap();
+        let rids = collect_rids(db.insert_tuples("t", one_block(batch), &txn).unwrap()).unwrap();
+
+        let scanned = collect(db.scan_with_rids("t", &txn).unwrap()).unwrap();
+        let scanned = DataBlock::concat(&scanned[0].schema(), &scanned).unwrap();
+        assert_eq!(
+            with_rid_schema(&db.get_table("t").unwrap().schema_ref()),
+            scanned.schema()
+        );
+        assert_eq!(
+            rids,
+            collect_rids(one_block(scanned.project(&[2]).unwrap())).unwrap()
+        );
+
+        // the deleted rid is ignored
+        let deleted = rids_to_block(vec![rids[2]]).unwrap();
+        assert_eq!(1, db.delete("t", one_block(deleted), &txn).unwrap());
+        let new_values = build_table(vec![20, 30], vec!["bb", "cc"]);
+        let mut columns = new_values.columns().to_vec();
+        columns.push(Arc::new(UInt64Array::from(vec![rids[1], rids[2]])));
+        let updates = DataBlock::try_new(scanned.schema(), columns).unwrap();
+        assert_eq!(1, db.update("t", one_block(updates.clone()), &txn).unwrap());
+        assert!(db
+            .update("t", one_block(updates.project(&[0, 2]).unwrap()), &txn)
+            .is_err());
+
+        let batches = collect(db.scan("t", &txn).unwrap()).unwrap();
+        let expected = [
+            "+----+------+",
+            "| id | name |",
+            "+----+------+",
+            "| 1  | a    |",
+            "| 20 | bb   |",
+            "+----+------+",
+        ];
+        crate::assert_batches_sorted_eq!(expected, &batches);
+    }
+
+    fn count_rows(db: &Sled, txn: &Txn) -> usize {
+        collect(db.scan("t", txn).unwrap())
+            .unwrap()
+            .iter()
+            .map(|b| b.num_rows())
+            .sum()
+    }
+
+    #[test]
+    fn test_sled_txn() {
+        let dir = TempDir::new().expect("failed creating temp dir");
+        let path = dir.path().join("db").to_str().unwrap().to_string();
+        let db = Sled::new(path.clone()).expect("failed creating sled");
+        let batch = build_table(vec![1, 2], vec!["a", "b"]);
+        db.create_table("t", batch.schema().as_ref().clone())
+            .unwrap();
+
+        let mgr = TxnManager::with_mvcc(db.mvcc());
+        let t1 = mgr.begin(IsolationLevel::RepeatableRead).unwrap();
+        let t2 = mgr.begin(IsolationLevel::RepeatableRead).unwrap();
+        let rids = collect_rids(db.insert_tuples("t", one_block(batch), &t1).unwrap()).unwrap();
+        assert_eq!(2, count_rows(&db, &t1));
+        assert_eq!(0, count_rows(&db, &t2));
+        assert_eq!(0, count_rows(&db, &Txn::new()));
+        assert_eq!(2, t1.write_set().len());
+        mgr.commit(&t1).unwrap();
+        // t2 keeps its snapshot
+        assert_eq!(0, count_rows(&db, &t2));
+        assert_eq!(2, count_rows(&db, &Txn::new()));
+
+        // concurrent writes to the same row conflict
+        let t3 = mgr.begin(IsolationLevel::RepeatableRead).unwrap();
+        let t4 = mgr.begin(IsolationLevel::RepeatableRead).unwrap();
+        let deleted = || one_block(rids_to_block(vec![rids[0]]).unwrap());
+        assert_eq!(1, db.delete("t", deleted(), &t3).unwrap());
+        assert_eq!(
+            Error::Serialization,
+            db.delete("t", deleted(), &t4).unwrap_err()
+        );
+        mgr.abort(&t4).unwrap();
+        assert_eq!(1, count_rows(&db, &t3));
+        mgr.abort(&t3).unwrap();
+        assert_eq!(2, count_rows(&db, &Txn::new()));
+
+        // an unfinished txn survives a restart and stays invisible until it commits
+        let t5 = mgr.begin(IsolationLevel::RepeatableRead).unwrap();
+        let id = t5.id();
+        db.insert_tuples("t", one_block(build_table(vec![3], vec!["c"])), &t5)
+            .unwrap();
+        // txns share the underlying tree with db
+        drop((t1, t2, t3, t4, t5, mgr));
+        drop(db);
+        let db = reopen(path);
+        assert_eq!(2, count_rows(&db, &Txn::new()));
+        let mgr = TxnManager::with_mvcc(db.mvcc());
+        assert!(mgr.begin(IsolationLevel::RepeatableRead).unwrap().id() > id);
+        let t5 = mgr.resume(id, IsolationLevel::RepeatableRead).unwrap();
+        assert_eq!(3, count_rows(&db, &t5));
+        mgr.commit(&t5).unwrap();
+        assert_eq!(3, count_rows(&db, &Txn::new()));
+    }
+
+    fn index_rids(db: &Sled, range: IndexRange) -> Vec<RID> {
+        collect_rids(db.index_scan("t_name", &range, &Txn::new()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_sled_index() {
+        let dir = TempDir::new().expect("failed creating temp dir");
+        let path = dir.path().join("db").to_str().unwrap().to_string();
+        let db = Sled::new(path).expect("failed creating sled");
+        let txn = Txn::new();
+        let utf8 = |v: &str| ScalarValue::Utf8(Some(v.to_string()));
+
+        let batch = build_table(vec![1, 2], vec!["b", "d"]);
+        db.create_table("t", batch.schema().as_ref().clone())
+            .unwrap();
+        let mut rids =
+            collect_rids(db.insert_tuples("t", one_block(batch), &txn).unwrap()).unwrap();
+        // rows inserted before the index was created are indexed
+        db.create_index("t_name", "t", &["name".to_string()])
+            .expect("creating index");
+        assert!(matches!(
+            db.create_index("t_name", "t", &["id".to_string()]),
+            Err(Error::AlreadyExists(_))
+        ));
+        assert!(matches!(
+            db.create_index("t_x", "t", &["x".to_string()]),
+            Err(Error::NotFound(_))
+        ));
+        let batch = build_table(vec![3, 4, 5], vec!["a", "c", "b"]);
+        rids.extend(collect_rids(db.insert_tuples("t", one_block(batch), &txn).unwrap()).unwrap());
+
+        let all = index_rids(&db, IndexRange::point(vec![]));
+        assert_eq!(vec![rids[2], rids[0], rids[4], rids[3], rids[1]], all);
+        let b = index_rids(&db, IndexRange::point(vec![utf8("b")]));
+        assert_eq!(vec![rids[0], rids[4]], b);
+        let range = IndexRange {
+            eq: vec![],
+            lower: Bound::Excluded(utf8("a")),
+            upper: Bound::Included(utf8("c")),
+        };
+        assert_eq!(
+            vec![rids[0], rids[4], rids[3]],
+            index_rids(&db, range.clone())
+        );
+
+        // entries follow updated and deleted rows
+        let mut columns = build_table(vec![5], vec!["z"]).columns().to_vec();
+        columns.push(Arc::new(UInt64Array::from(vec![rids[4]])));
+        let updates = DataBlock::try_new(
+            with_rid_schema(&db.get_table("t").unwrap().schema_ref()),
+            columns,
+        )
+        .unwrap();
+        db.update("t", one_block(updates), &txn).unwrap();
+        let deleted = rids_to_block(vec![rids[0]]).unwrap();
+        db.delete("t", one_block(deleted), &txn).unwrap();
+        assert_eq!(vec![rids[3]], index_rids(&db, range));
+        let fetched = collect(
+            db.get_tuples(
+                "t",
+                db.index_scan("t_name", &IndexRange::point(vec![]), &txn)
+                    .unwrap(),
+                &txn,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let expected = [
+            "+----+------+",
+            "| id | name |",
+            "+----+------+",
+            "| 3  | a    |",
+            "| 4  | c    |",
+            "| 2  | d    |",
+            "| 5  | z    |",
+            "+----+------+",
+        ];
+        // in the order of the index entries
+        crate::assert_batches_eq!(expected, &fetched);
+
+        db.drop_table("t").unwrap();
+        assert!(db.list_indexes("t").unwrap().is_empty());
+        assert!(matches!(
+            db.index_scan("t_name", &IndexRange::point(vec![]), &txn),
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_sled_store() {
+        let dir = TempDir::new().expect("failed creating temp dir");
+        let path = dir.path().join("db").to_str().unwrap().to_string();
+        let mut store = SledStore::new(path.clone()).unwrap();
+        test_store(&mut store);
+
+        store.set(b"persisted", vec![1]).unwrap();
+        store.flush().unwrap();
+        drop(store);
+        let mut store = SledStore::new(path.clone());
+        for _ in 0..100 {
+            if store.is_ok() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            store = SledStore::new(path.clone());
+        }
+        let store = store.expect("failed reopening sled");
+        assert_eq!(Some(vec![1]), store.get(b"persisted").unwrap());
+        assert_eq!(
+            vec![(b"persisted".to_vec(), vec![1])],
+            store
+                .scan(Range::from(b"p".to_vec()..b"q".to_vec()))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+        );
+    }
 }